@@ -0,0 +1,737 @@
+//! Generates types of the lexicons under `lexicons/` into `$OUT_DIR/lexicon.rs`, which `src/lexicon.rs` includes.
+//!
+//! Lexicons are copies of those upstream, trimmed to what is used, and only the definitions reachable
+//! from [`ROOTS`] are generated.
+//! Each lexicon becomes a module at the path of its NSID, e.g. `app.bsky.feed.post` is `app::bsky::feed::post`,
+//! holding `ID` and a type for each definition.
+//! - `main` of a record is `Record`. `main` of a query or procedure is `QueryParams`, `InputSchema` and `OutputSchema`,
+//!   and of a subscription `QueryParams` and `OutputSchema`, the union of its messages.
+//! - Other objects are named after the definition. `main` takes the last segment of the NSID.
+//! - Unions and strings of known values declared in place are named after the object and the property.
+//!
+//! Unions are tagged by `$type`, and keep what they don't know as `Unknown` unless they are closed.
+//! They are read through `crate::lexicon::data`, so members may hold CIDs, and `cid-link` fields are
+//! read both from the tag of DAG-CBOR and from `{"$link": ...}`.
+//! Strings of known values keep the others as `Other`.
+
+use std::{
+    collections::{BTreeMap, BTreeSet, HashSet},
+    fmt::Write,
+    path::Path,
+};
+
+use serde_json::Value;
+
+const LEXICONS: &str = "lexicons";
+
+/// Definitions used here. Those they refer to are generated with them, and the rest are left out.
+/// A lexicon id stands for its `main`.
+const ROOTS: &[&str] = &[
+    "app.bsky.actor.profile",
+    "app.bsky.feed.generator",
+    "app.bsky.feed.getFeedSkeleton",
+    "app.bsky.feed.like",
+    "app.bsky.feed.post",
+    "app.bsky.feed.postgate",
+    "app.bsky.feed.repost",
+    "app.bsky.feed.threadgate",
+    "app.bsky.graph.block",
+    "app.bsky.graph.follow",
+    "app.bsky.graph.list",
+    "app.bsky.graph.listitem",
+    "com.atproto.repo.deleteRecord",
+    "com.atproto.repo.getRecord",
+    "com.atproto.repo.putRecord",
+    "com.atproto.server.createSession",
+    "com.atproto.server.refreshSession",
+    "com.atproto.sync.getRecord",
+    "com.atproto.sync.getRepo",
+    "com.atproto.sync.listHosts",
+    "com.atproto.sync.subscribeRepos",
+];
+
+/// Types used in place of what lexicons declare, as (definition, property, type).
+/// A definition without `#` stands for every definition of the lexicon.
+const OVERRIDES: &[(&str, &str, &str)] = &[
+    // Sequence numbers are never negative, and serve as cursors as they are.
+    ("com.atproto.sync.subscribeRepos", "seq", "u64"),
+    // CAR files, indexed as they are looked up.
+    (
+        "com.atproto.sync.subscribeRepos#commit",
+        "blocks",
+        "crate::lexicon::car::CommitRawBlocks",
+    ),
+    (
+        "com.atproto.sync.subscribeRepos#sync",
+        "blocks",
+        "crate::lexicon::car::CommitRawBlocks",
+    ),
+];
+
+/// Definitions kept by hand in place of what lexicons declare, as (definition, type).
+/// They are not generated, and references to them are made to the type.
+const REPLACED: &[(&str, &str)] = &[
+    // Kept readable when the uri is not, as records referring to others are.
+    ("com.atproto.repo.strongRef", "crate::lexicon::StrongRef"),
+];
+
+const KEYWORDS: &[&str] = &[
+    "abstract", "as", "async", "await", "become", "box", "break", "const", "continue", "do", "dyn",
+    "else", "enum", "extern", "false", "final", "fn", "for", "if", "impl", "in", "let", "loop",
+    "macro", "match", "mod", "move", "mut", "override", "priv", "pub", "ref", "return", "static",
+    "struct", "trait", "true", "try", "type", "typeof", "unsafe", "unsized", "use", "virtual",
+    "where", "while", "yield",
+];
+
+fn main() {
+    println!("cargo:rerun-if-changed=build.rs");
+    println!("cargo:rerun-if-changed={LEXICONS}");
+
+    let mut lexicons = BTreeMap::new();
+    collect(Path::new(LEXICONS), &mut lexicons);
+    let reachable = reachable(&lexicons);
+
+    let mut root = Module::default();
+    for (id, names) in &reachable {
+        let body = Generator::new(&lexicons, id, names).generate();
+        let module = id.split('.').fold(&mut root, |module, segment| {
+            module.children.entry(snake_case(segment)).or_default()
+        });
+        module.body = body;
+    }
+
+    let mut out = String::new();
+    for (name, module) in &root.children {
+        module.write(name, &mut out);
+    }
+    let path = Path::new(&std::env::var("OUT_DIR").unwrap()).join("lexicon.rs");
+    std::fs::write(path, out).expect("Failed to write generated lexicons");
+}
+
+/// Read every lexicon under `dir` into `lexicons` by its id.
+fn collect(dir: &Path, lexicons: &mut BTreeMap<String, Value>) {
+    let entries =
+        std::fs::read_dir(dir).unwrap_or_else(|e| panic!("Failed to read {} - {e}", dir.display()));
+    for entry in entries {
+        let path = entry.unwrap().path();
+        if path.is_dir() {
+            collect(&path, lexicons);
+        } else if path.extension().is_some_and(|ext| ext == "json") {
+            let lexicon: Value = serde_json::from_slice(&std::fs::read(&path).unwrap())
+                .unwrap_or_else(|e| panic!("Invalid lexicon {} - {e}", path.display()));
+            let id = lexicon["id"]
+                .as_str()
+                .unwrap_or_else(|| panic!("Lexicon without id - {}", path.display()))
+                .to_string();
+            lexicons.insert(id, lexicon);
+        }
+    }
+}
+
+/// Definitions reachable from [`ROOTS`], by the id of their lexicon.
+fn reachable(lexicons: &BTreeMap<String, Value>) -> BTreeMap<String, BTreeSet<String>> {
+    let mut ret = BTreeMap::<String, BTreeSet<String>>::new();
+    let mut queue: Vec<_> = ROOTS.iter().map(|root| definition_of("", root)).collect();
+    while let Some((id, name)) = queue.pop() {
+        if !ret.entry(id.clone()).or_default().insert(name.clone())
+            || replaced(&id, &name).is_some()
+        {
+            continue;
+        }
+        let def = lexicons
+            .get(&id)
+            .and_then(|lexicon| lexicon["defs"].get(&name))
+            .unwrap_or_else(|| panic!("Unknown definition {id}#{name}"));
+        references(def, &mut |reference| {
+            queue.push(definition_of(&id, reference))
+        });
+    }
+    ret
+}
+
+/// Type kept by hand in place of the definition `name` of the lexicon `id`.
+fn replaced(id: &str, name: &str) -> Option<&'static str> {
+    REPLACED.iter().find_map(|(def, ty)| {
+        let matches = match def.split_once('#') {
+            Some((def_id, def_name)) => def_id == id && def_name == name,
+            None => *def == id && name == "main",
+        };
+        matches.then_some(*ty)
+    })
+}
+
+/// Every reference made in `schema`, by `ref` or among `refs` of a union.
+fn references<'v>(schema: &'v Value, f: &mut impl FnMut(&'v str)) {
+    match schema {
+        Value::Object(object) => {
+            for (key, value) in object {
+                match (key.as_str(), value) {
+                    ("ref", Value::String(reference)) => f(reference),
+                    ("refs", Value::Array(_)) => strings(value).into_iter().for_each(&mut *f),
+                    _ => references(value, f),
+                }
+            }
+        }
+        Value::Array(items) => items.iter().for_each(|item| references(item, f)),
+        _ => {}
+    }
+}
+
+/// Lexicon id and definition name of a reference made in the lexicon `id`.
+fn definition_of(id: &str, reference: &str) -> (String, String) {
+    match reference.split_once('#') {
+        Some(("", name)) => (id.to_string(), name.to_string()),
+        Some((id, name)) => (id.to_string(), name.to_string()),
+        None => (reference.to_string(), "main".to_string()),
+    }
+}
+
+#[derive(Default)]
+struct Module {
+    body: String,
+    children: BTreeMap<String, Module>,
+}
+
+impl Module {
+    fn write(&self, name: &str, out: &mut String) {
+        writeln!(out, "pub mod {name} {{").unwrap();
+        out.push_str(&self.body);
+        for (name, child) in &self.children {
+            child.write(name, out);
+        }
+        out.push_str("}\n");
+    }
+}
+
+/// Generates the module of a lexicon.
+struct Generator<'a> {
+    lexicons: &'a BTreeMap<String, Value>,
+    id: &'a str,
+    /// Definitions to generate.
+    names: &'a BTreeSet<String>,
+    out: String,
+    /// Types generated so far.
+    claimed: HashSet<String>,
+    /// Name of the definition being generated.
+    current: &'a str,
+}
+
+impl<'a> Generator<'a> {
+    fn new(
+        lexicons: &'a BTreeMap<String, Value>,
+        id: &'a str,
+        names: &'a BTreeSet<String>,
+    ) -> Self {
+        Self {
+            lexicons,
+            id,
+            names,
+            out: String::new(),
+            claimed: HashSet::new(),
+            current: "main",
+        }
+    }
+
+    fn generate(mut self) -> String {
+        let lexicon = &self.lexicons[self.id];
+        doc(&mut self.out, "//!", lexicon["description"].as_str());
+        writeln!(self.out, "pub const ID: &str = {:?};", self.id).unwrap();
+
+        let names = self.names;
+        let wanted = |name: &str| names.contains(name) && replaced(self.id, name).is_none();
+        for (name, def) in defs(lexicon).filter(|(name, _)| wanted(name)) {
+            self.current = name;
+            let description = def["description"].as_str();
+            match kind(def) {
+                "record" => self.object("Record", description, &def["record"]),
+                "query" | "procedure" => {
+                    if let Some(parameters) = def.get("parameters") {
+                        self.object("QueryParams", description, parameters);
+                    }
+                    if let Some(schema) = def.get("input").and_then(|input| input.get("schema")) {
+                        self.schema("InputSchema", schema);
+                    }
+                    if let Some(schema) = def.get("output").and_then(|output| output.get("schema"))
+                    {
+                        self.schema("OutputSchema", schema);
+                    }
+                }
+                "subscription" => {
+                    if let Some(parameters) = def.get("parameters") {
+                        self.object("QueryParams", description, parameters);
+                    }
+                    self.messages(&def["message"]["schema"]);
+                }
+                "object" => {
+                    let type_name = self.type_name(self.id, name);
+                    self.object(&type_name, description, def);
+                }
+                "token" => {
+                    doc(&mut self.out, "///", description);
+                    writeln!(
+                        self.out,
+                        "pub const {}: &str = \"{}#{name}\";",
+                        screaming_snake_case(name),
+                        self.id
+                    )
+                    .unwrap();
+                }
+                "string" if is_enum(def) => {
+                    let type_name = self.type_name(self.id, name);
+                    self.string_enum(&type_name, description, def);
+                }
+                _ => {
+                    let type_name = self.type_name(self.id, name);
+                    let ty = self.field_type(&type_name, "", def);
+                    self.claim(&type_name);
+                    doc(&mut self.out, "///", description);
+                    writeln!(self.out, "pub type {type_name} = {ty};").unwrap();
+                }
+            }
+        }
+
+        self.out
+    }
+
+    /// Body of a query or procedure, which is usually an object but may be a reference or a union.
+    fn schema(&mut self, name: &str, schema: &Value) {
+        if kind(schema) == "object" {
+            self.object(name, schema["description"].as_str(), schema);
+        } else {
+            let ty = self.field_type(name, "", schema);
+            self.claim(name);
+            writeln!(self.out, "pub type {name} = {ty};").unwrap();
+        }
+    }
+
+    fn object(&mut self, name: &str, description: Option<&str>, schema: &Value) {
+        self.claim(name);
+        let required = strings(&schema["required"]);
+        let nullable = strings(&schema["nullable"]);
+
+        let mut out = String::new();
+        doc(&mut out, "///", description);
+        out.push_str("#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]\n");
+        out.push_str("#[serde(rename_all = \"camelCase\")]\n");
+        writeln!(out, "pub struct {name} {{").unwrap();
+        let properties = schema["properties"].as_object().into_iter().flatten();
+        for (property, field) in properties {
+            let field_name = snake_case(property);
+            let mut attributes = vec![];
+            if camel_case(&field_name) != *property {
+                attributes.push(format!("rename = {property:?}"));
+            }
+            let mut ty = match self.field_override(property) {
+                Some(ty) => ty.to_string(),
+                None => self.field_type(name, property, field),
+            };
+            if !required.contains(&property.as_str()) {
+                match (field.get("default"), ty.as_str()) {
+                    (Some(default), "i64" | "bool" | "String") => {
+                        let function = format!("default_{}_{field_name}", snake_case(name));
+                        let value = match default {
+                            Value::String(value) => format!("{value:?}.to_string()"),
+                            value => value.to_string(),
+                        };
+                        writeln!(self.out, "fn {function}() -> {ty} {{\n{value}\n}}").unwrap();
+                        attributes.push(format!("default = {function:?}"));
+                    }
+                    _ => {
+                        ty = format!("Option<{ty}>");
+                        attributes.push("default".to_string());
+                        attributes.push("skip_serializing_if = \"Option::is_none\"".to_string());
+                    }
+                }
+            } else if nullable.contains(&property.as_str()) {
+                ty = format!("Option<{ty}>");
+            }
+
+            let with = match ty.as_str() {
+                "rs_car::Cid" => Some("crate::lexicon::data::cid_link"),
+                "Option<rs_car::Cid>" => Some("crate::lexicon::data::cid_link::option"),
+                "Vec<rs_car::Cid>" => Some("crate::lexicon::data::cid_link::vec"),
+                _ => None,
+            };
+            if let Some(with) = with {
+                attributes.push(format!("with = {with:?}"));
+            }
+
+            doc(&mut out, "///", field["description"].as_str());
+            if !attributes.is_empty() {
+                writeln!(out, "#[serde({})]", attributes.join(", ")).unwrap();
+            }
+            writeln!(out, "pub {}: {ty},", escape(&field_name)).unwrap();
+        }
+        out.push_str("}\n");
+        self.out.push_str(&out);
+    }
+
+    /// Type of a property of `owner`, generating types declared in place.
+    fn field_type(&mut self, owner: &str, property: &str, schema: &Value) -> String {
+        match kind(schema) {
+            "boolean" => "bool".to_string(),
+            "integer" => "i64".to_string(),
+            "string" if is_enum(schema) => {
+                let name = format!("{owner}{}", pascal_case(property));
+                self.string_enum(&name, None, schema);
+                name
+            }
+            "string" => "String".to_string(),
+            "bytes" => "serde_bytes::ByteBuf".to_string(),
+            "cid-link" => "rs_car::Cid".to_string(),
+            "blob" => "crate::lexicon::Blob".to_string(),
+            "unknown" => "serde_json::Value".to_string(),
+            "array" => format!(
+                "Vec<{}>",
+                self.field_type(owner, &format!("{property}Item"), &schema["items"])
+            ),
+            "ref" => self.reference(schema["ref"].as_str().unwrap()),
+            "union" => {
+                let name = format!("{owner}{}", pascal_case(property));
+                self.union(&name, schema);
+                name
+            }
+            other => panic!(
+                "Unsupported type {other} of {owner}.{property} in {}",
+                self.id
+            ),
+        }
+    }
+
+    fn field_override(&self, property: &str) -> Option<&'static str> {
+        OVERRIDES.iter().find_map(|(def, field, ty)| {
+            let matches = match def.split_once('#') {
+                Some((id, name)) => id == self.id && name == self.current,
+                None => *def == self.id,
+            };
+            (matches && *field == property).then_some(*ty)
+        })
+    }
+
+    fn string_enum(&mut self, name: &str, description: Option<&str>, schema: &Value) {
+        self.claim(name);
+        let closed = schema.get("enum").is_some();
+        let values = strings(schema.get("enum").unwrap_or(&schema["knownValues"]));
+
+        doc(&mut self.out, "///", description);
+        self.out.push_str(
+            "#[derive(Debug, Clone, PartialEq, Eq, Hash, serde::Serialize, serde::Deserialize)]\n",
+        );
+        writeln!(self.out, "pub enum {name} {{").unwrap();
+        for value in values {
+            let variant = value.rsplit(['#', '.']).next().unwrap();
+            writeln!(
+                self.out,
+                "#[serde(rename = {value:?})]\n{},",
+                pascal_case(variant)
+            )
+            .unwrap();
+        }
+        if !closed {
+            self.out.push_str("#[serde(untagged)]\nOther(String),\n");
+        }
+        self.out.push_str("}\n");
+    }
+
+    fn union(&mut self, name: &str, schema: &Value) {
+        self.claim(name);
+        let closed = schema["closed"].as_bool().unwrap_or_default();
+
+        let variants = self.variants(schema);
+
+        doc(&mut self.out, "///", schema["description"].as_str());
+        self.out
+            .push_str("#[derive(Debug, Clone, PartialEq, serde::Serialize)]\n");
+        self.out.push_str("#[serde(tag = \"$type\")]\n");
+        writeln!(self.out, "pub enum {name} {{").unwrap();
+        for (variant, tag, ty) in &variants {
+            writeln!(
+                self.out,
+                "#[serde(rename = {tag:?})]\n{variant}(Box<{ty}>),"
+            )
+            .unwrap();
+        }
+        if !closed {
+            self.out.push_str(
+                "#[serde(untagged, serialize_with = \"crate::lexicon::data::serialize\")]\n\
+                 Unknown(serde_json::Value),\n",
+            );
+        }
+        self.out.push_str("}\n");
+
+        // Members are read from the data model value of the whole union, as serde can't buffer CIDs.
+        writeln!(
+            self.out,
+            "impl<'de> serde::Deserialize<'de> for {name} {{\n\
+             fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {{\n\
+             let value = crate::lexicon::data::deserialize(deserializer)?;\n\
+             Ok(match crate::lexicon::data::type_of(&value) {{"
+        )
+        .unwrap();
+        for (variant, tag, _) in &variants {
+            let alias = if tag.contains('#') {
+                String::new()
+            } else {
+                format!(" | Some(\"{tag}#main\")")
+            };
+            writeln!(
+                self.out,
+                "Some({tag:?}){alias} => Self::{variant}(crate::lexicon::data::from_value(value)?),"
+            )
+            .unwrap();
+        }
+        if closed {
+            writeln!(
+                self.out,
+                "other => return Err(serde::de::Error::custom(format!(\"Unknown $type of {name} - {{other:?}}\"))),"
+            )
+            .unwrap();
+        } else {
+            self.out.push_str("_ => Self::Unknown(value),\n");
+        }
+        self.out.push_str("})\n}\n}\n");
+    }
+
+    /// Messages of a subscription. Frames tell the type of the message in their header instead of `$type`.
+    fn messages(&mut self, schema: &Value) {
+        self.claim("OutputSchema");
+        let variants = self.variants(schema);
+
+        // Read only by `from_cbor`, as deserializing a tagged enum buffers the content and loses CIDs.
+        self.out
+            .push_str("#[derive(Debug, Clone, PartialEq, serde::Serialize)]\n");
+        self.out.push_str("#[serde(tag = \"$type\")]\n");
+        self.out.push_str("pub enum OutputSchema {\n");
+        for (variant, tag, ty) in &variants {
+            writeln!(
+                self.out,
+                "#[serde(rename = {tag:?})]\n{variant}(Box<{ty}>),"
+            )
+            .unwrap();
+        }
+        self.out.push_str(
+            "/// Message of a type added after this was generated. `raw` is the DAG-CBOR body.\n\
+             #[serde(skip)]\n\
+             Unknown { tag: String, raw: Vec<u8> },\n}\n",
+        );
+
+        self.out.push_str(
+            "impl OutputSchema {\n\
+             pub fn from_cbor(tag: &str, bytes: &[u8]) -> anyhow::Result<Self> {\n\
+             use anyhow::Context;\n\
+             Ok(match tag {\n",
+        );
+        // Frames may be as large as a CAR, so only the start of them is kept in errors.
+        let refs = strings(&schema["refs"]);
+        for ((variant, _, _), reference) in variants.iter().zip(refs) {
+            let name = reference.trim_start_matches('#');
+            writeln!(
+                self.out,
+                "{reference:?} => OutputSchema::{variant}(\n\
+                 serde_ipld_dagcbor::from_slice(bytes)\n\
+                 .with_context(|| format!(\"tag: {name}, data: {{:?}} ({{}} bytes)\", \
+                 &bytes[..bytes.len().min(64)], bytes.len()))?,\n\
+                 ),"
+            )
+            .unwrap();
+        }
+        self.out.push_str(
+            "unknown => OutputSchema::Unknown {\n\
+             tag: unknown.to_string(),\n\
+             raw: bytes.to_vec(),\n\
+             },\n})\n}\n}\n",
+        );
+    }
+
+    /// (variant, `$type`, type) of each member of a union.
+    fn variants(&self, schema: &Value) -> Vec<(String, String, String)> {
+        let refs = strings(&schema["refs"]);
+        let names: Vec<_> = refs
+            .iter()
+            .map(|reference| {
+                let (id, name) = self.resolve(reference);
+                if name == "main" {
+                    pascal_case(id.rsplit('.').next().unwrap())
+                } else {
+                    pascal_case(name)
+                }
+            })
+            .collect();
+
+        refs.iter()
+            .zip(&names)
+            .map(|(reference, variant)| {
+                let (id, name) = self.resolve(reference);
+                // Members of the same name from different lexicons are told apart by the lexicon.
+                let variant = if names.iter().filter(|other| *other == variant).count() > 1 {
+                    format!("{}{variant}", pascal_case(id.rsplit('.').next().unwrap()))
+                } else {
+                    variant.clone()
+                };
+                let tag = if name == "main" {
+                    id.to_string()
+                } else {
+                    format!("{id}#{name}")
+                };
+                (variant, tag, self.reference(reference))
+            })
+            .collect()
+    }
+
+    /// Split a reference into the lexicon id and the definition name.
+    fn resolve<'r>(&self, reference: &'r str) -> (&'r str, &'r str)
+    where
+        'a: 'r,
+    {
+        match reference.split_once('#') {
+            Some(("", name)) => (self.id, name),
+            Some((id, name)) => (id, name),
+            None => (reference, "main"),
+        }
+    }
+
+    /// Path of the type a reference points to.
+    fn reference(&self, reference: &str) -> String {
+        let (id, name) = self.resolve(reference);
+        if let Some(ty) = replaced(id, name) {
+            return ty.to_string();
+        }
+        let def = self
+            .lexicons
+            .get(id)
+            .and_then(|lexicon| lexicon["defs"].get(name))
+            .unwrap_or_else(|| panic!("Unknown reference {reference} in {}", self.id));
+        match kind(def) {
+            "token" => return "String".to_string(),
+            "string" if !is_enum(def) => return "String".to_string(),
+            _ => {}
+        }
+
+        let type_name = self.type_name(id, name);
+        if id == self.id {
+            type_name
+        } else {
+            let path: Vec<_> = id.split('.').map(snake_case).collect();
+            format!("crate::lexicon::{}::{type_name}", path.join("::"))
+        }
+    }
+
+    fn type_name(&self, id: &str, name: &str) -> String {
+        let defs = &self.lexicons[id]["defs"];
+        if name != "main" {
+            return pascal_case(name);
+        }
+        if kind(&defs["main"]) == "record" {
+            return "Record".to_string();
+        }
+
+        let type_name = pascal_case(id.rsplit('.').next().unwrap());
+        let taken = defs
+            .as_object()
+            .unwrap()
+            .keys()
+            .any(|other| other != "main" && pascal_case(other) == type_name);
+        if taken {
+            "Main".to_string()
+        } else {
+            type_name
+        }
+    }
+
+    fn claim(&mut self, name: &str) {
+        if !self.claimed.insert(name.to_string()) {
+            panic!("{name} is generated twice in {}", self.id);
+        }
+    }
+}
+
+/// Definitions in the order they are declared, with `main` first.
+fn defs(lexicon: &Value) -> impl Iterator<Item = (&String, &Value)> {
+    let defs = lexicon["defs"]
+        .as_object()
+        .unwrap_or_else(|| panic!("Lexicon without defs - {}", lexicon["id"]));
+    let main = defs.iter().filter(|(name, _)| *name == "main");
+    main.chain(defs.iter().filter(|(name, _)| *name != "main"))
+}
+
+fn kind(schema: &Value) -> &str {
+    schema["type"]
+        .as_str()
+        .unwrap_or_else(|| panic!("Schema without type - {schema}"))
+}
+
+fn is_enum(schema: &Value) -> bool {
+    schema.get("enum").is_some() || schema.get("knownValues").is_some()
+}
+
+fn strings(value: &Value) -> Vec<&str> {
+    value
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(Value::as_str)
+        .collect()
+}
+
+fn doc(out: &mut String, prefix: &str, description: Option<&str>) {
+    for line in description.into_iter().flat_map(str::lines) {
+        writeln!(out, "{prefix} {line}").unwrap();
+    }
+}
+
+fn escape(name: &str) -> String {
+    match name {
+        "self" | "super" | "crate" => format!("{name}_"),
+        _ if KEYWORDS.contains(&name) => format!("r#{name}"),
+        _ => name.to_string(),
+    }
+}
+
+/// `getFeedSkeleton` to `get_feed_skeleton`.
+fn snake_case(name: &str) -> String {
+    let mut ret = String::new();
+    for c in name.chars() {
+        if c.is_ascii_uppercase() {
+            if !ret.is_empty() {
+                ret.push('_');
+            }
+            ret.push(c.to_ascii_lowercase());
+        } else if c.is_ascii_alphanumeric() {
+            ret.push(c);
+        } else if !ret.is_empty() && !ret.ends_with('_') {
+            ret.push('_');
+        }
+    }
+    ret
+}
+
+fn screaming_snake_case(name: &str) -> String {
+    snake_case(name).to_ascii_uppercase()
+}
+
+/// `feed_context` to `feedContext`, the way serde renames fields.
+fn camel_case(name: &str) -> String {
+    let pascal = pascal_case(name);
+    let mut chars = pascal.chars();
+    chars
+        .next()
+        .map(|first| first.to_ascii_lowercase().to_string() + chars.as_str())
+        .unwrap_or_default()
+}
+
+/// `skeletonFeedPost` and `no-unauthenticated` to `SkeletonFeedPost` and `NoUnauthenticated`.
+fn pascal_case(name: &str) -> String {
+    let mut ret = String::new();
+    let mut upper = true;
+    for c in name.chars() {
+        if !c.is_ascii_alphanumeric() {
+            upper = true;
+        } else if upper {
+            ret.push(c.to_ascii_uppercase());
+            upper = false;
+        } else {
+            ret.push(c);
+        }
+    }
+    ret
+}
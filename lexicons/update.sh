@@ -0,0 +1,35 @@
+#!/bin/sh
+# Replace the lexicons here with those of bluesky-social/atproto at a commit, as they are,
+# and keep the commit in REVISION. build.rs picks the definitions it needs out of them.
+#
+#     lexicons/update.sh <commit>
+set -eu
+
+rev=${1:-}
+case "$rev" in
+*[!0-9a-f]* | "")
+	echo "usage: $0 <full commit hash of bluesky-social/atproto>" >&2
+	exit 1
+	;;
+esac
+if [ "${#rev}" -ne 40 ]; then
+	echo "usage: $0 <full commit hash of bluesky-social/atproto>" >&2
+	exit 1
+fi
+
+dir=$(cd "$(dirname "$0")" && pwd)
+tmp=$(mktemp -d)
+trap 'rm -rf "$tmp"' EXIT
+
+curl -fsSL "https://codeload.github.com/bluesky-social/atproto/tar.gz/$rev" | tar -xzf - -C "$tmp"
+src="$tmp/atproto-$rev/lexicons"
+if [ ! -d "$src" ]; then
+	echo "No lexicons at $rev" >&2
+	exit 1
+fi
+
+find "$dir" -mindepth 1 -maxdepth 1 -type d -exec rm -rf {} +
+for namespace in "$src"/*/; do
+	cp -R "$namespace" "$dir/"
+done
+echo "$rev" >"$dir/REVISION"
@@ -0,0 +1,9 @@
+-- Events which could not be processed, kept to be retried later
+CREATE TABLE IF NOT EXISTS "dead_letter" (
+    "id" integer primary key autoincrement,
+    "seq" integer,
+    "repo" varchar,
+    "frame" blob not null,
+    "error" varchar not null,
+    "createdAt" varchar not null
+);
@@ -0,0 +1,6 @@
+-- Latest rev of each repository, to tell whether a commit follows the previous one
+CREATE TABLE IF NOT EXISTS "repo_rev" (
+    "did" varchar primary key,
+    "rev" varchar not null,
+    "desyncedAt" varchar
+);
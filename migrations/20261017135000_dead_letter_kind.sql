@@ -0,0 +1,2 @@
+-- Why each dead letter is kept: 'failed' to be processed, or 'too_big' waiting for its records to be fetched
+ALTER TABLE "dead_letter" ADD COLUMN "kind" varchar not null default 'failed';
@@ -0,0 +1,7 @@
+-- Feeds each post is put in, by the shortname of the feed
+CREATE TABLE IF NOT EXISTS "feed_post" (
+    "feed" varchar not null,
+    "uri" varchar not null,
+    primary key ("feed", "uri")
+);
+CREATE INDEX IF NOT EXISTS "feed_post_uri" ON "feed_post" ("uri");
@@ -1,20 +1,24 @@
-use std::{collections::HashMap, sync::Arc};
+use std::collections::HashMap;
 
 use async_trait::async_trait;
 use sqlx::SqlitePool;
 
-use crate::{config::Config, lexicon::app::bsky::feed::get_feed_skeleton};
-mod eueoeo;
+use crate::{
+    config::FeedConfig,
+    lexicon::{app::bsky::feed::get_feed_skeleton, identifier::RecordKey},
+};
+mod feed;
+
+pub use feed::{adopt, prune};
 
 #[derive(Clone)]
 pub struct Context {
     pub db: SqlitePool,
-    pub config: Arc<Config>,
 }
 
 #[async_trait]
 pub trait AlgoHandler {
-    fn short_name(&self) -> &str;
+    fn short_name(&self) -> &RecordKey;
     async fn handle(
         &self,
         context: Context,
@@ -22,12 +26,14 @@ pub trait AlgoHandler {
     ) -> anyhow::Result<get_feed_skeleton::OutputSchema>;
 }
 
-pub type AlgoHandlers = HashMap<String, Box<dyn AlgoHandler + Send + Sync>>;
+pub type AlgoHandlers = HashMap<RecordKey, Box<dyn AlgoHandler + Send + Sync>>;
 
-pub fn create() -> AlgoHandlers {
+/// Handlers of the feeds declared in the config.
+pub fn create(feeds: &[FeedConfig]) -> AlgoHandlers {
     type B = Box<dyn AlgoHandler + Send + Sync>;
-    [Box::new(eueoeo::Handler) as B]
-        .into_iter()
-        .map(|h| (h.short_name().to_string(), h))
+    feeds
+        .iter()
+        .map(|feed| Box::new(feed::Handler::new(feed.clone())) as B)
+        .map(|h| (h.short_name().clone(), h))
         .collect()
 }
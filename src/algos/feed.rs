@@ -0,0 +1,396 @@
+//! Feeds declared in the config, of the posts tagged with their shortname in `feed_post`.
+
+use anyhow::Context as _;
+use async_trait::async_trait;
+use chrono::Utc;
+use sqlx::SqlitePool;
+
+use crate::{
+    config::{FeedConfig, FeedSort, LEGACY_FEED},
+    data::Post,
+    lexicon::{
+        app::bsky::feed::{defs::SkeletonFeedPost, get_feed_skeleton},
+        identifier::RecordKey,
+    },
+};
+
+use super::{AlgoHandler, Context};
+
+/// Cursor times below this are in milliseconds, as those up to the year 5138 are,
+/// while ones in nanoseconds are of posts indexed after the second day of 1970.
+const MILLIS_CURSOR_BOUND: i64 = 100_000_000_000_000;
+
+/// Key of `app_state` telling posts indexed before feeds were declared are put in [`LEGACY_FEED`].
+const ADOPTED_KEY: &str = "feed_post_adopted";
+
+pub struct Handler {
+    feed: FeedConfig,
+}
+
+impl Handler {
+    pub fn new(feed: FeedConfig) -> Self {
+        Self { feed }
+    }
+}
+
+#[async_trait]
+impl AlgoHandler for Handler {
+    fn short_name(&self) -> &RecordKey {
+        &self.feed.shortname
+    }
+
+    async fn handle(
+        &self,
+        context: Context,
+        params: get_feed_skeleton::QueryParams,
+    ) -> anyhow::Result<get_feed_skeleton::OutputSchema> {
+        // Cursors are `{indexedAt in nanoseconds}::{cid}`, so as to point to the last post exactly.
+        // Those given out before were in milliseconds, which are told apart by how small they are.
+        let (time, cid) = if let Some(cursor) = params.cursor {
+            let (indexed_at, cid) = cursor.split_once("::").context("malformed cursor")?;
+            let time = indexed_at
+                .parse::<i64>()
+                .context("malformed cursor - invalid indexedAt part")?;
+            let time = if time.abs() < MILLIS_CURSOR_BOUND {
+                chrono::DateTime::<Utc>::from_timestamp_millis(time)
+                    .context("malformed cursor - invalid indexedAt part")?
+            } else {
+                chrono::DateTime::<Utc>::from_timestamp_nanos(time)
+            };
+            (Some(time.to_rfc3339()), Some(cid.to_string()))
+        } else {
+            (None, None)
+        };
+        let shortname = self.feed.shortname.as_str();
+        // Every post is indexed after the empty string, as after a retention longer than time goes back.
+        let since = self
+            .feed
+            .retention
+            .and_then(|retention| Utc::now().checked_sub_signed(retention))
+            .map(|since| since.to_rfc3339())
+            .unwrap_or_default();
+
+        let feed = match self.feed.sort {
+            FeedSort::Newest => {
+                sqlx::query_as!(
+                    Post,
+                    r#"
+                    SELECT `post`.`uri` AS "uri?", `post`.`cid` AS "cid?",
+                        `post`.`indexedAt` AS "indexedAt?"
+                        FROM `post` JOIN `feed_post` ON `feed_post`.`uri` = `post`.`uri`
+                        WHERE `feed_post`.`feed` = ?1 AND `post`.`indexedAt` >= ?2 AND (
+                            ?3 IS NULL OR `post`.`indexedAt` < ?3 OR (
+                                `post`.`indexedAt` = ?3 AND `post`.`cid` < ?4
+                            )
+                        )
+                        ORDER BY `post`.`indexedAt` DESC, `post`.`cid` DESC
+                        LIMIT ?5
+                    "#,
+                    shortname,
+                    since,
+                    time,
+                    cid,
+                    params.limit
+                )
+                .fetch_all(&context.db)
+                .await?
+            }
+            FeedSort::Oldest => {
+                sqlx::query_as!(
+                    Post,
+                    r#"
+                    SELECT `post`.`uri` AS "uri?", `post`.`cid` AS "cid?",
+                        `post`.`indexedAt` AS "indexedAt?"
+                        FROM `post` JOIN `feed_post` ON `feed_post`.`uri` = `post`.`uri`
+                        WHERE `feed_post`.`feed` = ?1 AND `post`.`indexedAt` >= ?2 AND (
+                            ?3 IS NULL OR `post`.`indexedAt` > ?3 OR (
+                                `post`.`indexedAt` = ?3 AND `post`.`cid` > ?4
+                            )
+                        )
+                        ORDER BY `post`.`indexedAt` ASC, `post`.`cid` ASC
+                        LIMIT ?5
+                    "#,
+                    shortname,
+                    since,
+                    time,
+                    cid,
+                    params.limit
+                )
+                .fetch_all(&context.db)
+                .await?
+            }
+        };
+
+        let cursor = if let Some(last) = feed.last() {
+            let last_indexed_at = unsafe { last.indexedAt.as_ref().unwrap_unchecked() };
+            let timestamp = chrono::DateTime::parse_from_rfc3339(last_indexed_at)
+                .with_context(|| last_indexed_at.clone())?
+                .timestamp_nanos_opt()
+                .with_context(|| format!("indexedAt out of range - {last_indexed_at}"))?;
+            Some(format!("{}::{}", timestamp, unsafe {
+                last.cid.as_ref().unwrap_unchecked()
+            }))
+        } else {
+            None
+        };
+
+        let feed = feed
+            .into_iter()
+            .map(|f| SkeletonFeedPost {
+                post: unsafe { f.uri.unwrap_unchecked() },
+                reason: None,
+                feed_context: None,
+            })
+            .collect();
+
+        Ok(get_feed_skeleton::OutputSchema { cursor, feed })
+    }
+}
+
+/// Put posts in no feed, which are those indexed before feeds were declared, in [`LEGACY_FEED`].
+/// Done once, as recorded in `app_state`. Returns how many posts are put in it.
+pub async fn adopt(db: &SqlitePool) -> anyhow::Result<u64> {
+    let mut tx = db.begin().await?;
+    let adopted = sqlx::query!(
+        "SELECT `value` FROM `app_state` WHERE `key` = ?",
+        ADOPTED_KEY
+    )
+    .fetch_optional(&mut *tx)
+    .await?;
+    if adopted.is_some() {
+        return Ok(0);
+    }
+
+    let adopted = sqlx::query!(
+        r#"
+        INSERT OR IGNORE INTO `feed_post` (`feed`, `uri`)
+            SELECT ?, `uri` FROM `post` WHERE `uri` NOT IN (SELECT `uri` FROM `feed_post`)
+        "#,
+        LEGACY_FEED
+    )
+    .execute(&mut *tx)
+    .await?
+    .rows_affected();
+    sqlx::query!(
+        "INSERT INTO `app_state` (`key`, `value`) VALUES (?, 'true')",
+        ADOPTED_KEY
+    )
+    .execute(&mut *tx)
+    .await?;
+    tx.commit().await?;
+
+    Ok(adopted)
+}
+
+/// Take posts out of feeds past their retention and out of feeds no longer declared,
+/// and remove posts left in no feed. Returns how many posts are taken out of feeds.
+pub async fn prune(db: &SqlitePool, feeds: &[FeedConfig]) -> anyhow::Result<u64> {
+    let shortnames = serde_json::to_string(
+        &feeds
+            .iter()
+            .map(|feed| feed.shortname.as_str())
+            .collect::<Vec<_>>(),
+    )?;
+    let mut pruned = sqlx::query!(
+        r#"
+        DELETE FROM `feed_post` WHERE `feed` NOT IN (SELECT `value` FROM json_each(?))
+        "#,
+        shortnames
+    )
+    .execute(db)
+    .await?
+    .rows_affected();
+    for feed in feeds {
+        // No post is indexed before a retention longer than time goes back.
+        let Some(before) = feed
+            .retention
+            .and_then(|retention| Utc::now().checked_sub_signed(retention))
+        else {
+            continue;
+        };
+        let shortname = feed.shortname.as_str();
+        let before = before.to_rfc3339();
+        pruned += sqlx::query!(
+            r#"
+            DELETE FROM `feed_post` WHERE `feed` = ? AND `uri` IN (
+                SELECT `uri` FROM `post` WHERE `indexedAt` < ?
+            )
+            "#,
+            shortname,
+            before
+        )
+        .execute(db)
+        .await?
+        .rows_affected();
+    }
+    sqlx::query!("DELETE FROM `post` WHERE `uri` NOT IN (SELECT `uri` FROM `feed_post`)")
+        .execute(db)
+        .await?;
+
+    Ok(pruned)
+}
+
+#[cfg(test)]
+mod tests {
+    use eueoeo_feed::atproto_subscription::mock_relay::memory_db;
+
+    use super::*;
+
+    /// Post indexed `age` minutes ago, in `feeds`.
+    async fn insert(db: &SqlitePool, rkey: &str, age: i64, feeds: &[&str]) {
+        let uri = format!("at://did:plc:alice/app.bsky.feed.post/{rkey}");
+        let indexed_at = (Utc::now() - chrono::Duration::minutes(age)).to_rfc3339();
+        sqlx::query("INSERT INTO `post` VALUES (?, ?, 'did:plc:alice', ?)")
+            .bind(&uri)
+            .bind(format!("bafy{rkey}"))
+            .bind(indexed_at)
+            .execute(db)
+            .await
+            .unwrap();
+        for feed in feeds {
+            sqlx::query("INSERT INTO `feed_post` VALUES (?, ?)")
+                .bind(feed)
+                .bind(&uri)
+                .execute(db)
+                .await
+                .unwrap();
+        }
+    }
+
+    fn feed(config: serde_json::Value) -> Handler {
+        Handler::new(serde_json::from_value(config).unwrap())
+    }
+
+    /// Rkeys of the whole feed, read `limit` posts a page.
+    async fn read(handler: &Handler, context: &Context, limit: i64) -> Vec<String> {
+        let mut ret = vec![];
+        let mut cursor = None;
+        loop {
+            let output = handler
+                .handle(
+                    context.clone(),
+                    get_feed_skeleton::QueryParams {
+                        feed: String::new(),
+                        limit,
+                        cursor,
+                    },
+                )
+                .await
+                .unwrap();
+            if output.feed.is_empty() {
+                return ret;
+            }
+            ret.extend(
+                output
+                    .feed
+                    .into_iter()
+                    .map(|post| post.post.rsplit('/').next().unwrap().to_string()),
+            );
+            cursor = output.cursor;
+        }
+    }
+
+    #[tokio::test]
+    async fn test_feeds() {
+        let db = memory_db().await;
+        let context = Context { db: db.clone() };
+        insert(&db, "a", 90, &["eueoeo"]).await;
+        insert(&db, "b", 30, &["eueoeo", "kkk"]).await;
+        insert(&db, "c", 20, &["kkk"]).await;
+        insert(&db, "d", 10, &["eueoeo"]).await;
+
+        let eueoeo = feed(serde_json::json!({ "shortname": "eueoeo", "matchers": [] }));
+        assert_eq!(read(&eueoeo, &context, 2).await, ["d", "b", "a"]);
+        let oldest = feed(serde_json::json!({
+            "shortname": "eueoeo",
+            "matchers": [],
+            "sort": "oldest",
+        }));
+        assert_eq!(read(&oldest, &context, 2).await, ["a", "b", "d"]);
+        let kkk = feed(serde_json::json!({
+            "shortname": "kkk",
+            "matchers": [],
+            "retention": 25 * 60 * 1000,
+        }));
+        assert_eq!(read(&kkk, &context, 1).await, ["c"]);
+        let forever = feed(serde_json::json!({
+            "shortname": "kkk",
+            "matchers": [],
+            "retention": 1_u64 << 62,
+        }));
+        assert_eq!(read(&forever, &context, 10).await, ["c", "b"]);
+
+        // A cursor in milliseconds as given out before, of `b`.
+        let indexed_at: String =
+            sqlx::query_scalar("SELECT `indexedAt` FROM `post` WHERE `cid` = 'bafyb'")
+                .fetch_one(&db)
+                .await
+                .unwrap();
+        let millis = chrono::DateTime::parse_from_rfc3339(&indexed_at)
+            .unwrap()
+            .timestamp_millis();
+        let output = eueoeo
+            .handle(
+                context.clone(),
+                get_feed_skeleton::QueryParams {
+                    feed: String::new(),
+                    limit: 10,
+                    cursor: Some(format!("{millis}::bafyb")),
+                },
+            )
+            .await
+            .unwrap();
+        assert_eq!(output.feed.len(), 1);
+        assert!(output.feed[0].post.ends_with("/a"));
+
+        let recent = feed(serde_json::json!({
+            "shortname": "eueoeo",
+            "matchers": [],
+            "retention": 60 * 60 * 1000,
+        }));
+        insert(&db, "e", 10, &["gone"]).await;
+        let pruned = prune(
+            &db,
+            &[recent.feed.clone(), kkk.feed.clone(), forever.feed.clone()],
+        )
+        .await
+        .unwrap();
+        // `a` out of `eueoeo`, `b` out of `kkk` and `e` out of `gone`, no longer declared.
+        assert_eq!(pruned, 3);
+        let posts: Vec<String> = sqlx::query_scalar("SELECT `cid` FROM `post` ORDER BY `cid`")
+            .fetch_all(&db)
+            .await
+            .unwrap();
+        assert_eq!(posts, ["bafyb", "bafyc", "bafyd"]);
+        assert_eq!(read(&eueoeo, &context, 10).await, ["d", "b"]);
+    }
+
+    #[tokio::test]
+    async fn test_adopt() {
+        let db = memory_db().await;
+        insert(&db, "a", 90, &[]).await;
+        insert(&db, "b", 30, &["eueoeo"]).await;
+        insert(&db, "c", 20, &["kkk"]).await;
+
+        assert_eq!(adopt(&db).await.unwrap(), 1);
+        // Once only, so as not to put posts of feeds taken out of the config back in.
+        sqlx::query("DELETE FROM `feed_post` WHERE `feed` = 'kkk'")
+            .execute(&db)
+            .await
+            .unwrap();
+        assert_eq!(adopt(&db).await.unwrap(), 0);
+        let feed_posts: Vec<(String, String)> =
+            sqlx::query_as("SELECT `feed`, `uri` FROM `feed_post` ORDER BY `uri`")
+                .fetch_all(&db)
+                .await
+                .unwrap();
+        let uri = |rkey| format!("at://did:plc:alice/app.bsky.feed.post/{rkey}");
+        assert_eq!(
+            feed_posts,
+            [
+                ("eueoeo".to_string(), uri("a")),
+                ("eueoeo".to_string(), uri("b")),
+            ]
+        );
+    }
+}
@@ -1,16 +1,35 @@
-use std::sync::Arc;
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
-use crate::lexicon::com::atproto::sync::subscribe_repos::OutputSchema as RepoEvent;
+use crate::lexicon::com::atproto::sync::subscribe_repos::{InfoName, OutputSchema as RepoEvent};
 use anyhow::{anyhow, Context};
 use async_trait::async_trait;
 use dagcbor::de::DeserializeOption;
-use futures_util::StreamExt;
+use futures_util::{SinkExt, Stream, StreamExt};
 #[allow(unused_imports)]
-use log::{debug, error, info};
+use log::{debug, error, info, warn};
+use rand::Rng;
 use serde_ipld_dagcbor as dagcbor;
-use sqlx::SqlitePool;
+use sqlx::{Acquire, Sqlite, SqliteConnection, SqlitePool, Transaction};
 use tokio::{sync::watch, task::JoinHandle};
-use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::tungstenite::{self, Message};
+
+pub mod capture;
+pub mod continuity;
+pub mod cursor;
+pub mod dead_letter;
+pub mod handlers;
+pub mod hosts;
+pub mod jetstream;
+#[cfg(any(test, feature = "test-support"))]
+pub mod mock_relay;
+pub mod pipeline;
+pub mod too_big;
+pub mod verify;
+mod writer;
 
 #[derive(Debug, thiserror::Error)]
 #[error("subscription error")]
@@ -29,18 +48,426 @@ impl SubscriptionError {
     }
 }
 
+/// Controls how long the subscription waits before reconnecting to the service.
+///
+/// The delay starts at `initial_delay`, doubles on each consecutive failure up to `max_delay`
+/// and is randomized to somewhere between half and the full value.
+/// Once a connection stays healthy for `reset_after`, the next failure starts from `initial_delay` again.
+#[derive(Debug, Clone)]
+pub struct ReconnectPolicy {
+    pub initial_delay: Duration,
+    pub max_delay: Duration,
+    pub reset_after: Duration,
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        Self {
+            initial_delay: Duration::from_secs(3),
+            max_delay: Duration::from_secs(60),
+            reset_after: Duration::from_secs(30),
+        }
+    }
+}
+
+impl ReconnectPolicy {
+    /// Upper bound of the delay for the given consecutive failure count (starting from 0).
+    pub fn max_delay_for(&self, attempt: u32) -> Duration {
+        self.initial_delay
+            .checked_mul(1u32.checked_shl(attempt).unwrap_or(u32::MAX))
+            .unwrap_or(self.max_delay)
+            .min(self.max_delay)
+    }
+
+    /// Delay with jitter applied for the given consecutive failure count (starting from 0).
+    pub fn delay_for(&self, attempt: u32) -> Duration {
+        let max = self.max_delay_for(attempt);
+        let half = max / 2;
+        half + rand::thread_rng().gen_range(Duration::ZERO..=(max - half))
+    }
+}
+
+/// Service to subscribe. Lower `priority` is preferred.
+#[derive(Debug, Clone, serde::Deserialize)]
+pub struct Endpoint {
+    pub url: String,
+    #[serde(default)]
+    pub priority: i32,
+}
+
+/// Controls when to move between endpoints.
+///
+/// The subscription moves on to the next endpoint once connecting to the current one failed
+/// `failover_after` times in a row. While it is not on the preferred endpoint,
+/// the preferred one is tried every `return_interval` and taken back as soon as it accepts a connection.
+#[derive(Debug, Clone)]
+pub struct FailoverPolicy {
+    pub failover_after: u32,
+    pub return_interval: Duration,
+}
+
+impl Default for FailoverPolicy {
+    fn default() -> Self {
+        Self {
+            failover_after: 3,
+            return_interval: Duration::from_secs(600),
+        }
+    }
+}
+
+/// Protocol of the service to subscribe.
+#[derive(Clone)]
+pub enum Source {
+    /// `com.atproto.sync.subscribeRepos` of a relay or a PDS.
+    Firehose,
+    /// Jetstream JSON stream.
+    Jetstream(jetstream::Options),
+}
+
+impl Source {
+    /// Prefix of the keys of `app_state` to keep cursors in. Cursors are not comparable across sources.
+    pub fn cursor_key(&self) -> &'static str {
+        match self {
+            Source::Firehose => "bsky_cursor",
+            Source::Jetstream(_) => "jetstream_cursor",
+        }
+    }
+
+    /// Whether commits are those of the repositories, signed and chained by rev.
+    /// Jetstream hands each op on its own without the commit object,
+    /// so its commits are neither verified nor checked against the rev chain.
+    pub fn carries_commits(&self) -> bool {
+        matches!(self, Source::Firehose)
+    }
+
+    fn url(&self, service: &str, cursor: Option<u64>) -> anyhow::Result<url::Url> {
+        let mut url = url::Url::parse(service).context("Failed to parse url")?;
+        let mut path = url
+            .path_segments_mut()
+            .map_err(|_| anyhow!("Not a valid service url - {service}"))?;
+        match self {
+            Source::Firehose => {
+                path.push("xrpc")
+                    .push(crate::lexicon::com::atproto::sync::subscribe_repos::ID);
+                drop(path);
+            }
+            Source::Jetstream(options) => {
+                path.push(jetstream::PATH);
+                drop(path);
+                options.append_query(&mut url);
+            }
+        }
+        if let Some(cursor) = cursor {
+            url.query_pairs_mut()
+                .append_pair("cursor", &cursor.to_string());
+        }
+
+        Ok(url)
+    }
+}
+
+/// Controls how often processed events are committed together with the cursor.
+///
+/// Pending events are committed once `max_events` of them are processed or
+/// `max_interval` has passed since the first of them, whichever comes first.
+#[derive(Debug, Clone)]
+pub struct FlushPolicy {
+    pub max_events: usize,
+    pub max_interval: Duration,
+}
+
+impl Default for FlushPolicy {
+    fn default() -> Self {
+        Self {
+            max_events: 100,
+            max_interval: Duration::from_secs(1),
+        }
+    }
+}
+
+/// Controls how a quiet connection is told from a dead one.
+///
+/// The service is pinged every `ping_interval`. When nothing, pongs included, arrives for `idle_timeout`,
+/// the connection is taken as dead and made again.
+#[derive(Debug, Clone)]
+pub struct KeepalivePolicy {
+    pub ping_interval: Duration,
+    pub idle_timeout: Duration,
+}
+
+impl Default for KeepalivePolicy {
+    fn default() -> Self {
+        Self {
+            ping_interval: Duration::from_secs(30),
+            idle_timeout: Duration::from_secs(90),
+        }
+    }
+}
+
+/// What to do with a frame which could not be decoded or handled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorPolicy {
+    /// Stop the subscription without advancing the cursor over the frame.
+    /// Events handled before it in the same batch are rolled back.
+    Stop,
+    /// Log the frame and move on.
+    #[default]
+    Skip,
+    /// Keep the frame in the dead letter store and move on.
+    DeadLetter,
+}
+
+/// Frame which could not be decoded or handled.
+#[derive(Debug)]
+pub struct FailedFrame {
+    pub seq: Option<u64>,
+    pub repo: Option<String>,
+    pub frame: Vec<u8>,
+    pub error: anyhow::Error,
+}
+
 #[async_trait]
 pub trait FirehoseSubscriptionHandler {
-    async fn handle_event(&self, event: RepoEvent) -> anyhow::Result<()>;
+    /// NSIDs of the collections to receive ops of. Other ops are removed from commits before
+    /// they are handled, and blocks of commits without a remaining op are never decoded.
+    /// `None` receives every op.
+    fn watched_collections(&self) -> Option<Vec<String>> {
+        None
+    }
+
+    /// Writes through `conn` belong to the same transaction as the cursor advance,
+    /// so they are committed or discarded together with it.
+    async fn handle_event(
+        &self,
+        conn: &mut SqliteConnection,
+        event: RepoEvent,
+    ) -> anyhow::Result<()>;
+}
+
+/// Events processed since the last commit.
+struct Batch {
+    tx: Transaction<'static, Sqlite>,
+    /// Cursors to advance with the commit, by key.
+    cursors: HashMap<String, u64>,
+    /// Whether commits too big are kept in the batch, to wake the backfill task for once committed.
+    backfill: bool,
+    events: usize,
+    started_at: Instant,
+}
+
+enum Received {
+    Decoded(Option<tokio::sync::oneshot::Receiver<pipeline::Decoded>>),
+    FlushDue,
+    Stop,
+    Switch,
+}
+
+/// Why receiving from a connection ended.
+enum Ended {
+    /// Frames ran out.
+    Closed,
+    /// Stop is signalled.
+    Stopped,
+    /// The preferred endpoint is back.
+    Switch,
 }
 
 #[derive(Clone)]
 pub struct FirehoseSubscription<H: Sized + Clone> {
     handler: H,
     db: SqlitePool,
-    service: Arc<String>,
+    endpoints: Arc<[Endpoint]>,
+    /// Index of the endpoint in use.
+    current: usize,
+    failover_policy: FailoverPolicy,
+    source: Source,
+    reconnect_policy: ReconnectPolicy,
+    flush_policy: FlushPolicy,
+    keepalive_policy: KeepalivePolicy,
+    pipeline_policy: pipeline::PipelinePolicy,
+    error_policy: ErrorPolicy,
+    watched_collections: Option<Arc<[String]>>,
+    verifier: Option<Arc<verify::Verifier>>,
+    record_fetcher: Option<Arc<too_big::RecordFetcher>>,
+    /// Wakes the backfill task, which fetches records of commits too big.
+    backfill: Option<tokio::sync::mpsc::Sender<()>>,
+    /// Subscribed host, which only events of the repositories it hosts are taken from.
+    host_check: Option<Arc<hosts::HostCheck>>,
+    metrics: Arc<pipeline::PipelineMetrics>,
+    sequence: continuity::SequenceTracker,
+    connected_at: Option<Instant>,
+    capture: Option<capture::CaptureSink>,
+    cursor_store: Arc<dyn cursor::CursorStore>,
+    continuity_store: Arc<dyn continuity::ContinuityStore>,
+    dead_letter_store: Arc<dyn dead_letter::DeadLetterStore>,
+    /// Writer to hand decoded frames to, in place of applying them here.
+    writer: Option<tokio::sync::mpsc::Sender<writer::Session<H>>>,
     stop_rx: watch::Receiver<bool>,
-    stop_tx: Arc<watch::Sender<bool>>,
+    /// Keeps `stop_rx` open even when the caller drops its handle to the sender.
+    _stop_tx: Arc<watch::Sender<bool>>,
+}
+
+pub struct FirehoseSubscriptionBuilder<H> {
+    db: Option<SqlitePool>,
+    handler: H,
+    endpoints: Vec<Endpoint>,
+    failover_policy: FailoverPolicy,
+    source: Source,
+    cursor_store: Option<Arc<dyn cursor::CursorStore>>,
+    continuity_store: Option<Arc<dyn continuity::ContinuityStore>>,
+    dead_letter_store: Option<Arc<dyn dead_letter::DeadLetterStore>>,
+    reconnect_policy: ReconnectPolicy,
+    flush_policy: FlushPolicy,
+    stop_tx: Option<Arc<watch::Sender<bool>>>,
+}
+
+impl<H: FirehoseSubscriptionHandler + Sized + Send + Sync + Clone + 'static>
+    FirehoseSubscriptionBuilder<H>
+{
+    pub fn endpoint(mut self, url: impl Into<String>) -> Self {
+        self.endpoints = vec![Endpoint {
+            url: url.into(),
+            priority: 0,
+        }];
+        self
+    }
+
+    /// Subscribe whichever of `endpoints` is preferred and up.
+    /// Each endpoint keeps its own cursor, as sequence numbers are not comparable across them.
+    pub fn endpoints(mut self, endpoints: Vec<Endpoint>, failover_policy: FailoverPolicy) -> Self {
+        self.endpoints = endpoints;
+        self.failover_policy = failover_policy;
+        self
+    }
+
+    pub fn source(mut self, source: Source) -> Self {
+        self.source = source;
+        self
+    }
+
+    /// Handle events in transactions of `db`, which keeps cursors, revs and dead letters too
+    /// unless other stores are given. Without one, handlers write through a private in-memory database
+    /// dropped with the subscription, and the stores keep everything in memory.
+    pub fn database(mut self, db: SqlitePool) -> Self {
+        self.db = Some(db);
+        self
+    }
+
+    pub fn cursor_store(mut self, cursor_store: impl cursor::CursorStore + 'static) -> Self {
+        self.cursor_store = Some(Arc::new(cursor_store));
+        self
+    }
+
+    pub fn continuity_store(
+        mut self,
+        continuity_store: impl continuity::ContinuityStore + 'static,
+    ) -> Self {
+        self.continuity_store = Some(Arc::new(continuity_store));
+        self
+    }
+
+    pub fn dead_letter_store(
+        mut self,
+        dead_letter_store: impl dead_letter::DeadLetterStore + 'static,
+    ) -> Self {
+        self.dead_letter_store = Some(Arc::new(dead_letter_store));
+        self
+    }
+
+    pub fn reconnect_policy(mut self, reconnect_policy: ReconnectPolicy) -> Self {
+        self.reconnect_policy = reconnect_policy;
+        self
+    }
+
+    pub fn flush_policy(mut self, flush_policy: FlushPolicy) -> Self {
+        self.flush_policy = flush_policy;
+        self
+    }
+
+    /// Stop the subscription when `true` is sent through `stop_tx`.
+    /// Without one, stop through [`FirehoseSubscription::stop_signal`].
+    pub fn stop_signal(mut self, stop_tx: Arc<watch::Sender<bool>>) -> Self {
+        self.stop_tx = Some(stop_tx);
+        self
+    }
+
+    pub fn build(self) -> anyhow::Result<FirehoseSubscription<H>> {
+        let Self {
+            db,
+            handler,
+            mut endpoints,
+            failover_policy,
+            source,
+            cursor_store,
+            continuity_store,
+            dead_letter_store,
+            reconnect_policy,
+            flush_policy,
+            stop_tx,
+        } = self;
+        if endpoints.is_empty() {
+            return Err(anyhow!("No endpoint to subscribe"));
+        }
+        endpoints.sort_by_key(|endpoint| endpoint.priority);
+        let (db, cursor_store, continuity_store, dead_letter_store) = match db {
+            Some(db) => (
+                db.clone(),
+                cursor_store
+                    .unwrap_or_else(|| Arc::new(cursor::SqliteCursorStore::new(db.clone()))),
+                continuity_store.unwrap_or_else(|| {
+                    Arc::new(continuity::SqliteContinuityStore::new(db.clone()))
+                }),
+                dead_letter_store
+                    .unwrap_or_else(|| Arc::new(dead_letter::SqliteDeadLetterStore::new(db))),
+            ),
+            None => (
+                // A single connection, as each connection to `:memory:` opens a database of its own.
+                sqlx::sqlite::SqlitePoolOptions::new()
+                    .max_connections(1)
+                    .connect_lazy("sqlite::memory:")
+                    .context("Failed to open in-memory database")?,
+                cursor_store.unwrap_or_else(|| Arc::new(cursor::MemoryCursorStore::default())),
+                continuity_store
+                    .unwrap_or_else(|| Arc::new(continuity::MemoryContinuityStore::default())),
+                dead_letter_store
+                    .unwrap_or_else(|| Arc::new(dead_letter::MemoryDeadLetterStore::default())),
+            ),
+        };
+        let stop_tx = stop_tx.unwrap_or_else(|| Arc::new(watch::channel(false).0));
+        let watched_collections = handler.watched_collections().map(Into::into);
+        // Jetstream sequences events by time.
+        let sequence = continuity::SequenceTracker::new(matches!(source, Source::Firehose));
+
+        Ok(FirehoseSubscription {
+            handler,
+            db,
+            endpoints: endpoints.into(),
+            current: 0,
+            failover_policy,
+            source,
+            reconnect_policy,
+            flush_policy,
+            keepalive_policy: Default::default(),
+            pipeline_policy: Default::default(),
+            error_policy: Default::default(),
+            watched_collections,
+            verifier: None,
+            record_fetcher: None,
+            backfill: None,
+            host_check: None,
+            metrics: Default::default(),
+            sequence,
+            connected_at: None,
+            capture: None,
+            cursor_store,
+            continuity_store,
+            dead_letter_store,
+            writer: None,
+            stop_rx: stop_tx.subscribe(),
+            _stop_tx: stop_tx,
+        })
+    }
 }
 
 impl<H: FirehoseSubscriptionHandler + Sized + Send + Sync + Clone + 'static>
@@ -49,184 +476,1020 @@ impl<H: FirehoseSubscriptionHandler + Sized + Send + Sync + Clone + 'static>
     pub async fn new(
         db: SqlitePool,
         service: String,
+        source: Source,
         handler: H,
+        reconnect_policy: ReconnectPolicy,
+        flush_policy: FlushPolicy,
         stop_tx: Arc<watch::Sender<bool>>,
     ) -> anyhow::Result<Self> {
-        Ok(Self {
+        Self::builder(handler)
+            .database(db)
+            .endpoint(service)
+            .source(source)
+            .reconnect_policy(reconnect_policy)
+            .flush_policy(flush_policy)
+            .stop_signal(stop_tx)
+            .build()
+    }
+
+    pub fn builder(handler: H) -> FirehoseSubscriptionBuilder<H> {
+        FirehoseSubscriptionBuilder {
+            db: None,
             handler,
-            db,
-            service: Arc::new(service),
-            stop_rx: stop_tx.subscribe(),
-            stop_tx,
-        })
+            endpoints: vec![],
+            failover_policy: Default::default(),
+            source: Source::Firehose,
+            cursor_store: None,
+            continuity_store: None,
+            dead_letter_store: None,
+            reconnect_policy: Default::default(),
+            flush_policy: Default::default(),
+            stop_tx: None,
+        }
+    }
+
+    /// Append every frame received from the service to `capture`, which is written on a thread of its own.
+    pub fn with_capture(mut self, capture: capture::CaptureWriter) -> Self {
+        self.capture = Some(capture::CaptureSink::spawn(capture));
+        self
+    }
+
+    pub fn with_keepalive(mut self, keepalive_policy: KeepalivePolicy) -> Self {
+        self.keepalive_policy = keepalive_policy;
+        self
+    }
+
+    pub fn with_pipeline(mut self, pipeline_policy: pipeline::PipelinePolicy) -> Self {
+        self.pipeline_policy = pipeline_policy;
+        self
+    }
+
+    pub fn with_error_policy(mut self, error_policy: ErrorPolicy) -> Self {
+        self.error_policy = error_policy;
+        self
+    }
+
+    /// Drop commits which fail verification. Commits of repositories whose key can't be resolved
+    /// go through the error policy, to be tried again later.
+    /// Only the firehose carries what is needed for verification,
+    /// so commits of other sources are handled unverified.
+    pub fn with_verifier(mut self, verifier: verify::Verifier) -> Self {
+        self.verifier = Some(Arc::new(verifier));
+        self
+    }
+
+    /// Fetch records of commits too big to carry them from the PDS of their repository.
+    /// Such commits are kept as dead letters of [`dead_letter::DeadLetterKind::TooBig`] in the batch
+    /// they are received in, and handled by a background task once their records are fetched.
+    /// Those failing stay kept, across restarts too, and are fetched again later.
+    pub fn with_record_fetcher(mut self, fetcher: too_big::RecordFetcher) -> Self {
+        self.record_fetcher = Some(Arc::new(fetcher));
+        self
+    }
+
+    pub fn metrics(&self) -> Arc<pipeline::PipelineMetrics> {
+        self.metrics.clone()
+    }
+
+    pub fn dead_letter_store(&self) -> Arc<dyn dead_letter::DeadLetterStore> {
+        self.dead_letter_store.clone()
+    }
+
+    /// Sending `true` stops the subscription.
+    pub fn stop_signal(&self) -> Arc<watch::Sender<bool>> {
+        self._stop_tx.clone()
+    }
+
+    /// Feed frames, e.g. [`capture::frames`], through the same path as frames from the service
+    /// until they run out or stop is signalled.
+    /// The cursor of the endpoint advances over them as well, unless another cursor store,
+    /// e.g. [`cursor::MemoryCursorStore`], is given to the builder.
+    pub async fn replay<S>(&mut self, mut frames: S) -> anyhow::Result<()>
+    where
+        S: Stream<Item = Result<Message, tungstenite::Error>> + Unpin,
+    {
+        self.sequence.reset(None);
+        let backfill = self.start_backfill();
+        let mut batch = None;
+        let ret = self
+            .receive(&mut frames, &mut batch, watch::channel(false).1)
+            .await;
+        if let (false, Some(batch)) = (matches!(ret, Err(SubscriptionError::Fatal(_))), batch) {
+            self.commit_batch(batch).await?;
+        }
+        // Closing the queue lets the backfill task end once it is done with the commits due.
+        self.backfill = None;
+        if let Some(backfill) = backfill {
+            backfill.await?;
+        }
+
+        match ret {
+            Ok(_) => Ok(()),
+            Err(SubscriptionError::Fatal(e) | SubscriptionError::Recoverable(e)) => Err(e),
+        }
     }
 
     pub fn run(&self) -> anyhow::Result<JoinHandle<anyhow::Result<()>>> {
         let mut subscription = self.clone();
+        subscription.start_backfill();
 
         Ok(tokio::spawn(async move {
-            if *subscription.stop_rx.borrow_and_update() {
-                return Ok(());
+            subscription
+                .adopt_legacy_cursor()
+                .await
+                .context("Failed to move cursor to the endpoint")?;
+            let capture = subscription.capture.clone();
+            let ret = subscription.run_loop().await;
+            if let Some(capture) = capture {
+                capture.close().await;
+            }
+            ret
+        }))
+    }
+
+    /// Subscribe each of `hosts`, e.g. PDSes found by [`hosts::list_hosts`], on its own task
+    /// in place of the endpoints. Every host keeps its own cursor, and one stopping by a fatal error
+    /// does not stop the others. Events of all of them are applied by a single writer task.
+    /// Events of a repository are taken only from the host its DID document, resolved by `resolver`,
+    /// names as its PDS. The returned task ends once all of them stop.
+    pub fn run_hosts(
+        &self,
+        hosts: Vec<String>,
+        resolver: Arc<dyn crate::identity::DidResolver>,
+    ) -> anyhow::Result<JoinHandle<anyhow::Result<()>>> {
+        if hosts.is_empty() {
+            return Err(anyhow!("No host to subscribe"));
+        }
+        let checks = hosts
+            .iter()
+            .map(|host| hosts::HostCheck::new(host, resolver.clone()).map(Arc::new))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        let mut base = self.clone();
+        base.start_backfill();
+        let (writer_tx, writer_rx) = tokio::sync::mpsc::channel(hosts.len());
+        let writer = tokio::spawn(base.clone().write(writer_rx));
+        let joins = hosts
+            .into_iter()
+            .zip(checks)
+            .map(|(host, check)| {
+                let mut subscription = base.clone();
+                subscription.endpoints = Arc::new([Endpoint {
+                    url: host.clone(),
+                    priority: 0,
+                }]);
+                subscription.current = 0;
+                subscription.writer = Some(writer_tx.clone());
+                subscription.host_check = Some(check);
+                (host, tokio::spawn(subscription.run_loop()))
+            })
+            .collect::<Vec<_>>();
+        let capture = base.capture.clone();
+
+        Ok(tokio::spawn(async move {
+            let mut ret = Ok(());
+            for (host, join) in joins {
+                if let Err(e) = join.await? {
+                    error!("Subscription of {host} is stopped - {e:?}");
+                    ret = Err(e);
+                }
             }
+            writer.await?;
+            if let Some(capture) = capture {
+                capture.close().await;
+            }
+            ret
+        }))
+    }
+
+    /// Spawn the task fetching records of commits too big, when a fetcher is given.
+    /// It goes through every commit kept waiting for its records, those left by earlier runs included,
+    /// and again whenever a batch keeping more of them is committed. Commits failing are fetched again
+    /// later, as [`too_big::FetchPolicy`] tells.
+    /// It ends once every clone of the subscription taken from here on is dropped.
+    fn start_backfill(&mut self) -> Option<JoinHandle<()>> {
+        let policy = self.record_fetcher.as_ref()?.policy().clone();
+        let (tx, mut rx) = tokio::sync::mpsc::channel::<()>(1);
+        // Taken before the queue is set, so that the task does not keep it open.
+        let subscription = self.clone();
+        self.backfill = Some(tx);
 
+        Some(tokio::spawn(async move {
+            let mut failures = HashMap::new();
             loop {
-                match subscription.loop_unit().await {
-                    Ok(true) => {}
-                    Ok(false) => {
-                        break;
-                    }
-                    Err(e) => {
-                        error!("Subscription connect is broken. retry later: {e:?}");
-                        if let SubscriptionError::Fatal(_) = &e {
-                            let _ = subscription.stop_tx.send(true);
-                            return Err(e).context("Stop subscription by fatal error");
-                        } else {
-                            const DELAY: std::time::Duration = std::time::Duration::from_secs(5);
-                            log::info!("Restart loop after {DELAY:?}");
-                            tokio::time::sleep(DELAY).await;
-                        }
+                let next = subscription.backfill(&policy, &mut failures).await;
+                let woken = async {
+                    match next {
+                        Some(at) => tokio::time::timeout_at(at.into(), rx.recv())
+                            .await
+                            .unwrap_or(Some(())),
+                        None => rx.recv().await,
                     }
                 };
+                if woken.await.is_none() {
+                    // Once more for the commits of the last batch.
+                    subscription.backfill(&policy, &mut failures).await;
+                    break;
+                }
             }
-
-            Ok(())
         }))
     }
 
+    /// Fetch records of the commits too big which are due, given how many times each of them
+    /// has failed in a row and when it may be tried again in `failures`.
+    /// Returns when the next of those failing is due.
+    async fn backfill(
+        &self,
+        policy: &too_big::FetchPolicy,
+        failures: &mut HashMap<i64, (u32, Instant)>,
+    ) -> Option<Instant> {
+        const PAGE: i64 = 100;
+
+        let mut waiting = HashSet::new();
+        let mut after = 0;
+        loop {
+            let letters = match self
+                .dead_letter_store
+                .page(Some(dead_letter::DeadLetterKind::TooBig), after, PAGE)
+                .await
+            {
+                Ok(letters) => letters,
+                Err(e) => {
+                    warn!("Failed to read commits too big - {e:#}");
+                    break;
+                }
+            };
+            let Some(last) = letters.last() else {
+                break;
+            };
+            after = last.id;
+            for letter in &letters {
+                if failures
+                    .get(&letter.id)
+                    .is_some_and(|(_, at)| *at > Instant::now())
+                {
+                    waiting.insert(letter.id);
+                    continue;
+                }
+                match self.retry_dead_letter(letter).await {
+                    Ok(true) => {
+                        failures.remove(&letter.id);
+                        continue;
+                    }
+                    Ok(false) => {}
+                    Err(e) => warn!(
+                        "Failed to fetch records of dead letter #{} - {e:#}",
+                        letter.id
+                    ),
+                }
+                let count = failures.get(&letter.id).map_or(0, |(count, _)| *count) + 1;
+                failures.insert(
+                    letter.id,
+                    (count, Instant::now() + policy.backfill_delay_for(count)),
+                );
+                waiting.insert(letter.id);
+            }
+        }
+        // Those gone were retried or purged by hand meanwhile.
+        failures.retain(|id, _| waiting.contains(id));
+
+        failures.values().map(|(_, at)| *at).min()
+    }
+
+    async fn run_loop(self) -> anyhow::Result<()> {
+        let mut subscription = self;
+        if *subscription.stop_rx.borrow_and_update() {
+            return Ok(());
+        }
+
+        let mut attempt = 0;
+        // Consecutive connect failures on the current endpoint.
+        let mut failures = 0;
+        loop {
+            match subscription.loop_unit().await {
+                Ok(true) => {
+                    attempt = 0;
+                    failures = 0;
+                }
+                Ok(false) => {
+                    break;
+                }
+                Err(e) => {
+                    error!("Subscription connect is broken. retry later: {e:?}");
+                    // Only the subscription stops. The feed is still served from what is indexed.
+                    if let SubscriptionError::Fatal(_) = &e {
+                        return Err(e).context("Stop subscription by fatal error");
+                    }
+
+                    match subscription.connected_at.take() {
+                        Some(connected_at) => {
+                            failures = 0;
+                            if connected_at.elapsed() >= subscription.reconnect_policy.reset_after {
+                                attempt = 0;
+                            }
+                        }
+                        None => {
+                            failures += 1;
+                            if failures >= subscription.failover_policy.failover_after
+                                && subscription.endpoints.len() > 1
+                            {
+                                subscription.current =
+                                    (subscription.current + 1) % subscription.endpoints.len();
+                                warn!(
+                                    "Fail over to {}",
+                                    subscription.endpoints[subscription.current].url
+                                );
+                                failures = 0;
+                                attempt = 0;
+                            }
+                        }
+                    }
+                    let delay = subscription.reconnect_policy.delay_for(attempt);
+                    attempt = attempt.saturating_add(1);
+                    info!("Restart loop after {delay:?} (attempt {attempt})");
+                    tokio::select! {
+                        _ = tokio::time::sleep(delay) => {}
+                        _ = subscription.stop_rx.wait_for(|v| *v) => {
+                            info!("Stop reconnecting subscription by stop signal");
+                            break;
+                        }
+                    }
+                }
+            };
+        }
+
+        Ok(())
+    }
+
     async fn loop_unit(&mut self) -> Result<bool, SubscriptionError> {
-        // TODO: reconnection
-        let mut url = url::Url::parse(&self.service)
-            .context("Failed to parse url")
-            .map_err(SubscriptionError::fatal)?;
-        unsafe { url.path_segments_mut().unwrap_unchecked() }
-            .push("xrpc")
-            .push(crate::lexicon::com::atproto::sync::subscribe_repos::ID);
-        if let Some(cursor) = self
+        let cursor = self
             .get_cursor()
             .await
             .context("Failed to get previous received position from DB")
-            .map_err(SubscriptionError::fatal)?
-        {
-            url.query_pairs_mut()
-                .append_pair("cursor", &cursor.to_string());
-        }
+            .map_err(SubscriptionError::fatal)?;
+        self.sequence.reset(cursor);
+        let url = self
+            .source
+            .url(&self.endpoints[self.current].url, cursor)
+            .map_err(SubscriptionError::fatal)?;
         let (stream, _) = tokio_tungstenite::connect_async(url.to_string())
             .await
             .with_context(|| format!("Failed to connect to service({url})"))
+            .map_err(SubscriptionError::recoverable)?;
+        self.connected_at = Some(Instant::now());
+        let (mut tx, rx) = stream.split();
+        let mut rx = std::pin::pin!(idle_timeout(
+            rx,
+            self.keepalive_policy.idle_timeout,
+            self.metrics.clone(),
+        ));
+        let ping_interval = self.keepalive_policy.ping_interval;
+        let keepalive = async move {
+            let mut interval = tokio::time::interval_at(
+                tokio::time::Instant::now() + ping_interval,
+                ping_interval,
+            );
+            loop {
+                interval.tick().await;
+                if let Err(e) = tx.send(Message::Ping(vec![])).await {
+                    // Receiving fails soon after, or times out.
+                    debug!("Failed to ping service - {e}");
+                    break;
+                }
+            }
+            std::future::pending::<()>().await
+        };
+
+        // Off the preferred endpoint, keep trying it to move back.
+        let preferred = (self.current != 0)
+            .then(|| self.source.url(&self.endpoints[0].url, None))
+            .transpose()
             .map_err(SubscriptionError::fatal)?;
-        let (_tx, mut rx) = stream.split();
-
-        while let Some(ret) = rx.next().await {
-            if self
-                .stop_rx
-                .has_changed()
-                .map_err(SubscriptionError::fatal)?
-                && *self.stop_rx.borrow_and_update()
-            {
-                info!("Stop processing subscription by stop signal");
-                return Ok(false);
+        let return_interval = self.failover_policy.return_interval;
+        let (switch_tx, switch_rx) = watch::channel(false);
+        let probe = async move {
+            if let Some(url) = preferred {
+                probe(&url, return_interval).await;
+                let _ = switch_tx.send(true);
             }
-            let message = ret
-                .context("Failed to receive message")
-                .map_err(SubscriptionError::fatal)?;
-            if let Message::Binary(data) = message {
-                let event = Self::parse_message(&data)
-                    .context("Failed to parse message")
-                    .map_err(SubscriptionError::fatal)?;
-
-                let cursor = if let RepoEvent::Commit(commit) = &event {
-                    let seq = commit._common.seq;
-                    (seq % 20 == 0).then_some(seq)
-                } else {
-                    None
-                };
+            // Receiving goes on until the apply stage takes the switch.
+            std::future::pending::<()>().await
+        };
 
-                self.handler
-                    .handle_event(event)
-                    .await
-                    .map_err(SubscriptionError::fatal)?;
+        let mut batch = None;
+        let ret = tokio::select! {
+            ret = self.receive(&mut rx, &mut batch, switch_rx) => ret,
+            _ = probe => unreachable!(),
+            _ = keepalive => unreachable!(),
+        };
+        // Events of the pending batch are fully handled unless a fatal error happened in the middle.
+        // On fatal error, drop them to roll back so they are replayed from the committed cursor.
+        if let (false, Some(batch)) = (matches!(ret, Err(SubscriptionError::Fatal(_))), batch) {
+            self.commit_batch(batch)
+                .await
+                .map_err(SubscriptionError::recoverable)?;
+        }
+
+        match ret? {
+            Ended::Closed => Err(SubscriptionError::recoverable(anyhow!(
+                "Connection to service is dropped"
+            ))),
+            Ended::Stopped => Ok(false),
+            Ended::Switch => {
+                self.current = 0;
+                info!("Move back to {}", self.endpoints[0].url);
+                Ok(true)
+            }
+        }
+    }
+
+    /// Receive until frames run out, stop is signalled or `switch_rx` turns `true`.
+    async fn receive<S>(
+        &mut self,
+        rx: &mut S,
+        batch: &mut Option<Batch>,
+        switch_rx: watch::Receiver<bool>,
+    ) -> Result<Ended, SubscriptionError>
+    where
+        S: Stream<Item = Result<Message, tungstenite::Error>> + Unpin,
+    {
+        let (pipeline, decoded_rx) = pipeline::start(
+            &self.pipeline_policy,
+            self.source.clone(),
+            pipeline::Stages {
+                watched_collections: self.watched_collections.clone(),
+                host: self.host_check.clone(),
+                verifier: self.verifier.clone(),
+                keep_too_big: self.record_fetcher.is_some(),
+            },
+            self.error_policy == ErrorPolicy::DeadLetter,
+            self.metrics.clone(),
+        );
+        let capture = self.capture.clone();
+        let reader = async {
+            pipeline.read(rx, capture).await;
+            // The apply stage finds the end of frames from the closed queue.
+            std::future::pending::<()>().await
+        };
+
+        let apply = async {
+            match self.writer.clone() {
+                Some(writer) => self.hand_off(&writer, decoded_rx).await,
+                None => self.apply(decoded_rx, batch, switch_rx).await,
+            }
+        };
+
+        tokio::select! {
+            ret = apply => ret,
+            _ = reader => unreachable!(),
+        }
+    }
+
+    /// Apply stage. Hands decoded events to the handler in the order they are received.
+    async fn apply(
+        &mut self,
+        mut decoded_rx: tokio::sync::mpsc::Receiver<
+            tokio::sync::oneshot::Receiver<pipeline::Decoded>,
+        >,
+        batch: &mut Option<Batch>,
+        mut switch_rx: watch::Receiver<bool>,
+    ) -> Result<Ended, SubscriptionError> {
+        loop {
+            let flush_at = batch.as_ref().map(|b| {
+                tokio::time::Instant::from_std(b.started_at + self.flush_policy.max_interval)
+            });
+            let received = tokio::select! {
+                ret = decoded_rx.recv() => Received::Decoded(ret),
+                _ = async {
+                    match flush_at {
+                        Some(flush_at) => tokio::time::sleep_until(flush_at).await,
+                        None => std::future::pending().await,
+                    }
+                } => Received::FlushDue,
+                _ = self.stop_rx.wait_for(|v| *v) => Received::Stop,
+                Ok(_) = switch_rx.wait_for(|v| *v) => Received::Switch,
+            };
+
+            let decoded = match received {
+                Received::Decoded(Some(decoded)) => decoded,
+                Received::Decoded(None) => return Ok(Ended::Closed),
+                Received::FlushDue => {
+                    if let Some(batch) = batch.take() {
+                        self.commit_batch(batch)
+                            .await
+                            .map_err(SubscriptionError::recoverable)?;
+                    }
+                    continue;
+                }
+                Received::Stop => {
+                    info!("Stop processing subscription by stop signal");
+                    return Ok(Ended::Stopped);
+                }
+                Received::Switch => return Ok(Ended::Switch),
+            };
+            let decoded = decoded
+                .await
+                .context("Decode worker is gone")
+                .map_err(SubscriptionError::fatal)?;
+            self.apply_frame(decoded, batch).await?;
 
-                if let Some(cursor) = cursor {
-                    self.update_cursor(cursor)
+            if batch
+                .as_ref()
+                .is_some_and(|batch| batch.events >= self.flush_policy.max_events)
+            {
+                if let Some(batch) = batch.take() {
+                    self.commit_batch(batch)
                         .await
                         .map_err(SubscriptionError::recoverable)?;
                 }
-            } else {
-                unreachable!("text message is not available by specification");
             }
         }
-
-        Ok(true)
     }
 
-    pub fn parse_message(data: &Vec<u8>) -> anyhow::Result<RepoEvent> {
-        let mut cursor = std::io::Cursor::new(&data);
+    /// Apply a decoded frame in `batch`, which is begun when there is none.
+    async fn apply_frame(
+        &mut self,
+        decoded: pipeline::Decoded,
+        batch: &mut Option<Batch>,
+    ) -> Result<(), SubscriptionError> {
+        self.metrics.applied();
+
+        let event = match decoded? {
+            pipeline::Frame::Ignored => return Ok(()),
+            pipeline::Frame::Dropped(commit) => {
+                let seq = Some(commit.seq).filter(|seq| self.check_sequence(*seq));
+                let key = self.cursor_key();
+                let current = self.current_batch(batch).await?;
+                self.continuity_store
+                    .pass(&mut current.tx, &commit)
+                    .await
+                    .context("Failed to take rev of dropped commit")
+                    .map_err(SubscriptionError::recoverable)?;
+                if let Some(seq) = seq {
+                    current.cursors.insert(key, seq);
+                }
+                return Ok(());
+            }
+            pipeline::Frame::Message(SubscriptionMessage::Message(event), frame) => {
+                Ok((event, frame))
+            }
+            pipeline::Frame::Message(SubscriptionMessage::Error(e), _) => {
+                if let Some(batch) = batch.take() {
+                    self.commit_batch(batch)
+                        .await
+                        .map_err(SubscriptionError::recoverable)?;
+                }
+                return Err(self.handle_error_frame(e).await);
+            }
+            pipeline::Frame::Undecodable(undecodable) => Err(undecodable),
+        };
 
-        let header: Header = dagcbor::from_reader_with_option(
-            &mut cursor,
-            DeserializeOption {
-                ignore_trailing: true,
+        match &event {
+            Ok((RepoEvent::Info(info), _)) => match info.name {
+                InfoName::OutdatedCursor => warn!(
+                    "Requested cursor is older than the service keeps. Events are missing from the gap - {}",
+                    info.message.as_deref().unwrap_or_default()
+                ),
+                InfoName::Other(_) => info!("Info received from service - {info:?}"),
             },
-        )
-        .context("Failed to parse header")?;
+            Ok((RepoEvent::Unknown { tag, .. }, _)) => {
+                debug!("Unknown event received - {tag}");
+            }
+            _ => {}
+        }
 
-        if header.operation == HeaderOperation::Error {
-            return Err(anyhow!("Received error operation"));
+        if let Some(time) = event.as_ref().ok().and_then(|(event, _)| event.time()) {
+            self.metrics.observe_lag(time);
         }
 
-        let Some(r#type) = header._type else {
-            unreachable!("When operation is OK, type must exist.");
+        let seq = match &event {
+            Ok((event, _)) => event.seq(),
+            Err(undecodable) => undecodable.seq,
         };
-
-        let body = &data[(cursor.position() as usize)..];
-        if body.is_empty() {
-            return Err(anyhow!("message has no body"));
+        // The cursor is not moved back by a repeated event.
+        let seq = seq.filter(|seq| self.check_sequence(*seq));
+        let key = self.cursor_key();
+        let current = self.current_batch(batch).await?;
+        match event {
+            Ok((event, frame)) => {
+                self.check_rev(&mut current.tx, &event)
+                    .await
+                    .map_err(SubscriptionError::recoverable)?;
+                match event {
+                    // Fetching would hold up every event after it, so it is left to the backfill task.
+                    RepoEvent::Commit(commit)
+                        if self.record_fetcher.is_some() && pipeline::lacks_records(&commit) =>
+                    {
+                        self.dead_letter_store
+                            .insert(
+                                &mut current.tx,
+                                &FailedFrame {
+                                    seq: Some(commit.seq),
+                                    repo: Some(commit.repo),
+                                    frame: frame.unwrap_or_default(),
+                                    error: anyhow!("Records of commit too big are not fetched yet"),
+                                },
+                                dead_letter::DeadLetterKind::TooBig,
+                            )
+                            .await
+                            .context("Failed to keep commit too big")
+                            .map_err(SubscriptionError::recoverable)?;
+                        current.backfill = true;
+                    }
+                    event => self.handle_event(&mut current.tx, event, frame).await?,
+                }
+            }
+            Err(undecodable) => {
+                self.metrics.count_undecodable();
+                self.handle_failed(&mut current.tx, undecodable).await?
+            }
         }
-        match header.operation {
-            HeaderOperation::Ok => {
-                Ok(RepoEvent::from_cbor(&r#type, body).context("Failed to parse event")?)
+        current.events += 1;
+        if let Some(seq) = seq {
+            current.cursors.insert(key, seq);
+        }
+
+        Ok(())
+    }
+
+    async fn current_batch<'a>(
+        &self,
+        batch: &'a mut Option<Batch>,
+    ) -> Result<&'a mut Batch, SubscriptionError> {
+        Ok(match batch {
+            Some(current) => current,
+            None => batch.insert(Batch {
+                tx: self
+                    .db
+                    .begin()
+                    .await
+                    .context("Failed to begin transaction")
+                    .map_err(SubscriptionError::recoverable)?,
+                cursors: HashMap::new(),
+                backfill: false,
+                events: 0,
+                started_at: Instant::now(),
+            }),
+        })
+    }
+
+    /// Returns `false` when the event is not after the previous one.
+    fn check_sequence(&mut self, seq: u64) -> bool {
+        match self.sequence.observe(seq) {
+            continuity::Sequence::InOrder => true,
+            continuity::Sequence::Gap(missed) => {
+                warn!("{missed} events are missing before #{seq}");
+                self.metrics.count_gap(missed);
+                true
+            }
+            continuity::Sequence::Duplicate => {
+                warn!("Event #{seq} is not after the previous one");
+                self.metrics.count_duplicate();
+                false
             }
-            HeaderOperation::Error => Err(anyhow::anyhow!(
-                "Error received from subscription - {}",
-                dagcbor::from_slice::<Error>(body)
-                    .context("Failed to parse error")?
-                    .error_type
-            )),
         }
     }
 
-    async fn update_cursor(&self, cursor: u64) -> anyhow::Result<()> {
-        let cursor = cursor as i64;
-        sqlx::query!(
-            r#"
-            INSERT INTO `app_state` (
-                `key`, `value`
-            ) VALUES (
-                "bsky_cursor", ?
-            ) ON CONFLICT (`key`) DO UPDATE SET
-                `value`=`excluded`.`value`
-            WHERE
-                `key` = "bsky_cursor"
-        "#,
-            cursor
-        )
-        .execute(&self.db)
-        .await?;
-        debug!("Cursor updated: {cursor}");
+    async fn check_rev(
+        &self,
+        conn: &mut SqliteConnection,
+        event: &RepoEvent,
+    ) -> anyhow::Result<()> {
+        match event {
+            RepoEvent::Commit(commit) if self.source.carries_commits() => {
+                if let Some(rev_break) = self
+                    .continuity_store
+                    .check_commit(conn, commit)
+                    .await
+                    .context("Failed to check rev")?
+                {
+                    warn!(
+                        "Commit #{} of {} is out of the rev chain - {rev_break}",
+                        commit.seq, commit.repo
+                    );
+                    self.metrics.count_rev_break();
+                }
+            }
+            RepoEvent::Sync(sync) => self
+                .continuity_store
+                .sync(conn, &sync.did, &sync.rev)
+                .await
+                .context("Failed to take rev of sync")?,
+            _ => {}
+        }
 
         Ok(())
     }
 
-    async fn get_cursor(&self) -> anyhow::Result<Option<u64>> {
-        sqlx::query!(
-            r#"
-            SELECT `value` FROM `app_state` WHERE `key` = "bsky_cursor"
-        "#
+    /// Hand an event to the handler. Unless failures stop the subscription, writes of a failed event
+    /// are rolled back alone and the rest of the batch goes on.
+    async fn handle_event(
+        &self,
+        conn: &mut SqliteConnection,
+        event: RepoEvent,
+        frame: Option<Vec<u8>>,
+    ) -> Result<(), SubscriptionError> {
+        if self.error_policy == ErrorPolicy::Stop {
+            return self
+                .handler
+                .handle_event(conn, event)
+                .await
+                .map_err(SubscriptionError::fatal);
+        }
+
+        let seq = event.seq();
+        let repo = event.repo().map(ToString::to_string);
+        let mut savepoint = conn
+            .begin()
+            .await
+            .context("Failed to begin savepoint")
+            .map_err(SubscriptionError::recoverable)?;
+        match self.handler.handle_event(&mut savepoint, event).await {
+            Ok(()) => savepoint
+                .commit()
+                .await
+                .context("Failed to release savepoint")
+                .map_err(SubscriptionError::recoverable),
+            Err(error) => {
+                savepoint
+                    .rollback()
+                    .await
+                    .context("Failed to roll back to savepoint")
+                    .map_err(SubscriptionError::recoverable)?;
+                self.metrics.count_failed();
+                self.handle_failed(
+                    conn,
+                    FailedFrame {
+                        seq,
+                        repo,
+                        frame: frame.unwrap_or_default(),
+                        error: error.context("Failed to handle event"),
+                    },
+                )
+                .await
+            }
+        }
+    }
+
+    async fn handle_failed(
+        &self,
+        conn: &mut SqliteConnection,
+        failed: FailedFrame,
+    ) -> Result<(), SubscriptionError> {
+        warn!(
+            "Failed to process frame (seq: {:?}, repo: {:?}): {:#}",
+            failed.seq, failed.repo, failed.error
+        );
+        match self.error_policy {
+            ErrorPolicy::Stop => Err(SubscriptionError::Fatal(failed.error)),
+            ErrorPolicy::Skip => Ok(()),
+            ErrorPolicy::DeadLetter => self
+                .dead_letter_store
+                .insert(conn, &failed, dead_letter::DeadLetterKind::Failed)
+                .await
+                .map(|_| ())
+                .context("Failed to keep failed frame")
+                .map_err(SubscriptionError::fatal),
+        }
+    }
+
+    /// Run dead letters through verification and the handler again, each in its own transaction.
+    /// Handled ones are removed and the others are kept with the new error.
+    /// `ids` limits which of them to retry. Returns how many of them are handled and failed.
+    pub async fn retry_dead_letters(&self, ids: Option<&[i64]>) -> anyhow::Result<(usize, usize)> {
+        const PAGE: i64 = 100;
+
+        let mut handled = 0;
+        let mut failed = 0;
+        let mut count = |retried| {
+            if retried {
+                handled += 1;
+            } else {
+                failed += 1;
+            }
+        };
+        match ids {
+            Some(ids) => {
+                for id in ids {
+                    match self.dead_letter_store.get(*id).await? {
+                        Some(letter) => count(self.retry_dead_letter(&letter).await?),
+                        None => warn!("Dead letter #{id} does not exist"),
+                    }
+                }
+            }
+            None => {
+                // Letters failing again stay behind `after`, so each of them is tried once.
+                let mut after = 0;
+                loop {
+                    let letters = self.dead_letter_store.page(None, after, PAGE).await?;
+                    let Some(last) = letters.last() else {
+                        break;
+                    };
+                    after = last.id;
+                    for letter in &letters {
+                        count(self.retry_dead_letter(letter).await?);
+                    }
+                }
+            }
+        }
+
+        Ok((handled, failed))
+    }
+
+    /// Returns whether the letter is handled this time.
+    async fn retry_dead_letter(&self, letter: &dead_letter::DeadLetter) -> anyhow::Result<bool> {
+        let mut decoded = pipeline::decode(
+            &self.source,
+            self.watched_collections.as_deref(),
+            letter.message(&self.source),
+        );
+        if let (Some(verifier), true) = (&self.verifier, self.source.carries_commits()) {
+            decoded = pipeline::verify(verifier, decoded).await;
+        }
+        if let Some(fetcher) = &self.record_fetcher {
+            decoded = pipeline::fetch_too_big(fetcher, decoded).await;
+        }
+        // Begun once the records are fetched, not to hold a connection meanwhile.
+        let mut tx = self
+            .db
+            .begin()
+            .await
+            .context("Failed to begin transaction")?;
+        let ret = match decoded {
+            Ok(pipeline::Frame::Ignored | pipeline::Frame::Dropped(_)) => Ok(()),
+            Ok(pipeline::Frame::Message(SubscriptionMessage::Message(event), _)) => {
+                self.handler.handle_event(&mut tx, event).await
+            }
+            Ok(pipeline::Frame::Message(SubscriptionMessage::Error(e), _)) => {
+                Err(anyhow!("Error frame - {e}"))
+            }
+            Ok(pipeline::Frame::Undecodable(undecodable)) => Err(undecodable.error),
+            Err(SubscriptionError::Fatal(e) | SubscriptionError::Recoverable(e)) => Err(e),
+        };
+
+        match ret {
+            Ok(()) => {
+                self.dead_letter_store.delete(&mut tx, letter.id).await?;
+                tx.commit()
+                    .await
+                    .context("Failed to commit retried event")?;
+                info!("Dead letter #{} is handled", letter.id);
+                Ok(true)
+            }
+            Err(e) => {
+                tx.rollback().await.context("Failed to roll back")?;
+                warn!("Dead letter #{} failed again: {e:#}", letter.id);
+                self.dead_letter_store.update_error(letter.id, &e).await?;
+                Ok(false)
+            }
+        }
+    }
+
+    async fn commit_batch(&self, batch: Batch) -> anyhow::Result<()> {
+        let Batch {
+            mut tx,
+            cursors,
+            backfill,
+            events,
+            ..
+        } = batch;
+        for (key, seq) in &cursors {
+            self.cursor_store.stage(&mut tx, key, *seq).await?;
+        }
+        tx.commit().await.context("Failed to commit events")?;
+        for (key, seq) in &cursors {
+            self.cursor_store.set(key, *seq).await?;
+            debug!("Cursor updated: {seq}");
+        }
+        debug!("Committed {events} events ({})", self.metrics.snapshot());
+        if let (true, Some(wake)) = (backfill, &self.backfill) {
+            // A full queue means the task is already to look for them.
+            let _ = wake.try_send(());
+        }
+
+        Ok(())
+    }
+
+    /// React to an error frame. The service closes the connection after sending one,
+    /// so this always ends up with a reconnection unless the cursor could not be cleared.
+    async fn handle_error_frame(&self, error: Error) -> SubscriptionError {
+        match error.error_type {
+            ErrorType::FutureCursor => {
+                warn!("Stored cursor is ahead of the service. Restart from the current position");
+                if let Err(e) = self.delete_cursor().await {
+                    return SubscriptionError::fatal(e);
+                }
+            }
+            ErrorType::ConsumerTooSlow => {
+                warn!("Service dropped the connection because we are consuming too slow");
+            }
+            ErrorType::Unknown(_) => {}
+        }
+
+        SubscriptionError::recoverable(anyhow!("Error received from subscription - {error}"))
+    }
+
+    pub fn parse_message(data: &[u8]) -> anyhow::Result<SubscriptionMessage<RepoEvent>> {
+        parse_message(data)
+    }
+
+    /// Key of `app_state` to keep the cursor of the current endpoint in.
+    fn cursor_key(&self) -> String {
+        format!(
+            "{}:{}",
+            self.source.cursor_key(),
+            self.endpoints[self.current].url
         )
-        .fetch_optional(&self.db)
-        .await
-        .context("Failed to get cursor")
-        .map(|v| v.map(|v| serde_json::from_str::<u64>(&v.value).unwrap()))
+    }
+
+    /// Cursors used to be kept without the endpoint. Hand such one to the preferred endpoint.
+    async fn adopt_legacy_cursor(&self) -> anyhow::Result<()> {
+        let legacy = self.source.cursor_key();
+        let key = format!("{legacy}:{}", self.endpoints[0].url);
+        self.cursor_store.rename(legacy, &key).await
+    }
+
+    async fn delete_cursor(&self) -> anyhow::Result<()> {
+        self.cursor_store.delete(&self.cursor_key()).await?;
+        debug!("Cursor cleared");
+
+        Ok(())
+    }
+
+    async fn get_cursor(&self) -> anyhow::Result<Option<u64>> {
+        self.cursor_store.get(&self.cursor_key()).await
+    }
+}
+
+/// Wait until `url` accepts a connection, trying every `interval`.
+async fn probe(url: &url::Url, interval: Duration) {
+    loop {
+        tokio::time::sleep(interval).await;
+        match tokio::time::timeout(interval, tokio_tungstenite::connect_async(url.as_str())).await {
+            Ok(Ok((mut stream, _))) => {
+                let _ = stream.close(None).await;
+                return;
+            }
+            Ok(Err(e)) => debug!("{url} is still down - {e}"),
+            Err(_) => debug!("{url} is still down - connection timed out"),
+        }
+    }
+}
+
+/// Pass frames of `rx` along, failing once nothing arrives for `timeout`.
+fn idle_timeout<S>(
+    rx: S,
+    timeout: Duration,
+    metrics: Arc<pipeline::PipelineMetrics>,
+) -> impl Stream<Item = Result<Message, tungstenite::Error>>
+where
+    S: Stream<Item = Result<Message, tungstenite::Error>> + Unpin,
+{
+    futures_util::stream::unfold(rx, move |mut rx| {
+        let metrics = metrics.clone();
+        async move {
+            match tokio::time::timeout(timeout, rx.next()).await {
+                Ok(Some(ret)) => Some((ret, rx)),
+                Ok(None) => None,
+                Err(_) => {
+                    metrics.count_stall();
+                    let error = std::io::Error::new(
+                        std::io::ErrorKind::TimedOut,
+                        format!("Nothing received for {timeout:?}"),
+                    );
+                    Some((Err(tungstenite::Error::Io(error)), rx))
+                }
+            }
+        }
+    })
+}
+
+fn parse_message(data: &[u8]) -> anyhow::Result<SubscriptionMessage<RepoEvent>> {
+    let mut cursor = std::io::Cursor::new(data);
+
+    let header: Header = dagcbor::from_reader_with_option(
+        &mut cursor,
+        DeserializeOption {
+            ignore_trailing: true,
+        },
+    )
+    .context("Failed to parse header")?;
+
+    let body = &data[(cursor.position() as usize)..];
+    if body.is_empty() {
+        return Err(anyhow!("message has no body"));
+    }
+    match header.operation {
+        HeaderOperation::Ok => {
+            let Some(r#type) = header._type else {
+                return Err(anyhow!("message has no type"));
+            };
+            Ok(SubscriptionMessage::Message(
+                RepoEvent::from_cbor(&r#type, body).context("Failed to parse event")?,
+            ))
+        }
+        HeaderOperation::Error => Ok(SubscriptionMessage::Error(
+            dagcbor::from_slice::<Error>(body).context("Failed to parse error")?,
+        )),
     }
 }
 
@@ -248,11 +1511,1508 @@ enum HeaderOperation {
 #[derive(Debug, serde::Deserialize)]
 pub struct Error {
     #[serde(rename = "error")]
-    pub error_type: String,
+    pub error_type: ErrorType,
     pub message: Option<String>,
 }
 
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.error_type)?;
+        if let Some(message) = &self.message {
+            write!(f, ": {message}")?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Errors declared by `com.atproto.sync.subscribeRepos`.
+#[derive(Debug, PartialEq, Eq, serde::Deserialize)]
+#[serde(from = "String")]
+pub enum ErrorType {
+    FutureCursor,
+    ConsumerTooSlow,
+    Unknown(String),
+}
+
+impl From<String> for ErrorType {
+    fn from(value: String) -> Self {
+        match value.as_str() {
+            "FutureCursor" => Self::FutureCursor,
+            "ConsumerTooSlow" => Self::ConsumerTooSlow,
+            _ => Self::Unknown(value),
+        }
+    }
+}
+
+impl std::fmt::Display for ErrorType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::FutureCursor => f.write_str("FutureCursor"),
+            Self::ConsumerTooSlow => f.write_str("ConsumerTooSlow"),
+            Self::Unknown(v) => f.write_str(v),
+        }
+    }
+}
+
+#[derive(Debug)]
 pub enum SubscriptionMessage<T> {
     Message(T),
     Error(Error),
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{future::Future, net::SocketAddr};
+
+    use futures_util::SinkExt;
+    use tokio::{net::TcpStream, sync::mpsc};
+    use tokio_tungstenite::{
+        tungstenite::handshake::server::{Request, Response},
+        WebSocketStream,
+    };
+
+    use super::{cursor::CursorStore, mock_relay::memory_db, *};
+
+    #[derive(Clone)]
+    struct NullHandler;
+
+    #[async_trait]
+    impl FirehoseSubscriptionHandler for NullHandler {
+        async fn handle_event(
+            &self,
+            _conn: &mut SqliteConnection,
+            _event: RepoEvent,
+        ) -> anyhow::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Writes each sequenced event into `post` to tell which of them are committed.
+    #[derive(Clone)]
+    struct RecordingHandler {
+        fail_at: Option<u64>,
+        handled: mpsc::UnboundedSender<u64>,
+    }
+
+    #[async_trait]
+    impl FirehoseSubscriptionHandler for RecordingHandler {
+        async fn handle_event(
+            &self,
+            conn: &mut SqliteConnection,
+            event: RepoEvent,
+        ) -> anyhow::Result<()> {
+            let Some(seq) = event.seq() else {
+                return Ok(());
+            };
+            if self.fail_at == Some(seq) {
+                return Err(anyhow!("Failed to handle {seq}"));
+            }
+            sqlx::query(
+                "INSERT INTO `post` (`uri`, `cid`, `author`, `indexedAt`) VALUES (?, '', '', '')",
+            )
+            .bind(seq.to_string())
+            .execute(conn)
+            .await?;
+            let _ = self.handled.send(seq);
+
+            Ok(())
+        }
+    }
+
+    fn frame(header: serde_json::Value, body: serde_json::Value) -> Vec<u8> {
+        let mut data = dagcbor::to_vec(&header).unwrap();
+        data.extend(dagcbor::to_vec(&body).unwrap());
+        data
+    }
+
+    fn identity_frame(seq: u64) -> Message {
+        Message::Binary(frame(
+            serde_json::json!({ "op": 1, "t": "#identity" }),
+            serde_json::json!({ "seq": seq, "time": "2024-01-01T00:00:00.000Z", "did": "did:plc:test" }),
+        ))
+    }
+
+    async fn committed_posts(db: &SqlitePool) -> Vec<String> {
+        sqlx::query_scalar("SELECT `uri` FROM `post` ORDER BY `uri`")
+            .fetch_all(db)
+            .await
+            .unwrap()
+    }
+
+    async fn next_handled(handled: &mut mpsc::UnboundedReceiver<u64>) -> u64 {
+        tokio::time::timeout(Duration::from_secs(5), handled.recv())
+            .await
+            .expect("event is not handled")
+            .unwrap()
+    }
+
+    async fn committed_cursors(db: &SqlitePool) -> Vec<(String, String)> {
+        sqlx::query_as("SELECT `key`, `value` FROM `app_state` ORDER BY `key`")
+            .fetch_all(db)
+            .await
+            .unwrap()
+    }
+
+    /// Run a websocket stand-in of the service.
+    /// Reports the accepted time and requested uri of each connection.
+    #[allow(clippy::result_large_err)] // handshake callback signature is fixed by tungstenite
+    async fn serve<F, Fut>(
+        on_connect: F,
+    ) -> (SocketAddr, mpsc::UnboundedReceiver<(Instant, String)>)
+    where
+        F: Fn(usize, WebSocketStream<TcpStream>) -> Fut + Send + 'static,
+        Fut: Future<Output = ()> + Send,
+    {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (accepted_tx, accepted_rx) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            let mut count = 0;
+            while let Ok((stream, _)) = listener.accept().await {
+                let mut uri = String::new();
+                let Ok(ws) =
+                    tokio_tungstenite::accept_hdr_async(stream, |req: &Request, resp: Response| {
+                        uri = req.uri().to_string();
+                        Ok(resp)
+                    })
+                    .await
+                else {
+                    continue;
+                };
+                let _ = accepted_tx.send((Instant::now(), uri));
+                on_connect(count, ws).await;
+                count += 1;
+            }
+        });
+
+        (addr, accepted_rx)
+    }
+
+    async fn next_connection(
+        accepted: &mut mpsc::UnboundedReceiver<(Instant, String)>,
+    ) -> (Instant, String) {
+        tokio::time::timeout(Duration::from_secs(5), accepted.recv())
+            .await
+            .expect("subscription did not connect")
+            .unwrap()
+    }
+
+    #[test]
+    fn test_reconnect_delay() {
+        let policy = ReconnectPolicy {
+            initial_delay: Duration::from_millis(100),
+            max_delay: Duration::from_secs(1),
+            reset_after: Duration::from_secs(10),
+        };
+
+        assert_eq!(policy.max_delay_for(0), Duration::from_millis(100));
+        assert_eq!(policy.max_delay_for(1), Duration::from_millis(200));
+        assert_eq!(policy.max_delay_for(3), Duration::from_millis(800));
+        assert_eq!(policy.max_delay_for(4), Duration::from_secs(1));
+        assert_eq!(policy.max_delay_for(u32::MAX), Duration::from_secs(1));
+
+        for attempt in 0..40 {
+            let max = policy.max_delay_for(attempt);
+            let delay = policy.delay_for(attempt);
+            assert!(delay >= max / 2 && delay <= max, "{delay:?} / {max:?}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_reconnect_on_dropped_connection() {
+        // Drop every connection right away, alternating between a clean close and an abrupt disconnect.
+        let (addr, mut accepted) = serve(|count, mut ws| async move {
+            if count % 2 == 1 {
+                let _ = ws.close(None).await;
+            }
+        })
+        .await;
+
+        let policy = ReconnectPolicy {
+            initial_delay: Duration::from_millis(10),
+            max_delay: Duration::from_millis(80),
+            reset_after: Duration::from_secs(10),
+        };
+        let (stop_tx, _) = watch::channel(false);
+        let stop_tx = Arc::new(stop_tx);
+        let subscription = FirehoseSubscription::new(
+            memory_db().await,
+            format!("ws://{addr}"),
+            Source::Firehose,
+            NullHandler,
+            policy.clone(),
+            FlushPolicy::default(),
+            stop_tx.clone(),
+        )
+        .await
+        .unwrap();
+        let join = subscription.run().unwrap();
+
+        let mut accepted_at = vec![];
+        while accepted_at.len() < 6 {
+            accepted_at.push(next_connection(&mut accepted).await.0);
+        }
+        stop_tx.send(true).unwrap();
+        tokio::time::timeout(Duration::from_secs(5), join)
+            .await
+            .expect("subscription did not stop")
+            .unwrap()
+            .unwrap();
+
+        for (attempt, pair) in accepted_at.windows(2).enumerate() {
+            assert!(pair[1] - pair[0] >= policy.max_delay_for(attempt as u32) / 2);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_idle_timeout() {
+        // The first connection goes silent without answering pings. Later ones answer them but send nothing.
+        let (addr, mut accepted) = serve(|count, mut ws| async move {
+            tokio::spawn(async move {
+                if count == 0 {
+                    tokio::time::sleep(Duration::from_secs(5)).await;
+                } else {
+                    while let Some(Ok(_)) = ws.next().await {}
+                }
+            });
+        })
+        .await;
+
+        let (stop_tx, _) = watch::channel(false);
+        let stop_tx = Arc::new(stop_tx);
+        let subscription = FirehoseSubscription::new(
+            memory_db().await,
+            format!("ws://{addr}"),
+            Source::Firehose,
+            NullHandler,
+            ReconnectPolicy {
+                initial_delay: Duration::from_millis(10),
+                max_delay: Duration::from_millis(10),
+                reset_after: Duration::from_secs(10),
+            },
+            FlushPolicy::default(),
+            stop_tx.clone(),
+        )
+        .await
+        .unwrap()
+        .with_keepalive(KeepalivePolicy {
+            ping_interval: Duration::from_millis(50),
+            idle_timeout: Duration::from_millis(200),
+        });
+        let metrics = subscription.metrics();
+        let join = subscription.run().unwrap();
+
+        let (first, _) = next_connection(&mut accepted).await;
+        let (second, _) = next_connection(&mut accepted).await;
+        assert!(second - first >= Duration::from_millis(200));
+        // Pongs keep the quiet connection.
+        assert!(
+            tokio::time::timeout(Duration::from_millis(600), accepted.recv())
+                .await
+                .is_err()
+        );
+        assert_eq!(metrics.snapshot().stalls, 1);
+
+        stop_tx.send(true).unwrap();
+        tokio::time::timeout(Duration::from_secs(5), join)
+            .await
+            .expect("subscription did not stop")
+            .unwrap()
+            .unwrap();
+    }
+
+    #[test]
+    fn test_parse_error_frame() {
+        let message = FirehoseSubscription::<NullHandler>::parse_message(&frame(
+            serde_json::json!({ "op": -1 }),
+            serde_json::json!({ "error": "FutureCursor", "message": "Cursor in the future." }),
+        ))
+        .unwrap();
+        let SubscriptionMessage::Error(error) = message else {
+            panic!("expected error frame - {message:?}");
+        };
+        assert_eq!(error.error_type, ErrorType::FutureCursor);
+        assert_eq!(error.message.as_deref(), Some("Cursor in the future."));
+
+        let message = FirehoseSubscription::<NullHandler>::parse_message(&frame(
+            serde_json::json!({ "op": -1 }),
+            serde_json::json!({ "error": "ConsumerTooSlow" }),
+        ))
+        .unwrap();
+        assert!(matches!(
+            message,
+            SubscriptionMessage::Error(Error {
+                error_type: ErrorType::ConsumerTooSlow,
+                message: None,
+            })
+        ));
+
+        let message = FirehoseSubscription::<NullHandler>::parse_message(&frame(
+            serde_json::json!({ "op": -1 }),
+            serde_json::json!({ "error": "SomethingNew" }),
+        ))
+        .unwrap();
+        let SubscriptionMessage::Error(error) = message else {
+            panic!("expected error frame - {message:?}");
+        };
+        assert_eq!(
+            error.error_type,
+            ErrorType::Unknown("SomethingNew".to_string())
+        );
+    }
+
+    #[test]
+    fn test_parse_info_frame() {
+        let message = FirehoseSubscription::<NullHandler>::parse_message(&frame(
+            serde_json::json!({ "op": 1, "t": "#info" }),
+            serde_json::json!({ "name": "OutdatedCursor", "message": "Requested cursor exceeded limit." }),
+        ))
+        .unwrap();
+        let SubscriptionMessage::Message(RepoEvent::Info(info)) = message else {
+            panic!("expected info frame - {message:?}");
+        };
+        assert_eq!(info.name, InfoName::OutdatedCursor);
+
+        let message = FirehoseSubscription::<NullHandler>::parse_message(&frame(
+            serde_json::json!({ "op": 1, "t": "#info" }),
+            serde_json::json!({ "name": "SomethingNew" }),
+        ))
+        .unwrap();
+        let SubscriptionMessage::Message(RepoEvent::Info(info)) = message else {
+            panic!("expected info frame - {message:?}");
+        };
+        assert_eq!(info.name, InfoName::Other("SomethingNew".to_string()));
+    }
+
+    #[test]
+    fn test_parse_unknown_and_sync_frame() {
+        let message = FirehoseSubscription::<NullHandler>::parse_message(&frame(
+            serde_json::json!({ "op": 1, "t": "#somethingNew" }),
+            serde_json::json!({ "seq": 7, "foo": "bar" }),
+        ))
+        .unwrap();
+        let SubscriptionMessage::Message(event) = message else {
+            panic!("expected event - {message:?}");
+        };
+        assert!(matches!(&event, RepoEvent::Unknown { tag, .. } if tag == "#somethingNew"));
+        assert_eq!(event.seq(), Some(7));
+
+        let message = FirehoseSubscription::<NullHandler>::parse_message(&mock_relay::sync(
+            8,
+            "did:plc:test",
+            "3kaaaaaaaaa22",
+        ))
+        .unwrap();
+        let SubscriptionMessage::Message(event) = message else {
+            panic!("expected event - {message:?}");
+        };
+        let RepoEvent::Sync(sync) = &event else {
+            panic!("expected sync event - {event:?}");
+        };
+        assert_eq!(sync.did, "did:plc:test");
+        assert_eq!(event.seq(), Some(8));
+    }
+
+    #[tokio::test]
+    async fn test_future_cursor_clears_cursor() {
+        let (addr, mut accepted) = serve(|count, mut ws| async move {
+            if count == 0 {
+                let _ = ws
+                    .send(Message::Binary(frame(
+                        serde_json::json!({ "op": -1 }),
+                        serde_json::json!({ "error": "FutureCursor" }),
+                    )))
+                    .await;
+                let _ = ws.close(None).await;
+            }
+        })
+        .await;
+
+        let (stop_tx, _) = watch::channel(false);
+        let stop_tx = Arc::new(stop_tx);
+        let subscription = FirehoseSubscription::new(
+            memory_db().await,
+            format!("ws://{addr}"),
+            Source::Firehose,
+            NullHandler,
+            ReconnectPolicy {
+                initial_delay: Duration::from_millis(10),
+                max_delay: Duration::from_millis(10),
+                reset_after: Duration::from_secs(10),
+            },
+            FlushPolicy::default(),
+            stop_tx.clone(),
+        )
+        .await
+        .unwrap();
+        subscription
+            .cursor_store
+            .stage(
+                &mut subscription.db.acquire().await.unwrap(),
+                "bsky_cursor",
+                100,
+            )
+            .await
+            .unwrap();
+        let join = subscription.run().unwrap();
+
+        let (_, first) = next_connection(&mut accepted).await;
+        let (_, second) = next_connection(&mut accepted).await;
+        stop_tx.send(true).unwrap();
+        tokio::time::timeout(Duration::from_secs(5), join)
+            .await
+            .expect("subscription did not stop")
+            .unwrap()
+            .unwrap();
+
+        assert!(first.ends_with("?cursor=100"), "{first}");
+        assert!(!second.contains("cursor="), "{second}");
+        assert_eq!(subscription.get_cursor().await.unwrap(), None);
+    }
+
+    async fn run_recording(
+        source: Source,
+        fail_at: Option<u64>,
+        error_policy: ErrorPolicy,
+        flush_policy: FlushPolicy,
+        frames: Vec<Message>,
+        capture: Option<capture::CaptureWriter>,
+    ) -> (
+        SqlitePool,
+        Arc<watch::Sender<bool>>,
+        JoinHandle<anyhow::Result<()>>,
+        mpsc::UnboundedReceiver<u64>,
+    ) {
+        let (addr, _) = serve(move |_, mut ws| {
+            let frames = frames.clone();
+            async move {
+                for frame in frames {
+                    let _ = ws.send(frame).await;
+                }
+                // Keep the connection open until the subscription goes away.
+                while ws.next().await.is_some() {}
+            }
+        })
+        .await;
+
+        let db = memory_db().await;
+        let (handled_tx, handled_rx) = mpsc::unbounded_channel();
+        let (stop_tx, _) = watch::channel(false);
+        let stop_tx = Arc::new(stop_tx);
+        let subscription = FirehoseSubscription::new(
+            db.clone(),
+            format!("ws://{addr}"),
+            source,
+            RecordingHandler {
+                fail_at,
+                handled: handled_tx,
+            },
+            ReconnectPolicy::default(),
+            flush_policy,
+            stop_tx.clone(),
+        )
+        .await
+        .unwrap()
+        .with_error_policy(error_policy);
+        let subscription = match capture {
+            Some(capture) => subscription.with_capture(capture),
+            None => subscription,
+        };
+        let join = subscription.run().unwrap();
+
+        (db, stop_tx, join, handled_rx)
+    }
+
+    #[tokio::test]
+    async fn test_rollback_uncommitted_events() {
+        let (db, _stop_tx, join, _) = run_recording(
+            Source::Firehose,
+            Some(5),
+            ErrorPolicy::Stop,
+            FlushPolicy {
+                max_events: 3,
+                max_interval: Duration::from_secs(60),
+            },
+            (1..=5).map(identity_frame).collect(),
+            None,
+        )
+        .await;
+
+        let ret = tokio::time::timeout(Duration::from_secs(5), join)
+            .await
+            .expect("subscription did not stop")
+            .unwrap();
+        assert!(ret.is_err());
+
+        // Only the first batch is committed. 4 is replayed from the cursor together with 5 after restart.
+        assert_eq!(committed_posts(&db).await, ["1", "2", "3"]);
+        assert_eq!(committed_cursors(&db).await[0].1, "3");
+    }
+
+    #[tokio::test]
+    async fn test_flush_by_interval() {
+        let (db, stop_tx, join, _) = run_recording(
+            Source::Firehose,
+            None,
+            ErrorPolicy::default(),
+            FlushPolicy {
+                max_events: 1000,
+                max_interval: Duration::from_millis(50),
+            },
+            (1..=2).map(identity_frame).collect(),
+            None,
+        )
+        .await;
+
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while committed_posts(&db).await.len() < 2 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("events are not flushed");
+
+        stop_tx.send(true).unwrap();
+        join.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_flush_on_stop() {
+        let (db, stop_tx, join, mut handled) = run_recording(
+            Source::Firehose,
+            None,
+            ErrorPolicy::default(),
+            FlushPolicy {
+                max_events: 1000,
+                max_interval: Duration::from_secs(60),
+            },
+            (1..=4).map(identity_frame).collect(),
+            None,
+        )
+        .await;
+
+        for expected in 1..=4 {
+            let seq = tokio::time::timeout(Duration::from_secs(5), handled.recv())
+                .await
+                .expect("event is not handled")
+                .unwrap();
+            assert_eq!(seq, expected);
+        }
+        stop_tx.send(true).unwrap();
+        join.await.unwrap().unwrap();
+
+        assert_eq!(committed_posts(&db).await, ["1", "2", "3", "4"]);
+        assert_eq!(committed_cursors(&db).await[0].1, "4");
+    }
+
+    #[tokio::test]
+    async fn test_jetstream_source() {
+        let frames = [1725516665234703u64, 1725516665234704]
+            .into_iter()
+            .map(|time_us| {
+                Message::Text(
+                    serde_json::json!({
+                        "did": "did:plc:test",
+                        "time_us": time_us,
+                        "kind": "identity",
+                        "identity": { "did": "did:plc:test", "seq": 1, "time": "2024-09-05T06:11:04.870Z" },
+                    })
+                    .to_string(),
+                )
+            })
+            .collect();
+        let (db, stop_tx, join, mut handled) = run_recording(
+            Source::Jetstream(jetstream::Options::new(
+                vec!["app.bsky.feed.post".to_string()],
+                None,
+            )),
+            None,
+            ErrorPolicy::default(),
+            FlushPolicy::default(),
+            frames,
+            None,
+        )
+        .await;
+
+        for _ in 0..2 {
+            tokio::time::timeout(Duration::from_secs(5), handled.recv())
+                .await
+                .expect("event is not handled")
+                .unwrap();
+        }
+        stop_tx.send(true).unwrap();
+        join.await.unwrap().unwrap();
+
+        let cursors = committed_cursors(&db).await;
+        assert_eq!(cursors.len(), 1);
+        assert!(cursors[0].0.starts_with("jetstream_cursor:ws://"));
+        assert_eq!(cursors[0].1, "1725516665234704");
+    }
+
+    #[test]
+    fn test_source_url() {
+        assert_eq!(
+            Source::Firehose
+                .url("wss://bsky.network", Some(10))
+                .unwrap()
+                .as_str(),
+            "wss://bsky.network/xrpc/com.atproto.sync.subscribeRepos?cursor=10"
+        );
+        assert_eq!(
+            Source::Jetstream(jetstream::Options::new(
+                vec!["app.bsky.feed.post".to_string()],
+                None
+            ))
+            .url("wss://jetstream.example.com", None)
+            .unwrap()
+            .as_str(),
+            "wss://jetstream.example.com/subscribe?wantedCollections=app.bsky.feed.post"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_record_and_replay() {
+        let path = std::env::temp_dir().join(format!(
+            "eueoeo-feed-test-capture-{}.bin",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&path);
+
+        let (_, stop_tx, join, mut handled) = run_recording(
+            Source::Firehose,
+            None,
+            ErrorPolicy::default(),
+            FlushPolicy::default(),
+            (1..=3).map(identity_frame).collect(),
+            Some(capture::CaptureWriter::open(&path).unwrap()),
+        )
+        .await;
+        for _ in 1..=3 {
+            tokio::time::timeout(Duration::from_secs(5), handled.recv())
+                .await
+                .expect("event is not handled")
+                .unwrap();
+        }
+        stop_tx.send(true).unwrap();
+        join.await.unwrap().unwrap();
+
+        let db = memory_db().await;
+        let (handled_tx, _handled_rx) = mpsc::unbounded_channel();
+        let mut subscription = FirehoseSubscription::builder(RecordingHandler {
+            fail_at: None,
+            handled: handled_tx,
+        })
+        .database(db.clone())
+        .endpoint("")
+        .cursor_store(cursor::MemoryCursorStore::default())
+        .build()
+        .unwrap();
+        let frames = capture::CaptureReader::open(&path).unwrap();
+        subscription
+            .replay(capture::frames(frames, false))
+            .await
+            .unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(committed_posts(&db).await, ["1", "2", "3"]);
+        assert_eq!(subscription.get_cursor().await.unwrap(), Some(3));
+        // The cursor of the service is left where it was.
+        assert!(committed_cursors(&db).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_drop_unverified_commits() {
+        const REPO: &str = "did:plc:author";
+        let key = k256::ecdsa::SigningKey::random(&mut rand::thread_rng());
+        let resolver = Arc::new(crate::identity::StubDidResolver::default());
+        resolver.insert(crate::identity::DidDocument::new(
+            REPO,
+            &crate::identity::PublicKey::K256(*key.verifying_key()),
+            None,
+        ));
+        let ops = || {
+            vec![mock_relay::Op::Create {
+                path: "app.bsky.feed.post/1".to_string(),
+                record: mock_relay::records::post("으어어"),
+            }]
+        };
+
+        let db = memory_db().await;
+        let (handled_tx, _handled_rx) = mpsc::unbounded_channel();
+        let mut subscription = FirehoseSubscription::new(
+            db.clone(),
+            String::new(),
+            Source::Firehose,
+            RecordingHandler {
+                fail_at: None,
+                handled: handled_tx,
+            },
+            ReconnectPolicy::default(),
+            FlushPolicy::default(),
+            Arc::new(watch::channel(false).0),
+        )
+        .await
+        .unwrap()
+        .with_verifier(verify::Verifier::new(resolver.clone()))
+        .with_error_policy(ErrorPolicy::DeadLetter);
+        let frames = [
+            mock_relay::signed_commit(1, REPO, "3kaaaaaaaaa22", ops(), &key),
+            mock_relay::commit(2, REPO, "3kaaaaaaaaa23", ops()),
+            mock_relay::signed_commit(3, "did:plc:unknown", "3kaaaaaaaaa24", ops(), &key),
+            // Follows 2, which is dropped.
+            mock_relay::signed_commit_since(4, REPO, "3kaaaaaaaaa25", "3kaaaaaaaaa23", ops(), &key),
+        ];
+        subscription
+            .replay(futures_util::stream::iter(
+                frames.into_iter().map(Message::Binary).map(Ok),
+            ))
+            .await
+            .unwrap();
+
+        assert_eq!(committed_posts(&db).await, ["1", "4"]);
+        assert_eq!(subscription.metrics().snapshot().rev_breaks, 0);
+        assert!(continuity::desynced_repos(&db).await.unwrap().is_empty());
+        // The key of 3 can't be resolved, which is not the fault of the commit.
+        let letters = dead_letter::list(&db).await.unwrap();
+        assert_eq!(letters.len(), 1);
+        assert_eq!(letters[0].seq, Some(3));
+        assert!(
+            letters[0]
+                .error
+                .contains("Failed to resolve DID document of did:plc:unknown"),
+            "{}",
+            letters[0].error
+        );
+
+        // Retried through verification once the key is resolved.
+        resolver.insert(crate::identity::DidDocument::new(
+            "did:plc:unknown",
+            &crate::identity::PublicKey::K256(*key.verifying_key()),
+            None,
+        ));
+        assert_eq!(subscription.retry_dead_letters(None).await.unwrap(), (1, 0));
+        assert_eq!(committed_posts(&db).await, ["1", "3", "4"]);
+    }
+
+    #[tokio::test]
+    async fn test_backfill_too_big_commits() {
+        const REPO: &str = "did:plc:author";
+        let pds = mock_relay::MockPds::start().await.unwrap();
+        pds.insert(
+            REPO,
+            "app.bsky.feed.post/1",
+            mock_relay::records::post("으어어"),
+        );
+        pds.throttle(1);
+        let key = k256::ecdsa::SigningKey::random(&mut rand::thread_rng());
+        let resolver = Arc::new(crate::identity::StubDidResolver::default());
+        resolver.insert(crate::identity::DidDocument::new(
+            REPO,
+            &crate::identity::PublicKey::K256(*key.verifying_key()),
+            Some(&pds.endpoint()),
+        ));
+
+        let db = memory_db().await;
+        let (handled_tx, _handled_rx) = mpsc::unbounded_channel();
+        let mut subscription = FirehoseSubscription::new(
+            db.clone(),
+            String::new(),
+            Source::Firehose,
+            RecordingHandler {
+                fail_at: None,
+                handled: handled_tx,
+            },
+            ReconnectPolicy::default(),
+            FlushPolicy::default(),
+            Arc::new(watch::channel(false).0),
+        )
+        .await
+        .unwrap()
+        .with_record_fetcher(
+            too_big::RecordFetcher::new(
+                resolver.clone(),
+                too_big::FetchPolicy {
+                    max_retries: 0,
+                    ..Default::default()
+                },
+            )
+            .unwrap()
+            .allow_private_hosts()
+            .unwrap(),
+        );
+        let frames = [
+            Message::Binary(mock_relay::too_big_commit(
+                1,
+                REPO,
+                "3kaaaaaaaaa22",
+                vec![mock_relay::Op::Create {
+                    path: "app.bsky.feed.post/1".to_string(),
+                    record: mock_relay::records::post("으어어"),
+                }],
+            )),
+            identity_frame(2),
+        ];
+        subscription
+            .replay(futures_util::stream::iter(frames.into_iter().map(Ok)))
+            .await
+            .unwrap();
+
+        // The commit waiting for its records does not hold the cursor back.
+        assert_eq!(committed_posts(&db).await, ["2"]);
+        assert_eq!(
+            committed_cursors(&db).await,
+            [("bsky_cursor:".to_string(), "2".to_string())]
+        );
+        // The PDS is throttled, so the commit is left waiting.
+        let letters = dead_letter::list(&db).await.unwrap();
+        assert_eq!(letters.len(), 1);
+        assert_eq!(letters[0].kind, dead_letter::DeadLetterKind::TooBig);
+        assert_eq!(letters[0].seq, Some(1));
+        assert!(
+            letters[0].error.contains("Failed to fetch"),
+            "{}",
+            letters[0].error
+        );
+        // Failed ones alone are purged unless asked.
+        let store = subscription.dead_letter_store();
+        assert_eq!(
+            store
+                .purge(dead_letter::DeadLetterKind::Failed)
+                .await
+                .unwrap(),
+            0
+        );
+
+        // Taken up again on start, and fetched again once the PDS fails once more.
+        pds.throttle(1);
+        let relay = mock_relay::MockRelay::start().await.unwrap();
+        let (handled_tx, _handled_rx) = mpsc::unbounded_channel();
+        let subscription = FirehoseSubscription::builder(RecordingHandler {
+            fail_at: None,
+            handled: handled_tx,
+        })
+        .database(db.clone())
+        .endpoint(relay.endpoint())
+        .build()
+        .unwrap()
+        .with_record_fetcher(
+            too_big::RecordFetcher::new(
+                resolver,
+                too_big::FetchPolicy {
+                    max_retries: 0,
+                    backfill_delay: Duration::from_millis(10),
+                    ..Default::default()
+                },
+            )
+            .unwrap()
+            .allow_private_hosts()
+            .unwrap(),
+        );
+        let join = subscription.run().unwrap();
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while committed_posts(&db).await != ["1", "2"] {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("records are not fetched");
+        subscription.stop_signal().send(true).unwrap();
+        join.await.unwrap().unwrap();
+        assert!(dead_letter::list(&db).await.unwrap().is_empty());
+        assert_eq!(pds.requests(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_jetstream_commits_unverified() {
+        let db = memory_db().await;
+        let (handled_tx, _handled_rx) = mpsc::unbounded_channel();
+        let mut subscription = FirehoseSubscription::new(
+            db.clone(),
+            String::new(),
+            Source::Jetstream(Default::default()),
+            RecordingHandler {
+                fail_at: None,
+                handled: handled_tx,
+            },
+            ReconnectPolicy::default(),
+            FlushPolicy::default(),
+            Arc::new(watch::channel(false).0),
+        )
+        .await
+        .unwrap()
+        // Knows no DID, so any commit verified would be dropped.
+        .with_verifier(verify::Verifier::new(Arc::new(
+            crate::identity::StubDidResolver::default(),
+        )));
+        // Ops of a commit come as events of the same rev.
+        let frames = ["3l3qo2vuowo2b", "3l3qo2vuowo2c"]
+            .into_iter()
+            .enumerate()
+            .map(|(i, rkey)| {
+                Message::Text(
+                    serde_json::json!({
+                        "did": "did:plc:author",
+                        "time_us": 1725911162329308u64 + i as u64,
+                        "kind": "commit",
+                        "commit": {
+                            "rev": "3l3qo2vutsw2b",
+                            "operation": "delete",
+                            "collection": "app.bsky.feed.post",
+                            "rkey": rkey,
+                        },
+                    })
+                    .to_string(),
+                )
+            })
+            .collect::<Vec<_>>();
+        subscription
+            .replay(futures_util::stream::iter(frames.into_iter().map(Ok)))
+            .await
+            .unwrap();
+
+        assert_eq!(
+            committed_posts(&db).await,
+            ["1725911162329308", "1725911162329309"]
+        );
+        assert_eq!(subscription.metrics().snapshot().rev_breaks, 0);
+        assert!(continuity::desynced_repos(&db).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_mock_relay_cursor() {
+        let relay = mock_relay::MockRelay::start().await.unwrap();
+        for seq in 1..=5 {
+            relay.push(seq, mock_relay::identity(seq, "did:plc:test", None));
+        }
+
+        let db = memory_db().await;
+        cursor::SqliteCursorStore::new(db.clone())
+            .stage(&mut db.acquire().await.unwrap(), "bsky_cursor", 2)
+            .await
+            .unwrap();
+        let (handled_tx, mut handled) = mpsc::unbounded_channel();
+        let (stop_tx, _) = watch::channel(false);
+        let stop_tx = Arc::new(stop_tx);
+        let subscription = FirehoseSubscription::new(
+            db.clone(),
+            relay.endpoint(),
+            Source::Firehose,
+            RecordingHandler {
+                fail_at: None,
+                handled: handled_tx,
+            },
+            ReconnectPolicy::default(),
+            FlushPolicy::default(),
+            stop_tx.clone(),
+        )
+        .await
+        .unwrap();
+        let join = subscription.run().unwrap();
+
+        for expected in 3..=6 {
+            if expected == 6 {
+                relay.push(6, mock_relay::identity(6, "did:plc:test", None));
+            }
+            let seq = tokio::time::timeout(Duration::from_secs(5), handled.recv())
+                .await
+                .expect("event is not handled")
+                .unwrap();
+            assert_eq!(seq, expected);
+        }
+        stop_tx.send(true).unwrap();
+        join.await.unwrap().unwrap();
+
+        assert_eq!(relay.requested_cursors(), [Some(2)]);
+        assert_eq!(committed_posts(&db).await, ["3", "4", "5", "6"]);
+    }
+
+    #[tokio::test]
+    async fn test_builder_cursor_store() {
+        let relay = mock_relay::MockRelay::start().await.unwrap();
+        for seq in 1..=3 {
+            relay.push(seq, mock_relay::identity(seq, "did:plc:test", None));
+        }
+
+        let db = memory_db().await;
+        let store = cursor::MemoryCursorStore::default();
+        store
+            .set(&format!("bsky_cursor:{}", relay.endpoint()), 1)
+            .await
+            .unwrap();
+        let (handled_tx, mut handled) = mpsc::unbounded_channel();
+        let subscription = FirehoseSubscription::builder(RecordingHandler {
+            fail_at: None,
+            handled: handled_tx,
+        })
+        .database(db.clone())
+        .endpoint(relay.endpoint())
+        .cursor_store(store)
+        .flush_policy(FlushPolicy {
+            max_events: 1,
+            ..Default::default()
+        })
+        .build()
+        .unwrap();
+        let join = subscription.run().unwrap();
+
+        for expected in 2..=3 {
+            let seq = tokio::time::timeout(Duration::from_secs(5), handled.recv())
+                .await
+                .expect("event is not handled")
+                .unwrap();
+            assert_eq!(seq, expected);
+        }
+        subscription.stop_signal().send(true).unwrap();
+        join.await.unwrap().unwrap();
+
+        assert_eq!(relay.requested_cursors(), [Some(1)]);
+        assert_eq!(subscription.get_cursor().await.unwrap(), Some(3));
+        // Nothing is kept in the database but the events.
+        assert!(committed_cursors(&db).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_without_database() {
+        let relay = mock_relay::MockRelay::start().await.unwrap();
+        // `post` does not exist in the private database, so every event fails.
+        let (handled_tx, _handled) = mpsc::unbounded_channel();
+        let subscription = FirehoseSubscription::builder(RecordingHandler {
+            fail_at: None,
+            handled: handled_tx,
+        })
+        .endpoint(relay.endpoint())
+        .build()
+        .unwrap()
+        .with_error_policy(ErrorPolicy::DeadLetter);
+        let join = subscription.run().unwrap();
+        relay.wait_for_connections(1).await;
+        for seq in 1..=3 {
+            relay.push(seq, mock_relay::identity(seq, "did:plc:test", None));
+        }
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while subscription.get_cursor().await.unwrap() != Some(3) {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("cursor is not advanced");
+        subscription.stop_signal().send(true).unwrap();
+        join.await.unwrap().unwrap();
+
+        assert_eq!(subscription.retry_dead_letters(None).await.unwrap(), (0, 3));
+        let store = subscription.dead_letter_store();
+        assert_eq!(store.list().await.unwrap().len(), 3);
+        assert_eq!(
+            store
+                .purge(dead_letter::DeadLetterKind::Failed)
+                .await
+                .unwrap(),
+            3
+        );
+        assert!(store.list().await.unwrap().is_empty());
+    }
+
+    async fn replay_with_policy(
+        error_policy: ErrorPolicy,
+    ) -> (
+        SqlitePool,
+        FirehoseSubscription<RecordingHandler>,
+        anyhow::Result<()>,
+    ) {
+        let db = memory_db().await;
+        let (handled_tx, _handled_rx) = mpsc::unbounded_channel();
+        let mut subscription = FirehoseSubscription::new(
+            db.clone(),
+            String::new(),
+            Source::Firehose,
+            RecordingHandler {
+                fail_at: None,
+                handled: handled_tx,
+            },
+            ReconnectPolicy::default(),
+            FlushPolicy::default(),
+            Arc::new(watch::channel(false).0),
+        )
+        .await
+        .unwrap()
+        .with_error_policy(error_policy);
+        let frames = [
+            identity_frame(1),
+            // Commit without the required fields.
+            Message::Binary(mock_relay::frame(
+                Some("#commit"),
+                &serde_json::json!({ "seq": 2, "repo": "did:plc:test" }),
+            )),
+            identity_frame(3),
+        ];
+        let ret = subscription
+            .replay(futures_util::stream::iter(frames.into_iter().map(Ok)))
+            .await;
+
+        (db, subscription, ret)
+    }
+
+    #[tokio::test]
+    async fn test_skip_undecodable() {
+        let (db, subscription, ret) = replay_with_policy(ErrorPolicy::Skip).await;
+        ret.unwrap();
+
+        assert_eq!(committed_posts(&db).await, ["1", "3"]);
+        assert_eq!(subscription.get_cursor().await.unwrap(), Some(3));
+        assert_eq!(subscription.metrics().snapshot().undecodable, 1);
+        let dead_letters: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM `dead_letter`")
+            .fetch_one(&db)
+            .await
+            .unwrap();
+        assert_eq!(dead_letters, 0);
+    }
+
+    #[tokio::test]
+    async fn test_dead_letter_undecodable() {
+        let (db, subscription, ret) = replay_with_policy(ErrorPolicy::DeadLetter).await;
+        ret.unwrap();
+
+        assert_eq!(committed_posts(&db).await, ["1", "3"]);
+        assert_eq!(subscription.get_cursor().await.unwrap(), Some(3));
+        let dead_letters: Vec<(Option<i64>, Option<String>, String)> =
+            sqlx::query_as("SELECT `seq`, `repo`, `error` FROM `dead_letter`")
+                .fetch_all(&db)
+                .await
+                .unwrap();
+        assert_eq!(dead_letters.len(), 1);
+        let (seq, repo, error) = &dead_letters[0];
+        assert_eq!(*seq, Some(2));
+        assert_eq!(repo.as_deref(), Some("did:plc:test"));
+        assert!(error.starts_with("Failed to parse message"), "{error}");
+    }
+
+    #[tokio::test]
+    async fn test_stop_on_undecodable() {
+        let (db, subscription, ret) = replay_with_policy(ErrorPolicy::Stop).await;
+        assert!(ret.is_err());
+
+        assert!(committed_posts(&db).await.is_empty());
+        assert_eq!(subscription.get_cursor().await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_dead_letter_failed_event() {
+        let db = memory_db().await;
+        let subscription = |fail_at| {
+            let db = db.clone();
+            async move {
+                FirehoseSubscription::new(
+                    db,
+                    String::new(),
+                    Source::Firehose,
+                    RecordingHandler {
+                        fail_at,
+                        handled: mpsc::unbounded_channel().0,
+                    },
+                    ReconnectPolicy::default(),
+                    FlushPolicy::default(),
+                    Arc::new(watch::channel(false).0),
+                )
+                .await
+                .unwrap()
+                .with_error_policy(ErrorPolicy::DeadLetter)
+            }
+        };
+
+        let mut failing = subscription(Some(2)).await;
+        failing
+            .replay(futures_util::stream::iter(
+                (1..=3).map(identity_frame).map(Ok),
+            ))
+            .await
+            .unwrap();
+        assert_eq!(committed_posts(&db).await, ["1", "3"]);
+        assert_eq!(failing.get_cursor().await.unwrap(), Some(3));
+        assert_eq!(failing.metrics().snapshot().failed, 1);
+
+        let letters = dead_letter::list(&db).await.unwrap();
+        assert_eq!(letters.len(), 1);
+        assert_eq!(letters[0].seq, Some(2));
+        assert_eq!(letters[0].repo.as_deref(), Some("did:plc:test"));
+        assert_eq!(letters[0].frame, identity_frame(2).into_data());
+        assert!(
+            letters[0].error.contains("Failed to handle 2"),
+            "{}",
+            letters[0].error
+        );
+
+        // Still failing. The letter is kept.
+        assert_eq!(failing.retry_dead_letters(None).await.unwrap(), (0, 1));
+        assert_eq!(dead_letter::list(&db).await.unwrap().len(), 1);
+
+        let fixed = subscription(None).await;
+        assert_eq!(fixed.retry_dead_letters(Some(&[])).await.unwrap(), (0, 0));
+        assert_eq!(fixed.retry_dead_letters(None).await.unwrap(), (1, 0));
+        assert_eq!(committed_posts(&db).await, ["1", "2", "3"]);
+        assert!(dead_letter::list(&db).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_retry_dead_letters_in_pages() {
+        let db = memory_db().await;
+        let mut conn = db.acquire().await.unwrap();
+        for seq in 1..=250 {
+            dead_letter::insert(
+                &mut conn,
+                &FailedFrame {
+                    seq: Some(seq),
+                    repo: Some("did:plc:test".to_string()),
+                    frame: identity_frame(seq).into_data(),
+                    error: anyhow!("{:?}", vec![0u8; 1000]).context("Failed to handle"),
+                },
+                dead_letter::DeadLetterKind::Failed,
+            )
+            .await
+            .unwrap();
+        }
+        drop(conn);
+        let letters = dead_letter::list(&db).await.unwrap();
+        // The dump is cut short, as the frame is kept on its own.
+        assert!(letters[0].error.starts_with("Failed to handle: [0, 0"));
+        assert!(letters[0].error.len() < 300, "{}", letters[0].error);
+
+        let subscription = FirehoseSubscription::new(
+            db.clone(),
+            String::new(),
+            Source::Firehose,
+            RecordingHandler {
+                fail_at: Some(120),
+                handled: mpsc::unbounded_channel().0,
+            },
+            ReconnectPolicy::default(),
+            FlushPolicy::default(),
+            Arc::new(watch::channel(false).0),
+        )
+        .await
+        .unwrap();
+        let ids = letters.iter().take(2).map(|l| l.id).collect::<Vec<_>>();
+        assert_eq!(
+            subscription.retry_dead_letters(Some(&ids)).await.unwrap(),
+            (2, 0)
+        );
+        assert_eq!(
+            subscription.retry_dead_letters(None).await.unwrap(),
+            (247, 1)
+        );
+        assert_eq!(committed_posts(&db).await.len(), 249);
+        let letters = dead_letter::list(&db).await.unwrap();
+        assert_eq!(letters.len(), 1);
+        assert_eq!(letters[0].seq, Some(120));
+    }
+
+    #[tokio::test]
+    async fn test_sequence_gaps() {
+        let db = memory_db().await;
+        let mut subscription = FirehoseSubscription::new(
+            db.clone(),
+            String::new(),
+            Source::Firehose,
+            NullHandler,
+            ReconnectPolicy::default(),
+            FlushPolicy::default(),
+            Arc::new(watch::channel(false).0),
+        )
+        .await
+        .unwrap();
+        subscription
+            .replay(futures_util::stream::iter(
+                [1, 2, 5, 5, 3, 6, 10].map(identity_frame).map(Ok),
+            ))
+            .await
+            .unwrap();
+
+        assert_eq!(subscription.get_cursor().await.unwrap(), Some(10));
+        let snapshot = subscription.metrics().snapshot();
+        assert_eq!(snapshot.seq_gaps, 2);
+        assert_eq!(snapshot.missed_seqs, 5);
+        assert_eq!(snapshot.duplicate_seqs, 2);
+    }
+
+    #[tokio::test]
+    async fn test_failover() {
+        // The preferred relay is down at first.
+        let preferred_addr = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let fallback = mock_relay::MockRelay::start().await.unwrap();
+
+        let db = memory_db().await;
+        let (handled_tx, mut handled) = mpsc::unbounded_channel();
+        let (stop_tx, _) = watch::channel(false);
+        let stop_tx = Arc::new(stop_tx);
+        let subscription = FirehoseSubscription::builder(RecordingHandler {
+            fail_at: None,
+            handled: handled_tx,
+        })
+        .database(db.clone())
+        .endpoints(
+            vec![
+                Endpoint {
+                    url: fallback.endpoint(),
+                    priority: 10,
+                },
+                Endpoint {
+                    url: format!("ws://{preferred_addr}"),
+                    priority: 0,
+                },
+            ],
+            FailoverPolicy {
+                failover_after: 2,
+                return_interval: Duration::from_millis(100),
+            },
+        )
+        .reconnect_policy(ReconnectPolicy {
+            initial_delay: Duration::from_millis(10),
+            max_delay: Duration::from_millis(10),
+            reset_after: Duration::from_secs(10),
+        })
+        .stop_signal(stop_tx.clone())
+        .build()
+        .unwrap();
+        let join = subscription.run().unwrap();
+
+        fallback.wait_for_connections(1).await;
+        fallback.push(101, mock_relay::identity(101, "did:plc:test", None));
+        assert_eq!(next_handled(&mut handled).await, 101);
+
+        // Moves back once the preferred relay is up.
+        let preferred = mock_relay::MockRelay::start_on(preferred_addr)
+            .await
+            .unwrap();
+        // One of them is the probe.
+        preferred.wait_for_connections(2).await;
+        preferred.push(1, mock_relay::identity(1, "did:plc:test", None));
+        assert_eq!(next_handled(&mut handled).await, 1);
+        stop_tx.send(true).unwrap();
+        join.await.unwrap().unwrap();
+
+        let mut expected = vec![
+            (
+                format!("bsky_cursor:{}", fallback.endpoint()),
+                "101".to_string(),
+            ),
+            (
+                format!("bsky_cursor:ws://{preferred_addr}"),
+                "1".to_string(),
+            ),
+        ];
+        expected.sort();
+        assert_eq!(committed_cursors(&db).await, expected);
+    }
+
+    #[tokio::test]
+    async fn test_multiple_hosts() {
+        let pds = [
+            mock_relay::MockRelay::start().await.unwrap(),
+            mock_relay::MockRelay::start().await.unwrap(),
+        ];
+        let hostnames: Vec<String> = pds
+            .iter()
+            .map(|pds| pds.endpoint().trim_start_matches("ws://").to_string())
+            .collect();
+
+        // Seed host listing the hosts over two pages.
+        let pages = [
+            serde_json::json!({
+                "cursor": "1",
+                "hosts": [
+                    { "hostname": hostnames[0], "seq": 10, "status": "active" },
+                    { "hostname": "offline.example.com", "status": "offline" },
+                ],
+            }),
+            serde_json::json!({ "hosts": [{ "hostname": hostnames[1] }] }),
+        ];
+        let app = axum::Router::new().route(
+            "/xrpc/com.atproto.sync.listHosts",
+            axum::routing::get(
+                move |axum::extract::Query(query): axum::extract::Query<
+                    std::collections::HashMap<String, String>,
+                >| {
+                    let page = match query.get("cursor").map(String::as_str) {
+                        None => &pages[0],
+                        Some(_) => &pages[1],
+                    };
+                    std::future::ready(axum::Json(page.clone()))
+                },
+            ),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let seed = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let hosts = hosts::list_hosts(&seed).await.unwrap();
+        assert_eq!(
+            hosts,
+            pds.iter().map(|pds| pds.endpoint()).collect::<Vec<_>>()
+        );
+
+        // Alice is hosted by the first and Bob by the second.
+        let resolver = Arc::new(crate::identity::StubDidResolver::default());
+        let key = k256::ecdsa::SigningKey::random(&mut rand::thread_rng());
+        for (did, pds) in ["did:plc:alice", "did:plc:bob"].into_iter().zip(&pds) {
+            resolver.insert(crate::identity::DidDocument::new(
+                did,
+                &crate::identity::PublicKey::K256(*key.verifying_key()),
+                Some(&pds.endpoint().replace("ws://", "http://")),
+            ));
+        }
+
+        let db = memory_db().await;
+        let (handled_tx, mut handled) = mpsc::unbounded_channel();
+        let (stop_tx, _) = watch::channel(false);
+        let stop_tx = Arc::new(stop_tx);
+        let join = FirehoseSubscription::new(
+            db.clone(),
+            String::new(),
+            Source::Firehose,
+            RecordingHandler {
+                fail_at: None,
+                handled: handled_tx,
+            },
+            ReconnectPolicy::default(),
+            // Nothing is committed before events of both hosts are handled,
+            // which a transaction of each host would not allow with a single connection.
+            FlushPolicy {
+                max_events: 100,
+                max_interval: Duration::from_secs(60),
+            },
+            stop_tx.clone(),
+        )
+        .await
+        .unwrap()
+        .run_hosts(hosts, resolver)
+        .unwrap();
+
+        pds[0].wait_for_connections(1).await;
+        pds[1].wait_for_connections(1).await;
+        pds[0].push(1, mock_relay::identity(1, "did:plc:alice", None));
+        // Not the PDS of Bob.
+        pds[0].push(2, mock_relay::identity(2, "did:plc:bob", None));
+        pds[0].push(3, mock_relay::identity(3, "did:plc:alice", None));
+        pds[1].push(4, mock_relay::identity(4, "did:plc:bob", None));
+        let mut seqs = vec![
+            next_handled(&mut handled).await,
+            next_handled(&mut handled).await,
+            next_handled(&mut handled).await,
+        ];
+        seqs.sort();
+        assert_eq!(seqs, [1, 3, 4]);
+        stop_tx.send(true).unwrap();
+        join.await.unwrap().unwrap();
+
+        let mut expected = pds
+            .iter()
+            .zip(["3", "4"])
+            .map(|(pds, seq)| (format!("bsky_cursor:{}", pds.endpoint()), seq.to_string()))
+            .collect::<Vec<_>>();
+        expected.sort();
+        assert_eq!(committed_cursors(&db).await, expected);
+    }
+}
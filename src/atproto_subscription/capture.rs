@@ -0,0 +1,411 @@
+//! Capture of raw frames received from the service, to replay them later.
+//!
+//! File layout: [`MAGIC`], then repeated frames of
+//! `received time (unix micros, u64 LE) | kind (u8) | payload length (u32 LE) | payload`.
+
+use std::{
+    fs::File,
+    io::{BufReader, BufWriter, ErrorKind, Read, Write},
+    path::Path,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use anyhow::{anyhow, Context};
+use futures_util::Stream;
+use log::error;
+use tokio::sync::mpsc;
+use tokio_tungstenite::tungstenite::{self, Message};
+
+pub const MAGIC: &[u8; 8] = b"EUFCAP01";
+
+/// Frames which may be received but not written yet.
+const QUEUE_DEPTH: usize = 1024;
+/// Frames which may be read ahead of replaying them.
+const READ_AHEAD: usize = 64;
+/// Payloads longer than this are taken as a corrupt length, as the service sends none near it.
+const MAX_PAYLOAD_LEN: usize = 16 << 20;
+
+const KIND_BINARY: u8 = 0;
+const KIND_TEXT: u8 = 1;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Frame {
+    /// Received time in unix microseconds.
+    pub time_us: i64,
+    pub message: Message,
+}
+
+pub struct CaptureWriter {
+    writer: Box<dyn Write + Send>,
+}
+
+impl CaptureWriter {
+    pub fn new(mut writer: Box<dyn Write + Send>) -> std::io::Result<Self> {
+        writer.write_all(MAGIC)?;
+        writer.flush()?;
+
+        Ok(Self { writer })
+    }
+
+    /// Create a new capture file or append to the existing one.
+    pub fn open(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        let file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("Failed to open capture file - {}", path.display()))?;
+        let writer = Box::new(BufWriter::new(file));
+        if path.metadata()?.len() == 0 {
+            Ok(Self::new(writer)?)
+        } else {
+            let mut magic = [0; MAGIC.len()];
+            File::open(path)?.read_exact(&mut magic)?;
+            if &magic != MAGIC {
+                return Err(anyhow!("Not a capture file - {}", path.display()));
+            }
+            Ok(Self { writer })
+        }
+    }
+
+    /// Append a frame received now, and flush it. Only binary and text frames are kept.
+    pub fn write(&mut self, message: &Message) -> std::io::Result<()> {
+        self.write_frame(&Frame {
+            time_us: chrono::Utc::now().timestamp_micros(),
+            message: message.clone(),
+        })?;
+        // Keep the frames even if the process crashes right after.
+        self.flush()
+    }
+
+    /// Append a frame without flushing it. Only binary and text frames are kept.
+    pub fn write_frame(&mut self, frame: &Frame) -> std::io::Result<()> {
+        let (kind, payload) = match &frame.message {
+            Message::Binary(data) => (KIND_BINARY, data.as_slice()),
+            Message::Text(text) => (KIND_TEXT, text.as_bytes()),
+            _ => return Ok(()),
+        };
+
+        self.writer.write_all(&frame.time_us.to_le_bytes())?;
+        self.writer.write_all(&[kind])?;
+        self.writer
+            .write_all(&(payload.len() as u32).to_le_bytes())?;
+        self.writer.write_all(payload)
+    }
+
+    pub fn flush(&mut self) -> std::io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+/// [`CaptureWriter`] running on a thread of its own, so receiving is not held up by the disk.
+/// Frames are flushed whenever the queue runs empty.
+/// [`CaptureSink::close`] waits until the queued frames are written. Dropping the last clone
+/// without closing it lets them be written in the background, unless it is dropped out of a runtime.
+#[derive(Clone)]
+pub struct CaptureSink {
+    inner: Arc<SinkInner>,
+}
+
+struct SinkInner {
+    tx: Mutex<Option<mpsc::Sender<Frame>>>,
+    thread: Mutex<Option<std::thread::JoinHandle<()>>>,
+}
+
+impl SinkInner {
+    /// Close the queue, which ends the thread once it is drained, and take the thread.
+    fn close(&self) -> Option<std::thread::JoinHandle<()>> {
+        self.tx.lock().unwrap().take();
+        self.thread.lock().unwrap().take()
+    }
+}
+
+impl Drop for SinkInner {
+    fn drop(&mut self) {
+        let Some(thread) = self.close() else {
+            return;
+        };
+        // Joining would block a worker of the runtime until the queue is drained.
+        match tokio::runtime::Handle::try_current() {
+            Ok(runtime) => drop(runtime.spawn_blocking(move || thread.join())),
+            Err(_) => {
+                let _ = thread.join();
+            }
+        }
+    }
+}
+
+impl CaptureSink {
+    pub fn spawn(mut writer: CaptureWriter) -> Self {
+        let (tx, mut rx) = mpsc::channel(QUEUE_DEPTH);
+        let thread = std::thread::spawn(move || {
+            if let Err(e) = drain(&mut writer, &mut rx) {
+                error!("Failed to write capture - {e}");
+            }
+        });
+
+        Self {
+            inner: Arc::new(SinkInner {
+                tx: Mutex::new(Some(tx)),
+                thread: Mutex::new(Some(thread)),
+            }),
+        }
+    }
+
+    /// Stop taking frames, through every clone, and wait until the queued ones are written.
+    pub async fn close(&self) {
+        let Some(thread) = self.inner.close() else {
+            return;
+        };
+        if let Ok(Err(_)) = tokio::task::spawn_blocking(move || thread.join()).await {
+            error!("Capture writer panicked");
+        }
+    }
+
+    /// Queue a frame received now. Waits while the queue is full,
+    /// and fails once the writer has stopped on an error.
+    pub async fn send(&self, message: &Message) -> anyhow::Result<()> {
+        if !matches!(message, Message::Binary(_) | Message::Text(_)) {
+            return Ok(());
+        }
+        let frame = Frame {
+            time_us: chrono::Utc::now().timestamp_micros(),
+            message: message.clone(),
+        };
+        let tx = self.inner.tx.lock().unwrap().clone();
+        let tx = tx.ok_or_else(|| anyhow!("Capture writer is closed"))?;
+
+        tx.send(frame)
+            .await
+            .map_err(|_| anyhow!("Capture writer is stopped"))
+    }
+}
+
+fn drain(writer: &mut CaptureWriter, rx: &mut mpsc::Receiver<Frame>) -> std::io::Result<()> {
+    while let Some(frame) = rx.blocking_recv() {
+        writer.write_frame(&frame)?;
+        while let Ok(frame) = rx.try_recv() {
+            writer.write_frame(&frame)?;
+        }
+        writer.flush()?;
+    }
+
+    Ok(())
+}
+
+pub struct CaptureReader<R> {
+    reader: R,
+}
+
+impl CaptureReader<BufReader<File>> {
+    pub fn open(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        let file = File::open(path)
+            .with_context(|| format!("Failed to open capture file - {}", path.display()))?;
+        Self::new(BufReader::new(file))
+    }
+}
+
+impl<R: Read> CaptureReader<R> {
+    pub fn new(mut reader: R) -> anyhow::Result<Self> {
+        let mut magic = [0; MAGIC.len()];
+        reader
+            .read_exact(&mut magic)
+            .context("Failed to read capture header")?;
+        if &magic != MAGIC {
+            return Err(anyhow!("Not a capture file"));
+        }
+
+        Ok(Self { reader })
+    }
+
+    pub fn read_frame(&mut self) -> std::io::Result<Option<Frame>> {
+        let mut time_us = [0; 8];
+        match self.reader.read_exact(&mut time_us) {
+            Ok(()) => {}
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e),
+        }
+        let mut kind = [0; 1];
+        self.reader.read_exact(&mut kind)?;
+        let mut len = [0; 4];
+        self.reader.read_exact(&mut len)?;
+        let len = u32::from_le_bytes(len) as usize;
+        if len > MAX_PAYLOAD_LEN {
+            return Err(std::io::Error::new(
+                ErrorKind::InvalidData,
+                format!("Frame too long - {len} bytes"),
+            ));
+        }
+        let mut payload = vec![0; len];
+        self.reader.read_exact(&mut payload)?;
+
+        let message = match kind[0] {
+            KIND_BINARY => Message::Binary(payload),
+            KIND_TEXT => Message::Text(
+                String::from_utf8(payload)
+                    .map_err(|e| std::io::Error::new(ErrorKind::InvalidData, e))?,
+            ),
+            kind => {
+                return Err(std::io::Error::new(
+                    ErrorKind::InvalidData,
+                    format!("Unknown frame kind - {kind}"),
+                ))
+            }
+        };
+
+        Ok(Some(Frame {
+            time_us: i64::from_le_bytes(time_us),
+            message,
+        }))
+    }
+}
+
+impl<R: Read> Iterator for CaptureReader<R> {
+    type Item = std::io::Result<Frame>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read_frame().transpose()
+    }
+}
+
+/// Frames of a capture as a websocket stream, read ahead on the blocking thread pool.
+/// Frames end after the first one failing to be read.
+/// With `realtime`, frames are delayed by the gap between their received times.
+pub fn frames<R: Read + Send + 'static>(
+    mut reader: CaptureReader<R>,
+    realtime: bool,
+) -> impl Stream<Item = Result<Message, tungstenite::Error>> + Unpin {
+    let (tx, rx) = mpsc::channel(READ_AHEAD);
+    tokio::task::spawn_blocking(move || {
+        while let Some(frame) = reader.read_frame().transpose() {
+            let failed = frame.is_err();
+            // Closed once the stream is dropped.
+            if tx.blocking_send(frame).is_err() || failed {
+                break;
+            }
+        }
+    });
+
+    Box::pin(futures_util::stream::unfold(
+        (rx, None::<i64>),
+        move |(mut rx, last_time_us)| async move {
+            let frame = match rx.recv().await? {
+                Ok(frame) => frame,
+                Err(e) => return Some((Err(tungstenite::Error::Io(e)), (rx, last_time_us))),
+            };
+            if let (true, Some(last_time_us)) = (realtime, last_time_us) {
+                let gap = frame.time_us.saturating_sub(last_time_us).max(0) as u64;
+                tokio::time::sleep(Duration::from_micros(gap)).await;
+            }
+
+            Some((Ok(frame.message), (rx, Some(frame.time_us))))
+        },
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use futures_util::StreamExt;
+
+    use super::*;
+
+    /// `Write` into a buffer which stays readable after the writer is boxed.
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_round_trip() {
+        let buffer = SharedBuffer::default();
+        let mut writer = CaptureWriter::new(Box::new(buffer.clone())).unwrap();
+        writer.write(&Message::Binary(vec![1, 2, 3])).unwrap();
+        writer.write(&Message::Ping(vec![])).unwrap();
+        writer.write(&Message::Text("{}".to_string())).unwrap();
+        writer.write(&Message::Binary(vec![])).unwrap();
+
+        let data = buffer.0.lock().unwrap().clone();
+        let frames = CaptureReader::new(data.as_slice())
+            .unwrap()
+            .map(|f| f.unwrap().message)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            frames,
+            [
+                Message::Binary(vec![1, 2, 3]),
+                Message::Text("{}".to_string()),
+                Message::Binary(vec![]),
+            ]
+        );
+
+        let streamed = super::frames(
+            CaptureReader::new(std::io::Cursor::new(data)).unwrap(),
+            true,
+        )
+        .map(Result::unwrap)
+        .collect::<Vec<_>>()
+        .await;
+        assert_eq!(streamed, frames);
+    }
+
+    #[tokio::test]
+    async fn test_sink() {
+        let buffer = SharedBuffer::default();
+        let sink = CaptureSink::spawn(CaptureWriter::new(Box::new(buffer.clone())).unwrap());
+        sink.send(&Message::Binary(vec![1, 2, 3])).await.unwrap();
+        sink.send(&Message::Pong(vec![])).await.unwrap();
+        sink.clone()
+            .send(&Message::Text("{}".to_string()))
+            .await
+            .unwrap();
+        sink.close().await;
+        assert!(sink.send(&Message::Binary(vec![4])).await.is_err());
+
+        let data = buffer.0.lock().unwrap().clone();
+        let frames = CaptureReader::new(data.as_slice())
+            .unwrap()
+            .map(|f| f.unwrap().message)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            frames,
+            [
+                Message::Binary(vec![1, 2, 3]),
+                Message::Text("{}".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_truncated() {
+        assert!(CaptureReader::new(b"EUFC".as_slice()).is_err());
+        assert!(CaptureReader::new(b"NOTACAPTURE".as_slice()).is_err());
+
+        let mut data = MAGIC.to_vec();
+        data.extend(0i64.to_le_bytes());
+        data.push(KIND_BINARY);
+        data.extend(4u32.to_le_bytes());
+        data.extend([1, 2]);
+        let mut reader = CaptureReader::new(data.as_slice()).unwrap();
+        assert!(reader.next().unwrap().is_err());
+
+        let mut data = MAGIC.to_vec();
+        data.extend(0i64.to_le_bytes());
+        data.push(KIND_BINARY);
+        data.extend(u32::MAX.to_le_bytes());
+        let mut reader = CaptureReader::new(data.as_slice()).unwrap();
+        let e = reader.next().unwrap().unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,420 @@
+//! Checks that nothing is missed between events.
+//!
+//! Sequence numbers of the firehose are contiguous, so a jump tells events are missing and going
+//! back tells events are repeated. Each commit names the rev it follows in `since`, so a commit
+//! which does not follow the latest known rev of its repository tells the repository has to be
+//! resynced.
+//!
+//! The latest rev of each repository is kept in a [`ContinuityStore`]. [`SqliteContinuityStore`]
+//! keeps it in `repo_rev`, in the transaction of the batch, so it never moves apart from the events.
+
+use std::{collections::HashMap, sync::Mutex};
+
+use async_trait::async_trait;
+use sqlx::{SqliteConnection, SqlitePool};
+
+use crate::lexicon::com::atproto::sync::subscribe_repos::Commit;
+
+/// What a sequence number tells compared with the previous one.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Sequence {
+    InOrder,
+    /// Sequence numbers are skipped. Holds how many of them.
+    Gap(u64),
+    /// Not after the previous one.
+    Duplicate,
+}
+
+#[derive(Debug, Clone)]
+pub(super) struct SequenceTracker {
+    /// Whether sequence numbers increase by one. Otherwise only the order is checked.
+    contiguous: bool,
+    last: Option<u64>,
+}
+
+impl SequenceTracker {
+    pub fn new(contiguous: bool) -> Self {
+        Self {
+            contiguous,
+            last: None,
+        }
+    }
+
+    /// Start over from `cursor`, the last sequence number received before.
+    pub fn reset(&mut self, cursor: Option<u64>) {
+        self.last = cursor;
+    }
+
+    pub fn observe(&mut self, seq: u64) -> Sequence {
+        let Some(last) = self.last else {
+            self.last = Some(seq);
+            return Sequence::InOrder;
+        };
+        if seq <= last {
+            return Sequence::Duplicate;
+        }
+
+        self.last = Some(seq);
+        match seq - last - 1 {
+            missing if self.contiguous && missing > 0 => Sequence::Gap(missing),
+            _ => Sequence::InOrder,
+        }
+    }
+}
+
+/// How a commit breaks the rev chain of its repository.
+#[derive(Debug, PartialEq, Eq)]
+pub enum RevBreak {
+    /// `since` is not the latest known rev.
+    Mismatch { since: String, latest: String },
+    /// Not newer than the latest known rev.
+    Stale { latest: String },
+}
+
+impl std::fmt::Display for RevBreak {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Mismatch { since, latest } => {
+                write!(f, "commit follows {since} but the latest rev is {latest}")
+            }
+            Self::Stale { latest } => write!(f, "commit is not newer than {latest}"),
+        }
+    }
+}
+
+/// How `commit` breaks the rev chain, whose latest rev is `latest`.
+fn compare(commit: &Commit, latest: String) -> Option<RevBreak> {
+    if commit.rev <= latest {
+        Some(RevBreak::Stale { latest })
+    } else {
+        match &commit.since {
+            Some(since) if *since != latest => Some(RevBreak::Mismatch {
+                since: since.clone(),
+                latest,
+            }),
+            _ => None,
+        }
+    }
+}
+
+/// Keeps the latest rev of each repository.
+///
+/// A repository is tracked from its first commit with an op left to handle, which keeps the store
+/// to the repositories the subscription cares about.
+#[async_trait]
+pub trait ContinuityStore: Send + Sync {
+    /// Check `commit` against the latest rev of its repository and record its rev
+    /// through `conn`, the transaction of the batch.
+    async fn check_commit(
+        &self,
+        conn: &mut SqliteConnection,
+        commit: &Commit,
+    ) -> anyhow::Result<Option<RevBreak>>;
+
+    /// Take the rev of a commit which is dropped without being checked, so that the commit following it
+    /// is not taken as out of the rev chain. Only repositories already tracked are updated.
+    async fn pass(&self, conn: &mut SqliteConnection, commit: &Commit) -> anyhow::Result<()>;
+
+    /// Take the rev of a `#sync` event, which replaces whatever is known of the repository.
+    async fn sync(&self, conn: &mut SqliteConnection, did: &str, rev: &str) -> anyhow::Result<()>;
+
+    /// Repositories whose commits did not follow one another, to be resynced.
+    async fn desynced_repos(&self) -> anyhow::Result<Vec<String>>;
+}
+
+/// Keeps revs in `repo_rev` of the database the events are handled in.
+pub struct SqliteContinuityStore {
+    db: SqlitePool,
+}
+
+impl SqliteContinuityStore {
+    pub fn new(db: SqlitePool) -> Self {
+        Self { db }
+    }
+}
+
+#[async_trait]
+impl ContinuityStore for SqliteContinuityStore {
+    async fn check_commit(
+        &self,
+        conn: &mut SqliteConnection,
+        commit: &Commit,
+    ) -> anyhow::Result<Option<RevBreak>> {
+        check_commit(conn, commit).await
+    }
+
+    async fn pass(&self, conn: &mut SqliteConnection, commit: &Commit) -> anyhow::Result<()> {
+        pass(conn, commit).await
+    }
+
+    async fn sync(&self, conn: &mut SqliteConnection, did: &str, rev: &str) -> anyhow::Result<()> {
+        sync(conn, did, rev).await
+    }
+
+    async fn desynced_repos(&self) -> anyhow::Result<Vec<String>> {
+        desynced_repos(&self.db).await
+    }
+}
+
+/// Keeps revs only while the process runs. Revs are taken as soon as they are checked,
+/// so those of a batch which is rolled back are kept all the same.
+#[derive(Default)]
+pub struct MemoryContinuityStore {
+    /// Latest rev and when the chain broke, by DID.
+    revs: Mutex<HashMap<String, (String, Option<String>)>>,
+}
+
+#[async_trait]
+impl ContinuityStore for MemoryContinuityStore {
+    async fn check_commit(
+        &self,
+        _conn: &mut SqliteConnection,
+        commit: &Commit,
+    ) -> anyhow::Result<Option<RevBreak>> {
+        let mut revs = self.revs.lock().unwrap();
+        let Some((latest, desynced_at)) = revs.get_mut(&commit.repo) else {
+            if !commit.ops.is_empty() {
+                revs.insert(commit.repo.clone(), (commit.rev.clone(), None));
+            }
+            return Ok(None);
+        };
+
+        let rev_break = compare(commit, latest.clone());
+        if commit.rev > *latest {
+            latest.clone_from(&commit.rev);
+        }
+        if rev_break.is_some() && desynced_at.is_none() {
+            *desynced_at = Some(chrono::Utc::now().to_rfc3339());
+        }
+        Ok(rev_break)
+    }
+
+    async fn pass(&self, _conn: &mut SqliteConnection, commit: &Commit) -> anyhow::Result<()> {
+        if let Some((latest, _)) = self.revs.lock().unwrap().get_mut(&commit.repo) {
+            if commit.rev > *latest {
+                latest.clone_from(&commit.rev);
+            }
+        }
+        Ok(())
+    }
+
+    async fn sync(&self, _conn: &mut SqliteConnection, did: &str, rev: &str) -> anyhow::Result<()> {
+        if let Some(state) = self.revs.lock().unwrap().get_mut(did) {
+            *state = (rev.to_string(), None);
+        }
+        Ok(())
+    }
+
+    async fn desynced_repos(&self) -> anyhow::Result<Vec<String>> {
+        let revs = self.revs.lock().unwrap();
+        let mut desynced = revs
+            .iter()
+            .filter_map(|(did, (_, desynced_at))| Some((desynced_at.as_ref()?, did)))
+            .collect::<Vec<_>>();
+        desynced.sort();
+        Ok(desynced.into_iter().map(|(_, did)| did.clone()).collect())
+    }
+}
+
+/// Check `commit` against the latest rev of its repository in `repo_rev` and record its rev.
+pub(super) async fn check_commit(
+    conn: &mut SqliteConnection,
+    commit: &Commit,
+) -> anyhow::Result<Option<RevBreak>> {
+    let latest = sqlx::query_scalar!(
+        r#"
+        SELECT `rev` FROM `repo_rev` WHERE `did` = ?
+    "#,
+        commit.repo
+    )
+    .fetch_optional(&mut *conn)
+    .await?;
+    let Some(latest) = latest else {
+        if !commit.ops.is_empty() {
+            sqlx::query!(
+                r#"
+                INSERT INTO `repo_rev` (`did`, `rev`) VALUES (?, ?)
+            "#,
+                commit.repo,
+                commit.rev
+            )
+            .execute(&mut *conn)
+            .await?;
+        }
+        return Ok(None);
+    };
+
+    let rev_break = compare(commit, latest);
+    let desynced_at = rev_break.as_ref().map(|_| chrono::Utc::now().to_rfc3339());
+    sqlx::query!(
+        r#"
+        UPDATE `repo_rev` SET
+            `rev` = MAX(`rev`, ?),
+            `desyncedAt` = COALESCE(`desyncedAt`, ?)
+        WHERE `did` = ?
+    "#,
+        commit.rev,
+        desynced_at,
+        commit.repo
+    )
+    .execute(&mut *conn)
+    .await?;
+
+    Ok(rev_break)
+}
+
+pub(super) async fn pass(conn: &mut SqliteConnection, commit: &Commit) -> anyhow::Result<()> {
+    sqlx::query!(
+        r#"
+        UPDATE `repo_rev` SET `rev` = MAX(`rev`, ?) WHERE `did` = ?
+    "#,
+        commit.rev,
+        commit.repo
+    )
+    .execute(conn)
+    .await?;
+
+    Ok(())
+}
+
+pub(super) async fn sync(conn: &mut SqliteConnection, did: &str, rev: &str) -> anyhow::Result<()> {
+    sqlx::query!(
+        r#"
+        UPDATE `repo_rev` SET `rev` = ?, `desyncedAt` = NULL WHERE `did` = ?
+    "#,
+        rev,
+        did
+    )
+    .execute(conn)
+    .await?;
+
+    Ok(())
+}
+
+/// Repositories whose commits did not follow one another, to be resynced.
+pub async fn desynced_repos(db: &SqlitePool) -> anyhow::Result<Vec<String>> {
+    Ok(sqlx::query_scalar!(
+        r#"
+        SELECT `did` AS "did!" FROM `repo_rev` WHERE `desyncedAt` IS NOT NULL ORDER BY `desyncedAt`
+    "#
+    )
+    .fetch_all(db)
+    .await?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        atproto_subscription::{mock_relay, parse_message, SubscriptionMessage},
+        lexicon::com::atproto::sync::subscribe_repos::OutputSchema as RepoEvent,
+    };
+
+    const REPO: &str = "did:plc:author";
+
+    fn commit(seq: u64, rev: &str, since: Option<&str>, ops: bool) -> Commit {
+        let ops = if ops {
+            vec![mock_relay::Op::Create {
+                path: format!("app.bsky.feed.post/{rev}"),
+                record: mock_relay::records::post("으어어"),
+            }]
+        } else {
+            vec![]
+        };
+        let SubscriptionMessage::Message(RepoEvent::Commit(mut commit)) =
+            parse_message(&mock_relay::commit(seq, REPO, rev, ops)).unwrap()
+        else {
+            panic!("expected commit");
+        };
+        commit.since = since.map(ToString::to_string);
+        *commit
+    }
+
+    #[test]
+    fn test_sequence() {
+        let mut tracker = SequenceTracker::new(true);
+        tracker.reset(Some(10));
+        assert_eq!(tracker.observe(11), Sequence::InOrder);
+        assert_eq!(tracker.observe(15), Sequence::Gap(3));
+        assert_eq!(tracker.observe(15), Sequence::Duplicate);
+        assert_eq!(tracker.observe(12), Sequence::Duplicate);
+        assert_eq!(tracker.observe(16), Sequence::InOrder);
+
+        tracker.reset(None);
+        assert_eq!(tracker.observe(3), Sequence::InOrder);
+        assert_eq!(tracker.observe(4), Sequence::InOrder);
+
+        let mut tracker = SequenceTracker::new(false);
+        tracker.reset(Some(1725516665234703));
+        assert_eq!(tracker.observe(1725516665239999), Sequence::InOrder);
+        assert_eq!(tracker.observe(1725516665234703), Sequence::Duplicate);
+    }
+
+    async fn check_rev_chain(store: &dyn ContinuityStore, conn: &mut SqliteConnection) {
+        // Not tracked until a commit has an op to handle.
+        let ret = store
+            .check_commit(conn, &commit(1, "3kaaaaaaaaa21", None, false))
+            .await;
+        assert_eq!(ret.unwrap(), None);
+        let ret = store
+            .check_commit(conn, &commit(2, "3kaaaaaaaaa22", None, true))
+            .await;
+        assert_eq!(ret.unwrap(), None);
+        let ret = store
+            .check_commit(
+                conn,
+                &commit(3, "3kaaaaaaaaa23", Some("3kaaaaaaaaa22"), false),
+            )
+            .await;
+        assert_eq!(ret.unwrap(), None);
+        assert!(store.desynced_repos().await.unwrap().is_empty());
+
+        // A commit in between is missing.
+        let ret = store
+            .check_commit(
+                conn,
+                &commit(5, "3kaaaaaaaaa25", Some("3kaaaaaaaaa24"), true),
+            )
+            .await;
+        assert_eq!(
+            ret.unwrap(),
+            Some(RevBreak::Mismatch {
+                since: "3kaaaaaaaaa24".to_string(),
+                latest: "3kaaaaaaaaa23".to_string(),
+            })
+        );
+        let ret = store
+            .check_commit(
+                conn,
+                &commit(6, "3kaaaaaaaaa24", Some("3kaaaaaaaaa23"), true),
+            )
+            .await;
+        assert_eq!(
+            ret.unwrap(),
+            Some(RevBreak::Stale {
+                latest: "3kaaaaaaaaa25".to_string(),
+            })
+        );
+        assert_eq!(store.desynced_repos().await.unwrap(), [REPO]);
+
+        store.sync(conn, REPO, "3kaaaaaaaaa27").await.unwrap();
+        assert!(store.desynced_repos().await.unwrap().is_empty());
+        let ret = store
+            .check_commit(
+                conn,
+                &commit(8, "3kaaaaaaaaa28", Some("3kaaaaaaaaa27"), true),
+            )
+            .await;
+        assert_eq!(ret.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_rev_chain() {
+        // Desynced repos are read through another connection of the pool.
+        let db = sqlx::SqlitePool::connect(":memory:").await.unwrap();
+        sqlx::migrate!().run(&db).await.unwrap();
+        let mut conn = db.acquire().await.unwrap();
+        check_rev_chain(&SqliteContinuityStore::new(db.clone()), &mut conn).await;
+        check_rev_chain(&MemoryContinuityStore::default(), &mut conn).await;
+    }
+}
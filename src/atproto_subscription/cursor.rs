@@ -0,0 +1,315 @@
+//! Where the subscription keeps the position to resume from.
+//!
+//! A cursor is saved each time a batch of events is committed. [`SqliteCursorStore`] writes it in the
+//! transaction of the batch, so the cursor never moves apart from the events.
+//! Other stores save it after the batch is committed, so events since the last saved cursor
+//! may be handed again after a crash.
+
+use std::{
+    collections::HashMap,
+    io::Write,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use anyhow::Context;
+use async_trait::async_trait;
+use sqlx::{SqliteConnection, SqlitePool};
+
+#[async_trait]
+pub trait CursorStore: Send + Sync {
+    async fn get(&self, key: &str) -> anyhow::Result<Option<u64>>;
+
+    /// Save `cursor` through `conn`, the transaction of the events handled up to it, before it is committed.
+    async fn stage(
+        &self,
+        _conn: &mut SqliteConnection,
+        _key: &str,
+        _cursor: u64,
+    ) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    /// Save `cursor` after the events handled up to it are committed.
+    async fn set(&self, _key: &str, _cursor: u64) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    async fn delete(&self, key: &str) -> anyhow::Result<()>;
+
+    /// Move the cursor of `from` to `to`, unless `to` already has one.
+    async fn rename(&self, from: &str, to: &str) -> anyhow::Result<()>;
+}
+
+/// Keeps cursors in `app_state` of the database the events are handled in.
+pub struct SqliteCursorStore {
+    db: SqlitePool,
+}
+
+impl SqliteCursorStore {
+    pub fn new(db: SqlitePool) -> Self {
+        Self { db }
+    }
+}
+
+#[async_trait]
+impl CursorStore for SqliteCursorStore {
+    async fn get(&self, key: &str) -> anyhow::Result<Option<u64>> {
+        sqlx::query!(
+            r#"
+            SELECT `value` FROM `app_state` WHERE `key` = ?
+        "#,
+            key
+        )
+        .fetch_optional(&self.db)
+        .await
+        .context("Failed to get cursor")?
+        .map(|v| serde_json::from_str::<u64>(&v.value).context("Invalid cursor"))
+        .transpose()
+    }
+
+    async fn stage(
+        &self,
+        conn: &mut SqliteConnection,
+        key: &str,
+        cursor: u64,
+    ) -> anyhow::Result<()> {
+        let cursor = cursor as i64;
+        sqlx::query!(
+            r#"
+            INSERT INTO `app_state` (
+                `key`, `value`
+            ) VALUES (
+                ?, ?
+            ) ON CONFLICT (`key`) DO UPDATE SET
+                `value`=`excluded`.`value`
+        "#,
+            key,
+            cursor
+        )
+        .execute(conn)
+        .await
+        .context("Failed to update cursor")?;
+
+        Ok(())
+    }
+
+    async fn delete(&self, key: &str) -> anyhow::Result<()> {
+        sqlx::query!(
+            r#"
+            DELETE FROM `app_state` WHERE `key` = ?
+        "#,
+            key
+        )
+        .execute(&self.db)
+        .await
+        .context("Failed to delete cursor")?;
+
+        Ok(())
+    }
+
+    async fn rename(&self, from: &str, to: &str) -> anyhow::Result<()> {
+        sqlx::query!(
+            r#"
+            UPDATE OR IGNORE `app_state` SET `key` = ? WHERE `key` = ?
+        "#,
+            to,
+            from
+        )
+        .execute(&self.db)
+        .await
+        .context("Failed to rename cursor")?;
+
+        Ok(())
+    }
+}
+
+/// Keeps cursors only while the process runs.
+#[derive(Default)]
+pub struct MemoryCursorStore {
+    cursors: Mutex<HashMap<String, u64>>,
+}
+
+#[async_trait]
+impl CursorStore for MemoryCursorStore {
+    async fn get(&self, key: &str) -> anyhow::Result<Option<u64>> {
+        Ok(self.cursors.lock().unwrap().get(key).copied())
+    }
+
+    async fn set(&self, key: &str, cursor: u64) -> anyhow::Result<()> {
+        self.cursors.lock().unwrap().insert(key.to_string(), cursor);
+        Ok(())
+    }
+
+    async fn delete(&self, key: &str) -> anyhow::Result<()> {
+        self.cursors.lock().unwrap().remove(key);
+        Ok(())
+    }
+
+    async fn rename(&self, from: &str, to: &str) -> anyhow::Result<()> {
+        let mut cursors = self.cursors.lock().unwrap();
+        if !cursors.contains_key(to) {
+            if let Some(cursor) = cursors.remove(from) {
+                cursors.insert(to.to_string(), cursor);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Keeps cursors in a JSON file, which is replaced as a whole on each save.
+pub struct FileCursorStore {
+    path: PathBuf,
+    cursors: tokio::sync::Mutex<HashMap<String, u64>>,
+}
+
+impl FileCursorStore {
+    /// Read cursors from `path`. A missing file is taken as having no cursor.
+    pub fn open(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let cursors = match std::fs::read(&path) {
+            Ok(data) => serde_json::from_slice(&data)
+                .with_context(|| format!("Invalid cursor file - {}", path.display()))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => HashMap::new(),
+            Err(e) => {
+                return Err(anyhow::Error::new(e)
+                    .context(format!("Failed to read cursor file - {}", path.display())))
+            }
+        };
+
+        Ok(Self {
+            path,
+            cursors: tokio::sync::Mutex::new(cursors),
+        })
+    }
+
+    /// Write through a temporary file not to leave a torn file behind.
+    async fn write(&self, cursors: &HashMap<String, u64>) -> anyhow::Result<()> {
+        let data = serde_json::to_vec(cursors)?;
+        let path = self.path.clone();
+        tokio::task::spawn_blocking(move || replace(&path, &data))
+            .await
+            .context("Cursor file writer panicked")?
+    }
+}
+
+/// Replace the file at `path` with `data`, syncing the file before it is renamed into place
+/// and the directory after, so a crash leaves either the old cursors or the new ones.
+fn replace(path: &Path, data: &[u8]) -> anyhow::Result<()> {
+    let mut temp = path.to_path_buf().into_os_string();
+    temp.push(".tmp");
+    (|| {
+        let mut file = std::fs::File::create(&temp)?;
+        file.write_all(data)?;
+        file.sync_all()
+    })()
+    .with_context(|| format!("Failed to write cursor file - {}", path.display()))?;
+    std::fs::rename(&temp, path)
+        .with_context(|| format!("Failed to replace cursor file - {}", path.display()))?;
+
+    // Only directories of Unix can be opened to be synced.
+    #[cfg(unix)]
+    {
+        let dir = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        std::fs::File::open(dir)
+            .and_then(|dir| dir.sync_all())
+            .with_context(|| format!("Failed to sync directory - {}", dir.display()))?;
+    }
+    Ok(())
+}
+
+#[async_trait]
+impl CursorStore for FileCursorStore {
+    async fn get(&self, key: &str) -> anyhow::Result<Option<u64>> {
+        Ok(self.cursors.lock().await.get(key).copied())
+    }
+
+    async fn set(&self, key: &str, cursor: u64) -> anyhow::Result<()> {
+        let mut cursors = self.cursors.lock().await;
+        cursors.insert(key.to_string(), cursor);
+        self.write(&cursors).await
+    }
+
+    async fn delete(&self, key: &str) -> anyhow::Result<()> {
+        let mut cursors = self.cursors.lock().await;
+        if cursors.remove(key).is_some() {
+            self.write(&cursors).await?;
+        }
+        Ok(())
+    }
+
+    async fn rename(&self, from: &str, to: &str) -> anyhow::Result<()> {
+        let mut cursors = self.cursors.lock().await;
+        if cursors.contains_key(to) {
+            return Ok(());
+        }
+        if let Some(cursor) = cursors.remove(from) {
+            cursors.insert(to.to_string(), cursor);
+            self.write(&cursors).await?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::atproto_subscription::mock_relay::memory_db;
+
+    async fn check(store: &dyn CursorStore) {
+        assert_eq!(store.get("a").await.unwrap(), None);
+        store.set("a", 1).await.unwrap();
+        store.set("b", 2).await.unwrap();
+        assert_eq!(store.get("a").await.unwrap(), Some(1));
+
+        // `b` is kept over the cursor of `a`.
+        store.rename("a", "b").await.unwrap();
+        assert_eq!(store.get("b").await.unwrap(), Some(2));
+        store.delete("b").await.unwrap();
+        store.rename("a", "b").await.unwrap();
+        assert_eq!(store.get("a").await.unwrap(), None);
+        assert_eq!(store.get("b").await.unwrap(), Some(1));
+    }
+
+    #[tokio::test]
+    async fn test_memory_store() {
+        check(&MemoryCursorStore::default()).await;
+    }
+
+    #[tokio::test]
+    async fn test_file_store() {
+        let path = std::env::temp_dir().join(format!("cursor-{}.json", rand::random::<u64>()));
+        check(&FileCursorStore::open(&path).unwrap()).await;
+
+        let reopened = FileCursorStore::open(&path).unwrap();
+        assert_eq!(reopened.get("b").await.unwrap(), Some(1));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_sqlite_store() {
+        let db = memory_db().await;
+        let store = SqliteCursorStore::new(db.clone());
+
+        // Nothing is saved until the transaction is committed.
+        let mut tx = db.begin().await.unwrap();
+        store.stage(&mut tx, "a", 1).await.unwrap();
+        tx.rollback().await.unwrap();
+        assert_eq!(store.get("a").await.unwrap(), None);
+
+        let mut tx = db.begin().await.unwrap();
+        store.stage(&mut tx, "a", 1).await.unwrap();
+        store.stage(&mut tx, "b", 2).await.unwrap();
+        tx.commit().await.unwrap();
+        assert_eq!(store.get("a").await.unwrap(), Some(1));
+
+        store.rename("a", "b").await.unwrap();
+        assert_eq!(store.get("b").await.unwrap(), Some(2));
+        store.delete("b").await.unwrap();
+        store.rename("a", "b").await.unwrap();
+        assert_eq!(store.get("b").await.unwrap(), Some(1));
+    }
+}
@@ -0,0 +1,368 @@
+//! Frames which could not be processed, kept in a [`DeadLetterStore`].
+//!
+//! [`SqliteDeadLetterStore`] keeps them in the `dead_letter` table, in the transaction of the batch
+//! they failed in, so a frame is kept only once the cursor moves over it.
+//! Commits too big to carry their records are kept the same way until the records are fetched,
+//! as letters of [`DeadLetterKind::TooBig`].
+
+use std::{collections::BTreeMap, ops::Bound, sync::Mutex};
+
+use async_trait::async_trait;
+use sqlx::{SqliteConnection, SqlitePool};
+use tokio_tungstenite::tungstenite::Message;
+
+use super::{FailedFrame, Source};
+
+/// Causes of a failure longer than this are cut, e.g. those dumping the frame.
+const MAX_CAUSE_LEN: usize = 256;
+
+/// Why a frame is kept.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, sqlx::Type)]
+#[sqlx(rename_all = "snake_case")]
+pub enum DeadLetterKind {
+    /// It failed to be processed.
+    Failed,
+    /// Commit too big to carry its records, waiting for them to be fetched.
+    TooBig,
+}
+
+impl std::fmt::Display for DeadLetterKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            DeadLetterKind::Failed => "failed",
+            DeadLetterKind::TooBig => "too big",
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct DeadLetter {
+    pub id: i64,
+    pub kind: DeadLetterKind,
+    pub seq: Option<i64>,
+    pub repo: Option<String>,
+    pub frame: Vec<u8>,
+    /// Chain of causes of the last failure, each cut short as the frame is kept on its own.
+    pub error: String,
+    pub created_at: String,
+}
+
+impl DeadLetter {
+    /// Frame as it is received from `source`.
+    pub(super) fn message(&self, source: &Source) -> Message {
+        match source {
+            Source::Firehose => Message::Binary(self.frame.clone()),
+            // Compressed frames are the binary ones.
+            Source::Jetstream(_) => match String::from_utf8(self.frame.clone()) {
+                Ok(text) => Message::Text(text),
+                Err(e) => Message::Binary(e.into_bytes()),
+            },
+        }
+    }
+}
+
+#[async_trait]
+pub trait DeadLetterStore: Send + Sync {
+    /// Keep `failed` through `conn`, the transaction of the batch it failed in.
+    /// Returns the id of the letter.
+    async fn insert(
+        &self,
+        conn: &mut SqliteConnection,
+        failed: &FailedFrame,
+        kind: DeadLetterKind,
+    ) -> anyhow::Result<i64>;
+
+    async fn get(&self, id: i64) -> anyhow::Result<Option<DeadLetter>>;
+
+    /// Up to `limit` dead letters after `after` in the order they are kept, only of `kind` if given.
+    async fn page(
+        &self,
+        kind: Option<DeadLetterKind>,
+        after: i64,
+        limit: i64,
+    ) -> anyhow::Result<Vec<DeadLetter>>;
+
+    /// Keep `error` as the last failure of the letter, which failed again.
+    async fn update_error(&self, id: i64, error: &anyhow::Error) -> anyhow::Result<()>;
+
+    /// Remove the letter through `conn`, the transaction it is handled in at last.
+    async fn delete(&self, conn: &mut SqliteConnection, id: i64) -> anyhow::Result<bool>;
+
+    /// Every dead letter in the order they are kept.
+    async fn list(&self) -> anyhow::Result<Vec<DeadLetter>>;
+
+    /// Remove every dead letter of `kind`. Returns how many of them are removed.
+    async fn purge(&self, kind: DeadLetterKind) -> anyhow::Result<u64>;
+}
+
+/// Keeps dead letters in `dead_letter` of the database the events are handled in.
+pub struct SqliteDeadLetterStore {
+    db: SqlitePool,
+}
+
+impl SqliteDeadLetterStore {
+    pub fn new(db: SqlitePool) -> Self {
+        Self { db }
+    }
+}
+
+#[async_trait]
+impl DeadLetterStore for SqliteDeadLetterStore {
+    async fn insert(
+        &self,
+        conn: &mut SqliteConnection,
+        failed: &FailedFrame,
+        kind: DeadLetterKind,
+    ) -> anyhow::Result<i64> {
+        insert(conn, failed, kind).await
+    }
+
+    async fn get(&self, id: i64) -> anyhow::Result<Option<DeadLetter>> {
+        get(&self.db, id).await
+    }
+
+    async fn page(
+        &self,
+        kind: Option<DeadLetterKind>,
+        after: i64,
+        limit: i64,
+    ) -> anyhow::Result<Vec<DeadLetter>> {
+        page(&self.db, kind, after, limit).await
+    }
+
+    async fn update_error(&self, id: i64, error: &anyhow::Error) -> anyhow::Result<()> {
+        update_error(&mut *self.db.acquire().await?, id, error).await
+    }
+
+    async fn delete(&self, conn: &mut SqliteConnection, id: i64) -> anyhow::Result<bool> {
+        delete(conn, id).await
+    }
+
+    async fn list(&self) -> anyhow::Result<Vec<DeadLetter>> {
+        list(&self.db).await
+    }
+
+    async fn purge(&self, kind: DeadLetterKind) -> anyhow::Result<u64> {
+        purge(&self.db, kind).await
+    }
+}
+
+/// Keeps dead letters only while the process runs. Letters are kept and removed as soon as
+/// they are asked to be, so changes of a transaction which is rolled back stay all the same.
+#[derive(Default)]
+pub struct MemoryDeadLetterStore {
+    letters: Mutex<BTreeMap<i64, DeadLetter>>,
+}
+
+#[async_trait]
+impl DeadLetterStore for MemoryDeadLetterStore {
+    async fn insert(
+        &self,
+        _conn: &mut SqliteConnection,
+        failed: &FailedFrame,
+        kind: DeadLetterKind,
+    ) -> anyhow::Result<i64> {
+        let mut letters = self.letters.lock().unwrap();
+        let id = letters.last_key_value().map_or(1, |(id, _)| id + 1);
+        letters.insert(
+            id,
+            DeadLetter {
+                id,
+                kind,
+                seq: failed.seq.map(|seq| seq as i64),
+                repo: failed.repo.clone(),
+                frame: failed.frame.clone(),
+                error: describe(&failed.error),
+                created_at: chrono::Utc::now().to_rfc3339(),
+            },
+        );
+        Ok(id)
+    }
+
+    async fn get(&self, id: i64) -> anyhow::Result<Option<DeadLetter>> {
+        Ok(self.letters.lock().unwrap().get(&id).cloned())
+    }
+
+    async fn page(
+        &self,
+        kind: Option<DeadLetterKind>,
+        after: i64,
+        limit: i64,
+    ) -> anyhow::Result<Vec<DeadLetter>> {
+        Ok(self
+            .letters
+            .lock()
+            .unwrap()
+            .range((Bound::Excluded(after), Bound::Unbounded))
+            .filter(|(_, letter)| kind.is_none_or(|kind| letter.kind == kind))
+            .take(limit.try_into().unwrap_or_default())
+            .map(|(_, letter)| letter.clone())
+            .collect())
+    }
+
+    async fn update_error(&self, id: i64, error: &anyhow::Error) -> anyhow::Result<()> {
+        if let Some(letter) = self.letters.lock().unwrap().get_mut(&id) {
+            letter.error = describe(error);
+        }
+        Ok(())
+    }
+
+    async fn delete(&self, _conn: &mut SqliteConnection, id: i64) -> anyhow::Result<bool> {
+        Ok(self.letters.lock().unwrap().remove(&id).is_some())
+    }
+
+    async fn list(&self) -> anyhow::Result<Vec<DeadLetter>> {
+        Ok(self.letters.lock().unwrap().values().cloned().collect())
+    }
+
+    async fn purge(&self, kind: DeadLetterKind) -> anyhow::Result<u64> {
+        let mut letters = self.letters.lock().unwrap();
+        let before = letters.len();
+        letters.retain(|_, letter| letter.kind != kind);
+        Ok((before - letters.len()) as u64)
+    }
+}
+
+/// Returns the id of the letter.
+pub async fn insert(
+    conn: &mut SqliteConnection,
+    failed: &FailedFrame,
+    kind: DeadLetterKind,
+) -> anyhow::Result<i64> {
+    let seq = failed.seq.map(|seq| seq as i64);
+    let error = describe(&failed.error);
+    let now = chrono::Utc::now().to_rfc3339();
+    let id = sqlx::query!(
+        r#"
+        INSERT INTO `dead_letter` (
+            `kind`, `seq`, `repo`, `frame`, `error`, `createdAt`
+        ) VALUES (
+            ?, ?, ?, ?, ?, ?
+        )
+    "#,
+        kind,
+        seq,
+        failed.repo,
+        failed.frame,
+        error,
+        now
+    )
+    .execute(conn)
+    .await?
+    .last_insert_rowid();
+
+    Ok(id)
+}
+
+/// Chain of causes of `error`, with each of them cut to [`MAX_CAUSE_LEN`] characters.
+fn describe(error: &anyhow::Error) -> String {
+    error
+        .chain()
+        .map(|cause| {
+            let cause = cause.to_string();
+            match cause.char_indices().nth(MAX_CAUSE_LEN) {
+                Some((end, _)) => format!("{}...", &cause[..end]),
+                None => cause,
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(": ")
+}
+
+pub async fn list(db: &SqlitePool) -> anyhow::Result<Vec<DeadLetter>> {
+    Ok(sqlx::query_as!(
+        DeadLetter,
+        r#"
+        SELECT
+            `id` AS "id!", `kind` AS "kind: DeadLetterKind", `seq`, `repo`, `frame`, `error`,
+            `createdAt` AS `created_at`
+        FROM `dead_letter` ORDER BY `id`
+    "#
+    )
+    .fetch_all(db)
+    .await?)
+}
+
+/// Up to `limit` dead letters after `after` in the order they are kept, only of `kind` if given.
+pub async fn page(
+    db: &SqlitePool,
+    kind: Option<DeadLetterKind>,
+    after: i64,
+    limit: i64,
+) -> anyhow::Result<Vec<DeadLetter>> {
+    Ok(sqlx::query_as!(
+        DeadLetter,
+        r#"
+        SELECT
+            `id` AS "id!", `kind` AS "kind: DeadLetterKind", `seq`, `repo`, `frame`, `error`,
+            `createdAt` AS `created_at`
+        FROM `dead_letter` WHERE `id` > ?1 AND (?2 IS NULL OR `kind` = ?2) ORDER BY `id` LIMIT ?3
+    "#,
+        after,
+        kind,
+        limit
+    )
+    .fetch_all(db)
+    .await?)
+}
+
+pub async fn get(db: &SqlitePool, id: i64) -> anyhow::Result<Option<DeadLetter>> {
+    Ok(sqlx::query_as!(
+        DeadLetter,
+        r#"
+        SELECT
+            `id` AS "id!", `kind` AS "kind: DeadLetterKind", `seq`, `repo`, `frame`, `error`,
+            `createdAt` AS `created_at`
+        FROM `dead_letter` WHERE `id` = ?
+    "#,
+        id
+    )
+    .fetch_optional(db)
+    .await?)
+}
+
+pub async fn update_error(
+    conn: &mut SqliteConnection,
+    id: i64,
+    error: &anyhow::Error,
+) -> anyhow::Result<()> {
+    let error = describe(error);
+    sqlx::query!(
+        r#"
+        UPDATE `dead_letter` SET `error` = ? WHERE `id` = ?
+    "#,
+        error,
+        id
+    )
+    .execute(conn)
+    .await?;
+
+    Ok(())
+}
+
+pub async fn delete(conn: &mut SqliteConnection, id: i64) -> anyhow::Result<bool> {
+    let ret = sqlx::query!(
+        r#"
+        DELETE FROM `dead_letter` WHERE `id` = ?
+    "#,
+        id
+    )
+    .execute(conn)
+    .await?;
+
+    Ok(ret.rows_affected() > 0)
+}
+
+/// Delete every dead letter of `kind`. Returns how many of them are deleted.
+pub async fn purge(db: &SqlitePool, kind: DeadLetterKind) -> anyhow::Result<u64> {
+    let ret = sqlx::query!(
+        r#"
+        DELETE FROM `dead_letter` WHERE `kind` = ?
+    "#,
+        kind
+    )
+    .execute(db)
+    .await?;
+
+    Ok(ret.rows_affected())
+}
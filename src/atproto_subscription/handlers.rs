@@ -0,0 +1,491 @@
+//! Combinators to build a handler out of smaller ones, through [`HandlerExt`].
+//!
+//! ```ignore
+//! let handler = Indexer::new(db)
+//!     .collections([bsky::feed::post::ID])
+//!     .and(Stats::default().isolated("stats"));
+//! ```
+
+use std::sync::Arc;
+
+use anyhow::Context;
+use async_trait::async_trait;
+use log::warn;
+use sqlx::{Acquire, SqliteConnection};
+
+use super::FirehoseSubscriptionHandler;
+use crate::lexicon::{com::atproto::sync::subscribe_repos::OutputSchema as RepoEvent, EventKind};
+
+pub trait HandlerExt: FirehoseSubscriptionHandler + Sized {
+    /// Hand each event to `self`, then to `other`. The event fails once either of them fails.
+    fn and<B: FirehoseSubscriptionHandler>(self, other: B) -> Fanout<Self, B> {
+        Fanout {
+            first: self,
+            second: other,
+        }
+    }
+
+    /// Hand only the events `predicate` accepts.
+    fn filter<F>(self, predicate: F) -> Filter<Self, F>
+    where
+        F: Fn(&RepoEvent) -> bool,
+    {
+        Filter {
+            inner: self,
+            predicate: Arc::new(predicate),
+        }
+    }
+
+    /// Hand only the events of `kinds`.
+    fn kinds(self, kinds: impl IntoIterator<Item = EventKind>) -> Kinds<Self> {
+        Kinds {
+            inner: self,
+            kinds: kinds.into_iter().collect(),
+        }
+    }
+
+    /// Hand only the ops on `collections`. Commits without any of them are not handed at all,
+    /// and other events are handed as they are.
+    fn collections<C: Into<String>>(
+        self,
+        collections: impl IntoIterator<Item = C>,
+    ) -> Collections<Self> {
+        Collections {
+            inner: self,
+            collections: collections.into_iter().map(Into::into).collect(),
+        }
+    }
+
+    /// Hand the events `f` turns events into. Events turned into `None` are not handed.
+    fn map<F>(self, f: F) -> Map<Self, F>
+    where
+        F: Fn(RepoEvent) -> Option<RepoEvent>,
+    {
+        Map {
+            inner: self,
+            f: Arc::new(f),
+        }
+    }
+
+    /// Keep failures of `self` from failing the event. Writes of `self` on the failed event are rolled back
+    /// and the failure is logged under `name`.
+    fn isolated(self, name: impl Into<String>) -> Isolated<Self> {
+        Isolated {
+            inner: self,
+            name: name.into().into(),
+        }
+    }
+}
+
+impl<H: FirehoseSubscriptionHandler> HandlerExt for H {}
+
+#[derive(Clone)]
+pub struct Fanout<A, B> {
+    first: A,
+    second: B,
+}
+
+#[async_trait]
+impl<A, B> FirehoseSubscriptionHandler for Fanout<A, B>
+where
+    A: FirehoseSubscriptionHandler + Send + Sync,
+    B: FirehoseSubscriptionHandler + Send + Sync,
+{
+    fn watched_collections(&self) -> Option<Vec<String>> {
+        let mut watched = self.first.watched_collections()?;
+        for collection in self.second.watched_collections()? {
+            if !watched.contains(&collection) {
+                watched.push(collection);
+            }
+        }
+        Some(watched)
+    }
+
+    async fn handle_event(
+        &self,
+        conn: &mut SqliteConnection,
+        event: RepoEvent,
+    ) -> anyhow::Result<()> {
+        // Clones share the blocks of commits, which are most of an event.
+        self.first.handle_event(conn, event.clone()).await?;
+        self.second.handle_event(conn, event).await
+    }
+}
+
+pub struct Filter<H, F> {
+    inner: H,
+    predicate: Arc<F>,
+}
+
+impl<H: Clone, F> Clone for Filter<H, F> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+            predicate: self.predicate.clone(),
+        }
+    }
+}
+
+#[async_trait]
+impl<H, F> FirehoseSubscriptionHandler for Filter<H, F>
+where
+    H: FirehoseSubscriptionHandler + Send + Sync,
+    F: Fn(&RepoEvent) -> bool + Send + Sync,
+{
+    fn watched_collections(&self) -> Option<Vec<String>> {
+        self.inner.watched_collections()
+    }
+
+    async fn handle_event(
+        &self,
+        conn: &mut SqliteConnection,
+        event: RepoEvent,
+    ) -> anyhow::Result<()> {
+        if !(self.predicate)(&event) {
+            return Ok(());
+        }
+        self.inner.handle_event(conn, event).await
+    }
+}
+
+#[derive(Clone)]
+pub struct Kinds<H> {
+    inner: H,
+    kinds: Arc<[EventKind]>,
+}
+
+#[async_trait]
+impl<H> FirehoseSubscriptionHandler for Kinds<H>
+where
+    H: FirehoseSubscriptionHandler + Send + Sync,
+{
+    fn watched_collections(&self) -> Option<Vec<String>> {
+        self.inner.watched_collections()
+    }
+
+    async fn handle_event(
+        &self,
+        conn: &mut SqliteConnection,
+        event: RepoEvent,
+    ) -> anyhow::Result<()> {
+        if !self.kinds.contains(&event.kind()) {
+            return Ok(());
+        }
+        self.inner.handle_event(conn, event).await
+    }
+}
+
+#[derive(Clone)]
+pub struct Collections<H> {
+    inner: H,
+    collections: Arc<[String]>,
+}
+
+#[async_trait]
+impl<H> FirehoseSubscriptionHandler for Collections<H>
+where
+    H: FirehoseSubscriptionHandler + Send + Sync,
+{
+    fn watched_collections(&self) -> Option<Vec<String>> {
+        let mut watched = self.collections.to_vec();
+        if let Some(inner) = self.inner.watched_collections() {
+            watched.retain(|collection| inner.contains(collection));
+        }
+        Some(watched)
+    }
+
+    async fn handle_event(
+        &self,
+        conn: &mut SqliteConnection,
+        mut event: RepoEvent,
+    ) -> anyhow::Result<()> {
+        if let RepoEvent::Commit(commit) = &mut event {
+            commit.ops.retain(|op| {
+                let collection = op.path.split_once('/').map_or(op.path.as_str(), |(c, _)| c);
+                self.collections.iter().any(|c| c == collection)
+            });
+            if commit.ops.is_empty() {
+                return Ok(());
+            }
+        }
+        self.inner.handle_event(conn, event).await
+    }
+}
+
+pub struct Map<H, F> {
+    inner: H,
+    f: Arc<F>,
+}
+
+impl<H: Clone, F> Clone for Map<H, F> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+            f: self.f.clone(),
+        }
+    }
+}
+
+#[async_trait]
+impl<H, F> FirehoseSubscriptionHandler for Map<H, F>
+where
+    H: FirehoseSubscriptionHandler + Send + Sync,
+    F: Fn(RepoEvent) -> Option<RepoEvent> + Send + Sync,
+{
+    fn watched_collections(&self) -> Option<Vec<String>> {
+        self.inner.watched_collections()
+    }
+
+    async fn handle_event(
+        &self,
+        conn: &mut SqliteConnection,
+        event: RepoEvent,
+    ) -> anyhow::Result<()> {
+        match (self.f)(event) {
+            Some(event) => self.inner.handle_event(conn, event).await,
+            None => Ok(()),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Isolated<H> {
+    inner: H,
+    name: Arc<str>,
+}
+
+#[async_trait]
+impl<H> FirehoseSubscriptionHandler for Isolated<H>
+where
+    H: FirehoseSubscriptionHandler + Send + Sync,
+{
+    fn watched_collections(&self) -> Option<Vec<String>> {
+        self.inner.watched_collections()
+    }
+
+    async fn handle_event(
+        &self,
+        conn: &mut SqliteConnection,
+        event: RepoEvent,
+    ) -> anyhow::Result<()> {
+        let seq = event.seq();
+        let mut savepoint = conn.begin().await.context("Failed to begin savepoint")?;
+        match self.inner.handle_event(&mut savepoint, event).await {
+            Ok(()) => savepoint
+                .commit()
+                .await
+                .context("Failed to release savepoint")?,
+            Err(e) => {
+                warn!("{} failed on event {seq:?}: {e:#}", self.name);
+                savepoint
+                    .rollback()
+                    .await
+                    .context("Failed to roll back to savepoint")?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use anyhow::anyhow;
+
+    use super::*;
+    use crate::atproto_subscription::{
+        mock_relay::{self, memory_db, records, Op},
+        parse_message, SubscriptionMessage,
+    };
+
+    /// Writes `{name}:{seq}` into `post` and keeps the paths of ops it is handed.
+    #[derive(Clone)]
+    struct Recorder {
+        name: &'static str,
+        watched: Option<Vec<String>>,
+        fail: bool,
+        paths: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl Recorder {
+        fn new(name: &'static str) -> Self {
+            Self {
+                name,
+                watched: None,
+                fail: false,
+                paths: Default::default(),
+            }
+        }
+
+        fn paths(&self) -> Vec<String> {
+            self.paths.lock().unwrap().clone()
+        }
+    }
+
+    #[async_trait]
+    impl FirehoseSubscriptionHandler for Recorder {
+        fn watched_collections(&self) -> Option<Vec<String>> {
+            self.watched.clone()
+        }
+
+        async fn handle_event(
+            &self,
+            conn: &mut SqliteConnection,
+            event: RepoEvent,
+        ) -> anyhow::Result<()> {
+            sqlx::query(
+                "INSERT INTO `post` (`uri`, `cid`, `author`, `indexedAt`) VALUES (?, '', '', '')",
+            )
+            .bind(format!("{}:{}", self.name, event.seq().unwrap_or_default()))
+            .execute(&mut *conn)
+            .await?;
+            if let RepoEvent::Commit(commit) = &event {
+                self.paths
+                    .lock()
+                    .unwrap()
+                    .extend(commit.ops.iter().map(|op| op.path.clone()));
+            }
+            if self.fail {
+                return Err(anyhow!("{} failed", self.name));
+            }
+            Ok(())
+        }
+    }
+
+    fn event(frame: Vec<u8>) -> RepoEvent {
+        let Ok(SubscriptionMessage::Message(event)) = parse_message(&frame) else {
+            panic!("expected event");
+        };
+        event
+    }
+
+    fn commit(seq: u64) -> RepoEvent {
+        event(mock_relay::commit(
+            seq,
+            "did:plc:author",
+            "3kaaaaaaaaa22",
+            vec![
+                Op::Create {
+                    path: "app.bsky.feed.post/1".to_string(),
+                    record: records::post("으어어"),
+                },
+                Op::Create {
+                    path: "app.bsky.feed.like/1".to_string(),
+                    record: records::like("at://did:plc:other/app.bsky.feed.post/1", "bafyreia"),
+                },
+            ],
+        ))
+    }
+
+    /// Hand `events` in a transaction and return what is written by them.
+    async fn handle(
+        handler: &impl FirehoseSubscriptionHandler,
+        events: impl IntoIterator<Item = RepoEvent>,
+    ) -> anyhow::Result<Vec<String>> {
+        let db = memory_db().await;
+        let mut tx = db.begin().await?;
+        for event in events {
+            handler.handle_event(&mut tx, event).await?;
+        }
+        tx.commit().await?;
+        Ok(
+            sqlx::query_scalar("SELECT `uri` FROM `post` ORDER BY `uri`")
+                .fetch_all(&db)
+                .await?,
+        )
+    }
+
+    #[tokio::test]
+    async fn test_fanout() {
+        let handler = Recorder {
+            watched: Some(vec!["app.bsky.feed.post".to_string()]),
+            ..Recorder::new("a")
+        }
+        .and(Recorder {
+            watched: Some(vec![
+                "app.bsky.feed.post".to_string(),
+                "app.bsky.feed.like".to_string(),
+            ]),
+            ..Recorder::new("b")
+        });
+        assert_eq!(
+            handler.watched_collections().unwrap(),
+            ["app.bsky.feed.post", "app.bsky.feed.like"]
+        );
+        assert!(handler
+            .clone()
+            .and(Recorder::new("c"))
+            .watched_collections()
+            .is_none());
+
+        let written = handle(&handler, [commit(1)]).await.unwrap();
+        assert_eq!(written, ["a:1", "b:1"]);
+
+        let failing = Recorder {
+            fail: true,
+            ..Recorder::new("a")
+        }
+        .and(Recorder::new("b"));
+        assert!(handle(&failing, [commit(1)]).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_filters() {
+        let identity = || event(mock_relay::identity(2, "did:plc:author", None));
+
+        let recorder = Recorder::new("a");
+        let handler = recorder.clone().collections(["app.bsky.feed.like"]);
+        assert_eq!(
+            handler.watched_collections().unwrap(),
+            ["app.bsky.feed.like"]
+        );
+        let written = handle(&handler, [commit(1), identity()]).await.unwrap();
+        assert_eq!(written, ["a:1", "a:2"]);
+        assert_eq!(recorder.paths(), ["app.bsky.feed.like/1"]);
+
+        // Commits without a remaining op are not handed.
+        let handler = Recorder::new("a").collections(["app.bsky.graph.follow"]);
+        let written = handle(&handler, [commit(1)]).await.unwrap();
+        assert!(written.is_empty());
+
+        let handler = Recorder::new("a").kinds([EventKind::Identity]);
+        let written = handle(&handler, [commit(1), identity()]).await.unwrap();
+        assert_eq!(written, ["a:2"]);
+
+        let handler = Recorder::new("a").filter(|event| event.seq() == Some(1));
+        let written = handle(&handler, [commit(1), identity()]).await.unwrap();
+        assert_eq!(written, ["a:1"]);
+    }
+
+    #[tokio::test]
+    async fn test_map() {
+        let recorder = Recorder::new("a");
+        let handler = recorder.clone().map(|mut event| {
+            let RepoEvent::Commit(commit) = &mut event else {
+                return None;
+            };
+            commit.ops.truncate(1);
+            Some(event)
+        });
+        let identity = event(mock_relay::identity(2, "did:plc:author", None));
+        let written = handle(&handler, [commit(1), identity]).await.unwrap();
+        assert_eq!(written, ["a:1"]);
+        assert_eq!(recorder.paths(), ["app.bsky.feed.post/1"]);
+    }
+
+    #[tokio::test]
+    async fn test_isolated() {
+        let handler = Recorder {
+            fail: true,
+            ..Recorder::new("a")
+        }
+        .isolated("a")
+        .and(Recorder::new("b"));
+
+        // Writes of the failed handler are rolled back, and the rest goes on.
+        let written = handle(&handler, [commit(1), commit(2)]).await.unwrap();
+        assert_eq!(written, ["b:1", "b:2"]);
+    }
+}
@@ -0,0 +1,105 @@
+//! Discovery of PDS hosts to subscribe directly, without a relay.
+
+use std::{sync::Arc, time::Duration};
+
+use anyhow::{anyhow, Context};
+use log::debug;
+
+use crate::{
+    identity::DidResolver,
+    lexicon::com::atproto::sync::{defs::HostStatus, list_hosts},
+};
+
+/// Endpoints of the hosts known to `seed`, through `com.atproto.sync.listHosts`.
+/// Hosts which are offline, throttled or banned are left out.
+pub async fn list_hosts(seed: &str) -> anyhow::Result<Vec<String>> {
+    let mut url = url::Url::parse(seed).context("Invalid seed host url")?;
+    let scheme = match url.scheme() {
+        "https" | "wss" => "wss",
+        "http" | "ws" => "ws",
+        scheme => return Err(anyhow!("Unsupported scheme of seed host - {scheme}")),
+    };
+    url.path_segments_mut()
+        .map_err(|_| anyhow!("Not a valid seed host url - {seed}"))?
+        .push("xrpc")
+        .push(list_hosts::ID);
+    let client = reqwest::Client::builder()
+        .timeout(Duration::from_secs(10))
+        .build()?;
+
+    let mut hosts = vec![];
+    let mut cursor = None;
+    loop {
+        let output: list_hosts::OutputSchema = client
+            .get(url.clone())
+            .query(&list_hosts::QueryParams {
+                limit: 1000,
+                cursor: cursor.take(),
+            })
+            .send()
+            .await
+            .and_then(|r| r.error_for_status())
+            .with_context(|| format!("Failed to list hosts of {seed}"))?
+            .json()
+            .await
+            .with_context(|| format!("Invalid host list of {seed}"))?;
+        let empty = output.hosts.is_empty();
+        for host in output.hosts {
+            match host.status {
+                None | Some(HostStatus::Active | HostStatus::Idle) => {
+                    hosts.push(format!("{scheme}://{}", host.hostname));
+                }
+                Some(status) => debug!("Skip {} which is {status:?}", host.hostname),
+            }
+        }
+
+        match output.cursor {
+            Some(next) if !empty => cursor = Some(next),
+            _ => break,
+        }
+    }
+
+    Ok(hosts)
+}
+
+/// Tells whether a host is the PDS of a repository, by the DID document of the repository.
+/// A host can send events of any repository, but only those of the repositories it hosts are authentic.
+pub struct HostCheck {
+    endpoint: String,
+    /// Host and port of `endpoint`, which are compared regardless of the scheme.
+    authority: String,
+    resolver: Arc<dyn DidResolver>,
+}
+
+impl HostCheck {
+    pub fn new(endpoint: &str, resolver: Arc<dyn DidResolver>) -> anyhow::Result<Self> {
+        Ok(Self {
+            endpoint: endpoint.to_string(),
+            authority: authority(endpoint)
+                .with_context(|| format!("Not a valid host url - {endpoint}"))?,
+            resolver,
+        })
+    }
+
+    pub fn endpoint(&self) -> &str {
+        &self.endpoint
+    }
+
+    /// Whether the DID document of `did` names this host as its PDS.
+    pub async fn hosts(&self, did: &str) -> anyhow::Result<bool> {
+        let document = self.resolver.resolve(did).await?;
+        Ok(document
+            .pds_endpoint()
+            .and_then(authority)
+            .is_some_and(|authority| authority == self.authority))
+    }
+}
+
+fn authority(url: &str) -> Option<String> {
+    let url = url::Url::parse(url).ok()?;
+    Some(format!(
+        "{}:{}",
+        url.host_str()?.to_ascii_lowercase(),
+        url.port_or_known_default()?
+    ))
+}
@@ -0,0 +1,407 @@
+//! [Jetstream](https://github.com/bluesky-social/jetstream) - JSON re-encoding of the firehose.
+//!
+//! Events are converted into [`RepoEvent`] so the same [`super::FirehoseSubscriptionHandler`] can handle them.
+//! Jetstream has no sequence number. `time_us` takes its place, both as `seq` of the converted event and as the cursor.
+
+use std::{io::Read, str::FromStr, sync::Arc};
+
+use anyhow::Context;
+use rs_car::Cid;
+use tokio_tungstenite::tungstenite::Message;
+
+use crate::lexicon::{
+    car::CommitRawBlocks,
+    com::atproto::sync::subscribe_repos::{
+        self, AccountStatus, OutputSchema as RepoEvent, RepoOp, RepoOpAction,
+    },
+    data,
+};
+
+pub const PATH: &str = "subscribe";
+
+#[derive(Clone, Default)]
+pub struct Options {
+    /// Collection NSIDs to receive commits of. Receive all when empty.
+    pub wanted_collections: Vec<String>,
+    /// Dictionary of zstd compressed frames. Compression is requested only when it is given.
+    pub zstd_dictionary: Option<Arc<zstd::dict::DecoderDictionary<'static>>>,
+}
+
+impl Options {
+    pub fn new(wanted_collections: Vec<String>, zstd_dictionary: Option<&[u8]>) -> Self {
+        Self {
+            wanted_collections,
+            zstd_dictionary: zstd_dictionary
+                .map(|dict| Arc::new(zstd::dict::DecoderDictionary::copy(dict))),
+        }
+    }
+
+    pub fn append_query(&self, url: &mut url::Url) {
+        let mut query = url.query_pairs_mut();
+        for collection in &self.wanted_collections {
+            query.append_pair("wantedCollections", collection);
+        }
+        if self.zstd_dictionary.is_some() {
+            query.append_pair("compress", "true");
+        }
+    }
+
+    /// Decode a frame. Returns `None` for frames which don't carry an event we can represent.
+    pub fn decode(&self, message: &Message) -> anyhow::Result<Option<RepoEvent>> {
+        let event: Event = match message {
+            Message::Text(text) => {
+                serde_json::from_str(text).context("Failed to parse jetstream event")?
+            }
+            Message::Binary(data) => {
+                let Some(dictionary) = &self.zstd_dictionary else {
+                    return Err(anyhow::anyhow!(
+                        "Received compressed frame without zstd dictionary"
+                    ));
+                };
+                let mut json = Vec::new();
+                zstd::stream::read::Decoder::with_prepared_dictionary(data.as_slice(), dictionary)
+                    .and_then(|mut decoder| decoder.read_to_end(&mut json))
+                    .context("Failed to decompress jetstream event")?;
+                serde_json::from_slice(&json).context("Failed to parse jetstream event")?
+            }
+            _ => return Ok(None),
+        };
+
+        event.into_repo_event()
+    }
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct Event {
+    pub did: String,
+    pub time_us: u64,
+    #[serde(flatten)]
+    pub kind: EventKind,
+}
+
+#[derive(Debug, serde::Deserialize)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum EventKind {
+    Commit {
+        commit: Commit,
+    },
+    Identity {
+        identity: Identity,
+    },
+    Account {
+        account: Account,
+    },
+    #[serde(other)]
+    Unknown,
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct Commit {
+    pub rev: String,
+    pub operation: RepoOpAction,
+    pub collection: String,
+    pub rkey: String,
+    pub record: Option<serde_json::Value>,
+    pub cid: Option<String>,
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct Identity {
+    pub did: String,
+    pub handle: Option<String>,
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct Account {
+    pub did: String,
+    pub active: bool,
+    pub status: Option<AccountStatus>,
+}
+
+impl Event {
+    pub fn into_repo_event(self) -> anyhow::Result<Option<RepoEvent>> {
+        let time = chrono::DateTime::from_timestamp_micros(self.time_us as _)
+            .with_context(|| format!("time_us out of range - {}", self.time_us))?
+            .to_rfc3339_opts(chrono::SecondsFormat::Micros, true);
+
+        Ok(Some(match self.kind {
+            EventKind::Commit { commit } => {
+                let cid = commit
+                    .cid
+                    .as_deref()
+                    .map(Cid::from_str)
+                    .transpose()
+                    .context("Invalid record cid")?;
+                let record = match (&cid, commit.record) {
+                    (Some(cid), Some(record)) => Some((
+                        *cid,
+                        data::to_cbor(&record).context("Failed to encode record")?,
+                    )),
+                    _ => None,
+                };
+                // Always carry blocks even if empty, as the firehose does for deletes.
+                let blocks = CommitRawBlocks::from_blocks(
+                    &[],
+                    record.iter().map(|(cid, block)| (cid, block.as_slice())),
+                );
+
+                RepoEvent::Commit(Box::new(subscribe_repos::Commit {
+                    seq: self.time_us,
+                    time,
+                    rebase: false,
+                    too_big: false,
+                    repo: self.did,
+                    // Jetstream doesn't deliver the commit object.
+                    // `Source::carries_commits` keeps this one from being verified.
+                    commit: Cid::default(),
+                    rev: commit.rev,
+                    since: None,
+                    blocks,
+                    ops: vec![RepoOp {
+                        action: commit.operation,
+                        path: format!("{}/{}", commit.collection, commit.rkey),
+                        cid,
+                        prev: None,
+                    }],
+                    blobs: vec![],
+                    prev_data: None,
+                }))
+            }
+            EventKind::Identity { identity } => {
+                RepoEvent::Identity(Box::new(subscribe_repos::Identity {
+                    seq: self.time_us,
+                    did: identity.did,
+                    time,
+                    handle: identity.handle,
+                }))
+            }
+            EventKind::Account { account } => {
+                RepoEvent::Account(Box::new(subscribe_repos::Account {
+                    seq: self.time_us,
+                    did: account.did,
+                    time,
+                    active: account.active,
+                    status: account.status,
+                }))
+            }
+            EventKind::Unknown => return Ok(None),
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        atproto_subscription::{mock_relay::cid_for, verify::check_hash},
+        lexicon::Record,
+    };
+
+    const POST_CID: &str = "bafyreidfayvfuwqa7qlnopdjiqrxzs6blmoeu4rujcjtnci5beludirz2a";
+    const POST_EVENT: &str = r#"{
+        "did": "did:plc:eygmaihciaxprqvxpfvl6flk",
+        "time_us": 1725911162329308,
+        "kind": "commit",
+        "commit": {
+            "rev": "3l3qo2vutsw2b",
+            "operation": "create",
+            "collection": "app.bsky.feed.post",
+            "rkey": "3l3qo2vuowo2b",
+            "record": {
+                "$type": "app.bsky.feed.post",
+                "createdAt": "2024-09-09T19:46:02.102Z",
+                "langs": ["ko"],
+                "text": "으어어"
+            },
+            "cid": "bafyreidfayvfuwqa7qlnopdjiqrxzs6blmoeu4rujcjtnci5beludirz2a"
+        }
+    }"#;
+
+    #[tokio::test]
+    async fn test_decode_commit() {
+        let event = Options::default()
+            .decode(&Message::Text(POST_EVENT.to_string()))
+            .unwrap()
+            .unwrap();
+        assert_eq!(event.seq(), Some(1725911162329308));
+        let RepoEvent::Commit(commit) = event else {
+            panic!("expected commit - {event:?}");
+        };
+        assert_eq!(commit.repo, "did:plc:eygmaihciaxprqvxpfvl6flk");
+        assert_eq!(commit.time, "2024-09-09T19:46:02.329308Z");
+        assert_eq!(commit.ops.len(), 1);
+        assert_eq!(commit.ops[0].path, "app.bsky.feed.post/3l3qo2vuowo2b");
+        let cid = commit.ops[0].cid.unwrap();
+        assert_eq!(cid.to_string(), POST_CID);
+
+        let blocks = commit.blocks.parse().unwrap();
+        let Record::Post(post) = blocks.get(&cid).unwrap().unwrap() else {
+            panic!("expected post");
+        };
+        assert_eq!(post.text, "으어어");
+    }
+
+    #[tokio::test]
+    async fn test_decode_commit_with_link() {
+        #[derive(serde::Serialize)]
+        struct Blob {
+            #[serde(rename = "ref")]
+            r#ref: Cid,
+            size: u64,
+            #[serde(rename = "$type")]
+            r#type: &'static str,
+            #[serde(rename = "mimeType")]
+            mime_type: &'static str,
+        }
+        // Fields in the order of DAG-CBOR, shorter keys first.
+        #[derive(serde::Serialize)]
+        struct Avatar {
+            #[serde(rename = "$type")]
+            r#type: &'static str,
+            avatar: Blob,
+        }
+        let image =
+            Cid::from_str("bafkreibme22gw2h7y2h7tg2fhqotaqjucnbc24deqo72b6mkl2egezxhvy").unwrap();
+        let block = serde_ipld_dagcbor::to_vec(&Avatar {
+            r#type: "app.bsky.actor.profile",
+            avatar: Blob {
+                r#ref: image,
+                size: 1000,
+                r#type: "blob",
+                mime_type: "image/jpeg",
+            },
+        })
+        .unwrap();
+        let cid = cid_for(&block);
+
+        let event = serde_json::json!({
+            "did": "did:plc:eygmaihciaxprqvxpfvl6flk",
+            "time_us": 1725911162329310_u64,
+            "kind": "commit",
+            "commit": {
+                "rev": "3l3qo2vutsw2d",
+                "operation": "update",
+                "collection": "app.bsky.actor.profile",
+                "rkey": "self",
+                "record": {
+                    "$type": "app.bsky.actor.profile",
+                    "avatar": {
+                        "$type": "blob",
+                        "ref": { "$link": image.to_string() },
+                        "mimeType": "image/jpeg",
+                        "size": 1000
+                    }
+                },
+                "cid": cid.to_string()
+            }
+        });
+        let event = Options::default()
+            .decode(&Message::Text(event.to_string()))
+            .unwrap()
+            .unwrap();
+        let RepoEvent::Commit(commit) = event else {
+            panic!("expected commit - {event:?}");
+        };
+        let blocks = commit.blocks.parse().unwrap();
+        check_hash(&cid, blocks.get_raw(&cid).unwrap()).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_decode_delete() {
+        let event = Options::default()
+            .decode(&Message::Text(
+                r#"{
+                    "did": "did:plc:eygmaihciaxprqvxpfvl6flk",
+                    "time_us": 1725911162329309,
+                    "kind": "commit",
+                    "commit": {
+                        "rev": "3l3qo2vutsw2c",
+                        "operation": "delete",
+                        "collection": "app.bsky.feed.post",
+                        "rkey": "3l3qo2vuowo2b"
+                    }
+                }"#
+                .to_string(),
+            ))
+            .unwrap()
+            .unwrap();
+        let RepoEvent::Commit(commit) = event else {
+            panic!("expected commit - {event:?}");
+        };
+        assert!(matches!(commit.ops[0].action, RepoOpAction::Delete));
+        assert_eq!(commit.ops[0].cid, None);
+        let blocks = commit.blocks.parse().unwrap();
+        assert_eq!(blocks.keys().count(), 0);
+    }
+
+    #[test]
+    fn test_decode_identity_and_unknown() {
+        let event = Options::default()
+            .decode(&Message::Text(
+                r#"{
+                    "did": "did:plc:ufbl4k27gp6kzas5glhz7fim",
+                    "time_us": 1725516665234703,
+                    "kind": "identity",
+                    "identity": {
+                        "did": "did:plc:ufbl4k27gp6kzas5glhz7fim",
+                        "handle": "yohenrique.bsky.social",
+                        "seq": 1409752997,
+                        "time": "2024-09-05T06:11:04.870Z"
+                    }
+                }"#
+                .to_string(),
+            ))
+            .unwrap()
+            .unwrap();
+        let RepoEvent::Identity(identity) = event else {
+            panic!("expected identity - {event:?}");
+        };
+        assert_eq!(identity.seq, 1725516665234703);
+        assert_eq!(identity.handle.as_deref(), Some("yohenrique.bsky.social"));
+
+        let event = Options::default()
+            .decode(&Message::Text(
+                r#"{"did": "did:plc:ufbl4k27gp6kzas5glhz7fim", "time_us": 1725516665234704, "kind": "something"}"#
+                    .to_string(),
+            ))
+            .unwrap();
+        assert!(event.is_none());
+    }
+
+    #[test]
+    fn test_decode_compressed() {
+        let dictionary =
+            b"did:plc: app.bsky.feed.post commit create collection operation rkey record";
+        let compressed = zstd::bulk::Compressor::with_dictionary(3, dictionary)
+            .unwrap()
+            .compress(POST_EVENT.as_bytes())
+            .unwrap();
+
+        let options = Options::new(vec![], Some(dictionary));
+        let event = options
+            .decode(&Message::Binary(compressed.clone()))
+            .unwrap();
+        assert_eq!(event.unwrap().seq(), Some(1725911162329308));
+
+        assert!(Options::default()
+            .decode(&Message::Binary(compressed))
+            .is_err());
+    }
+
+    #[test]
+    fn test_append_query() {
+        let mut url = url::Url::parse("wss://jetstream.example.com/subscribe").unwrap();
+        Options::new(
+            vec![
+                "app.bsky.feed.post".to_string(),
+                "app.bsky.feed.like".to_string(),
+            ],
+            Some(b"dictionary"),
+        )
+        .append_query(&mut url);
+        assert_eq!(
+            url.query(),
+            Some("wantedCollections=app.bsky.feed.post&wantedCollections=app.bsky.feed.like&compress=true")
+        );
+    }
+}
@@ -0,0 +1,622 @@
+//! Local `com.atproto.sync.subscribeRepos` server and frame builders for tests.
+//!
+//! Sequenced events are kept and replayed to connections which request a `cursor`,
+//! connections without a cursor receive only events pushed after they are accepted, like a relay does.
+//! [`MockPds`] serves records of repositories, for what the firehose leaves out.
+
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+};
+
+use futures_util::{SinkExt, StreamExt};
+use rs_car::Cid;
+use serde::Serialize;
+use sha2::Digest;
+use tokio::sync::{broadcast, watch};
+use tokio_tungstenite::tungstenite::{
+    handshake::server::{Request, Response},
+    Message,
+};
+
+use crate::lexicon::car::CommitRawBlocks;
+
+pub const TIME: &str = "2024-01-01T00:00:00.000Z";
+
+/// Database in memory with the migrations run. It is kept on a single connection,
+/// so a test holding a transaction has the database to itself.
+pub async fn memory_db() -> sqlx::SqlitePool {
+    let db = sqlx::sqlite::SqlitePoolOptions::new()
+        .max_connections(1)
+        .acquire_timeout(std::time::Duration::from_secs(5))
+        .connect(":memory:")
+        .await
+        .expect("in-memory database is opened");
+    sqlx::migrate!().run(&db).await.expect("migrations are run");
+    db
+}
+
+#[derive(Debug, Clone)]
+enum Outgoing {
+    Frame(Vec<u8>),
+    Close,
+}
+
+#[derive(Default)]
+struct State {
+    events: Vec<(u64, Vec<u8>)>,
+    requested_cursors: Vec<Option<u64>>,
+}
+
+pub struct MockRelay {
+    addr: SocketAddr,
+    state: Arc<Mutex<State>>,
+    live: broadcast::Sender<Outgoing>,
+    connections: watch::Receiver<usize>,
+}
+
+impl MockRelay {
+    pub async fn start() -> std::io::Result<Self> {
+        Self::start_on(([127, 0, 0, 1], 0).into()).await
+    }
+
+    /// Start on `addr`, e.g. to bring back a relay which was down.
+    #[allow(clippy::result_large_err)] // handshake callback signature is fixed by tungstenite
+    pub async fn start_on(addr: SocketAddr) -> std::io::Result<Self> {
+        let listener = tokio::net::TcpListener::bind(addr).await?;
+        let addr = listener.local_addr()?;
+        let state = Arc::new(Mutex::new(State::default()));
+        let (live, _) = broadcast::channel(1024);
+        let (connections_tx, connections) = watch::channel(0);
+
+        let relay = Self {
+            addr,
+            state: state.clone(),
+            live: live.clone(),
+            connections,
+        };
+
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let mut cursor = None;
+                let Ok(ws) =
+                    tokio_tungstenite::accept_hdr_async(stream, |req: &Request, resp: Response| {
+                        cursor = req.uri().query().and_then(|query| {
+                            url::form_urlencoded::parse(query.as_bytes())
+                                .find(|(k, _)| k == "cursor")
+                                .and_then(|(_, v)| v.parse::<u64>().ok())
+                        });
+                        Ok(resp)
+                    })
+                    .await
+                else {
+                    continue;
+                };
+
+                // Collect the backlog and subscribe the live events at once not to miss anything between them.
+                let (backlog, live_rx) = {
+                    let mut state = state.lock().unwrap();
+                    state.requested_cursors.push(cursor);
+                    let last_seq = state.events.last().map(|(seq, _)| *seq).unwrap_or(0);
+                    let backlog = match cursor {
+                        Some(cursor) if cursor > last_seq => vec![
+                            Outgoing::Frame(error("FutureCursor", Some("Cursor in the future."))),
+                            Outgoing::Close,
+                        ],
+                        Some(cursor) => state
+                            .events
+                            .iter()
+                            .filter(|(seq, _)| *seq > cursor)
+                            .map(|(_, frame)| Outgoing::Frame(frame.clone()))
+                            .collect(),
+                        None => vec![],
+                    };
+                    (backlog, live.subscribe())
+                };
+                connections_tx.send_modify(|v| *v += 1);
+
+                tokio::spawn(serve(ws, backlog, live_rx));
+            }
+        });
+
+        Ok(relay)
+    }
+
+    /// Service url to subscribe.
+    pub fn endpoint(&self) -> String {
+        format!("ws://{}", self.addr)
+    }
+
+    /// Cursors requested by each accepted connection, in order.
+    pub fn requested_cursors(&self) -> Vec<Option<u64>> {
+        self.state.lock().unwrap().requested_cursors.clone()
+    }
+
+    pub async fn wait_for_connections(&self, count: usize) {
+        let _ = self.connections.clone().wait_for(|v| *v >= count).await;
+    }
+
+    /// Keep a sequenced event frame and send it to the current connections.
+    pub fn push(&self, seq: u64, frame: Vec<u8>) {
+        let mut state = self.state.lock().unwrap();
+        state.events.push((seq, frame.clone()));
+        let _ = self.live.send(Outgoing::Frame(frame));
+    }
+
+    /// Send an unsequenced frame, like `#info`, to the current connections.
+    pub fn send(&self, frame: Vec<u8>) {
+        let _ = self.live.send(Outgoing::Frame(frame));
+    }
+
+    /// Send an error frame to the current connections and close them.
+    pub fn send_error(&self, error_type: &str, message: Option<&str>) {
+        let _ = self.live.send(Outgoing::Frame(error(error_type, message)));
+        self.disconnect_all();
+    }
+
+    pub fn disconnect_all(&self) {
+        let _ = self.live.send(Outgoing::Close);
+    }
+}
+
+async fn serve(
+    ws: tokio_tungstenite::WebSocketStream<tokio::net::TcpStream>,
+    backlog: Vec<Outgoing>,
+    mut live: broadcast::Receiver<Outgoing>,
+) {
+    let (mut tx, mut rx) = ws.split();
+    // Drain incoming frames so control frames are handled.
+    tokio::spawn(async move { while let Some(Ok(_)) = rx.next().await {} });
+
+    let mut backlog = backlog.into_iter();
+    loop {
+        let outgoing = match backlog.next() {
+            Some(outgoing) => outgoing,
+            None => match live.recv().await {
+                Ok(outgoing) => outgoing,
+                Err(broadcast::error::RecvError::Lagged(_)) => {
+                    let _ = tx
+                        .send(Message::Binary(error("ConsumerTooSlow", None)))
+                        .await;
+                    break;
+                }
+                Err(broadcast::error::RecvError::Closed) => break,
+            },
+        };
+        match outgoing {
+            Outgoing::Frame(frame) => {
+                if tx.send(Message::Binary(frame)).await.is_err() {
+                    return;
+                }
+            }
+            Outgoing::Close => break,
+        }
+    }
+    let _ = tx.close().await;
+}
+
+#[derive(Default)]
+struct PdsState {
+    records: HashMap<(String, String), Vec<u8>>,
+    throttled: usize,
+    requests: usize,
+}
+
+/// Local `com.atproto.sync.getRecord` server.
+pub struct MockPds {
+    addr: SocketAddr,
+    state: Arc<Mutex<PdsState>>,
+}
+
+impl MockPds {
+    pub async fn start() -> std::io::Result<Self> {
+        let listener = tokio::net::TcpListener::bind(("127.0.0.1", 0)).await?;
+        let addr = listener.local_addr()?;
+        let state = Arc::new(Mutex::new(PdsState::default()));
+
+        let app = axum::Router::new()
+            .route(
+                "/xrpc/com.atproto.sync.getRecord",
+                axum::routing::get(get_record),
+            )
+            .with_state(state.clone());
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        Ok(Self { addr, state })
+    }
+
+    /// Service url of the PDS.
+    pub fn endpoint(&self) -> String {
+        format!("http://{}", self.addr)
+    }
+
+    /// Put `record` in the repository of `did` at `path`.
+    pub fn insert(&self, did: &str, path: &str, record: serde_json::Value) {
+        let block = serde_ipld_dagcbor::to_vec(&record).expect("record is serializable");
+        self.state
+            .lock()
+            .unwrap()
+            .records
+            .insert((did.to_string(), path.to_string()), block);
+    }
+
+    /// Respond `429` to the next `count` requests.
+    pub fn throttle(&self, count: usize) {
+        self.state.lock().unwrap().throttled = count;
+    }
+
+    /// Requests received so far, throttled ones included.
+    pub fn requests(&self) -> usize {
+        self.state.lock().unwrap().requests
+    }
+}
+
+async fn get_record(
+    axum::extract::State(state): axum::extract::State<Arc<Mutex<PdsState>>>,
+    axum::extract::Query(query): axum::extract::Query<HashMap<String, String>>,
+) -> axum::response::Response {
+    use axum::{http::StatusCode, response::IntoResponse};
+
+    let mut state = state.lock().unwrap();
+    state.requests += 1;
+    if state.throttled > 0 {
+        state.throttled -= 1;
+        return (
+            StatusCode::TOO_MANY_REQUESTS,
+            [("retry-after", "0")],
+            axum::Json(serde_json::json!({ "error": "RateLimitExceeded" })),
+        )
+            .into_response();
+    }
+
+    let key = (
+        query.get("did").cloned().unwrap_or_default(),
+        format!(
+            "{}/{}",
+            query.get("collection").map_or("", String::as_str),
+            query.get("rkey").map_or("", String::as_str)
+        ),
+    );
+    match state.records.get(&key) {
+        // The commit and MST blocks proving the record are left out.
+        Some(block) => {
+            let cid = cid_for(block);
+            let car = CommitRawBlocks::from_blocks(&[cid], [(&cid, block.as_slice())]);
+            (
+                [("content-type", "application/vnd.ipld.car")],
+                car.raw().to_vec(),
+            )
+                .into_response()
+        }
+        None => (
+            StatusCode::BAD_REQUEST,
+            axum::Json(serde_json::json!({
+                "error": "RecordNotFound",
+                "message": "Could not locate record",
+            })),
+        )
+            .into_response(),
+    }
+}
+
+/// CID v1 of a DAG-CBOR block, as the repository uses.
+pub fn cid_for(block: &[u8]) -> Cid {
+    const CID_V1: u8 = 0x01;
+    const DAG_CBOR: u8 = 0x71;
+    const SHA2_256: u8 = 0x12;
+
+    let mut bytes = vec![CID_V1, DAG_CBOR, SHA2_256, 32];
+    bytes.extend(sha2::Sha256::digest(block));
+    Cid::try_from(bytes.as_slice()).expect("CID bytes are well-formed")
+}
+
+/// Encode a header and body pair. Frames without `type` are error frames.
+pub fn frame<T: Serialize>(r#type: Option<&str>, body: &T) -> Vec<u8> {
+    #[derive(Serialize)]
+    struct Header<'a> {
+        op: i8,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        t: Option<&'a str>,
+    }
+
+    let header = Header {
+        op: if r#type.is_some() { 1 } else { -1 },
+        t: r#type,
+    };
+    let mut frame = serde_ipld_dagcbor::to_vec(&header).expect("header is serializable");
+    frame.extend(serde_ipld_dagcbor::to_vec(body).expect("body is serializable"));
+    frame
+}
+
+/// An operation of a commit.
+pub enum Op {
+    Create {
+        path: String,
+        record: serde_json::Value,
+    },
+    Update {
+        path: String,
+        record: serde_json::Value,
+    },
+    Delete {
+        path: String,
+    },
+}
+
+/// `#commit` frame carrying the records of `ops` in its CAR `blocks`.
+/// The commit object is not signed. See [`signed_commit`].
+pub fn commit(seq: u64, repo: &str, rev: &str, ops: Vec<Op>) -> Vec<u8> {
+    build_commit(seq, repo, rev, None, ops, None, false)
+}
+
+/// [`commit`] marked `tooBig`, which carries no blocks.
+pub fn too_big_commit(seq: u64, repo: &str, rev: &str, ops: Vec<Op>) -> Vec<u8> {
+    build_commit(seq, repo, rev, None, ops, None, true)
+}
+
+/// [`commit`] signed by `key`. Its MST holds the records of creates and updates of `ops` only.
+pub fn signed_commit(
+    seq: u64,
+    repo: &str,
+    rev: &str,
+    ops: Vec<Op>,
+    key: &k256::ecdsa::SigningKey,
+) -> Vec<u8> {
+    build_commit(seq, repo, rev, None, ops, Some(key), false)
+}
+
+/// [`signed_commit`] which follows `since`.
+pub fn signed_commit_since(
+    seq: u64,
+    repo: &str,
+    rev: &str,
+    since: &str,
+    ops: Vec<Op>,
+    key: &k256::ecdsa::SigningKey,
+) -> Vec<u8> {
+    build_commit(seq, repo, rev, Some(since), ops, Some(key), false)
+}
+
+fn build_commit(
+    seq: u64,
+    repo: &str,
+    rev: &str,
+    since: Option<&str>,
+    ops: Vec<Op>,
+    key: Option<&k256::ecdsa::SigningKey>,
+    too_big: bool,
+) -> Vec<u8> {
+    #[derive(Serialize)]
+    struct RepoOp {
+        action: &'static str,
+        path: String,
+        cid: Option<Cid>,
+    }
+
+    // Fields are in the DAG-CBOR canonical order.
+    #[derive(Serialize)]
+    struct CommitObject<'a> {
+        did: &'a str,
+        rev: &'a str,
+        #[serde(skip_serializing_if = "Option::is_none", with = "serde_bytes")]
+        sig: Option<Vec<u8>>,
+        data: Cid,
+        prev: Option<Cid>,
+        version: u8,
+    }
+
+    #[derive(Serialize)]
+    struct Node {
+        e: Vec<Entry>,
+        l: Option<Cid>,
+    }
+
+    #[derive(Serialize)]
+    struct Entry {
+        k: serde_bytes::ByteBuf,
+        p: usize,
+        t: Option<Cid>,
+        v: Cid,
+    }
+
+    #[derive(Serialize)]
+    #[serde(rename_all = "camelCase")]
+    struct Body<'a> {
+        seq: u64,
+        time: &'a str,
+        rebase: bool,
+        too_big: bool,
+        repo: &'a str,
+        commit: Cid,
+        prev: Option<Cid>,
+        rev: &'a str,
+        since: Option<&'a str>,
+        #[serde(with = "serde_bytes")]
+        blocks: Vec<u8>,
+        ops: Vec<RepoOp>,
+        blobs: Vec<Cid>,
+    }
+
+    let mut blocks = vec![];
+    let mut leaves = vec![];
+    let ops = ops
+        .into_iter()
+        .map(|op| {
+            let (action, path, record) = match op {
+                Op::Create { path, record } => ("create", path, Some(record)),
+                Op::Update { path, record } => ("update", path, Some(record)),
+                Op::Delete { path } => ("delete", path, None),
+            };
+            let cid = record.map(|record| {
+                let block = serde_ipld_dagcbor::to_vec(&record).expect("record is serializable");
+                let cid = cid_for(&block);
+                blocks.push((cid, block));
+                leaves.push((path.clone(), cid));
+                cid
+            });
+            RepoOp { action, path, cid }
+        })
+        .collect();
+
+    // A single node tree. Layers of keys are not considered.
+    leaves.sort();
+    let mut prev_key: &[u8] = &[];
+    let entries = leaves
+        .iter()
+        .map(|(path, cid)| {
+            let key = path.as_bytes();
+            let p = key.iter().zip(prev_key).take_while(|(a, b)| a == b).count();
+            prev_key = key;
+            Entry {
+                k: serde_bytes::ByteBuf::from(&key[p..]),
+                p,
+                t: None,
+                v: *cid,
+            }
+        })
+        .collect();
+    let node = serde_ipld_dagcbor::to_vec(&Node {
+        e: entries,
+        l: None,
+    })
+    .expect("node is serializable");
+    let data = cid_for(&node);
+    blocks.push((data, node));
+
+    let mut commit_object = CommitObject {
+        did: repo,
+        rev,
+        sig: None,
+        data,
+        prev: None,
+        version: 3,
+    };
+    let unsigned = serde_ipld_dagcbor::to_vec(&commit_object).expect("commit is serializable");
+    commit_object.sig = Some(match key {
+        Some(key) => {
+            use k256::ecdsa::signature::Signer;
+            let signature: k256::ecdsa::Signature = key.sign(&unsigned);
+            signature.to_vec()
+        }
+        None => vec![0; 64],
+    });
+    let commit_block = serde_ipld_dagcbor::to_vec(&commit_object).expect("commit is serializable");
+    let commit_cid = cid_for(&commit_block);
+    blocks.push((commit_cid, commit_block));
+
+    let blocks = CommitRawBlocks::from_blocks(
+        &[commit_cid],
+        blocks.iter().map(|(cid, block)| (cid, block.as_slice())),
+    );
+
+    frame(
+        Some("#commit"),
+        &Body {
+            seq,
+            time: TIME,
+            rebase: false,
+            too_big,
+            repo,
+            commit: commit_cid,
+            prev: None,
+            rev,
+            since,
+            blocks: if too_big {
+                vec![]
+            } else {
+                blocks.raw().to_vec()
+            },
+            ops,
+            blobs: vec![],
+        },
+    )
+}
+
+pub fn identity(seq: u64, did: &str, handle: Option<&str>) -> Vec<u8> {
+    frame(
+        Some("#identity"),
+        &serde_json::json!({ "seq": seq, "time": TIME, "did": did, "handle": handle }),
+    )
+}
+
+pub fn account(seq: u64, did: &str, active: bool, status: Option<&str>) -> Vec<u8> {
+    frame(
+        Some("#account"),
+        &serde_json::json!({ "seq": seq, "time": TIME, "did": did, "active": active, "status": status }),
+    )
+}
+
+/// `#sync` frame without the commit object in its `blocks`.
+pub fn sync(seq: u64, did: &str, rev: &str) -> Vec<u8> {
+    #[derive(Serialize)]
+    struct Body<'a> {
+        seq: u64,
+        time: &'a str,
+        did: &'a str,
+        rev: &'a str,
+        #[serde(with = "serde_bytes")]
+        blocks: Vec<u8>,
+    }
+
+    frame(
+        Some("#sync"),
+        &Body {
+            seq,
+            time: TIME,
+            did,
+            rev,
+            blocks: CommitRawBlocks::from_blocks(&[], []).raw().to_vec(),
+        },
+    )
+}
+
+pub fn tombstone(seq: u64, did: &str) -> Vec<u8> {
+    frame(
+        Some("#tombstone"),
+        &serde_json::json!({ "seq": seq, "time": TIME, "did": did }),
+    )
+}
+
+pub fn info(name: &str, message: Option<&str>) -> Vec<u8> {
+    frame(
+        Some("#info"),
+        &serde_json::json!({ "name": name, "message": message }),
+    )
+}
+
+pub fn error(error_type: &str, message: Option<&str>) -> Vec<u8> {
+    frame(
+        None,
+        &serde_json::json!({ "error": error_type, "message": message }),
+    )
+}
+
+/// Record values to put in [`Op`]s.
+pub mod records {
+    use super::TIME;
+
+    pub fn post(text: &str) -> serde_json::Value {
+        serde_json::json!({ "$type": "app.bsky.feed.post", "text": text, "createdAt": TIME })
+    }
+
+    pub fn like(uri: &str, cid: &str) -> serde_json::Value {
+        serde_json::json!({
+            "$type": "app.bsky.feed.like",
+            "subject": { "uri": uri, "cid": cid },
+            "createdAt": TIME,
+        })
+    }
+
+    pub fn repost(uri: &str, cid: &str) -> serde_json::Value {
+        serde_json::json!({
+            "$type": "app.bsky.feed.repost",
+            "subject": { "uri": uri, "cid": cid },
+            "createdAt": TIME,
+        })
+    }
+
+    pub fn follow(did: &str) -> serde_json::Value {
+        serde_json::json!({ "$type": "app.bsky.graph.follow", "subject": did, "createdAt": TIME })
+    }
+}
@@ -0,0 +1,853 @@
+//! Staged processing of received frames.
+//!
+//! The reader stage hands frames to a pool of decode workers. Frames are sharded by repository DID,
+//! so frames of a repository are always decoded by the same worker in the order they arrived.
+//! Workers decode on the blocking thread pool, so decoding doesn't hold up the runtime.
+//! The apply stage takes decoded frames back in the order they arrived,
+//! which keeps per-repository order and lets the cursor advance only past applied events.
+//! Nothing here waits on other services than the one subscribed, except for DID documents,
+//! so records of commits too big to carry them are left to be fetched in the background.
+
+use std::{
+    borrow::Cow,
+    hash::{DefaultHasher, Hash, Hasher},
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use anyhow::anyhow;
+use dagcbor::de::DeserializeOption;
+use futures_util::{Stream, StreamExt};
+use log::{debug, warn};
+use serde_ipld_dagcbor as dagcbor;
+use tokio::sync::{mpsc, oneshot};
+use tokio_tungstenite::tungstenite::{self, Message};
+
+use super::{
+    capture,
+    hosts::HostCheck,
+    too_big::RecordFetcher,
+    verify::{Verifier, VerifyError},
+    FailedFrame, Header, Source, SubscriptionError, SubscriptionMessage,
+};
+use crate::lexicon::com::atproto::sync::subscribe_repos::{Commit, OutputSchema as RepoEvent};
+
+/// Sizes of the stages between the socket and the handler.
+#[derive(Debug, Clone)]
+pub struct PipelinePolicy {
+    pub decode_workers: usize,
+    /// Frames which may be received but not applied yet.
+    /// The socket is not read while the queue is full.
+    pub queue_depth: usize,
+}
+
+impl Default for PipelinePolicy {
+    fn default() -> Self {
+        Self {
+            decode_workers: std::thread::available_parallelism()
+                .map(|n| n.get())
+                .unwrap_or(1),
+            queue_depth: 1024,
+        }
+    }
+}
+
+/// Counters of the pipeline, shared by every connection of a subscription.
+#[derive(Debug, Default)]
+pub struct PipelineMetrics {
+    received: AtomicU64,
+    in_flight: AtomicUsize,
+    backpressure_waits: AtomicU64,
+    backpressure_wait_us: AtomicU64,
+    undecodable: AtomicU64,
+    failed: AtomicU64,
+    seq_gaps: AtomicU64,
+    missed_seqs: AtomicU64,
+    duplicate_seqs: AtomicU64,
+    rev_breaks: AtomicU64,
+    stalls: AtomicU64,
+    lag_ms: AtomicU64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+pub struct PipelineMetricsSnapshot {
+    /// Frames read from the socket.
+    pub received: u64,
+    /// Frames read but not applied yet.
+    pub in_flight: usize,
+    /// How many times the reader had to wait for a slot in a full queue.
+    pub backpressure_waits: u64,
+    /// Total time the reader spent waiting for a slot.
+    pub backpressure_wait_ms: u64,
+    /// Frames which could not be decoded into an event, or whose commit could not be verified for now.
+    pub undecodable: u64,
+    /// Events the handler failed on.
+    pub failed: u64,
+    /// How many times sequence numbers jumped.
+    pub seq_gaps: u64,
+    /// Sequence numbers skipped by the jumps.
+    pub missed_seqs: u64,
+    /// Events which are not after the previous one.
+    pub duplicate_seqs: u64,
+    /// Commits which do not follow the latest rev of their repository.
+    pub rev_breaks: u64,
+    /// Connections dropped for receiving nothing within the idle timeout.
+    pub stalls: u64,
+    /// How far behind the service the last applied event is.
+    pub lag_ms: u64,
+}
+
+impl PipelineMetrics {
+    pub fn snapshot(&self) -> PipelineMetricsSnapshot {
+        PipelineMetricsSnapshot {
+            received: self.received.load(Ordering::Relaxed),
+            in_flight: self.in_flight.load(Ordering::Relaxed),
+            backpressure_waits: self.backpressure_waits.load(Ordering::Relaxed),
+            backpressure_wait_ms: self.backpressure_wait_us.load(Ordering::Relaxed) / 1000,
+            undecodable: self.undecodable.load(Ordering::Relaxed),
+            failed: self.failed.load(Ordering::Relaxed),
+            seq_gaps: self.seq_gaps.load(Ordering::Relaxed),
+            missed_seqs: self.missed_seqs.load(Ordering::Relaxed),
+            duplicate_seqs: self.duplicate_seqs.load(Ordering::Relaxed),
+            rev_breaks: self.rev_breaks.load(Ordering::Relaxed),
+            stalls: self.stalls.load(Ordering::Relaxed),
+            lag_ms: self.lag_ms.load(Ordering::Relaxed),
+        }
+    }
+
+    pub(super) fn applied(&self) {
+        self.in_flight.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub(super) fn count_undecodable(&self) {
+        self.undecodable.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(super) fn count_failed(&self) {
+        self.failed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(super) fn count_gap(&self, missed: u64) {
+        self.seq_gaps.fetch_add(1, Ordering::Relaxed);
+        self.missed_seqs.fetch_add(missed, Ordering::Relaxed);
+    }
+
+    pub(super) fn count_duplicate(&self) {
+        self.duplicate_seqs.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(super) fn count_rev_break(&self) {
+        self.rev_breaks.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(super) fn count_stall(&self) {
+        self.stalls.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Take the lag from `time` of an event, given in RFC 3339.
+    pub(super) fn observe_lag(&self, time: &str) {
+        let Ok(time) = chrono::DateTime::parse_from_rfc3339(time) else {
+            return;
+        };
+        // Clocks of the service may be ahead of ours.
+        let lag = (chrono::Utc::now() - time.with_timezone(&chrono::Utc))
+            .num_milliseconds()
+            .max(0);
+        self.lag_ms.store(lag as u64, Ordering::Relaxed);
+    }
+}
+
+impl std::fmt::Display for PipelineMetricsSnapshot {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "received: {}, in flight: {}, backpressure: {} waits for {}ms, undecodable: {}, failed: {}, \
+            gaps: {} ({} missed), duplicates: {}, rev breaks: {}, stalls: {}, lag: {}ms",
+            self.received,
+            self.in_flight,
+            self.backpressure_waits,
+            self.backpressure_wait_ms,
+            self.undecodable,
+            self.failed,
+            self.seq_gaps,
+            self.missed_seqs,
+            self.duplicate_seqs,
+            self.rev_breaks,
+            self.stalls,
+            self.lag_ms
+        )
+    }
+}
+
+pub(super) enum Frame {
+    /// Frames which don't carry anything to handle.
+    Ignored,
+    /// Decoded message with the frame it is decoded from, which is kept only when asked to.
+    Message(SubscriptionMessage<RepoEvent>, Option<Vec<u8>>),
+    Undecodable(FailedFrame),
+    /// Commit which failed verification. Nothing of it is handled, but the next commit of its
+    /// repository follows its rev.
+    Dropped(Box<Commit>),
+}
+
+pub(super) type Decoded = Result<Frame, SubscriptionError>;
+
+type Job = (Message, oneshot::Sender<Decoded>);
+
+/// Reader stage. Dropping it stops the decode workers and closes the apply queue.
+pub(super) struct Pipeline {
+    source: Source,
+    workers: Vec<mpsc::Sender<Job>>,
+    next_worker: usize,
+    apply_tx: mpsc::Sender<oneshot::Receiver<Decoded>>,
+    metrics: Arc<PipelineMetrics>,
+}
+
+/// What decode workers do to frames after decoding them.
+#[derive(Default)]
+pub(super) struct Stages {
+    /// Ops on collections out of these are removed from commits.
+    pub watched_collections: Option<Arc<[String]>>,
+    /// Events of repositories which the host is not the PDS of are dropped.
+    pub host: Option<Arc<HostCheck>>,
+    /// Commits failing verification are dropped.
+    pub verifier: Option<Arc<Verifier>>,
+    /// Frames of commits too big to carry their records are kept, for the records to be fetched later.
+    pub keep_too_big: bool,
+}
+
+/// Spawn the decode workers. Decoded frames come out of the returned queue in the order they are read.
+/// Frames of decoded messages are passed along when `keep_frames` is set.
+pub(super) fn start(
+    policy: &PipelinePolicy,
+    source: Source,
+    stages: Stages,
+    keep_frames: bool,
+    metrics: Arc<PipelineMetrics>,
+) -> (Pipeline, mpsc::Receiver<oneshot::Receiver<Decoded>>) {
+    let Stages {
+        watched_collections,
+        host,
+        verifier,
+        keep_too_big,
+    } = stages;
+    let queue_depth = policy.queue_depth.max(1);
+    let verifier = verifier.filter(|_| source.carries_commits());
+    let shared_source = Arc::new(source.clone());
+    let workers = (0..policy.decode_workers.max(1))
+        .map(|_| {
+            let (tx, mut rx) = mpsc::channel::<Job>(queue_depth);
+            let source = shared_source.clone();
+            let watched_collections = watched_collections.clone();
+            let host = host.clone();
+            let verifier = verifier.clone();
+            tokio::spawn(async move {
+                while let Some((message, decoded_tx)) = rx.recv().await {
+                    let source = source.clone();
+                    let watched_collections = watched_collections.clone();
+                    // Parsing CBOR and CAR is CPU-bound, so it is kept off the threads of the runtime.
+                    let mut decoded = tokio::task::spawn_blocking(move || {
+                        decode(&source, watched_collections.as_deref(), message)
+                    })
+                    .await
+                    .unwrap_or_else(|e| {
+                        Err(SubscriptionError::fatal(
+                            anyhow::Error::new(e).context("Failed to decode frame"),
+                        ))
+                    });
+                    if let Some(host) = &host {
+                        decoded = check_host(host, decoded).await;
+                    }
+                    if let Some(verifier) = &verifier {
+                        decoded = verify(verifier, decoded).await;
+                    }
+                    if let Ok(Frame::Message(message, frame)) = &mut decoded {
+                        let keep = keep_frames
+                            || keep_too_big
+                                && matches!(
+                                    message,
+                                    SubscriptionMessage::Message(RepoEvent::Commit(commit))
+                                        if lacks_records(commit)
+                                );
+                        if !keep {
+                            *frame = None;
+                        }
+                    }
+                    let _ = decoded_tx.send(decoded);
+                }
+            });
+            tx
+        })
+        .collect();
+    let (apply_tx, apply_rx) = mpsc::channel(queue_depth);
+    // Frames left in the previous pipeline are dropped with it.
+    metrics.in_flight.store(0, Ordering::Relaxed);
+
+    (
+        Pipeline {
+            source,
+            workers,
+            next_worker: 0,
+            apply_tx,
+            metrics,
+        },
+        apply_rx,
+    )
+}
+
+impl Pipeline {
+    /// Read frames until the stream ends, fails or the apply stage goes away.
+    pub(super) async fn read<S>(mut self, rx: &mut S, capture: Option<capture::CaptureSink>)
+    where
+        S: Stream<Item = Result<Message, tungstenite::Error>> + Unpin,
+    {
+        while let Some(ret) = rx.next().await {
+            let message = match ret {
+                Ok(message) => message,
+                Err(e) => {
+                    self.fail(SubscriptionError::recoverable(
+                        anyhow::Error::new(e).context("Failed to receive message"),
+                    ))
+                    .await;
+                    return;
+                }
+            };
+            if let Some(capture) = &capture {
+                if let Err(e) = capture.send(&message).await {
+                    self.fail(SubscriptionError::fatal(
+                        e.context("Failed to write capture"),
+                    ))
+                    .await;
+                    return;
+                }
+            }
+
+            match message {
+                Message::Binary(_) | Message::Text(_) => {}
+                Message::Close(frame) => {
+                    self.fail(SubscriptionError::recoverable(anyhow!(
+                        "Connection closed by service - {frame:?}"
+                    )))
+                    .await;
+                    return;
+                }
+                _ => continue,
+            }
+            if !self.dispatch(message).await {
+                return;
+            }
+        }
+    }
+
+    /// Returns `false` when the apply stage is gone.
+    async fn dispatch(&mut self, message: Message) -> bool {
+        let worker = match peek(&self.source, &message).repo {
+            Some(key) => {
+                let mut hasher = DefaultHasher::new();
+                key.hash(&mut hasher);
+                hasher.finish() as usize % self.workers.len()
+            }
+            None => {
+                self.next_worker = (self.next_worker + 1) % self.workers.len();
+                self.next_worker
+            }
+        };
+        self.metrics.received.fetch_add(1, Ordering::Relaxed);
+        self.metrics.in_flight.fetch_add(1, Ordering::Relaxed);
+
+        let (decoded_tx, decoded_rx) = oneshot::channel();
+        send(&self.metrics, &self.workers[worker], (message, decoded_tx)).await
+            && send(&self.metrics, &self.apply_tx, decoded_rx).await
+    }
+
+    /// Pass an error to the apply stage in order.
+    async fn fail(&self, error: SubscriptionError) {
+        let (decoded_tx, decoded_rx) = oneshot::channel();
+        let _ = decoded_tx.send(Err(error));
+        self.metrics.in_flight.fetch_add(1, Ordering::Relaxed);
+        send(&self.metrics, &self.apply_tx, decoded_rx).await;
+    }
+}
+
+/// Send to a bounded queue, counting the waits for a slot.
+async fn send<T>(metrics: &PipelineMetrics, tx: &mpsc::Sender<T>, item: T) -> bool {
+    let item = match tx.try_send(item) {
+        Ok(()) => return true,
+        Err(mpsc::error::TrySendError::Closed(_)) => return false,
+        Err(mpsc::error::TrySendError::Full(item)) => item,
+    };
+
+    let started_at = Instant::now();
+    let ret = tx.send(item).await.is_ok();
+    let waited = started_at.elapsed();
+    metrics.backpressure_waits.fetch_add(1, Ordering::Relaxed);
+    metrics
+        .backpressure_wait_us
+        .fetch_add(waited.as_micros() as u64, Ordering::Relaxed);
+    if waited > Duration::from_secs(1) {
+        debug!("Reading from service was blocked for {waited:?} by a full queue");
+    }
+
+    ret
+}
+
+/// Sequence and repository DID of a frame, read without decoding the whole frame.
+#[derive(Default)]
+struct Peek<'a> {
+    seq: Option<u64>,
+    repo: Option<Cow<'a, str>>,
+}
+
+fn peek<'a>(source: &Source, message: &'a Message) -> Peek<'a> {
+    #[derive(serde::Deserialize)]
+    struct Body<'a> {
+        seq: Option<u64>,
+        time_us: Option<u64>,
+        #[serde(borrow)]
+        repo: Option<Cow<'a, str>>,
+        #[serde(borrow)]
+        did: Option<Cow<'a, str>>,
+    }
+
+    let body: Option<Body> = match (source, message) {
+        (Source::Firehose, Message::Binary(data)) => {
+            let mut cursor = std::io::Cursor::new(data.as_slice());
+            dagcbor::from_reader_with_option::<Header, _>(
+                &mut cursor,
+                DeserializeOption {
+                    ignore_trailing: true,
+                },
+            )
+            .ok()
+            .and_then(|_| dagcbor::from_slice(&data[(cursor.position() as usize)..]).ok())
+        }
+        // Compressed frames are not worth decompressing twice.
+        (Source::Jetstream(_), Message::Text(text)) => serde_json::from_str(text).ok(),
+        _ => None,
+    };
+
+    body.map_or_else(Peek::default, |body| Peek {
+        seq: body.seq.or(body.time_us),
+        repo: body.repo.or(body.did),
+    })
+}
+
+pub(super) fn decode(source: &Source, watched: Option<&[String]>, message: Message) -> Decoded {
+    let decoded = match (source, &message) {
+        (Source::Firehose, Message::Binary(data)) => super::parse_message(data).map(Some),
+        (Source::Jetstream(options), message) => options
+            .decode(message)
+            .map(|event| event.map(SubscriptionMessage::Message)),
+        _ => Ok(None),
+    };
+    let mut decoded = match decoded {
+        Ok(Some(decoded)) => decoded,
+        Ok(None) => return Ok(Frame::Ignored),
+        Err(error) => {
+            let peek = peek(source, &message);
+            return Ok(Frame::Undecodable(FailedFrame {
+                seq: peek.seq,
+                repo: peek.repo.map(Cow::into_owned),
+                frame: message.into_data(),
+                error: error.context("Failed to parse message"),
+            }));
+        }
+    };
+
+    if let SubscriptionMessage::Message(RepoEvent::Commit(commit)) = &mut decoded {
+        if let Some(watched) = watched {
+            commit.ops.retain(|op| {
+                let collection = op.path.split_once('/').map_or(op.path.as_str(), |(c, _)| c);
+                watched.iter().any(|w| w == collection)
+            });
+        }
+        // Blocks are indexed only when an op refers to one of them.
+        if commit.ops.iter().any(|op| op.cid.is_some()) {
+            // Errors are kept and returned again when the handler parses them.
+            let _ = commit.blocks.parse();
+        }
+    }
+
+    Ok(Frame::Message(decoded, Some(message.into_data())))
+}
+
+/// Drop events of repositories hosted elsewhere than `host`.
+/// Events whose repository can't be resolved fail as undecodable to go through the error policy.
+async fn check_host(host: &HostCheck, mut decoded: Decoded) -> Decoded {
+    let Ok(Frame::Message(SubscriptionMessage::Message(event), frame)) = &mut decoded else {
+        return decoded;
+    };
+    let Some(repo) = event.repo() else {
+        return decoded;
+    };
+    match host.hosts(repo).await {
+        Ok(true) => decoded,
+        Ok(false) => {
+            warn!(
+                "Drop event #{:?} of {repo} which is not hosted by {}",
+                event.seq(),
+                host.endpoint()
+            );
+            Ok(Frame::Ignored)
+        }
+        Err(e) => Ok(Frame::Undecodable(FailedFrame {
+            seq: event.seq(),
+            repo: Some(repo.to_string()),
+            frame: frame.take().unwrap_or_default(),
+            error: e.context(format!("Failed to check host of {repo}")),
+        })),
+    }
+}
+
+/// Commit of a decoded frame which is to be verified.
+fn commit_to_verify(decoded: &Decoded) -> Option<&Commit> {
+    let Ok(Frame::Message(SubscriptionMessage::Message(RepoEvent::Commit(commit)), _)) = decoded
+    else {
+        return None;
+    };
+    // Nothing of a commit without ops reaches the handler.
+    // Commits too big carry nothing to verify with, and are left to the fetcher.
+    (!commit.ops.is_empty() && !commit.too_big).then_some(commit)
+}
+
+/// Drop commits which fail verification. Commits which can't be verified for now,
+/// as the key of their repository can't be resolved, fail as undecodable to go through the error policy.
+pub(super) async fn verify(verifier: &Arc<Verifier>, mut decoded: Decoded) -> Decoded {
+    let Some(repo) = commit_to_verify(&decoded).map(|commit| commit.repo.clone()) else {
+        return decoded;
+    };
+    let mut fresh = false;
+    let error = loop {
+        let key = match verifier.signing_key(&repo, fresh).await {
+            Ok(key) => key,
+            Err(e) => break e,
+        };
+        // Signatures and proofs are CPU-bound, so they are checked off the threads of the runtime.
+        let verifier = verifier.clone();
+        let checked = tokio::task::spawn_blocking(move || {
+            let commit = commit_to_verify(&decoded).expect("commit is checked above");
+            let ret = verifier.check(commit, &key);
+            (decoded, ret)
+        })
+        .await;
+        let ret;
+        (decoded, ret) = match checked {
+            Ok(checked) => checked,
+            Err(e) => {
+                return Err(SubscriptionError::fatal(
+                    anyhow::Error::new(e).context("Failed to verify commit"),
+                ))
+            }
+        };
+        match ret {
+            Ok(()) => return decoded,
+            // The key may have been rotated since it is resolved.
+            Err(VerifyError::Signature(_)) if !fresh => fresh = true,
+            Err(e) => break e,
+        }
+    };
+
+    let Ok(Frame::Message(SubscriptionMessage::Message(RepoEvent::Commit(commit)), frame)) =
+        decoded
+    else {
+        unreachable!("commit is checked above");
+    };
+    if let VerifyError::Unresolved { .. } = error {
+        return Ok(Frame::Undecodable(FailedFrame {
+            seq: Some(commit.seq),
+            repo: Some(commit.repo.clone()),
+            frame: frame.unwrap_or_default(),
+            error: anyhow::Error::new(error).context("Failed to verify commit"),
+        }));
+    }
+    warn!(
+        "Drop commit #{} of {} which failed verification - {error:?}",
+        commit.seq, commit.repo
+    );
+
+    Ok(Frame::Dropped(commit))
+}
+
+/// Whether `commit` is too big to carry the records its ops point to.
+pub(super) fn lacks_records(commit: &Commit) -> bool {
+    commit.too_big && commit.ops.iter().any(|op| op.cid.is_some())
+}
+
+/// Fill in the records of a commit too big to carry them.
+/// The frame fails as undecodable when they can't be fetched.
+pub(super) async fn fetch_too_big(fetcher: &RecordFetcher, mut decoded: Decoded) -> Decoded {
+    let Ok(Frame::Message(SubscriptionMessage::Message(RepoEvent::Commit(commit)), frame)) =
+        &mut decoded
+    else {
+        return decoded;
+    };
+    if !lacks_records(commit) {
+        return decoded;
+    }
+    if let Err(e) = fetcher.fill(commit).await {
+        return Ok(Frame::Undecodable(FailedFrame {
+            seq: Some(commit.seq),
+            repo: Some(commit.repo.clone()),
+            frame: frame.take().unwrap_or_default(),
+            error: e.context("Failed to fetch records of commit too big"),
+        }));
+    }
+    let _ = commit.blocks.parse();
+
+    decoded
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_util::stream;
+
+    use super::*;
+    use crate::atproto_subscription::mock_relay::{self, Op};
+
+    fn binary(
+        frames: impl IntoIterator<Item = Vec<u8>>,
+    ) -> Vec<Result<Message, tungstenite::Error>> {
+        frames.into_iter().map(Message::Binary).map(Ok).collect()
+    }
+
+    #[test]
+    fn test_peek() {
+        let commit = Message::Binary(mock_relay::commit(
+            1,
+            "did:plc:author",
+            "3kaaaaaaaaa22",
+            vec![Op::Create {
+                path: "app.bsky.feed.post/1".to_string(),
+                record: mock_relay::records::post("hello"),
+            }],
+        ));
+        let peeked = peek(&Source::Firehose, &commit);
+        assert_eq!(peeked.seq, Some(1));
+        assert_eq!(peeked.repo.as_deref(), Some("did:plc:author"));
+        let identity = Message::Binary(mock_relay::identity(2, "did:plc:other", None));
+        let peeked = peek(&Source::Firehose, &identity);
+        assert_eq!(peeked.seq, Some(2));
+        assert_eq!(peeked.repo.as_deref(), Some("did:plc:other"));
+        let info = Message::Binary(mock_relay::info("OutdatedCursor", None));
+        let peeked = peek(&Source::Firehose, &info);
+        assert_eq!((peeked.seq, peeked.repo), (None, None));
+
+        let jetstream = Source::Jetstream(Default::default());
+        let text = Message::Text(
+            r#"{"did": "did:plc:author", "time_us": 1, "kind": "identity", "identity": {"did": "did:plc:author"}}"#
+                .to_string(),
+        );
+        let peeked = peek(&jetstream, &text);
+        assert_eq!(peeked.seq, Some(1));
+        assert_eq!(peeked.repo.as_deref(), Some("did:plc:author"));
+    }
+
+    #[test]
+    fn test_undecodable() {
+        let frame = mock_relay::frame(
+            Some("#commit"),
+            &serde_json::json!({ "seq": 3, "repo": "did:plc:author" }),
+        );
+        let Ok(Frame::Undecodable(undecodable)) =
+            decode(&Source::Firehose, None, Message::Binary(frame.clone()))
+        else {
+            panic!("expected undecodable");
+        };
+        assert_eq!(undecodable.seq, Some(3));
+        assert_eq!(undecodable.repo.as_deref(), Some("did:plc:author"));
+        assert_eq!(undecodable.frame, frame);
+
+        let Ok(Frame::Undecodable(undecodable)) =
+            decode(&Source::Firehose, None, Message::Binary(vec![0xff]))
+        else {
+            panic!("expected undecodable");
+        };
+        assert_eq!((undecodable.seq, undecodable.repo), (None, None));
+    }
+
+    #[test]
+    fn test_watched_collections() {
+        let frame = |ops| {
+            Message::Binary(mock_relay::commit(
+                1,
+                "did:plc:author",
+                "3kaaaaaaaaa22",
+                ops,
+            ))
+        };
+        let watched = ["app.bsky.feed.post".to_string()];
+        let ops = |decoded: Decoded| {
+            let Ok(Frame::Message(SubscriptionMessage::Message(RepoEvent::Commit(commit)), _)) =
+                decoded
+            else {
+                panic!("expected commit");
+            };
+            commit.ops.into_iter().map(|op| op.path).collect::<Vec<_>>()
+        };
+
+        let decoded = decode(
+            &Source::Firehose,
+            Some(&watched),
+            frame(vec![
+                Op::Create {
+                    path: "app.bsky.feed.like/1".to_string(),
+                    record: mock_relay::records::like(
+                        "at://did:plc:other/app.bsky.feed.post/1",
+                        "bafyreia",
+                    ),
+                },
+                Op::Delete {
+                    path: "app.bsky.feed.post/2".to_string(),
+                },
+            ]),
+        );
+        assert_eq!(ops(decoded), ["app.bsky.feed.post/2"]);
+
+        let decoded = decode(
+            &Source::Firehose,
+            Some(&watched),
+            frame(vec![Op::Create {
+                path: "app.bsky.graph.follow/1".to_string(),
+                record: mock_relay::records::follow("did:plc:other"),
+            }]),
+        );
+        assert!(ops(decoded).is_empty());
+
+        let decoded = decode(
+            &Source::Firehose,
+            None,
+            frame(vec![Op::Create {
+                path: "app.bsky.graph.follow/1".to_string(),
+                record: mock_relay::records::follow("did:plc:other"),
+            }]),
+        );
+        assert_eq!(ops(decoded), ["app.bsky.graph.follow/1"]);
+    }
+
+    #[test]
+    fn test_lag() {
+        let metrics = PipelineMetrics::default();
+        let time = chrono::Utc::now() - chrono::Duration::seconds(2);
+        metrics.observe_lag(&time.to_rfc3339());
+        let lag = metrics.snapshot().lag_ms;
+        assert!((2000..10000).contains(&lag), "lag: {lag}");
+
+        // Ahead of our clock
+        let time = chrono::Utc::now() + chrono::Duration::seconds(2);
+        metrics.observe_lag(&time.to_rfc3339());
+        assert_eq!(metrics.snapshot().lag_ms, 0);
+    }
+
+    #[tokio::test]
+    async fn test_keep_order() {
+        let metrics = Arc::new(PipelineMetrics::default());
+        let (pipeline, mut apply_rx) = start(
+            &PipelinePolicy {
+                decode_workers: 4,
+                queue_depth: 8,
+            },
+            Source::Firehose,
+            Default::default(),
+            false,
+            metrics.clone(),
+        );
+        let mut frames =
+            stream::iter(binary((1..=100).map(|seq| {
+                mock_relay::identity(seq, &format!("did:plc:{}", seq % 7), None)
+            })));
+
+        let reader = pipeline.read(&mut frames, None);
+        let apply = async {
+            let mut seqs = vec![];
+            while let Some(decoded) = apply_rx.recv().await {
+                let Frame::Message(SubscriptionMessage::Message(event), _) =
+                    decoded.await.unwrap().unwrap()
+                else {
+                    panic!("expected event");
+                };
+                metrics.applied();
+                seqs.push(event.seq().unwrap());
+            }
+            seqs
+        };
+        let ((), seqs) = tokio::join!(reader, apply);
+
+        assert_eq!(seqs, (1..=100).collect::<Vec<_>>());
+        let snapshot = metrics.snapshot();
+        assert_eq!(snapshot.received, 100);
+        assert_eq!(snapshot.in_flight, 0);
+    }
+
+    #[tokio::test]
+    async fn test_backpressure() {
+        let metrics = Arc::new(PipelineMetrics::default());
+        let (pipeline, mut apply_rx) = start(
+            &PipelinePolicy {
+                decode_workers: 1,
+                queue_depth: 2,
+            },
+            Source::Firehose,
+            Default::default(),
+            false,
+            metrics.clone(),
+        );
+        let mut frames = stream::iter(binary(
+            (1..=5).map(|seq| mock_relay::identity(seq, "did:plc:test", None)),
+        ));
+        let reader = tokio::spawn(async move { pipeline.read(&mut frames, None).await });
+
+        // Nothing is applied, so the reader stops once the queues are full.
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(!reader.is_finished());
+        let snapshot = metrics.snapshot();
+        assert!(snapshot.backpressure_waits >= 1);
+        assert!(snapshot.in_flight < 5);
+
+        let mut applied = 0;
+        while let Some(decoded) = apply_rx.recv().await {
+            decoded.await.unwrap().unwrap();
+            metrics.applied();
+            applied += 1;
+        }
+        reader.await.unwrap();
+        assert_eq!(applied, 5);
+        assert_eq!(metrics.snapshot().in_flight, 0);
+    }
+
+    #[tokio::test]
+    async fn test_close_after_frames() {
+        let (pipeline, mut apply_rx) = start(
+            &PipelinePolicy::default(),
+            Source::Firehose,
+            Default::default(),
+            false,
+            Default::default(),
+        );
+        let mut frames = stream::iter(vec![
+            Ok(Message::Binary(mock_relay::identity(
+                1,
+                "did:plc:test",
+                None,
+            ))),
+            Ok(Message::Close(None)),
+            Ok(Message::Binary(mock_relay::identity(
+                2,
+                "did:plc:test",
+                None,
+            ))),
+        ]);
+        pipeline.read(&mut frames, None).await;
+
+        let first = apply_rx.recv().await.unwrap().await.unwrap();
+        assert!(matches!(
+            first,
+            Ok(Frame::Message(SubscriptionMessage::Message(_), None))
+        ));
+        let second = apply_rx.recv().await.unwrap().await.unwrap();
+        assert!(matches!(second, Err(SubscriptionError::Recoverable(_))));
+        assert!(apply_rx.recv().await.is_none());
+    }
+}
@@ -0,0 +1,519 @@
+//! Records of commits too big to carry their blocks (`tooBig`), fetched from the PDS of the repository.
+//!
+//! The relay leaves the blocks out of such commits, so the records their ops point to are fetched
+//! one by one through `com.atproto.sync.getRecord`, and put in `blocks` for the handler as usual.
+//! Records are checked against the CIDs of the ops, without the proof of the commit.
+//! Only PDSes served over https at public addresses are asked, as DID documents are controlled
+//! by whoever owns the DID and could otherwise point requests at internal services.
+
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use anyhow::{anyhow, Context};
+use log::debug;
+use reqwest::StatusCode;
+use rs_car::Cid;
+use tokio::time::Instant;
+
+use super::verify::check_hash;
+use crate::{
+    identity::DidResolver,
+    lexicon::{
+        car::CommitRawBlocks,
+        com::atproto::sync::{get_record, subscribe_repos::Commit},
+    },
+};
+
+/// Retry-After longer than this fails the request instead of holding the repository back.
+const MAX_RETRY_AFTER: Duration = Duration::from_secs(60);
+
+/// Controls how hard the PDS of each repository is asked for records.
+///
+/// Requests to a PDS are spaced by `interval`. Requests failed by the network, rate limiting or the PDS
+/// are retried up to `max_retries` times, waiting `retry_delay` doubled on each retry
+/// or as long as the PDS tells to.
+/// Commits whose records still fail are fetched again later, waiting `backfill_delay`
+/// doubled on each failure up to `max_backfill_delay`.
+#[derive(Debug, Clone)]
+pub struct FetchPolicy {
+    pub interval: Duration,
+    pub max_retries: u32,
+    pub retry_delay: Duration,
+    pub backfill_delay: Duration,
+    pub max_backfill_delay: Duration,
+}
+
+impl Default for FetchPolicy {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_millis(100),
+            max_retries: 3,
+            retry_delay: Duration::from_secs(1),
+            backfill_delay: Duration::from_secs(60),
+            max_backfill_delay: Duration::from_secs(60 * 60),
+        }
+    }
+}
+
+impl FetchPolicy {
+    /// Delay before fetching records of a commit again after `failures` consecutive failures.
+    pub fn backfill_delay_for(&self, failures: u32) -> Duration {
+        self.backfill_delay
+            .checked_mul(
+                1u32.checked_shl(failures.saturating_sub(1))
+                    .unwrap_or(u32::MAX),
+            )
+            .unwrap_or(self.max_backfill_delay)
+            .min(self.max_backfill_delay)
+    }
+}
+
+#[derive(serde::Deserialize)]
+struct XrpcError {
+    error: String,
+}
+
+pub struct RecordFetcher {
+    resolver: Arc<dyn DidResolver>,
+    client: reqwest::Client,
+    policy: FetchPolicy,
+    /// Whether PDSes are asked only over https at public addresses.
+    public_only: bool,
+    /// When the next request to each host may be sent.
+    next_request: Mutex<HashMap<String, Instant>>,
+}
+
+impl RecordFetcher {
+    pub fn new(resolver: Arc<dyn DidResolver>, policy: FetchPolicy) -> anyhow::Result<Self> {
+        Ok(Self {
+            resolver,
+            client: client(true)?,
+            policy,
+            public_only: true,
+            next_request: Default::default(),
+        })
+    }
+
+    /// Ask PDSes at any address over any scheme, e.g. [`mock_relay::MockPds`](super::mock_relay::MockPds).
+    #[cfg(any(test, feature = "test-support"))]
+    pub fn allow_private_hosts(mut self) -> anyhow::Result<Self> {
+        self.client = client(false)?;
+        self.public_only = false;
+        Ok(self)
+    }
+
+    pub fn policy(&self) -> &FetchPolicy {
+        &self.policy
+    }
+
+    /// Replace `blocks` of `commit` with the records its creates and updates point to.
+    /// Records changed or deleted since the commit are left out, as later commits carry them.
+    pub async fn fill(&self, commit: &mut Commit) -> anyhow::Result<()> {
+        let document = self.resolver.resolve(&commit.repo).await?;
+        let pds = document
+            .pds_endpoint()
+            .ok_or_else(|| anyhow!("{} has no PDS", commit.repo))?;
+        let mut url = url::Url::parse(pds).with_context(|| format!("Invalid PDS url - {pds}"))?;
+        if self.public_only {
+            check_public(&url).with_context(|| format!("PDS of {} is refused", commit.repo))?;
+        }
+        url.path_segments_mut()
+            .map_err(|_| anyhow!("Not a valid PDS url - {pds}"))?
+            .pop_if_empty()
+            .push("xrpc")
+            .push(get_record::ID);
+
+        let mut blocks: Vec<(Cid, Vec<u8>)> = vec![];
+        for op in &commit.ops {
+            let Some(cid) = op.cid else {
+                continue;
+            };
+            let (collection, rkey) = op
+                .path
+                .split_once('/')
+                .ok_or_else(|| anyhow!("Invalid op path - {}", op.path))?;
+            let Some(car) = self
+                .get_record(&url, &commit.repo, collection, rkey)
+                .await?
+            else {
+                debug!("{}/{} is deleted since", commit.repo, op.path);
+                continue;
+            };
+            let car = CommitRawBlocks::from(serde_bytes::ByteBuf::from(car));
+            let fetched = car
+                .parse()
+                .with_context(|| format!("Invalid CAR of {}/{}", commit.repo, op.path))?;
+            match fetched.get_raw(&cid) {
+                Some(block) => {
+                    check_hash(&cid, block)?;
+                    blocks.push((cid, block.to_vec()));
+                }
+                None => debug!("{}/{} is changed since", commit.repo, op.path),
+            }
+        }
+
+        commit.blocks = CommitRawBlocks::from_blocks(
+            &[commit.commit],
+            blocks.iter().map(|(cid, block)| (cid, block.as_slice())),
+        );
+        Ok(())
+    }
+
+    /// CAR of the record, or `None` when the PDS does not have it.
+    async fn get_record(
+        &self,
+        url: &url::Url,
+        did: &str,
+        collection: &str,
+        rkey: &str,
+    ) -> anyhow::Result<Option<Vec<u8>>> {
+        let mut delay = self.policy.retry_delay;
+        let mut retries = 0;
+        loop {
+            self.wait_turn(url.host_str().unwrap_or_default()).await;
+            let ret = self
+                .client
+                .get(url.clone())
+                .query(&get_record::QueryParams {
+                    did: did.to_string(),
+                    collection: collection.to_string(),
+                    rkey: rkey.to_string(),
+                })
+                .send()
+                .await;
+            let (error, retry_after) = match ret {
+                Ok(response) if response.status().is_success() => {
+                    let car = response
+                        .bytes()
+                        .await
+                        .with_context(|| format!("Failed to read record of {did}"))?;
+                    return Ok(Some(car.to_vec()));
+                }
+                Ok(response)
+                    if response.status() == StatusCode::TOO_MANY_REQUESTS
+                        || response.status().is_server_error() =>
+                {
+                    let retry_after = response
+                        .headers()
+                        .get(reqwest::header::RETRY_AFTER)
+                        .and_then(|v| v.to_str().ok())
+                        .and_then(|v| v.parse().ok())
+                        .map(Duration::from_secs);
+                    (anyhow!("PDS responded {}", response.status()), retry_after)
+                }
+                Ok(response) => {
+                    let status = response.status();
+                    let error = response.json::<XrpcError>().await.ok();
+                    if let Some(XrpcError { error }) = &error {
+                        if error == "RecordNotFound" || error == "RepoNotFound" {
+                            return Ok(None);
+                        }
+                    }
+                    return Err(anyhow!(
+                        "PDS responded {status} to {did}/{collection}/{rkey} - {:?}",
+                        error.map(|e| e.error)
+                    ));
+                }
+                Err(e) => (anyhow::Error::new(e), None),
+            };
+
+            let wait = retry_after.unwrap_or(delay);
+            if retries >= self.policy.max_retries || wait > MAX_RETRY_AFTER {
+                return Err(error.context(format!(
+                    "Failed to fetch {did}/{collection}/{rkey} after {retries} retries"
+                )));
+            }
+            debug!("Retry fetching {did}/{collection}/{rkey} in {wait:?} - {error:#}");
+            tokio::time::sleep(wait).await;
+            retries += 1;
+            delay *= 2;
+        }
+    }
+
+    /// Wait until a request may be sent to `host`.
+    async fn wait_turn(&self, host: &str) {
+        let at = {
+            let mut next_request = self.next_request.lock().unwrap();
+            let now = Instant::now();
+            let next = next_request.entry(host.to_string()).or_insert(now);
+            let at = (*next).max(now);
+            *next = at + self.policy.interval;
+            at
+        };
+        tokio::time::sleep_until(at).await;
+    }
+}
+
+fn client(public_only: bool) -> anyhow::Result<reqwest::Client> {
+    let builder = reqwest::Client::builder().timeout(Duration::from_secs(10));
+    let builder = if public_only {
+        builder
+            .dns_resolver(Arc::new(PublicResolver))
+            .https_only(true)
+            // A redirect could lead anywhere.
+            .redirect(reqwest::redirect::Policy::none())
+    } else {
+        builder
+    };
+    Ok(builder.build()?)
+}
+
+/// Refuse `url` unless it is https at a public address, as far as it tells without resolving its host.
+fn check_public(url: &url::Url) -> anyhow::Result<()> {
+    if url.scheme() != "https" {
+        return Err(anyhow!("Not https - {url}"));
+    }
+    let public = match url.host() {
+        Some(url::Host::Domain(domain)) => {
+            let domain = domain.trim_end_matches('.').to_ascii_lowercase();
+            domain != "localhost" && !domain.ends_with(".localhost")
+        }
+        Some(url::Host::Ipv4(ip)) => is_public(IpAddr::V4(ip)),
+        Some(url::Host::Ipv6(ip)) => is_public(IpAddr::V6(ip)),
+        None => false,
+    };
+    if !public {
+        return Err(anyhow!("Not a public host - {url}"));
+    }
+
+    Ok(())
+}
+
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+            !(ip.is_unspecified()
+                || ip.is_loopback()
+                || ip.is_private()
+                || ip.is_link_local()
+                || ip.is_broadcast()
+                || ip.is_documentation()
+                || ip.is_multicast()
+                // Shared address space of carrier-grade NAT
+                || (a == 100 && (b & 0xc0) == 64)
+                || a == 0)
+        }
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_public(IpAddr::V4(ip)),
+            None => {
+                let first = ip.segments()[0];
+                !(ip.is_unspecified()
+                    || ip.is_loopback()
+                    || ip.is_multicast()
+                    // Unique local
+                    || (first & 0xfe00) == 0xfc00
+                    // Link local
+                    || (first & 0xffc0) == 0xfe80)
+            }
+        },
+    }
+}
+
+/// Resolves hosts to their public addresses only, so that a name can't lead to internal services either.
+struct PublicResolver;
+
+impl reqwest::dns::Resolve for PublicResolver {
+    fn resolve(&self, name: reqwest::dns::Name) -> reqwest::dns::Resolving {
+        let host = name.as_str().to_string();
+        Box::pin(async move {
+            let addrs = tokio::net::lookup_host((host.as_str(), 0))
+                .await?
+                .filter(|addr| is_public(addr.ip()))
+                .collect::<Vec<SocketAddr>>();
+            if addrs.is_empty() {
+                return Err(format!("{host} has no public address").into());
+            }
+            Ok(Box::new(addrs.into_iter()) as reqwest::dns::Addrs)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        atproto_subscription::{
+            mock_relay::{self, records, MockPds, Op},
+            parse_message, SubscriptionMessage,
+        },
+        identity::{DidDocument, PublicKey, StubDidResolver},
+        lexicon::{com::atproto::sync::subscribe_repos::OutputSchema as RepoEvent, Record},
+    };
+
+    const REPO: &str = "did:plc:author";
+
+    fn fetcher(pds: &MockPds, policy: FetchPolicy) -> RecordFetcher {
+        let key = k256::ecdsa::SigningKey::random(&mut rand::thread_rng());
+        let resolver = StubDidResolver::default();
+        resolver.insert(DidDocument::new(
+            REPO,
+            &PublicKey::K256(*key.verifying_key()),
+            Some(&pds.endpoint()),
+        ));
+        RecordFetcher::new(Arc::new(resolver), policy)
+            .unwrap()
+            .allow_private_hosts()
+            .unwrap()
+    }
+
+    fn too_big_commit(ops: Vec<Op>) -> Commit {
+        let frame = mock_relay::too_big_commit(1, REPO, "3kaaaaaaaaa22", ops);
+        let Ok(SubscriptionMessage::Message(RepoEvent::Commit(commit))) = parse_message(&frame)
+        else {
+            panic!("expected commit");
+        };
+        *commit
+    }
+
+    #[tokio::test]
+    async fn test_fill() {
+        let pds = MockPds::start().await.unwrap();
+        pds.insert(REPO, "app.bsky.feed.post/1", records::post("으어어"));
+        pds.insert(REPO, "app.bsky.feed.post/3", records::post("edited"));
+        pds.throttle(1);
+        let fetcher = fetcher(
+            &pds,
+            FetchPolicy {
+                interval: Duration::ZERO,
+                max_retries: 1,
+                retry_delay: Duration::from_millis(10),
+                ..Default::default()
+            },
+        );
+
+        let mut commit = too_big_commit(vec![
+            Op::Create {
+                path: "app.bsky.feed.post/1".to_string(),
+                record: records::post("으어어"),
+            },
+            // Deleted since
+            Op::Create {
+                path: "app.bsky.feed.post/2".to_string(),
+                record: records::post("deleted"),
+            },
+            // Updated since
+            Op::Create {
+                path: "app.bsky.feed.post/3".to_string(),
+                record: records::post("original"),
+            },
+            Op::Delete {
+                path: "app.bsky.feed.post/4".to_string(),
+            },
+        ]);
+        assert!(commit.too_big);
+        fetcher.fill(&mut commit).await.unwrap();
+
+        // The throttled request is retried, and the deletion is not asked for.
+        assert_eq!(pds.requests(), 4);
+        let blocks = commit.blocks.parse().unwrap();
+        let Some(Ok(Record::Post(post))) = blocks.get(&commit.ops[0].cid.unwrap()) else {
+            panic!("expected post");
+        };
+        assert_eq!(post.text, "으어어");
+        assert!(blocks.get(&commit.ops[1].cid.unwrap()).is_none());
+        assert!(blocks.get(&commit.ops[2].cid.unwrap()).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_give_up_retrying() {
+        let pds = MockPds::start().await.unwrap();
+        pds.insert(REPO, "app.bsky.feed.post/1", records::post("으어어"));
+        pds.throttle(3);
+        let fetcher = fetcher(
+            &pds,
+            FetchPolicy {
+                interval: Duration::ZERO,
+                max_retries: 2,
+                retry_delay: Duration::from_millis(10),
+                ..Default::default()
+            },
+        );
+
+        let mut commit = too_big_commit(vec![Op::Create {
+            path: "app.bsky.feed.post/1".to_string(),
+            record: records::post("으어어"),
+        }]);
+        assert!(fetcher.fill(&mut commit).await.is_err());
+        assert_eq!(pds.requests(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_refuse_private_hosts() {
+        let pds = MockPds::start().await.unwrap();
+        pds.insert(REPO, "app.bsky.feed.post/1", records::post("으어어"));
+        let key = k256::ecdsa::SigningKey::random(&mut rand::thread_rng());
+        let resolver = StubDidResolver::default();
+        resolver.insert(DidDocument::new(
+            REPO,
+            &PublicKey::K256(*key.verifying_key()),
+            Some(&pds.endpoint()),
+        ));
+        let fetcher = RecordFetcher::new(Arc::new(resolver), FetchPolicy::default()).unwrap();
+
+        let mut commit = too_big_commit(vec![Op::Create {
+            path: "app.bsky.feed.post/1".to_string(),
+            record: records::post("으어어"),
+        }]);
+        let error = fetcher.fill(&mut commit).await.unwrap_err();
+        assert!(format!("{error:#}").contains("Not https"), "{error:#}");
+        assert_eq!(pds.requests(), 0);
+
+        let public = |url: &str| check_public(&url::Url::parse(url).unwrap()).is_ok();
+        assert!(public("https://pds.example.com"));
+        assert!(public("https://8.8.8.8"));
+        assert!(!public("https://localhost:2583"));
+        assert!(!public("https://127.0.0.1"));
+        assert!(!public("https://10.0.0.1"));
+        assert!(!public("https://169.254.169.254"));
+        assert!(!public("https://100.64.0.1"));
+        assert!(!public("https://[::1]"));
+        assert!(!public("https://[fd00::1]"));
+        assert!(!public("https://[::ffff:192.168.0.1]"));
+    }
+
+    #[test]
+    fn test_backfill_delay() {
+        let policy = FetchPolicy {
+            backfill_delay: Duration::from_secs(60),
+            max_backfill_delay: Duration::from_secs(60 * 60),
+            ..Default::default()
+        };
+        assert_eq!(policy.backfill_delay_for(1), Duration::from_secs(60));
+        assert_eq!(policy.backfill_delay_for(3), Duration::from_secs(240));
+        assert_eq!(policy.backfill_delay_for(7), Duration::from_secs(60 * 60));
+        assert_eq!(
+            policy.backfill_delay_for(u32::MAX),
+            Duration::from_secs(60 * 60)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_rate_limit() {
+        let pds = MockPds::start().await.unwrap();
+        let fetcher = fetcher(
+            &pds,
+            FetchPolicy {
+                interval: Duration::from_millis(50),
+                ..Default::default()
+            },
+        );
+
+        let mut commit = too_big_commit(
+            (1..=4)
+                .map(|i| Op::Create {
+                    path: format!("app.bsky.feed.post/{i}"),
+                    record: records::post("으어어"),
+                })
+                .collect(),
+        );
+        let started_at = Instant::now();
+        fetcher.fill(&mut commit).await.unwrap();
+        assert!(started_at.elapsed() >= Duration::from_millis(150));
+        assert_eq!(pds.requests(), 4);
+    }
+}
@@ -0,0 +1,385 @@
+//! Verification of commits against the signing key of the repository.
+//!
+//! A commit is accepted when its commit object is signed by the `#atproto` key of the repository DID,
+//! and every op is proven by the MST blocks carried with it: creates and updates point to the op CID,
+//! deletes point to nothing.
+
+use std::sync::Arc;
+
+use anyhow::{anyhow, Context};
+use log::debug;
+use rs_car::Cid;
+use sha2::Digest;
+
+use crate::{
+    identity::{DidResolver, PublicKey},
+    lexicon::{
+        car::CommitBlocks,
+        com::atproto::sync::subscribe_repos::{Commit, RepoOpAction},
+    },
+};
+
+const SHA2_256: u64 = 0x12;
+
+/// Commit object as it is signed.
+#[derive(serde::Deserialize)]
+struct SignedCommit {
+    did: String,
+    version: u8,
+    data: Cid,
+    rev: String,
+    prev: Option<Cid>,
+    #[serde(with = "serde_bytes")]
+    sig: Vec<u8>,
+}
+
+/// Commit object without `sig`. Fields are in the DAG-CBOR canonical order.
+#[derive(serde::Serialize)]
+struct UnsignedCommit<'a> {
+    did: &'a str,
+    rev: &'a str,
+    data: &'a Cid,
+    prev: &'a Option<Cid>,
+    version: u8,
+}
+
+#[derive(serde::Deserialize)]
+struct Node {
+    l: Option<Cid>,
+    e: Vec<Entry>,
+}
+
+#[derive(serde::Deserialize)]
+struct Entry {
+    p: usize,
+    #[serde(with = "serde_bytes")]
+    k: Vec<u8>,
+    v: Cid,
+    t: Option<Cid>,
+}
+
+/// Why a commit is not verified.
+#[derive(Debug, thiserror::Error)]
+pub enum VerifyError {
+    /// The DID document of the repository could not be resolved, e.g. while its directory is down.
+    /// The commit may well be valid, so it is worth trying again later.
+    #[error("Failed to resolve DID document of {did}")]
+    Unresolved {
+        did: String,
+        #[source]
+        source: anyhow::Error,
+    },
+    /// The commit object is not signed by the key given.
+    #[error(transparent)]
+    Signature(anyhow::Error),
+    /// The commit is malformed or does not prove its ops.
+    #[error(transparent)]
+    Invalid(#[from] anyhow::Error),
+}
+
+pub struct Verifier {
+    resolver: Arc<dyn DidResolver>,
+}
+
+impl Verifier {
+    pub fn new(resolver: Arc<dyn DidResolver>) -> Self {
+        Self { resolver }
+    }
+
+    pub async fn verify(&self, commit: &Commit) -> Result<(), VerifyError> {
+        let key = self.signing_key(&commit.repo, false).await?;
+        match self.check(commit, &key) {
+            Err(VerifyError::Signature(_)) => {
+                // The key may have been rotated since it is resolved.
+                debug!(
+                    "Signature of {} mismatched. Resolve the key again",
+                    commit.repo
+                );
+                let key = self.signing_key(&commit.repo, true).await?;
+                self.check(commit, &key)
+            }
+            ret => ret,
+        }
+    }
+
+    /// `#atproto` key of `did`. With `fresh`, the document is resolved again instead of taken from a cache.
+    pub async fn signing_key(&self, did: &str, fresh: bool) -> Result<PublicKey, VerifyError> {
+        if fresh {
+            self.resolver.invalidate(did);
+        }
+        let document =
+            self.resolver
+                .resolve(did)
+                .await
+                .map_err(|source| VerifyError::Unresolved {
+                    did: did.to_string(),
+                    source,
+                })?;
+
+        Ok(document.signing_key()?)
+    }
+
+    /// Check `commit` against the signing `key` of its repository.
+    /// Unlike [`Self::verify`], this doesn't wait on anything, so it can run on a blocking thread.
+    pub fn check(&self, commit: &Commit, key: &PublicKey) -> Result<(), VerifyError> {
+        let blocks = commit.blocks.parse().map_err(anyhow::Error::new)?;
+
+        let signed: SignedCommit = serde_ipld_dagcbor::from_slice(block(&blocks, &commit.commit)?)
+            .context("Invalid commit object")?;
+        if signed.did != commit.repo {
+            return Err(anyhow!("Commit object is of another repository - {}", signed.did).into());
+        }
+        if signed.rev != commit.rev {
+            return Err(anyhow!("Commit object is of another rev - {}", signed.rev).into());
+        }
+        let unsigned = serde_ipld_dagcbor::to_vec(&UnsignedCommit {
+            did: &signed.did,
+            rev: &signed.rev,
+            data: &signed.data,
+            prev: &signed.prev,
+            version: signed.version,
+        })
+        .context("Failed to encode unsigned commit")?;
+        key.verify(&unsigned, &signed.sig)
+            .with_context(|| format!("Invalid commit signature of {}", commit.repo))
+            .map_err(VerifyError::Signature)?;
+
+        for op in &commit.ops {
+            let found = lookup(&blocks, &signed.data, op.path.as_bytes())
+                .with_context(|| format!("Failed to prove op on {}", op.path))?;
+            let expected = match &op.action {
+                RepoOpAction::Create | RepoOpAction::Update => op.cid,
+                RepoOpAction::Delete => None,
+                RepoOpAction::Other(action) => {
+                    return Err(anyhow!("Unknown action {action} of op on {}", op.path).into())
+                }
+            };
+            if found != expected {
+                return Err(anyhow!(
+                    "Op on {} is not in the tree. expected: {expected:?}, found: {found:?}",
+                    op.path
+                )
+                .into());
+            }
+            if let Some(cid) = &expected {
+                // The record itself may be left out, but what is given must match.
+                if let Some(record) = blocks.get_raw(cid) {
+                    check_hash(cid, record)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn block<'a>(blocks: &CommitBlocks<'a>, cid: &Cid) -> anyhow::Result<&'a [u8]> {
+    let block = blocks
+        .get_raw(cid)
+        .ok_or_else(|| anyhow!("Block {cid} is missing"))?;
+    check_hash(cid, block)?;
+
+    Ok(block)
+}
+
+pub(super) fn check_hash(cid: &Cid, block: &[u8]) -> anyhow::Result<()> {
+    let hash = cid.hash();
+    if hash.code() != SHA2_256 {
+        return Err(anyhow!("Unsupported hash of {cid}"));
+    }
+    if hash.digest() != sha2::Sha256::digest(block).as_slice() {
+        return Err(anyhow!("Block does not match {cid}"));
+    }
+
+    Ok(())
+}
+
+/// Find the value of `key` in the MST under `root`.
+fn lookup(blocks: &CommitBlocks, root: &Cid, key: &[u8]) -> anyhow::Result<Option<Cid>> {
+    let mut node_cid = *root;
+    loop {
+        let node: Node = serde_ipld_dagcbor::from_slice(block(blocks, &node_cid)?)
+            .with_context(|| format!("Invalid MST node {node_cid}"))?;
+
+        // Subtree holding keys between the previous entry and the current one.
+        let mut subtree = node.l;
+        let mut entry_key = Vec::new();
+        let mut descend = None;
+        for entry in node.e {
+            if entry.p > entry_key.len() {
+                return Err(anyhow!("Invalid key prefix in MST node {node_cid}"));
+            }
+            entry_key.truncate(entry.p);
+            entry_key.extend(entry.k);
+            match key.cmp(entry_key.as_slice()) {
+                std::cmp::Ordering::Less => {
+                    descend = Some(subtree);
+                    break;
+                }
+                std::cmp::Ordering::Equal => return Ok(Some(entry.v)),
+                std::cmp::Ordering::Greater => subtree = entry.t,
+            }
+        }
+
+        match descend.unwrap_or(subtree) {
+            Some(next) => node_cid = next,
+            None => return Ok(None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        atproto_subscription::{
+            mock_relay::{self, records, Op},
+            SubscriptionMessage,
+        },
+        identity::{DidDocument, PublicKey, StubDidResolver},
+        lexicon::com::atproto::sync::subscribe_repos::OutputSchema as RepoEvent,
+    };
+
+    const REPO: &str = "did:plc:author";
+
+    fn parse_commit(frame: &[u8]) -> Box<Commit> {
+        match super::super::parse_message(frame).unwrap() {
+            SubscriptionMessage::Message(RepoEvent::Commit(commit)) => commit,
+            _ => panic!("expected commit"),
+        }
+    }
+
+    fn ops() -> Vec<Op> {
+        vec![
+            Op::Create {
+                path: "app.bsky.feed.post/3kaaaaaaaaa22".to_string(),
+                record: records::post("으어어"),
+            },
+            Op::Update {
+                path: "app.bsky.actor.profile/self".to_string(),
+                record: serde_json::json!({ "$type": "app.bsky.actor.profile" }),
+            },
+            Op::Delete {
+                path: "app.bsky.feed.post/3kaaaaaaaaa23".to_string(),
+            },
+        ]
+    }
+
+    fn verifier(key: &k256::ecdsa::SigningKey) -> Verifier {
+        let resolver = StubDidResolver::default();
+        resolver.insert(DidDocument::new(
+            REPO,
+            &PublicKey::K256(*key.verifying_key()),
+            None,
+        ));
+        Verifier::new(Arc::new(resolver))
+    }
+
+    #[tokio::test]
+    async fn test_verify() {
+        let key = k256::ecdsa::SigningKey::random(&mut rand::thread_rng());
+        let commit = parse_commit(&mock_relay::signed_commit(
+            1,
+            REPO,
+            "3kaaaaaaaaa22",
+            ops(),
+            &key,
+        ));
+        verifier(&key).verify(&commit).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_reject_signature() {
+        let key = k256::ecdsa::SigningKey::random(&mut rand::thread_rng());
+        let other = k256::ecdsa::SigningKey::random(&mut rand::thread_rng());
+        let commit = parse_commit(&mock_relay::signed_commit(
+            1,
+            REPO,
+            "3kaaaaaaaaa22",
+            ops(),
+            &other,
+        ));
+        assert!(matches!(
+            verifier(&key).verify(&commit).await,
+            Err(VerifyError::Signature(_))
+        ));
+
+        let commit = parse_commit(&mock_relay::commit(1, REPO, "3kaaaaaaaaa22", ops()));
+        assert!(verifier(&key).verify(&commit).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_unresolved() {
+        let key = k256::ecdsa::SigningKey::random(&mut rand::thread_rng());
+        let commit = parse_commit(&mock_relay::signed_commit(
+            1,
+            "did:plc:unknown",
+            "3kaaaaaaaaa22",
+            ops(),
+            &key,
+        ));
+        assert!(matches!(
+            verifier(&key).verify(&commit).await,
+            Err(VerifyError::Unresolved { did, .. }) if did == "did:plc:unknown"
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_reject_injected_op() {
+        let key = k256::ecdsa::SigningKey::random(&mut rand::thread_rng());
+        let verifier = verifier(&key);
+
+        // An op which is not in the signed tree.
+        let mut commit = parse_commit(&mock_relay::signed_commit(
+            1,
+            REPO,
+            "3kaaaaaaaaa22",
+            ops(),
+            &key,
+        ));
+        let injected = parse_commit(&mock_relay::commit(
+            2,
+            REPO,
+            "3kaaaaaaaaa22",
+            vec![Op::Create {
+                path: "app.bsky.feed.post/3kaaaaaaaaa24".to_string(),
+                record: records::post("injected"),
+            }],
+        ));
+        commit.ops.extend(injected.ops);
+        assert!(verifier.verify(&commit).await.is_err());
+
+        // An existing path pointing to another record.
+        let mut commit = parse_commit(&mock_relay::signed_commit(
+            1,
+            REPO,
+            "3kaaaaaaaaa22",
+            ops(),
+            &key,
+        ));
+        commit.ops[0].cid = Some(mock_relay::cid_for(b"other"));
+        assert!(verifier.verify(&commit).await.is_err());
+
+        // A delete of a record which is still in the tree.
+        let mut commit = parse_commit(&mock_relay::signed_commit(
+            1,
+            REPO,
+            "3kaaaaaaaaa22",
+            ops(),
+            &key,
+        ));
+        commit.ops[0].action = RepoOpAction::Delete;
+        assert!(verifier.verify(&commit).await.is_err());
+
+        // A commit of another repository.
+        let mut commit = parse_commit(&mock_relay::signed_commit(
+            1,
+            REPO,
+            "3kaaaaaaaaa22",
+            ops(),
+            &key,
+        ));
+        commit.repo = "did:plc:other".to_string();
+        assert!(verifier.verify(&commit).await.is_err());
+    }
+}
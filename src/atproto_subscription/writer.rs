@@ -0,0 +1,205 @@
+//! Single writer of the subscriptions of many hosts.
+//!
+//! SQLite takes one writer at a time, so a transaction held by each host would keep the others waiting.
+//! Each host reads and decodes its frames on its own and hands them here, where frames of every host
+//! are applied in one batch which advances the cursor of each host together.
+
+use std::collections::HashMap;
+
+use anyhow::{anyhow, Context};
+use futures_util::{
+    stream::{self, SelectAll},
+    Stream, StreamExt,
+};
+use log::info;
+use tokio::sync::{mpsc, oneshot};
+
+use super::{
+    pipeline, Batch, Ended, FirehoseSubscription, FirehoseSubscriptionHandler, SubscriptionError,
+};
+
+type Ending = oneshot::Sender<Result<Ended, SubscriptionError>>;
+
+/// Frames received over a connection to a host, applied by the host's own subscription.
+pub(super) struct Session<H: Clone> {
+    pub subscription: FirehoseSubscription<H>,
+    pub decoded_rx: mpsc::Receiver<oneshot::Receiver<pipeline::Decoded>>,
+    /// Tells the host why applying its frames ended.
+    pub ended_tx: Ending,
+}
+
+enum Received<H: Clone> {
+    Session(Option<Box<Session<H>>>),
+    Frame(usize, Option<anyhow::Result<pipeline::Decoded>>),
+    FlushDue,
+    Stop,
+}
+
+/// Decoded frames of a session in the order they are read, ending with `None` when they run out.
+fn frames(
+    id: usize,
+    decoded_rx: mpsc::Receiver<oneshot::Receiver<pipeline::Decoded>>,
+) -> impl Stream<Item = (usize, Option<anyhow::Result<pipeline::Decoded>>)> {
+    stream::unfold(decoded_rx, |mut decoded_rx| async move {
+        let decoded = decoded_rx.recv().await?;
+        Some((decoded.await.context("Decode worker is gone"), decoded_rx))
+    })
+    .map(Some)
+    .chain(stream::once(async { None }))
+    .map(move |decoded| (id, decoded))
+}
+
+impl<H: FirehoseSubscriptionHandler + Sized + Send + Sync + Clone + 'static>
+    FirehoseSubscription<H>
+{
+    /// Apply frames of the sessions coming through `sessions_rx`
+    /// until every sender is gone or stop is signalled.
+    pub(super) async fn write(mut self, mut sessions_rx: mpsc::Receiver<Session<H>>) {
+        let mut sessions = HashMap::<usize, (FirehoseSubscription<H>, Ending)>::new();
+        let mut next_id = 0;
+        let mut frames = SelectAll::new();
+        let mut batch = None;
+        let mut open = true;
+        while open || !sessions.is_empty() {
+            let flush_at = batch.as_ref().map(|b: &Batch| {
+                tokio::time::Instant::from_std(b.started_at + self.flush_policy.max_interval)
+            });
+            let received = tokio::select! {
+                session = sessions_rx.recv(), if open => Received::Session(session.map(Box::new)),
+                Some((id, decoded)) = frames.next() => Received::Frame(id, decoded),
+                _ = async {
+                    match flush_at {
+                        Some(flush_at) => tokio::time::sleep_until(flush_at).await,
+                        None => std::future::pending().await,
+                    }
+                } => Received::FlushDue,
+                _ = self.stop_rx.wait_for(|v| *v) => Received::Stop,
+            };
+
+            let (id, decoded) = match received {
+                Received::Session(Some(session)) => {
+                    let Session {
+                        subscription,
+                        decoded_rx,
+                        ended_tx,
+                    } = *session;
+                    sessions.insert(next_id, (subscription, ended_tx));
+                    frames.push(Box::pin(self::frames(next_id, decoded_rx)));
+                    next_id += 1;
+                    continue;
+                }
+                Received::Session(None) => {
+                    open = false;
+                    continue;
+                }
+                Received::Frame(id, decoded) => (id, decoded),
+                Received::FlushDue => {
+                    if let Some(batch) = batch.take() {
+                        if let Err(e) = self.commit_batch(batch).await {
+                            end_all(&mut sessions, e);
+                        }
+                    }
+                    continue;
+                }
+                Received::Stop => {
+                    info!("Stop processing subscriptions by stop signal");
+                    break;
+                }
+            };
+            // Frames left after the session ended are dropped with it.
+            let Some((subscription, _)) = sessions.get_mut(&id) else {
+                continue;
+            };
+
+            let ret = match decoded {
+                None => Ok(Some(Ended::Closed)),
+                Some(Err(e)) => Err(SubscriptionError::fatal(e)),
+                Some(Ok(decoded)) => subscription
+                    .apply_frame(decoded, &mut batch)
+                    .await
+                    .map(|()| None),
+            };
+            let ended = match ret {
+                Ok(None) => {
+                    if batch
+                        .as_ref()
+                        .is_some_and(|batch| batch.events >= self.flush_policy.max_events)
+                    {
+                        if let Some(batch) = batch.take() {
+                            if let Err(e) = self.commit_batch(batch).await {
+                                end_all(&mut sessions, e);
+                            }
+                        }
+                    }
+                    continue;
+                }
+                Ok(Some(ended)) => Ok(ended),
+                Err(e) => Err(e),
+            };
+            // Events of the other hosts in the batch are rolled back with those of the failed one,
+            // so they all start over from their committed cursors.
+            if let Err(SubscriptionError::Fatal(_)) = &ended {
+                batch = None;
+                if let Some((_, ended_tx)) = sessions.remove(&id) {
+                    let _ = ended_tx.send(ended);
+                }
+                end_all(
+                    &mut sessions,
+                    anyhow!("Events are rolled back by a failure of another host"),
+                );
+                continue;
+            }
+            // The host reconnects from its cursor, so it has to be committed first.
+            if let Some(batch) = batch.take() {
+                if let Err(e) = self.commit_batch(batch).await {
+                    end_all(&mut sessions, e);
+                    continue;
+                }
+            }
+            if let Some((_, ended_tx)) = sessions.remove(&id) {
+                let _ = ended_tx.send(ended);
+            }
+        }
+
+        if let Some(batch) = batch.take() {
+            if let Err(e) = self.commit_batch(batch).await {
+                end_all(&mut sessions, e);
+            }
+        }
+        for (_, (_, ended_tx)) in sessions {
+            let _ = ended_tx.send(Ok(Ended::Stopped));
+        }
+    }
+
+    /// Hand frames of the current connection to the writer, and wait until it is done with them.
+    pub(super) async fn hand_off(
+        &self,
+        writer: &mpsc::Sender<Session<H>>,
+        decoded_rx: mpsc::Receiver<oneshot::Receiver<pipeline::Decoded>>,
+    ) -> Result<Ended, SubscriptionError> {
+        let (ended_tx, ended_rx) = oneshot::channel();
+        let session = Session {
+            subscription: self.clone(),
+            decoded_rx,
+            ended_tx,
+        };
+        // The writer is gone only once stop is signalled.
+        if writer.send(session).await.is_err() {
+            return Ok(Ended::Stopped);
+        }
+        ended_rx.await.unwrap_or(Ok(Ended::Stopped))
+    }
+}
+
+/// End every session with a recoverable error, to reconnect from the committed cursors.
+fn end_all<H: Clone>(
+    sessions: &mut HashMap<usize, (FirehoseSubscription<H>, Ending)>,
+    error: anyhow::Error,
+) {
+    let error = format!("{error:#}");
+    for (_, (_, ended_tx)) in sessions.drain() {
+        let _ = ended_tx.send(Err(SubscriptionError::recoverable(anyhow!(
+            "Failed to write events - {error}"
+        ))));
+    }
+}
@@ -1,12 +1,116 @@
+use std::collections::HashSet;
+
+use eueoeo_feed::{
+    atproto_subscription::{Endpoint, ErrorPolicy},
+    lexicon::identifier::{Did, RecordKey},
+};
+
+use crate::matcher::Matcher;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SubscriptionSource {
+    Firehose,
+    Jetstream,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FeedSort {
+    /// Posts indexed later first.
+    #[default]
+    Newest,
+    Oldest,
+}
+
+/// Shortname of the only feed served before feeds were declared, which posts indexed then are in.
+pub const LEGACY_FEED: &str = "eueoeo";
+
+/// Feed served under its shortname, the record key of its `app.bsky.feed.generator` record.
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(try_from = "FeedConfigRaw")]
+pub struct FeedConfig {
+    pub shortname: RecordKey,
+    /// Rules of posts to put in the feed, any of which should match.
+    pub matchers: Vec<Matcher>,
+    pub sort: FeedSort,
+    /// How long posts stay in the feed after they are indexed. Forever when not given.
+    pub retention: Option<chrono::Duration>,
+}
+
+impl TryFrom<FeedConfigRaw> for FeedConfig {
+    type Error = String;
+
+    fn try_from(raw: FeedConfigRaw) -> Result<Self, Self::Error> {
+        let retention = raw
+            .retention
+            .map(|v| {
+                chrono::Duration::from_std(std::time::Duration::from_millis(v))
+                    .map_err(|_| format!("retention out of range - {v}"))
+            })
+            .transpose()?;
+
+        Ok(Self {
+            shortname: raw.shortname,
+            matchers: raw.matchers,
+            sort: raw.sort.unwrap_or_default(),
+            retention,
+        })
+    }
+}
+
+#[derive(serde::Deserialize)]
+struct FeedConfigRaw {
+    shortname: RecordKey,
+    matchers: Vec<Matcher>,
+    sort: Option<FeedSort>,
+    retention: Option<u64>,
+}
+
 pub struct Config {
     pub port: u16,
     pub listen_host: String,
     pub host_name: String,
     pub sqlite_db: String,
+    pub subscription_source: SubscriptionSource,
     pub subscription_endpoint: String,
+    /// Endpoints to fail over between. Replaces `subscription_endpoint` when given.
+    pub subscription_endpoints: Vec<Endpoint>,
+    pub subscription_failover_after: u32,
+    pub subscription_return_interval: chrono::Duration,
+    /// PDS hosts to subscribe directly instead of the endpoints.
+    /// Events of a repository are taken only from the host its DID document names as its PDS.
+    pub subscription_hosts: Vec<String>,
+    /// Host to discover PDS hosts from, in addition to `subscription_hosts`.
+    pub subscription_host_seed: Option<String>,
     pub service_did: String,
-    pub publisher_did: String,
+    pub publisher_did: Did,
     pub subscription_reconnect_delay: chrono::Duration,
+    pub subscription_reconnect_max_delay: chrono::Duration,
+    pub subscription_reconnect_reset_after: chrono::Duration,
+    pub subscription_flush_events: usize,
+    pub subscription_flush_interval: chrono::Duration,
+    pub subscription_ping_interval: chrono::Duration,
+    /// Time without any frame after which the connection is taken as dead.
+    pub subscription_idle_timeout: chrono::Duration,
+    pub subscription_decode_workers: usize,
+    pub subscription_queue_depth: usize,
+    pub subscription_verify: bool,
+    pub subscription_error_policy: ErrorPolicy,
+    /// Fetch records of commits too big to carry them from the PDS of the repository.
+    /// Off by default, as it sends requests to hosts named by whoever owns the repository.
+    pub subscription_fetch_too_big: bool,
+    pub too_big_fetch_interval: chrono::Duration,
+    pub too_big_fetch_max_retries: u32,
+    pub too_big_fetch_retry_delay: chrono::Duration,
+    pub plc_directory: String,
+    pub did_cache_ttl: chrono::Duration,
+    /// DID documents kept at most.
+    pub did_cache_capacity: usize,
+    pub jetstream_wanted_collections: Vec<String>,
+    pub jetstream_zstd_dictionary: Option<String>,
+    /// Feeds to serve. Without `feeds`, the only feed is [`LEGACY_FEED`] of `matchers`.
+    pub feeds: Vec<FeedConfig>,
 }
 
 impl<'de> serde::Deserialize<'de> for Config {
@@ -20,27 +124,129 @@ impl<'de> serde::Deserialize<'de> for Config {
         let listen_host = raw.listen_host.unwrap_or_else(|| "localhost".to_string());
         let host_name = raw.host_name.unwrap_or_else(|| "localhost".to_string());
         let sqlite_db = raw.sqlite_db.unwrap_or_else(|| ":memory:".to_string());
-        let subscription_endpoint = raw
-            .subscription_endpoint
-            .unwrap_or_else(|| "wss://bsky.social".to_string());
+        let subscription_source = raw
+            .subscription_source
+            .unwrap_or(SubscriptionSource::Firehose);
+        let subscription_endpoint = raw.subscription_endpoint.unwrap_or_else(|| {
+            match subscription_source {
+                SubscriptionSource::Firehose => "wss://bsky.social",
+                SubscriptionSource::Jetstream => "wss://jetstream2.us-east.bsky.network",
+            }
+            .to_string()
+        });
+        let subscription_endpoints = raw.subscription_endpoints.unwrap_or_default();
+        let subscription_failover_after = raw.subscription_failover_after.unwrap_or(3);
+        let subscription_return_interval = chrono::Duration::milliseconds(
+            raw.subscription_return_interval.unwrap_or(600_000) as _,
+        );
+        let subscription_hosts = raw.subscription_hosts.unwrap_or_default();
         let service_did = raw
             .service_did
             .unwrap_or_else(|| format!("did:web:{host_name}"));
-        let publisher_did = raw
-            .publisher_did
-            .unwrap_or_else(|| "did:exapmle:alice".to_string());
+        let publisher_did = raw.publisher_did.unwrap_or_else(|| {
+            "did:exapmle:alice"
+                .parse()
+                .expect("Default publisher DID should be valid")
+        });
         let subscription_reconnect_delay =
             chrono::Duration::milliseconds(raw.subscription_reconnect_delay.unwrap_or(3000) as _);
+        let subscription_reconnect_max_delay = chrono::Duration::milliseconds(
+            raw.subscription_reconnect_max_delay.unwrap_or(60000) as _,
+        );
+        let subscription_reconnect_reset_after = chrono::Duration::milliseconds(
+            raw.subscription_reconnect_reset_after.unwrap_or(30000) as _,
+        );
+        let subscription_flush_events = raw.subscription_flush_events.unwrap_or(100);
+        let subscription_flush_interval =
+            chrono::Duration::milliseconds(raw.subscription_flush_interval.unwrap_or(1000) as _);
+        let subscription_ping_interval =
+            chrono::Duration::milliseconds(raw.subscription_ping_interval.unwrap_or(30_000) as _);
+        let subscription_idle_timeout =
+            chrono::Duration::milliseconds(raw.subscription_idle_timeout.unwrap_or(90_000) as _);
+        let default_pipeline =
+            eueoeo_feed::atproto_subscription::pipeline::PipelinePolicy::default();
+        let subscription_decode_workers = raw
+            .subscription_decode_workers
+            .unwrap_or(default_pipeline.decode_workers);
+        let subscription_queue_depth = raw
+            .subscription_queue_depth
+            .unwrap_or(default_pipeline.queue_depth);
+        let subscription_verify = raw.subscription_verify.unwrap_or(false);
+        let subscription_error_policy = raw.subscription_error_policy.unwrap_or_default();
+        let subscription_fetch_too_big = raw.subscription_fetch_too_big.unwrap_or(false);
+        let too_big_fetch_interval =
+            chrono::Duration::milliseconds(raw.too_big_fetch_interval.unwrap_or(100) as _);
+        let too_big_fetch_max_retries = raw.too_big_fetch_max_retries.unwrap_or(3);
+        let too_big_fetch_retry_delay =
+            chrono::Duration::milliseconds(raw.too_big_fetch_retry_delay.unwrap_or(1000) as _);
+        let plc_directory = raw
+            .plc_directory
+            .unwrap_or_else(|| "https://plc.directory".to_string());
+        let did_cache_ttl =
+            chrono::Duration::milliseconds(raw.did_cache_ttl.unwrap_or(3_600_000) as _);
+        let did_cache_capacity = raw.did_cache_capacity.unwrap_or(100_000);
+        let jetstream_wanted_collections = raw
+            .jetstream_wanted_collections
+            .unwrap_or_else(|| vec!["app.bsky.feed.post".to_string()]);
+        let feeds = match (raw.feeds, raw.matchers) {
+            (Some(_), Some(_)) => {
+                return Err(serde::de::Error::custom(
+                    "matchers are of the default feed, give them to each of feeds instead",
+                ))
+            }
+            (Some(feeds), None) => feeds,
+            (None, matchers) => vec![FeedConfig {
+                shortname: LEGACY_FEED
+                    .parse()
+                    .expect("Default feed shortname should be valid"),
+                matchers: matchers.unwrap_or_else(Matcher::defaults),
+                sort: FeedSort::default(),
+                retention: None,
+            }],
+        };
+        let mut shortnames = HashSet::new();
+        if let Some(feed) = feeds.iter().find(|f| !shortnames.insert(&f.shortname)) {
+            return Err(serde::de::Error::custom(format!(
+                "Duplicated feed shortname - {}",
+                feed.shortname
+            )));
+        }
 
         Ok(Self {
             port,
             listen_host,
             host_name,
             sqlite_db,
+            subscription_source,
             subscription_endpoint,
+            subscription_endpoints,
+            subscription_failover_after,
+            subscription_return_interval,
+            subscription_hosts,
+            subscription_host_seed: raw.subscription_host_seed,
             service_did,
             publisher_did,
             subscription_reconnect_delay,
+            subscription_reconnect_max_delay,
+            subscription_reconnect_reset_after,
+            subscription_flush_events,
+            subscription_flush_interval,
+            subscription_ping_interval,
+            subscription_idle_timeout,
+            subscription_decode_workers,
+            subscription_queue_depth,
+            subscription_verify,
+            subscription_error_policy,
+            subscription_fetch_too_big,
+            too_big_fetch_interval,
+            too_big_fetch_max_retries,
+            too_big_fetch_retry_delay,
+            plc_directory,
+            did_cache_ttl,
+            did_cache_capacity,
+            jetstream_wanted_collections,
+            jetstream_zstd_dictionary: raw.jetstream_zstd_dictionary,
+            feeds,
         })
     }
 }
@@ -51,8 +257,92 @@ struct ConfigRaw {
     listen_host: Option<String>,
     host_name: Option<String>,
     sqlite_db: Option<String>,
+    subscription_source: Option<SubscriptionSource>,
     subscription_endpoint: Option<String>,
+    subscription_endpoints: Option<Vec<Endpoint>>,
+    subscription_failover_after: Option<u32>,
+    subscription_return_interval: Option<u32>,
+    subscription_hosts: Option<Vec<String>>,
+    subscription_host_seed: Option<String>,
     service_did: Option<String>,
-    publisher_did: Option<String>,
+    publisher_did: Option<Did>,
     subscription_reconnect_delay: Option<u32>,
+    subscription_reconnect_max_delay: Option<u32>,
+    subscription_reconnect_reset_after: Option<u32>,
+    subscription_flush_events: Option<usize>,
+    subscription_flush_interval: Option<u32>,
+    subscription_ping_interval: Option<u32>,
+    subscription_idle_timeout: Option<u32>,
+    subscription_decode_workers: Option<usize>,
+    subscription_queue_depth: Option<usize>,
+    subscription_verify: Option<bool>,
+    subscription_error_policy: Option<ErrorPolicy>,
+    subscription_fetch_too_big: Option<bool>,
+    too_big_fetch_interval: Option<u32>,
+    too_big_fetch_max_retries: Option<u32>,
+    too_big_fetch_retry_delay: Option<u32>,
+    plc_directory: Option<String>,
+    did_cache_ttl: Option<u32>,
+    did_cache_capacity: Option<usize>,
+    jetstream_wanted_collections: Option<Vec<String>>,
+    jetstream_zstd_dictionary: Option<String>,
+    matchers: Option<Vec<Matcher>>,
+    feeds: Option<Vec<FeedConfig>>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(config: serde_json::Value) -> Result<Config, serde_json::Error> {
+        serde_json::from_value(config)
+    }
+
+    #[test]
+    fn test_feeds() {
+        let config = parse(serde_json::json!({})).unwrap();
+        let [feed] = &config.feeds[..] else {
+            panic!("expected the default feed");
+        };
+        assert_eq!(feed.shortname, "eueoeo");
+        assert!(feed.matchers[0].is_match("으어어"));
+
+        let config = parse(serde_json::json!({
+            "feeds": [
+                { "shortname": "eueoeo", "matchers": [{ "type": "exact", "text": "으어어" }] },
+                {
+                    "shortname": "tt",
+                    "matchers": [{ "type": "hangul", "pattern": "ㅠ{2,}" }],
+                    "sort": "oldest",
+                    "retention": 86_400_000,
+                },
+            ],
+        }))
+        .unwrap();
+        assert_eq!(config.feeds[1].sort, FeedSort::Oldest);
+        assert_eq!(config.feeds[1].retention, Some(chrono::Duration::days(1)));
+
+        for invalid in [
+            serde_json::json!({ "feeds": [{ "shortname": "으어어", "matchers": [] }] }),
+            serde_json::json!({ "feeds": [{ "shortname": "a/b", "matchers": [] }] }),
+            serde_json::json!({
+                "feeds": [
+                    { "shortname": "eueoeo", "matchers": [] },
+                    { "shortname": "eueoeo", "matchers": [] },
+                ],
+            }),
+            serde_json::json!({
+                "matchers": [],
+                "feeds": [{ "shortname": "eueoeo", "matchers": [] }],
+            }),
+            serde_json::json!({
+                "feeds": [{ "shortname": "eueoeo", "matchers": [], "retention": u64::MAX }],
+            }),
+        ] {
+            assert!(
+                parse(invalid.clone()).is_err(),
+                "{invalid} should be invalid"
+            );
+        }
+    }
 }
@@ -1,8 +1,7 @@
-#![allow(non_snake_case)]
-
+/// Row of `post`, named after its columns.
+#[allow(non_snake_case)]
 pub struct Post {
     pub uri: Option<String>,
     pub cid: Option<String>,
-    pub author: Option<String>,
     pub indexedAt: Option<String>,
 }
@@ -0,0 +1,529 @@
+//! DID resolution, for signing keys and service endpoints of repositories.
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Context};
+use async_trait::async_trait;
+use log::debug;
+
+const SECP256K1_PUB: [u8; 2] = [0xe7, 0x01];
+const P256_PUB: [u8; 2] = [0x80, 0x24];
+
+const DEFAULT_CACHE_CAPACITY: usize = 100_000;
+
+#[derive(Debug, Clone, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DidDocument {
+    pub id: String,
+    #[serde(default)]
+    pub also_known_as: Vec<String>,
+    #[serde(default)]
+    pub verification_method: Vec<VerificationMethod>,
+    #[serde(default)]
+    pub service: Vec<Service>,
+}
+
+#[derive(Debug, Clone, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct VerificationMethod {
+    pub id: String,
+    pub r#type: String,
+    pub controller: String,
+    pub public_key_multibase: Option<String>,
+}
+
+#[serde_with::serde_as]
+#[derive(Debug, Clone, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Service {
+    pub id: String,
+    pub r#type: String,
+    /// Only plain url endpoints are used.
+    #[serde_as(as = "serde_with::DefaultOnError")]
+    #[serde(default)]
+    pub service_endpoint: Option<String>,
+}
+
+impl DidDocument {
+    /// Document with an `#atproto` signing key and an optional `#atproto_pds` service.
+    pub fn new(did: &str, key: &PublicKey, pds: Option<&str>) -> Self {
+        Self {
+            id: did.to_string(),
+            also_known_as: vec![],
+            verification_method: vec![VerificationMethod {
+                id: format!("{did}#atproto"),
+                r#type: "Multikey".to_string(),
+                controller: did.to_string(),
+                public_key_multibase: Some(key.to_multikey()),
+            }],
+            service: pds
+                .map(|pds| Service {
+                    id: "#atproto_pds".to_string(),
+                    r#type: "AtprotoPersonalDataServer".to_string(),
+                    service_endpoint: Some(pds.to_string()),
+                })
+                .into_iter()
+                .collect(),
+        }
+    }
+
+    /// The `#atproto` verification method, which signs repository commits.
+    pub fn signing_key(&self) -> anyhow::Result<PublicKey> {
+        let method = self
+            .verification_method
+            .iter()
+            .find(|m| m.id == "#atproto" || m.id.strip_prefix(&self.id) == Some("#atproto"))
+            .ok_or_else(|| anyhow!("No atproto signing key in DID document of {}", self.id))?;
+        let multibase = method
+            .public_key_multibase
+            .as_deref()
+            .ok_or_else(|| anyhow!("Signing key of {} has no publicKeyMultibase", self.id))?;
+
+        PublicKey::from_multikey(multibase)
+    }
+
+    /// Endpoint of the `#atproto_pds` service, which hosts the repository.
+    pub fn pds_endpoint(&self) -> Option<&str> {
+        self.service
+            .iter()
+            .find(|s| s.id == "#atproto_pds" || s.id.strip_prefix(&self.id) == Some("#atproto_pds"))
+            .and_then(|s| s.service_endpoint.as_deref())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PublicKey {
+    K256(k256::ecdsa::VerifyingKey),
+    P256(p256::ecdsa::VerifyingKey),
+}
+
+impl PublicKey {
+    /// Decode a multibase encoded, multicodec prefixed key as used by `publicKeyMultibase`.
+    pub fn from_multikey(multikey: &str) -> anyhow::Result<Self> {
+        let (_, bytes) = multibase::decode(multikey).context("Invalid multibase key")?;
+        if let Some(key) = bytes.strip_prefix(&SECP256K1_PUB) {
+            Ok(Self::K256(
+                k256::ecdsa::VerifyingKey::from_sec1_bytes(key).context("Invalid secp256k1 key")?,
+            ))
+        } else if let Some(key) = bytes.strip_prefix(&P256_PUB) {
+            Ok(Self::P256(
+                p256::ecdsa::VerifyingKey::from_sec1_bytes(key).context("Invalid P-256 key")?,
+            ))
+        } else {
+            Err(anyhow!("Unsupported key type - {multikey}"))
+        }
+    }
+
+    pub fn from_did_key(did_key: &str) -> anyhow::Result<Self> {
+        Self::from_multikey(
+            did_key
+                .strip_prefix("did:key:")
+                .ok_or_else(|| anyhow!("Not a did:key - {did_key}"))?,
+        )
+    }
+
+    pub fn to_multikey(&self) -> String {
+        let mut bytes = vec![];
+        match self {
+            Self::K256(key) => {
+                bytes.extend(SECP256K1_PUB);
+                bytes.extend(key.to_encoded_point(true).as_bytes());
+            }
+            Self::P256(key) => {
+                bytes.extend(P256_PUB);
+                bytes.extend(key.to_encoded_point(true).as_bytes());
+            }
+        }
+
+        multibase::encode(multibase::Base::Base58Btc, bytes)
+    }
+
+    /// Verify a 64 bytes compact ECDSA signature over SHA-256 of `message`.
+    /// Signatures must be in the low-S form.
+    pub fn verify(&self, message: &[u8], signature: &[u8]) -> anyhow::Result<()> {
+        use k256::ecdsa::signature::Verifier;
+
+        match self {
+            Self::K256(key) => {
+                let signature =
+                    k256::ecdsa::Signature::from_slice(signature).context("Invalid signature")?;
+                if signature.normalize_s().is_some() {
+                    return Err(anyhow!("Signature is not in the low-S form"));
+                }
+                key.verify(message, &signature)
+                    .context("Signature mismatch")
+            }
+            Self::P256(key) => {
+                let signature =
+                    p256::ecdsa::Signature::from_slice(signature).context("Invalid signature")?;
+                if signature.normalize_s().is_some() {
+                    return Err(anyhow!("Signature is not in the low-S form"));
+                }
+                key.verify(message, &signature)
+                    .context("Signature mismatch")
+            }
+        }
+    }
+}
+
+#[async_trait]
+pub trait DidResolver: Send + Sync {
+    async fn resolve(&self, did: &str) -> anyhow::Result<Arc<DidDocument>>;
+
+    /// Forget what is kept for `did`, e.g. after its key turned out to be stale.
+    fn invalidate(&self, _did: &str) {}
+}
+
+/// Resolves `did:plc` through a PLC directory and `did:web` through `/.well-known/did.json`.
+pub struct HttpDidResolver {
+    client: reqwest::Client,
+    plc_directory: url::Url,
+}
+
+impl HttpDidResolver {
+    pub fn new(plc_directory: &str) -> anyhow::Result<Self> {
+        Ok(Self {
+            client: reqwest::Client::builder()
+                .timeout(Duration::from_secs(10))
+                .build()?,
+            plc_directory: url::Url::parse(plc_directory).context("Invalid PLC directory url")?,
+        })
+    }
+
+    fn document_url(&self, did: &str) -> anyhow::Result<url::Url> {
+        if did.starts_with("did:plc:") {
+            let mut url = self.plc_directory.clone();
+            url.path_segments_mut()
+                .map_err(|_| anyhow!("Invalid PLC directory url"))?
+                .pop_if_empty()
+                .push(did);
+            Ok(url)
+        } else if let Some(host) = did.strip_prefix("did:web:") {
+            if host.contains(':') {
+                return Err(anyhow!("did:web with path is not supported - {did}"));
+            }
+            let host = host.replace("%3A", ":");
+            url::Url::parse(&format!("https://{host}/.well-known/did.json"))
+                .with_context(|| format!("Invalid did:web - {did}"))
+        } else {
+            Err(anyhow!("Unsupported DID method - {did}"))
+        }
+    }
+}
+
+#[async_trait]
+impl DidResolver for HttpDidResolver {
+    async fn resolve(&self, did: &str) -> anyhow::Result<Arc<DidDocument>> {
+        let url = self.document_url(did)?;
+        debug!("Resolve {did} from {url}");
+        let document: DidDocument = self
+            .client
+            .get(url)
+            .send()
+            .await
+            .and_then(|r| r.error_for_status())
+            .with_context(|| format!("Failed to fetch DID document of {did}"))?
+            .json()
+            .await
+            .with_context(|| format!("Invalid DID document of {did}"))?;
+        if document.id != did {
+            return Err(anyhow!(
+                "DID document of {did} is for another DID - {}",
+                document.id
+            ));
+        }
+
+        Ok(Arc::new(document))
+    }
+}
+
+/// Keeps resolved documents for `ttl`, up to `capacity` of them.
+/// Once it is full, expired documents are swept out, then the ones resolved earliest.
+pub struct CachedDidResolver<R> {
+    inner: R,
+    ttl: Duration,
+    capacity: usize,
+    cache: Mutex<HashMap<String, (Instant, Arc<DidDocument>)>>,
+}
+
+impl<R: DidResolver> CachedDidResolver<R> {
+    pub fn new(inner: R, ttl: Duration) -> Self {
+        Self {
+            inner,
+            ttl,
+            capacity: DEFAULT_CACHE_CAPACITY,
+            cache: Default::default(),
+        }
+    }
+
+    pub fn with_capacity(mut self, capacity: usize) -> Self {
+        self.capacity = capacity.max(1);
+        self
+    }
+
+    fn insert(&self, did: &str, document: Arc<DidDocument>) {
+        let mut cache = self.cache.lock().unwrap();
+        if cache.len() >= self.capacity && !cache.contains_key(did) {
+            cache.retain(|_, (resolved_at, _)| resolved_at.elapsed() < self.ttl);
+            // Sweeping frees a tenth of the room at once, so it is not done on every insert.
+            let excess = (cache.len() + 1).saturating_sub(self.capacity - self.capacity / 10);
+            if excess > 0 {
+                let mut oldest = cache
+                    .iter()
+                    .map(|(did, (resolved_at, _))| (*resolved_at, did.clone()))
+                    .collect::<Vec<_>>();
+                oldest.sort_unstable();
+                for (_, did) in oldest.into_iter().take(excess) {
+                    cache.remove(&did);
+                }
+            }
+        }
+        cache.insert(did.to_string(), (Instant::now(), document));
+    }
+}
+
+#[async_trait]
+impl<R: DidResolver> DidResolver for CachedDidResolver<R> {
+    async fn resolve(&self, did: &str) -> anyhow::Result<Arc<DidDocument>> {
+        if let Some((resolved_at, document)) = self.cache.lock().unwrap().get(did) {
+            if resolved_at.elapsed() < self.ttl {
+                return Ok(document.clone());
+            }
+        }
+
+        let document = self.inner.resolve(did).await?;
+        self.insert(did, document.clone());
+
+        Ok(document)
+    }
+
+    fn invalidate(&self, did: &str) {
+        self.cache.lock().unwrap().remove(did);
+        self.inner.invalidate(did);
+    }
+}
+
+/// Resolves only the documents put in it.
+#[cfg(any(test, feature = "test-support"))]
+#[derive(Default)]
+pub struct StubDidResolver {
+    documents: Mutex<HashMap<String, Arc<DidDocument>>>,
+}
+
+#[cfg(any(test, feature = "test-support"))]
+impl StubDidResolver {
+    pub fn insert(&self, document: DidDocument) {
+        self.documents
+            .lock()
+            .unwrap()
+            .insert(document.id.clone(), Arc::new(document));
+    }
+}
+
+#[cfg(any(test, feature = "test-support"))]
+#[async_trait]
+impl DidResolver for StubDidResolver {
+    async fn resolve(&self, did: &str) -> anyhow::Result<Arc<DidDocument>> {
+        self.documents
+            .lock()
+            .unwrap()
+            .get(did)
+            .cloned()
+            .ok_or_else(|| anyhow!("Unknown DID - {did}"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+
+    #[test]
+    fn test_multikey() {
+        for did_key in [
+            "did:key:zQ3shXjHeiBuRCKmM36cuYnm7YEMzhGnCmCyW92sRJ9pribSF",
+            "did:key:zDnaembgSGUhZULN2Caob4HLJPaxBh92N7rtH21TErzqf8HQo",
+        ] {
+            let key = PublicKey::from_did_key(did_key).unwrap();
+            assert_eq!(format!("did:key:{}", key.to_multikey()), did_key);
+        }
+        assert!(matches!(
+            PublicKey::from_did_key("did:key:zQ3shXjHeiBuRCKmM36cuYnm7YEMzhGnCmCyW92sRJ9pribSF")
+                .unwrap(),
+            PublicKey::K256(_)
+        ));
+        assert!(
+            PublicKey::from_multikey("z6MkhaXgBZDvotDkL5257faiztiGiC2QtKLGpbnnEGta2doK").is_err()
+        );
+    }
+
+    #[test]
+    fn test_verify() {
+        use k256::ecdsa::signature::Signer;
+
+        let message = b"commit";
+        let k256_key = k256::ecdsa::SigningKey::random(&mut rand::thread_rng());
+        let signature: k256::ecdsa::Signature = k256_key.sign(message);
+        let public = PublicKey::K256(*k256_key.verifying_key());
+        public.verify(message, &signature.to_bytes()).unwrap();
+        assert!(public.verify(b"other", &signature.to_bytes()).is_err());
+
+        let p256_key = p256::ecdsa::SigningKey::random(&mut rand::thread_rng());
+        let signature: p256::ecdsa::Signature = p256_key.sign(message);
+        let signature = signature.normalize_s().unwrap_or(signature);
+        let public = PublicKey::P256(*p256_key.verifying_key());
+        public.verify(message, &signature.to_bytes()).unwrap();
+        assert!(public.verify(message, &[0; 64]).is_err());
+
+        // The high-S twin of a valid signature is rejected.
+        let (r, s) = signature.split_scalars();
+        let high_s = p256::ecdsa::Signature::from_scalars(r, -*s).unwrap();
+        assert!(public.verify(message, &high_s.to_bytes()).is_err());
+    }
+
+    #[test]
+    fn test_document() {
+        let document: DidDocument = serde_json::from_str(
+            r##"{
+                "@context": ["https://www.w3.org/ns/did/v1"],
+                "id": "did:plc:ewvi7nxzyoun6zhxrhs64oiz",
+                "alsoKnownAs": ["at://atproto.com"],
+                "verificationMethod": [{
+                    "id": "did:plc:ewvi7nxzyoun6zhxrhs64oiz#atproto",
+                    "type": "Multikey",
+                    "controller": "did:plc:ewvi7nxzyoun6zhxrhs64oiz",
+                    "publicKeyMultibase": "zQ3shXjHeiBuRCKmM36cuYnm7YEMzhGnCmCyW92sRJ9pribSF"
+                }],
+                "service": [{
+                    "id": "#atproto_pds",
+                    "type": "AtprotoPersonalDataServer",
+                    "serviceEndpoint": "https://enoki.us-east.host.bsky.network"
+                }, {
+                    "id": "#other",
+                    "type": "Other",
+                    "serviceEndpoint": { "uri": "https://example.com" }
+                }]
+            }"##,
+        )
+        .unwrap();
+        assert!(matches!(
+            document.signing_key().unwrap(),
+            PublicKey::K256(_)
+        ));
+        assert_eq!(
+            document.pds_endpoint(),
+            Some("https://enoki.us-east.host.bsky.network")
+        );
+    }
+
+    struct CountingResolver(StubDidResolver, AtomicUsize);
+
+    #[async_trait]
+    impl DidResolver for CountingResolver {
+        async fn resolve(&self, did: &str) -> anyhow::Result<Arc<DidDocument>> {
+            self.1.fetch_add(1, Ordering::Relaxed);
+            self.0.resolve(did).await
+        }
+    }
+
+    #[tokio::test]
+    async fn test_cache() {
+        let key = PublicKey::K256(
+            *k256::ecdsa::SigningKey::random(&mut rand::thread_rng()).verifying_key(),
+        );
+        let stub = StubDidResolver::default();
+        stub.insert(DidDocument::new("did:plc:test", &key, None));
+        let resolver = CachedDidResolver::new(
+            CountingResolver(stub, AtomicUsize::new(0)),
+            Duration::from_secs(60),
+        );
+
+        for _ in 0..3 {
+            let document = resolver.resolve("did:plc:test").await.unwrap();
+            assert_eq!(document.signing_key().unwrap(), key);
+        }
+        assert_eq!(resolver.inner.1.load(Ordering::Relaxed), 1);
+        resolver.invalidate("did:plc:test");
+        resolver.resolve("did:plc:test").await.unwrap();
+        assert_eq!(resolver.inner.1.load(Ordering::Relaxed), 2);
+        assert!(resolver.resolve("did:plc:unknown").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_cache_capacity() {
+        let key = PublicKey::K256(
+            *k256::ecdsa::SigningKey::random(&mut rand::thread_rng()).verifying_key(),
+        );
+        let stub = StubDidResolver::default();
+        let dids = (0..25).map(|i| format!("did:plc:{i}")).collect::<Vec<_>>();
+        for did in &dids {
+            stub.insert(DidDocument::new(did, &key, None));
+        }
+        let resolver = CachedDidResolver::new(
+            CountingResolver(stub, AtomicUsize::new(0)),
+            Duration::from_secs(60),
+        )
+        .with_capacity(10);
+
+        for did in &dids {
+            resolver.resolve(did).await.unwrap();
+            assert!(resolver.cache.lock().unwrap().len() <= 10);
+        }
+        // The latest one is kept, the earliest one is not.
+        resolver.resolve(&dids[24]).await.unwrap();
+        assert_eq!(resolver.inner.1.load(Ordering::Relaxed), 25);
+        resolver.resolve(&dids[0]).await.unwrap();
+        assert_eq!(resolver.inner.1.load(Ordering::Relaxed), 26);
+
+        // Expired ones go first.
+        let resolver = CachedDidResolver::new(
+            CountingResolver(StubDidResolver::default(), AtomicUsize::new(0)),
+            Duration::ZERO,
+        )
+        .with_capacity(2);
+        for did in &dids[..2] {
+            resolver.insert(did, Arc::new(DidDocument::new(did, &key, None)));
+        }
+        resolver.insert(&dids[2], Arc::new(DidDocument::new(&dids[2], &key, None)));
+        let cache = resolver.cache.lock().unwrap();
+        assert_eq!(cache.keys().collect::<Vec<_>>(), [&dids[2]]);
+    }
+
+    #[tokio::test]
+    async fn test_resolve_plc() {
+        let key = PublicKey::K256(
+            *k256::ecdsa::SigningKey::random(&mut rand::thread_rng()).verifying_key(),
+        );
+        let document = DidDocument::new("did:plc:test", &key, Some("https://pds.example.com"));
+        let app = axum::Router::new().route(
+            "/:did",
+            axum::routing::get(
+                move |axum::extract::Path(did): axum::extract::Path<String>| {
+                    let document = document.clone();
+                    async move {
+                        if did == document.id {
+                            Ok(axum::Json(document))
+                        } else {
+                            Err(axum::http::StatusCode::NOT_FOUND)
+                        }
+                    }
+                },
+            ),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let resolver = HttpDidResolver::new(&format!("http://{addr}")).unwrap();
+        let resolved = resolver.resolve("did:plc:test").await.unwrap();
+        assert_eq!(resolved.signing_key().unwrap(), key);
+        assert_eq!(resolved.pds_endpoint(), Some("https://pds.example.com"));
+        assert!(resolver.resolve("did:plc:unknown").await.is_err());
+        assert!(resolver.resolve("did:example:test").await.is_err());
+    }
+}
@@ -1,39 +1,71 @@
+//! Types of the lexicons under `lexicons/`, generated by `build.rs`, and what they are used with.
+
 use std::{fmt::Display, str::FromStr};
 
-#[derive(Debug, serde_with::DeserializeFromStr, PartialEq, Eq)]
+use rs_car::Cid;
+
+use identifier::{AtIdentifier, IdentifierError, Nsid, RecordKey};
+
+pub mod car;
+pub mod data;
+pub mod identifier;
+
+include!(concat!(env!("OUT_DIR"), "/lexicon.rs"));
+
+/// `at://{authority}[/{collection}[/{rkey}]][?{query}][#{fragment}]`
+///
+/// `rkey` is taken only along with `collection`.
+#[derive(
+    Debug, Clone, PartialEq, Eq, Hash, serde_with::DeserializeFromStr, serde_with::SerializeDisplay,
+)]
 pub struct AtUri {
-    pub authority: String,
-    pub collection: Option<String>,
-    pub rkey: Option<String>,
+    pub authority: AtIdentifier,
+    pub collection: Option<Nsid>,
+    pub rkey: Option<RecordKey>,
+    pub query: Option<String>,
+    pub fragment: Option<String>,
 }
 
 impl AtUri {
-    pub fn new(authority: String, collection: Option<String>, rkey: Option<String>) -> Self {
+    const MAX_LEN: usize = 8 * 1024;
+
+    pub fn new(
+        authority: impl Into<AtIdentifier>,
+        collection: Option<Nsid>,
+        rkey: Option<RecordKey>,
+    ) -> Self {
         Self {
-            authority,
+            authority: authority.into(),
             collection,
             rkey,
+            query: None,
+            fragment: None,
         }
     }
 
-    pub fn with_auth(authority: String) -> Self {
-        Self {
-            authority,
-            collection: None,
-            rkey: None,
-        }
+    pub fn with_auth(authority: impl Into<AtIdentifier>) -> Self {
+        Self::new(authority, None, None)
     }
 
-    pub fn with_auth_path(authority: String, path: String) -> Self {
-        let mut splitted = path.split('/');
-        let collection = splitted.next().map(ToString::to_string);
-        let rkey = splitted.next().map(ToString::to_string);
+    /// Build from `{collection}/{rkey}`, the path of an op in a commit.
+    pub fn with_auth_path(
+        authority: impl Into<AtIdentifier>,
+        path: &str,
+    ) -> Result<Self, AtUriParseError> {
+        let (collection, rkey) = Self::parse_path(path)?;
 
-        Self {
-            authority,
-            collection,
-            rkey,
+        Ok(Self::new(authority, Some(collection), rkey))
+    }
+
+    fn parse_path(path: &str) -> Result<(Nsid, Option<RecordKey>), AtUriParseError> {
+        let mut segments = path.split('/');
+        let collection = segments.next().unwrap_or_default().parse()?;
+        let rkey = segments.next().map(str::parse).transpose()?;
+        if segments.next().is_some() {
+            return Err(AtUriParseError::InvalidPath(path.to_string()));
         }
+
+        Ok((collection, rkey))
     }
 }
 
@@ -46,6 +78,12 @@ impl Display for AtUri {
                 write!(f, "/{rkey}")?;
             }
         }
+        if let Some(query) = &self.query {
+            write!(f, "?{query}")?;
+        }
+        if let Some(fragment) = &self.fragment {
+            write!(f, "#{fragment}")?;
+        }
 
         Ok(())
     }
@@ -55,454 +93,803 @@ impl Display for AtUri {
 pub enum AtUriParseError {
     #[error("AtUri should start with at://")]
     InvalidProtocolPrefix,
+    #[error("AtUri is longer than 8KiB")]
+    TooLong,
+    #[error("Invalid path of AtUri - {0}")]
+    InvalidPath(String),
+    #[error("Invalid character in query or fragment of AtUri - {0:?}")]
+    InvalidCharacter(char),
+    #[error(transparent)]
+    InvalidIdentifier(#[from] IdentifierError),
 }
 
 impl FromStr for AtUri {
     type Err = AtUriParseError;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        if !s.starts_with("at://") {
-            return Err(AtUriParseError::InvalidProtocolPrefix);
+        let s = s
+            .strip_prefix("at://")
+            .ok_or(AtUriParseError::InvalidProtocolPrefix)?;
+        if s.len() > Self::MAX_LEN {
+            return Err(AtUriParseError::TooLong);
+        }
+        if let Some(c) = s.chars().find(|c| c.is_whitespace() || c.is_control()) {
+            return Err(AtUriParseError::InvalidCharacter(c));
         }
 
-        let s = &s[5..];
-        let (authority, collection, rkey) = s
-            .split_once('/')
-            .map(|(d, r)| {
-                let (c, r) = r
-                    .split_once('/')
-                    .map(|(c, r)| (Some(c), Some(r)))
-                    .unwrap_or_else(|| (Some(s), None));
-                (d, c, r)
-            })
-            .unwrap_or_else(|| (s, None, None));
+        let (s, fragment) = s
+            .split_once('#')
+            .map_or((s, None), |(s, fragment)| (s, Some(fragment.to_string())));
+        let (s, query) = s
+            .split_once('?')
+            .map_or((s, None), |(s, query)| (s, Some(query.to_string())));
+        let (authority, path) = s.split_once('/').map_or((s, None), |(a, p)| (a, Some(p)));
+        let (collection, rkey) = match path {
+            Some(path) => {
+                let (collection, rkey) = Self::parse_path(path)?;
+                (Some(collection), rkey)
+            }
+            None => (None, None),
+        };
 
         Ok(Self {
-            authority: authority.to_string(),
-            collection: collection.map(ToString::to_string),
-            rkey: rkey.map(ToString::to_string),
+            authority: authority.parse()?,
+            collection,
+            rkey,
+            query,
+            fragment,
         })
     }
 }
 
-pub mod app {
-    pub mod bsky {
-        pub mod feed {
-            pub mod post {
-                pub const ID: &str = "app.bsky.feed.post";
+/// `com.atproto.repo.strongRef`, a record at a version. A reference whose uri can't be parsed
+/// is kept as `Invalid`, so that the record holding it is still read.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StrongRef {
+    Valid { uri: AtUri, cid: String },
+    Invalid,
+}
 
-                #[derive(Debug, serde::Deserialize)]
-                pub struct Record {
-                    pub text: String,
-                }
+impl<'de> serde::Deserialize<'de> for StrongRef {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(serde::Deserialize)]
+        struct Raw {
+            #[serde(rename = "$type")]
+            r#type: Option<String>,
+            uri: String,
+            cid: String,
+        }
+
+        let raw = Raw::deserialize(deserializer)?;
+        if let Some(r#type) = raw.r#type {
+            let id = com::atproto::repo::strong_ref::ID;
+            if r#type != id {
+                return Err(serde::de::Error::invalid_value(
+                    serde::de::Unexpected::Str(&r#type),
+                    &id,
+                ));
             }
-            pub mod repost {
-                use crate::lexicon::com::atproto::repo::StrongRef;
+        }
+
+        Ok(match raw.uri.parse() {
+            Ok(uri) => Self::Valid { uri, cid: raw.cid },
+            Err(_) => Self::Invalid,
+        })
+    }
+}
 
-                pub const ID: &str = "app.bsky.feed.repost";
+impl serde::Serialize for StrongRef {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        use serde::ser::SerializeStruct;
+
+        let Self::Valid { uri, cid } = self else {
+            return Err(serde::ser::Error::custom(
+                "Invalid strong ref can't be written",
+            ));
+        };
+        let mut s = serializer.serialize_struct("StrongRef", 2)?;
+        s.serialize_field("cid", cid)?;
+        s.serialize_field("uri", uri)?;
+        s.end()
+    }
+}
 
-                #[derive(Debug, serde::Deserialize)]
-                #[serde(rename_all = "camelCase")]
-                pub struct Record {
-                    pub subject: StrongRef,
-                    pub created_at: String,
-                }
-            }
-            pub mod like {
-                use crate::lexicon::com::atproto::repo::StrongRef;
+/// File uploaded along with a record, which refers to it by `ref`.
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(tag = "$type", rename = "blob", rename_all = "camelCase")]
+pub struct Blob {
+    #[serde(rename = "ref", with = "data::cid_link")]
+    pub cid: Cid,
+    pub mime_type: String,
+    pub size: i64,
+}
 
-                pub const ID: &str = "app.bsky.feed.like";
+/// Record of any collection, told by its `$type`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Record {
+    Post(Box<app::bsky::feed::post::Record>),
+    RePost(app::bsky::feed::repost::Record),
+    Like(app::bsky::feed::like::Record),
+    Follow(app::bsky::graph::follow::Record),
+    Profile(Box<app::bsky::actor::profile::Record>),
+    Block(app::bsky::graph::block::Record),
+    List(Box<app::bsky::graph::list::Record>),
+    ListItem(app::bsky::graph::listitem::Record),
+    Threadgate(Box<app::bsky::feed::threadgate::Record>),
+    Postgate(Box<app::bsky::feed::postgate::Record>),
+    Generator(Box<app::bsky::feed::generator::Record>),
+    /// Record of a collection not modelled here, in the JSON form of [`data`].
+    Unknown(serde_json::Value),
+}
 
-                #[derive(Debug, serde::Deserialize)]
-                #[serde(rename_all = "camelCase")]
-                pub struct Record {
-                    pub subject: StrongRef,
-                    pub created_at: String,
-                }
-            }
-            pub mod get_feed_skeleton {
-                #[derive(Debug, serde::Deserialize)]
-                pub struct QueryParams {
-                    pub feed: String,
-                    pub limit: u32,
-                    pub cursor: Option<String>,
-                }
+impl<'de> serde::Deserialize<'de> for Record {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        use app::bsky::{actor, feed, graph};
+
+        let value = data::deserialize(deserializer)?;
+        Ok(match data::type_of(&value) {
+            Some(feed::post::ID) => Record::Post(data::from_value(value)?),
+            Some(feed::repost::ID) => Record::RePost(data::from_value(value)?),
+            Some(feed::like::ID) => Record::Like(data::from_value(value)?),
+            Some(graph::follow::ID) => Record::Follow(data::from_value(value)?),
+            Some(actor::profile::ID) => Record::Profile(data::from_value(value)?),
+            Some(graph::block::ID) => Record::Block(data::from_value(value)?),
+            Some(graph::list::ID) => Record::List(data::from_value(value)?),
+            Some(graph::listitem::ID) => Record::ListItem(data::from_value(value)?),
+            Some(feed::threadgate::ID) => Record::Threadgate(data::from_value(value)?),
+            Some(feed::postgate::ID) => Record::Postgate(data::from_value(value)?),
+            Some(feed::generator::ID) => Record::Generator(data::from_value(value)?),
+            Some(_) => Record::Unknown(value),
+            None => return Err(serde::de::Error::missing_field("$type")),
+        })
+    }
+}
 
-                #[derive(Debug, serde::Serialize)]
-                pub struct Feed {
-                    pub post: String,
-                }
+impl Record {
+    /// NSID of the record type, which is also the collection it is kept in,
+    /// or `None` for an unknown record without a string `$type`.
+    pub fn nsid(&self) -> Option<&str> {
+        use app::bsky::{actor, feed, graph};
+
+        Some(match self {
+            Record::Post(_) => feed::post::ID,
+            Record::RePost(_) => feed::repost::ID,
+            Record::Like(_) => feed::like::ID,
+            Record::Follow(_) => graph::follow::ID,
+            Record::Profile(_) => actor::profile::ID,
+            Record::Block(_) => graph::block::ID,
+            Record::List(_) => graph::list::ID,
+            Record::ListItem(_) => graph::listitem::ID,
+            Record::Threadgate(_) => feed::threadgate::ID,
+            Record::Postgate(_) => feed::postgate::ID,
+            Record::Generator(_) => feed::generator::ID,
+            Record::Unknown(value) => return data::type_of(value),
+        })
+    }
+}
 
-                #[derive(Debug, serde::Serialize)]
-                pub struct OutputSchema {
-                    pub cursor: Option<String>,
-                    pub feed: Vec<Feed>,
-                }
-            }
-        }
-        pub mod graph {
-            pub mod follow {
-                #[derive(Debug, serde::Deserialize)]
-                #[serde(rename_all = "camelCase")]
-                pub struct Record {
-                    pub subject: String, // did
-                    pub created_at: String,
-                }
-            }
+/// Writes `$type` along with the record, and keys in the canonical order of DAG-CBOR.
+impl serde::Serialize for Record {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        use serde::ser::Error;
+
+        let value = match self {
+            Record::Post(record) => serde_json::to_value(record),
+            Record::RePost(record) => serde_json::to_value(record),
+            Record::Like(record) => serde_json::to_value(record),
+            Record::Follow(record) => serde_json::to_value(record),
+            Record::Profile(record) => serde_json::to_value(record),
+            Record::Block(record) => serde_json::to_value(record),
+            Record::List(record) => serde_json::to_value(record),
+            Record::ListItem(record) => serde_json::to_value(record),
+            Record::Threadgate(record) => serde_json::to_value(record),
+            Record::Postgate(record) => serde_json::to_value(record),
+            Record::Generator(record) => serde_json::to_value(record),
+            Record::Unknown(value) => return data::serialize(value, serializer),
+        };
+        let mut value = value.map_err(S::Error::custom)?;
+        if let (Some(object), Some(nsid)) = (value.as_object_mut(), self.nsid()) {
+            object.insert("$type".to_string(), nsid.into());
         }
+        data::serialize(&value, serializer)
     }
 }
 
-pub mod com {
-    pub mod atproto {
-        pub mod repo {
-            use std::str::FromStr;
-
-            use crate::lexicon::AtUri;
+use com::atproto::sync::subscribe_repos::OutputSchema;
+
+/// Type of [`OutputSchema`] without its content.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EventKind {
+    Commit,
+    Sync,
+    Identity,
+    Account,
+    Handle,
+    Migrate,
+    Tombstone,
+    Info,
+    Unknown,
+}
 
-            #[derive(Debug, PartialEq, Eq)]
-            pub enum StrongRef {
-                Valid { uri: AtUri, cid: String },
-                Invalid,
-            }
+impl OutputSchema {
+    pub fn kind(&self) -> EventKind {
+        match self {
+            OutputSchema::Commit(_) => EventKind::Commit,
+            OutputSchema::Sync(_) => EventKind::Sync,
+            OutputSchema::Identity(_) => EventKind::Identity,
+            OutputSchema::Account(_) => EventKind::Account,
+            OutputSchema::Handle(_) => EventKind::Handle,
+            OutputSchema::Migrate(_) => EventKind::Migrate,
+            OutputSchema::Tombstone(_) => EventKind::Tombstone,
+            OutputSchema::Info(_) => EventKind::Info,
+            OutputSchema::Unknown { .. } => EventKind::Unknown,
+        }
+    }
 
-            impl<'de> serde::Deserialize<'de> for StrongRef {
-                fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
-                where
-                    D: serde::Deserializer<'de>,
-                {
-                    const FIELDS: &[&str] = &["uri", "cid"];
-
-                    struct Visitor;
-
-                    impl<'de> serde::de::Visitor<'de> for Visitor {
-                        type Value = StrongRef;
-
-                        fn expecting(
-                            &self,
-                            formatter: &mut std::fmt::Formatter,
-                        ) -> std::fmt::Result {
-                            formatter.write_str("struct with uri, cid fields")
-                        }
-
-                        fn visit_map<A>(self, mut map: A) -> Result<Self::Value, A::Error>
-                        where
-                            A: serde::de::MapAccess<'de>,
-                        {
-                            let mut cid: Option<String> = None;
-                            let mut uri: Option<String> = None;
-                            while let Some(key) = map.next_key()? {
-                                match key {
-                                    "cid" => {
-                                        if cid.is_some() {
-                                            return Err(serde::de::Error::duplicate_field("cid"));
-                                        }
-                                        cid = Some(map.next_value()?)
-                                    }
-                                    "uri" => {
-                                        if uri.is_some() {
-                                            return Err(serde::de::Error::duplicate_field("uri"));
-                                        }
-                                        uri = Some(map.next_value()?)
-                                    }
-                                    "$type" => {
-                                        let r#type: String = map.next_value()?;
-                                        const TYPE: &str = "com.atproto.repo.strongRef";
-                                        if r#type != TYPE {
-                                            return Err(serde::de::Error::invalid_value(
-                                                serde::de::Unexpected::Str(&r#type),
-                                                &TYPE,
-                                            ));
-                                        }
-                                    }
-                                    _ => { /* ignore */ }
-                                }
-                            }
-
-                            let Some(cid) = cid else {
-                                return Err(serde::de::Error::missing_field("cid"));
-                            };
-                            let Some(uri) = uri else {
-                                return Err(serde::de::Error::missing_field("uri"));
-                            };
-                            let Ok(uri) = AtUri::from_str(&uri) else {
-                                return Ok(StrongRef::Invalid);
-                            };
-
-                            Ok(StrongRef::Valid { uri, cid })
-                        }
-                    }
-
-                    deserializer.deserialize_struct("StrongRef", FIELDS, Visitor)
-                }
-            }
+    /// DID of the repository the event is about.
+    pub fn repo(&self) -> Option<&str> {
+        match self {
+            OutputSchema::Commit(v) => Some(&v.repo),
+            OutputSchema::Sync(v) => Some(&v.did),
+            OutputSchema::Identity(v) => Some(&v.did),
+            OutputSchema::Account(v) => Some(&v.did),
+            OutputSchema::Handle(v) => Some(&v.did),
+            OutputSchema::Migrate(v) => Some(&v.did),
+            OutputSchema::Tombstone(v) => Some(&v.did),
+            OutputSchema::Info(_) | OutputSchema::Unknown { .. } => None,
+        }
+    }
 
-            #[test]
-            fn test_parse_strong_ref() {
-                assert_eq!(
-                    serde_json::from_str::<StrongRef>(r#"{"cid":"","uri":"at://example.com"}"#)
-                        .unwrap(),
-                    StrongRef::Valid {
-                        uri: AtUri {
-                            authority: "example.com".to_string(),
-                            collection: None,
-                            rkey: None
-                        },
-                        cid: "".to_string(),
-                    }
-                );
+    /// When the event is emitted by the service.
+    pub fn time(&self) -> Option<&str> {
+        match self {
+            OutputSchema::Commit(v) => Some(&v.time),
+            OutputSchema::Sync(v) => Some(&v.time),
+            OutputSchema::Identity(v) => Some(&v.time),
+            OutputSchema::Account(v) => Some(&v.time),
+            OutputSchema::Handle(v) => Some(&v.time),
+            OutputSchema::Migrate(v) => Some(&v.time),
+            OutputSchema::Tombstone(v) => Some(&v.time),
+            OutputSchema::Info(_) | OutputSchema::Unknown { .. } => None,
+        }
+    }
 
-                assert_eq!(
-                    serde_json::from_str::<StrongRef>(r#"{"$type":"com.atproto.repo.strongRef","cid":"","uri":"at://example.com"}"#)
-                        .unwrap(),
-                    StrongRef::Valid {
-                        uri: AtUri {
-                            authority: "example.com".to_string(),
-                            collection: None,
-                            rkey: None
-                        },
-                        cid: "".to_string(),
-                    }
-                );
+    /// Sequence number of the event. `#info` is not sequenced.
+    pub fn seq(&self) -> Option<u64> {
+        match self {
+            OutputSchema::Commit(v) => Some(v.seq),
+            OutputSchema::Sync(v) => Some(v.seq),
+            OutputSchema::Identity(v) => Some(v.seq),
+            OutputSchema::Account(v) => Some(v.seq),
+            OutputSchema::Handle(v) => Some(v.seq),
+            OutputSchema::Migrate(v) => Some(v.seq),
+            OutputSchema::Tombstone(v) => Some(v.seq),
+            OutputSchema::Info(_) => None,
+            OutputSchema::Unknown { raw, .. } => {
+                #[derive(serde::Deserialize)]
+                struct Seq {
+                    seq: Option<u64>,
+                }
 
-                assert_eq!(
-                    serde_json::from_str::<StrongRef>(r#"{"cid":"","uri":""}"#).unwrap(),
-                    StrongRef::Invalid
-                );
+                serde_ipld_dagcbor::from_slice::<Seq>(raw).ok()?.seq
             }
         }
-        pub mod sync {
-            pub mod subscribe_repos {
-                use anyhow::Context;
-                use futures_util::StreamExt;
-                use rs_car::Cid;
-                use std::collections::HashMap;
-
-                pub const ID: &str = "com.atproto.sync.subscribeRepos";
-
-                #[derive(Debug, serde::Serialize)]
-                pub struct QueryParams {
-                    pub cursor: Option<u64>,
-                }
-
-                #[derive(Debug, serde::Deserialize)]
-                pub struct CommonPart {
-                    pub seq: u64,
-                    pub time: String,
-                }
+    }
+}
 
-                #[serde_with::serde_as]
-                #[derive(Debug, serde::Deserialize)]
-                #[serde(rename_all = "camelCase")]
-                pub struct Commit {
-                    #[serde(flatten)]
-                    pub _common: CommonPart,
-                    pub rebase: bool,
-                    pub too_big: bool,
-                    pub repo: String,
-                    pub commit: Cid,
-                    #[serde_as(as = "serde_with::DefaultOnError")]
-                    pub prev: Option<Cid>,
-                    pub rev: String,
-                    pub since: Option<String>,
-                    pub blocks: Option<CommitRawBlocks>,
-                    pub ops: Vec<RepoOp>,
-                    pub blobs: Vec<Cid>,
-                }
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::*;
+    use app::bsky::{embed, feed::post, richtext::facet};
+    use com::atproto::sync::subscribe_repos::RepoOpAction;
+
+    #[test]
+    fn test_parse_strong_ref() {
+        let expected = StrongRef::Valid {
+            uri: "at://example.com".parse().unwrap(),
+            cid: "".to_string(),
+        };
+        assert_eq!(
+            serde_json::from_str::<StrongRef>(r#"{"cid":"","uri":"at://example.com"}"#).unwrap(),
+            expected
+        );
+        assert_eq!(
+            serde_json::from_str::<StrongRef>(
+                r#"{"$type":"com.atproto.repo.strongRef","cid":"","uri":"at://example.com"}"#
+            )
+            .unwrap(),
+            expected
+        );
+        assert_eq!(
+            serde_json::from_str::<StrongRef>(r#"{"cid":"","uri":""}"#).unwrap(),
+            StrongRef::Invalid
+        );
+        assert!(serde_json::from_str::<StrongRef>(r#"{"uri":"at://example.com"}"#).is_err());
+        assert!(serde_json::from_str::<StrongRef>(
+            r#"{"$type":"app.bsky.feed.like","cid":"","uri":"at://example.com"}"#
+        )
+        .is_err());
+
+        assert_eq!(
+            serde_json::to_value(&expected).unwrap(),
+            serde_json::json!({ "cid": "", "uri": "at://example.com" })
+        );
+        assert!(serde_json::to_value(StrongRef::Invalid).is_err());
+    }
 
-                #[derive(Debug, serde::Deserialize)]
-                #[repr(transparent)]
-                pub struct CommitRawBlocks(pub serde_bytes::ByteBuf);
-
-                impl CommitRawBlocks {
-                    pub async fn parse(&self) -> Result<CommitBlocks, rs_car::CarDecodeError> {
-                        let mut blocks = futures_util::io::Cursor::new(&self.0);
-                        let mut ret = HashMap::new();
-                        let mut reader = rs_car::CarReader::new(&mut blocks, false).await?;
-                        while let Some(item) = reader.next().await {
-                            let (cid, block) = item?;
-                            ret.insert(cid, block);
-                        }
-
-                        Ok(CommitBlocks(ret))
-                    }
-                }
+    #[test]
+    fn test_generated_post() {
+        let json = serde_json::json!({
+            "$type": "app.bsky.feed.post",
+            "text": "으어어 @alice.test",
+            "createdAt": "2024-01-01T00:00:00.000Z",
+            "langs": ["ko"],
+            "facets": [{
+                "index": { "byteStart": 10, "byteEnd": 21 },
+                "features": [
+                    { "$type": "app.bsky.richtext.facet#mention", "did": "did:plc:alice" },
+                    { "$type": "app.bsky.richtext.facet#unknown", "value": 1 },
+                ],
+            }],
+            "embed": {
+                "$type": "app.bsky.embed.record",
+                "record": { "uri": "at://did:plc:alice/app.bsky.feed.post/1", "cid": "bafy" },
+            },
+        });
+        let post: post::Record = serde_json::from_value(json.clone()).unwrap();
+        assert_eq!(post.created_at, "2024-01-01T00:00:00.000Z");
+        assert_eq!(post.langs.as_deref(), Some(&["ko".to_string()][..]));
+        assert!(post.reply.is_none());
+        let facets = post.facets.as_ref().unwrap();
+        let facet::FacetFeaturesItem::Mention(mention) = &facets[0].features[0] else {
+            panic!("expected mention");
+        };
+        assert_eq!(mention.did, "did:plc:alice");
+        // Unknown members of open unions are kept as they are.
+        let facet::FacetFeaturesItem::Unknown(unknown) = &facets[0].features[1] else {
+            panic!("expected unknown feature");
+        };
+        assert_eq!(unknown["value"], 1);
+        let Some(post::RecordEmbed::Record(quote)) = &post.embed else {
+            panic!("expected quote");
+        };
+        let StrongRef::Valid { cid, .. } = &quote.record else {
+            panic!("expected valid ref");
+        };
+        assert_eq!(cid, "bafy");
+
+        // `$type` of the record itself is written by the enclosing union.
+        let mut expected = json;
+        expected.as_object_mut().unwrap().remove("$type");
+        assert_eq!(serde_json::to_value(&post).unwrap(), expected);
+    }
 
-                #[derive(Debug)]
-                #[repr(transparent)]
-                pub struct CommitBlocks(pub HashMap<Cid, Vec<u8>>);
+    /// Decode a post in DAG-CBOR, as repositories keep it, and check nothing is lost on the way back.
+    fn decode_post(fixture: &[u8]) -> post::Record {
+        let record = serde_ipld_dagcbor::from_slice::<Record>(fixture).unwrap();
+        assert_eq!(
+            serde_json::to_value(&record).unwrap(),
+            data::from_cbor(fixture).unwrap()
+        );
+        assert_eq!(serde_ipld_dagcbor::to_vec(&record).unwrap(), fixture);
+
+        let Record::Post(post) = record else {
+            panic!("expected post");
+        };
+        *post
+    }
 
-                #[derive(Debug, thiserror::Error)]
-                pub enum CommitBlockParseError {
-                    #[error("Type mismatched with target type. actual value: {0}")]
-                    InvalidParseTargetType(serde_json::Value),
-                    #[error("Failed to parse. raw value: {0:?}")]
-                    UnknownError(Vec<u8>),
-                }
+    #[test]
+    fn test_post_reply() {
+        let post = decode_post(include_bytes!("lexicon/fixtures/post_reply.cbor"));
+        assert_eq!(post.text, "으어어 @bob.test https://eueoeo.example #으어어");
+        assert_eq!(post.created_at, "2024-09-09T19:46:02.102Z");
+        assert_eq!(post.langs.unwrap(), ["ko"]);
+        assert!(post.embed.is_none());
+
+        let reply = post.reply.unwrap();
+        let (
+            StrongRef::Valid { uri: root, .. },
+            StrongRef::Valid {
+                uri: parent,
+                cid: parent_cid,
+            },
+        ) = (&reply.root, &reply.parent)
+        else {
+            panic!("expected valid refs");
+        };
+        assert_eq!(root.rkey.as_ref().unwrap(), "3l3qo2vuowo2b");
+        assert_ne!(parent, root);
+        assert!(parent_cid.starts_with("bafyrei"));
+
+        let facets = post.facets.unwrap();
+        let slices = facets
+            .iter()
+            .map(|facet| {
+                &post.text.as_bytes()
+                    [facet.index.byte_start as usize..facet.index.byte_end as usize]
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            slices,
+            [
+                "@bob.test".as_bytes(),
+                b"https://eueoeo.example",
+                "#으어어".as_bytes()
+            ]
+        );
+        let [facet::FacetFeaturesItem::Mention(mention)] = &facets[0].features[..] else {
+            panic!("expected mention");
+        };
+        assert_eq!(mention.did, "did:plc:z72i7hdynmk6r22z27h6tvur");
+        let [facet::FacetFeaturesItem::Link(link)] = &facets[1].features[..] else {
+            panic!("expected link");
+        };
+        assert_eq!(link.uri, "https://eueoeo.example");
+        let [facet::FacetFeaturesItem::Tag(tag)] = &facets[2].features[..] else {
+            panic!("expected tag");
+        };
+        assert_eq!(tag.tag, "으어어");
+    }
 
-                impl CommitBlocks {
-                    pub fn get(&self, key: &Cid) -> Option<Result<Record, CommitBlockParseError>> {
-                        let block = self.0.get(key)?;
-                        if let Ok(ret) = serde_ipld_dagcbor::from_slice::<Record>(block) {
-                            Some(Ok(ret))
-                        } else if let Ok(v) =
-                            serde_ipld_dagcbor::from_slice::<serde_json::Value>(block)
-                        {
-                            Some(Err(CommitBlockParseError::InvalidParseTargetType(v)))
-                        } else {
-                            Some(Err(CommitBlockParseError::UnknownError(block.clone())))
-                        }
-                    }
-
-                    pub fn keys(&self) -> impl Iterator<Item = &Cid> {
-                        self.0.keys()
-                    }
-                }
+    #[test]
+    fn test_post_images() {
+        let post = decode_post(include_bytes!("lexicon/fixtures/post_images.cbor"));
+        assert_eq!(post.langs.unwrap(), ["ko", "en"]);
+        let Some(post::RecordEmbed::Images(images)) = post.embed else {
+            panic!("expected images");
+        };
+        let [first, second] = &images.images[..] else {
+            panic!("expected two images");
+        };
+        assert_eq!(first.alt, "");
+        assert_eq!(first.image.mime_type, "image/jpeg");
+        assert_eq!(first.image.size, 523718);
+        assert_eq!(
+            first.image.cid.to_string(),
+            "bafkreiam6rl6essht4bp2tjukqbyt5za6cah3t7zfj2weeebmwzgg7vif4"
+        );
+        let aspect_ratio = first.aspect_ratio.as_ref().unwrap();
+        assert_eq!((aspect_ratio.width, aspect_ratio.height), (2000, 1500));
+        assert_eq!(second.alt, "으어어 하는 고양이");
+        assert!(second.aspect_ratio.is_none());
+    }
 
-                use super::super::super::super::app::bsky;
-
-                #[derive(Debug, serde::Deserialize)]
-                #[serde(tag = "$type")]
-                pub enum Record {
-                    #[serde(rename = "app.bsky.feed.post")]
-                    Post(bsky::feed::post::Record),
-                    #[serde(rename = "app.bsky.feed.repost")]
-                    RePost(bsky::feed::repost::Record),
-                    #[serde(rename = "app.bsky.feed.like")]
-                    Like(bsky::feed::like::Record),
-                    #[serde(rename = "app.bsky.graph.follow")]
-                    Follow(bsky::graph::follow::Record),
-                    #[serde(other)]
-                    Unknown,
-                }
+    #[test]
+    fn test_post_external() {
+        let post = decode_post(include_bytes!("lexicon/fixtures/post_external.cbor"));
+        assert_eq!(post.text, "");
+        let Some(post::RecordEmbed::External(external)) = post.embed else {
+            panic!("expected external");
+        };
+        assert_eq!(external.external.uri, "https://eueoeo.example/about");
+        assert_eq!(external.external.title, "으어어");
+        assert_eq!(external.external.thumb.unwrap().mime_type, "image/jpeg");
+    }
 
-                #[serde_with::serde_as]
-                #[derive(Debug, serde::Deserialize)]
-                #[serde(rename_all = "camelCase")]
-                pub struct Identity {
-                    #[serde(flatten)]
-                    pub _common: CommonPart,
-                    pub did: String,
-                    pub handle: Option<String>,
-                }
+    #[test]
+    fn test_post_quote() {
+        let post = decode_post(include_bytes!("lexicon/fixtures/post_quote.cbor"));
+        let Some(post::RecordEmbed::Record(quote)) = post.embed else {
+            panic!("expected quote");
+        };
+        let StrongRef::Valid { uri, .. } = quote.record else {
+            panic!("expected valid ref");
+        };
+        assert_eq!(uri.collection.unwrap(), post::ID);
+    }
 
-                #[serde_with::serde_as]
-                #[derive(Debug, serde::Deserialize)]
-                #[serde(rename_all = "camelCase")]
-                pub struct Account {
-                    #[serde(flatten)]
-                    pub _common: CommonPart,
-                    pub did: String,
-                    pub active: bool,
-                    pub status: Option<String>,
-                }
+    #[test]
+    fn test_post_record_with_media() {
+        let fixture = include_bytes!("lexicon/fixtures/post_record_with_media.cbor");
+        let post = decode_post(fixture);
+
+        // `$type` of objects out of unions is taken, though not kept.
+        let mut value = data::from_cbor(fixture).unwrap();
+        value["embed"]["record"]["$type"] = embed::record::ID.into();
+        assert_eq!(
+            data::from_value::<post::Record, serde_json::Error>(value).unwrap(),
+            post
+        );
+
+        let Some(post::RecordEmbed::RecordWithMedia(embed)) = post.embed else {
+            panic!("expected record with media");
+        };
+        let StrongRef::Valid { uri, .. } = &embed.record.record else {
+            panic!("expected valid ref");
+        };
+        assert_eq!(uri.rkey.as_ref().unwrap(), "3l3qo2vuowo2b");
+        let embed::record_with_media::RecordWithMediaMedia::Images(images) = &embed.media else {
+            panic!("expected images");
+        };
+        assert_eq!(images.images[0].image.mime_type, "image/webp");
+    }
 
-                #[derive(Debug, serde::Deserialize)]
-                pub struct Handle {
-                    #[serde(flatten)]
-                    pub _common: CommonPart,
-                    pub did: String,
-                    pub handle: String,
-                }
+    #[test]
+    fn test_post_video() {
+        let post = decode_post(include_bytes!("lexicon/fixtures/post_video.cbor"));
+        assert_eq!(post.tags.unwrap(), ["eueoeo", "으어어"]);
+        let Some(post::RecordLabels::SelfLabels(labels)) = post.labels else {
+            panic!("expected self labels");
+        };
+        assert_eq!(labels.values[0].val, "graphic-media");
+        let Some(post::RecordEmbed::Video(video)) = post.embed else {
+            panic!("expected video");
+        };
+        assert_eq!(video.video.mime_type, "video/mp4");
+        assert_eq!(video.alt.as_deref(), Some("으어어"));
+        assert!(video.captions.is_none());
+    }
 
-                #[derive(Debug, serde::Deserialize)]
-                #[serde(rename_all = "camelCase")]
-                pub struct Migrate {
-                    #[serde(flatten)]
-                    pub _common: CommonPart,
-                    pub did: String,
-                    pub migrate_to: Option<String>,
+    /// Posts captured by `fixtures/fetch.sh`, each checked against the CID its repository gave it.
+    #[test]
+    fn test_captured_posts() {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("src/lexicon/fixtures");
+        for entry in std::fs::read_dir(dir).unwrap() {
+            let path = entry.unwrap().path();
+            if path.extension().is_none_or(|extension| extension != "car") {
+                continue;
+            }
+            let car = car::CommitRawBlocks::from(serde_bytes::ByteBuf::from(
+                std::fs::read(&path).unwrap(),
+            ));
+            let blocks = car.parse().unwrap();
+            let mut posts = 0;
+            for cid in blocks.keys() {
+                let block = blocks.get_raw(cid).unwrap();
+                assert_eq!(
+                    &crate::atproto_subscription::mock_relay::cid_for(block),
+                    cid,
+                    "{path:?}"
+                );
+                let value = data::from_cbor(block).unwrap();
+                if data::type_of(&value) == Some(post::ID) {
+                    decode_post(block);
+                    posts += 1;
                 }
+            }
+            assert_eq!(posts, 1, "{path:?}");
+        }
+    }
 
-                #[derive(Debug, serde::Deserialize)]
-                pub struct Tombstone {
-                    #[serde(flatten)]
-                    pub _common: CommonPart,
-                    pub did: String,
-                }
+    #[test]
+    fn test_record_types() {
+        #[derive(serde::Serialize)]
+        struct Data(#[serde(serialize_with = "data::serialize")] serde_json::Value);
+        let decode = |value: serde_json::Value| {
+            let cbor = serde_ipld_dagcbor::to_vec(&Data(value)).unwrap();
+            let record = serde_ipld_dagcbor::from_slice::<Record>(&cbor).unwrap();
+            assert_eq!(serde_ipld_dagcbor::to_vec(&record).unwrap(), cbor);
+            record
+        };
+        let avatar = serde_json::json!({
+            "$type": "blob",
+            "ref": { "$link": "bafkreiam6rl6essht4bp2tjukqbyt5za6cah3t7zfj2weeebmwzgg7vif4" },
+            "mimeType": "image/jpeg",
+            "size": 12034,
+        });
+        let created_at = "2024-09-09T19:46:02.102Z";
+        let list = "at://did:plc:alice/app.bsky.graph.list/3l3qo2vuowo2b";
+
+        let Record::Profile(profile) = decode(serde_json::json!({
+            "$type": "app.bsky.actor.profile",
+            "displayName": "으어어",
+            "avatar": avatar,
+            "labels": {
+                "$type": "com.atproto.label.defs#selfLabels",
+                "values": [{ "val": "!no-unauthenticated" }],
+            },
+        })) else {
+            panic!("expected profile");
+        };
+        assert_eq!(profile.display_name.as_deref(), Some("으어어"));
+        assert_eq!(profile.avatar.unwrap().size, 12034);
+
+        let Record::Block(block) = decode(serde_json::json!({
+            "$type": "app.bsky.graph.block",
+            "subject": "did:plc:bob",
+            "createdAt": created_at,
+        })) else {
+            panic!("expected block");
+        };
+        assert_eq!(block.subject, "did:plc:bob");
+
+        let Record::List(record) = decode(serde_json::json!({
+            "$type": "app.bsky.graph.list",
+            "purpose": "app.bsky.graph.defs#modlist",
+            "name": "으어어",
+            "createdAt": created_at,
+        })) else {
+            panic!("expected list");
+        };
+        assert_eq!(record.purpose, app::bsky::graph::defs::ListPurpose::Modlist);
+
+        let Record::ListItem(item) = decode(serde_json::json!({
+            "$type": "app.bsky.graph.listitem",
+            "subject": "did:plc:bob",
+            "list": list,
+            "createdAt": created_at,
+        })) else {
+            panic!("expected list item");
+        };
+        assert_eq!(item.list, list);
+
+        let Record::Threadgate(threadgate) = decode(serde_json::json!({
+            "$type": "app.bsky.feed.threadgate",
+            "post": "at://did:plc:alice/app.bsky.feed.post/3l3qo2vuowo2b",
+            "allow": [
+                { "$type": "app.bsky.feed.threadgate#mentionRule" },
+                { "$type": "app.bsky.feed.threadgate#listRule", "list": list },
+            ],
+            "createdAt": created_at,
+        })) else {
+            panic!("expected threadgate");
+        };
+        use app::bsky::feed::threadgate::RecordAllowItem;
+        let [RecordAllowItem::MentionRule(_), RecordAllowItem::ListRule(rule)] =
+            &threadgate.allow.unwrap()[..]
+        else {
+            panic!("expected mention and list rules");
+        };
+        assert_eq!(rule.list, list);
+
+        let Record::Postgate(postgate) = decode(serde_json::json!({
+            "$type": "app.bsky.feed.postgate",
+            "post": "at://did:plc:alice/app.bsky.feed.post/3l3qo2vuowo2b",
+            "embeddingRules": [{ "$type": "app.bsky.feed.postgate#disableRule" }],
+            "createdAt": created_at,
+        })) else {
+            panic!("expected postgate");
+        };
+        assert_eq!(postgate.embedding_rules.unwrap().len(), 1);
+
+        let Record::Generator(generator) = decode(serde_json::json!({
+            "$type": "app.bsky.feed.generator",
+            "did": "did:web:eueoeo.example",
+            "displayName": "으어어",
+            "avatar": avatar,
+            "contentMode": "app.bsky.feed.defs#contentModeVideo",
+            "createdAt": created_at,
+        })) else {
+            panic!("expected generator");
+        };
+        assert_eq!(generator.did, "did:web:eueoeo.example");
+        assert_eq!(
+            generator.content_mode,
+            Some(app::bsky::feed::generator::RecordContentMode::ContentModeVideo)
+        );
+
+        // Records not modelled are kept in the JSON form, CIDs and all.
+        let Record::Unknown(unknown) = decode(serde_json::json!({
+            "$type": "app.bsky.graph.starterpack",
+            "name": "으어어",
+            "avatar": avatar,
+        })) else {
+            panic!("expected unknown");
+        };
+        assert_eq!(unknown["avatar"], avatar);
+        assert!(serde_json::from_value::<Record>(serde_json::json!({ "text": "으어어" })).is_err());
+    }
 
-                #[derive(Debug, serde::Deserialize)]
-                pub struct Info {
-                    pub name: InfoName,
-                    pub message: Option<String>,
-                }
+    #[test]
+    fn test_known_values() {
+        let actions: Vec<RepoOpAction> =
+            serde_json::from_str(r#"["create", "delete", "rebase"]"#).unwrap();
+        assert_eq!(
+            actions,
+            [
+                RepoOpAction::Create,
+                RepoOpAction::Delete,
+                RepoOpAction::Other("rebase".to_string())
+            ]
+        );
+        assert_eq!(
+            serde_json::to_string(&actions).unwrap(),
+            r#"["create","delete","rebase"]"#
+        );
+    }
 
-                #[derive(Debug, serde::Deserialize)]
-                pub enum InfoName {
-                    OutDatedCursor,
-                }
+    #[test]
+    fn test_cid_links() {
+        use com::atproto::sync::subscribe_repos::RepoOp;
+
+        let cid = "bafyreidfayvfuwqa7qlnopdjiqrxzs6blmoeu4rujcjtnci5beludirz2a";
+        let json = serde_json::json!({
+            "action": "update",
+            "path": "app.bsky.feed.post/3l3qo2vuowo2b",
+            "cid": { "$link": cid },
+            "prev": null,
+        });
+        let op: RepoOp = serde_json::from_value(json).unwrap();
+        assert_eq!(op.cid.unwrap().to_string(), cid);
+        assert_eq!(op.prev, None);
+        assert_eq!(
+            serde_json::to_value(&op).unwrap()["cid"],
+            serde_json::json!({ "$link": cid })
+        );
+
+        // Read from the tag of DAG-CBOR, and from `$link` of a value buffered through the data model.
+        let cbor = serde_ipld_dagcbor::to_vec(&op).unwrap();
+        assert_eq!(serde_ipld_dagcbor::from_slice::<RepoOp>(&cbor).unwrap(), op);
+        let value = data::from_cbor(&cbor).unwrap();
+        assert_eq!(value["cid"], serde_json::json!({ "$link": cid }));
+        assert_eq!(
+            data::from_value::<RepoOp, serde_json::Error>(value).unwrap(),
+            op
+        );
+    }
 
-                #[derive(Debug, serde::Deserialize)]
-                pub struct RepoOp {
-                    pub action: RepoOpAction,
-                    pub path: String,
-                    pub cid: Option<Cid>,
-                }
+    #[test]
+    fn test_undecodable_message() {
+        // A map of a thousand entries cut short, as large as a CAR would make it.
+        let mut bytes = vec![0xb9, 0x03, 0xe8];
+        bytes.resize(100 * 1024, 0x61);
+        let error = OutputSchema::from_cbor("#commit", &bytes).unwrap_err();
+        let context = error.to_string();
+        assert!(context.starts_with("tag: commit, data: [185, 3, 232, 97"));
+        assert!(context.ends_with(" (102400 bytes)"));
+        assert!(context.len() < 512);
+    }
 
-                #[derive(Debug, serde::Deserialize)]
-                #[serde(rename_all = "lowercase")]
-                pub enum RepoOpAction {
-                    Create,
-                    Update,
-                    Delete,
-                }
+    #[test]
+    fn test_at_uri() {
+        let uri = "at://did:plc:alice/app.bsky.feed.post"
+            .parse::<AtUri>()
+            .unwrap();
+        assert_eq!(uri.authority.to_string(), "did:plc:alice");
+        assert_eq!(uri.collection.unwrap(), "app.bsky.feed.post");
+        assert_eq!(uri.rkey, None);
+
+        let uri = "at://alice.test/app.bsky.feed.generator/eueoeo?limit=1#/text"
+            .parse::<AtUri>()
+            .unwrap();
+        assert!(matches!(uri.authority, AtIdentifier::Handle(_)));
+        assert_eq!(uri.rkey.as_ref().unwrap(), "eueoeo");
+        assert_eq!(uri.query.as_deref(), Some("limit=1"));
+        assert_eq!(uri.fragment.as_deref(), Some("/text"));
+
+        for invalid in [
+            "https://alice.test",
+            "at://alice",
+            "at://alice.test/",
+            "at://alice.test/app.bsky.feed.post/",
+            "at://alice.test/post/abc",
+            "at://alice.test/app.bsky.feed.post/abc/def",
+            "at://alice.test/app.bsky.feed.post/a b",
+        ] {
+            assert!(invalid.parse::<AtUri>().is_err(), "{invalid}");
+        }
 
-                #[derive(Debug, serde::Deserialize)]
-                #[serde(tag = "$type")]
-                pub enum OutputSchema {
-                    // Too large. Use Box
-                    #[serde(rename = "com.atproto.sync.subscribeRepos#commit")]
-                    Commit(Box<Commit>),
-                    #[serde(rename = "com.atproto.sync.subscribeRepos#identity")]
-                    Identity(Identity),
-                    #[serde(rename = "com.atproto.sync.subscribeRepos#account")]
-                    Account(Account),
-                    #[serde(rename = "com.atproto.sync.subscribeRepos#handle")]
-                    Handle(Handle),
-                    #[serde(rename = "com.atproto.sync.subscribeRepos#migrate")]
-                    Migrate(Migrate),
-                    #[serde(rename = "com.atproto.sync.subscribeRepos#tombstone")]
-                    Tombstone(Tombstone),
-                    #[serde(rename = "com.atproto.sync.subscribeRepos#info")]
-                    Info(Info),
-                }
+        let did = "did:plc:alice".parse::<identifier::Did>().unwrap();
+        assert_eq!(
+            AtUri::with_auth_path(did.clone(), "app.bsky.feed.post/3l3qo2vuowo2b")
+                .unwrap()
+                .to_string(),
+            "at://did:plc:alice/app.bsky.feed.post/3l3qo2vuowo2b"
+        );
+        assert!(AtUri::with_auth_path(did, "app.bsky.feed.post/a/b").is_err());
+    }
 
-                impl OutputSchema {
-                    pub fn from_cbor(tag: &str, bytes: &[u8]) -> anyhow::Result<Self> {
-                        Ok(match tag {
-                            "#commit" => OutputSchema::Commit(
-                                serde_ipld_dagcbor::from_slice(bytes)
-                                    .with_context(|| format!("tag: commit, data: {bytes:?}"))?,
-                            ),
-                            "#identity" => OutputSchema::Identity(
-                                serde_ipld_dagcbor::from_slice(bytes)
-                                    .with_context(|| format!("tag: identity, data: {bytes:?}"))?,
-                            ),
-                            "#account" => OutputSchema::Account(
-                                serde_ipld_dagcbor::from_slice(bytes)
-                                    .with_context(|| format!("tag: account, data: {bytes:?}"))?,
-                            ),
-                            "#handle" => OutputSchema::Handle(
-                                serde_ipld_dagcbor::from_slice(bytes)
-                                    .with_context(|| format!("tag: handle, data: {bytes:?}"))?,
-                            ),
-                            "#migrate" => OutputSchema::Migrate(
-                                serde_ipld_dagcbor::from_slice(bytes)
-                                    .with_context(|| format!("tag: migrate, data: {bytes:?}"))?,
-                            ),
-                            "#tombstone" => OutputSchema::Tombstone(
-                                serde_ipld_dagcbor::from_slice(bytes)
-                                    .with_context(|| format!("tag: tombstone, data: {bytes:?}"))?,
-                            ),
-                            "#info" => OutputSchema::Info(
-                                serde_ipld_dagcbor::from_slice(bytes)
-                                    .with_context(|| format!("tag: info, data: {bytes:?}"))?,
-                            ),
-                            unknown => return Err(anyhow::anyhow!("Unknown tag - {unknown}")),
-                        })
-                    }
-                }
-            }
+    proptest::proptest! {
+        #[test]
+        fn test_at_uri_round_trip(
+            authority in "did:[a-z]{1,8}:[a-zA-Z0-9._:-]{0,20}[a-zA-Z0-9._-]|([a-z0-9]{1,10}\\.){1,3}[a-z][a-z0-9]{0,5}",
+            collection in proptest::option::of("[a-z]{1,8}(\\.[a-z][a-z0-9-]{0,8}[a-z0-9]){1,3}\\.[a-zA-Z][a-zA-Z0-9]{0,10}"),
+            rkey in proptest::option::of("[a-zA-Z0-9._:~-]{3,20}"),
+            query in proptest::option::of("[a-zA-Z0-9=&%/?-]{0,20}"),
+            fragment in proptest::option::of("[a-zA-Z0-9=&%/?#-]{0,20}"),
+        ) {
+            let uri = AtUri {
+                authority: authority.parse().unwrap(),
+                rkey: collection.as_ref().and(rkey).map(|rkey| rkey.parse().unwrap()),
+                collection: collection.map(|collection| collection.parse().unwrap()),
+                query,
+                fragment,
+            };
+            proptest::prop_assert_eq!(uri.to_string().parse::<AtUri>().unwrap(), uri);
         }
     }
 }
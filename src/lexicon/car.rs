@@ -0,0 +1,228 @@
+//! CAR files carried by commits, holding the blocks of records and of the repository tree.
+
+use std::{
+    collections::HashMap,
+    ops::Range,
+    sync::{Arc, OnceLock},
+};
+
+use rs_car::Cid;
+
+use super::{data, Record};
+
+/// Clones share the blocks and their index, so an event can be handed to many handlers without copying them.
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(from = "serde_bytes::ByteBuf")]
+pub struct CommitRawBlocks(Arc<RawBlocks>);
+
+#[derive(Debug)]
+struct RawBlocks {
+    raw: serde_bytes::ByteBuf,
+    index: OnceLock<Result<HashMap<Cid, Range<usize>>, CarIndexError>>,
+}
+
+impl serde::Serialize for CommitRawBlocks {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.0.raw.serialize(serializer)
+    }
+}
+
+/// The index is left out, as it is built from the CAR file.
+impl PartialEq for CommitRawBlocks {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.raw == other.0.raw
+    }
+}
+
+impl From<serde_bytes::ByteBuf> for CommitRawBlocks {
+    fn from(raw: serde_bytes::ByteBuf) -> Self {
+        Self(Arc::new(RawBlocks {
+            raw,
+            index: OnceLock::new(),
+        }))
+    }
+}
+
+#[derive(Debug, Clone, thiserror::Error)]
+pub enum CarIndexError {
+    #[error("CAR file is truncated")]
+    Truncated,
+    #[error("Invalid CID in CAR file - {0}")]
+    InvalidCid(String),
+}
+
+impl CommitRawBlocks {
+    /// The CAR file.
+    pub fn raw(&self) -> &[u8] {
+        &self.0.raw
+    }
+
+    /// Locate blocks in the CAR file without copying or decoding them.
+    /// The index is built once and kept, so it can be built ahead of handling the event.
+    pub fn parse(&self) -> Result<CommitBlocks<'_>, CarIndexError> {
+        let index = self
+            .0
+            .index
+            .get_or_init(|| Self::build_index(&self.0.raw))
+            .as_ref()
+            .map_err(Clone::clone)?;
+
+        Ok(CommitBlocks {
+            raw: &self.0.raw,
+            index,
+        })
+    }
+
+    fn build_index(raw: &[u8]) -> Result<HashMap<Cid, Range<usize>>, CarIndexError> {
+        fn read_varint(raw: &[u8], pos: &mut usize) -> Result<usize, CarIndexError> {
+            let mut value = 0usize;
+            for shift in (0..usize::BITS).step_by(7) {
+                let byte = *raw.get(*pos).ok_or(CarIndexError::Truncated)?;
+                *pos += 1;
+                value |= ((byte & 0x7f) as usize) << shift;
+                if byte & 0x80 == 0 {
+                    return Ok(value);
+                }
+            }
+            Err(CarIndexError::Truncated)
+        }
+
+        let mut pos = 0;
+        let header_len = read_varint(raw, &mut pos)?;
+        pos = pos
+            .checked_add(header_len)
+            .filter(|end| *end <= raw.len())
+            .ok_or(CarIndexError::Truncated)?;
+
+        let mut ret = HashMap::new();
+        while pos < raw.len() {
+            let section_len = read_varint(raw, &mut pos)?;
+            let end = pos
+                .checked_add(section_len)
+                .filter(|end| *end <= raw.len())
+                .ok_or(CarIndexError::Truncated)?;
+            let mut section = &raw[pos..end];
+            let cid = Cid::read_bytes(&mut section)
+                .map_err(|e| CarIndexError::InvalidCid(e.to_string()))?;
+            ret.insert(cid, (end - section.len())..end);
+            pos = end;
+        }
+
+        Ok(ret)
+    }
+
+    /// Encode blocks as a CARv1 file, the same form as `blocks` of a commit event.
+    pub fn from_blocks<'a>(
+        roots: &[Cid],
+        blocks: impl IntoIterator<Item = (&'a Cid, &'a [u8])>,
+    ) -> Self {
+        #[derive(serde::Serialize)]
+        struct CarHeader<'a> {
+            roots: &'a [Cid],
+            version: u64,
+        }
+
+        fn write_varint(buf: &mut Vec<u8>, mut value: u64) {
+            while value >= 0x80 {
+                buf.push((value as u8) | 0x80);
+                value >>= 7;
+            }
+            buf.push(value as u8);
+        }
+
+        let header = serde_ipld_dagcbor::to_vec(&CarHeader { roots, version: 1 })
+            .expect("CAR header is always serializable");
+        let mut ret = Vec::new();
+        write_varint(&mut ret, header.len() as u64);
+        ret.extend(header);
+        for (cid, block) in blocks {
+            let cid = cid.to_bytes();
+            write_varint(&mut ret, (cid.len() + block.len()) as u64);
+            ret.extend(cid);
+            ret.extend_from_slice(block);
+        }
+
+        serde_bytes::ByteBuf::from(ret).into()
+    }
+}
+
+/// Blocks of a commit, decoded one by one as they are requested.
+#[derive(Debug, Clone, Copy)]
+pub struct CommitBlocks<'a> {
+    raw: &'a [u8],
+    index: &'a HashMap<Cid, Range<usize>>,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum CommitBlockParseError {
+    #[error("Type mismatched with target type. actual value: {0}")]
+    InvalidParseTargetType(serde_json::Value),
+    #[error("Failed to parse. raw value: {0:?}")]
+    UnknownError(Vec<u8>),
+}
+
+impl<'a> CommitBlocks<'a> {
+    pub fn get(&self, key: &Cid) -> Option<Result<Record, CommitBlockParseError>> {
+        let block = self.get_raw(key)?;
+        if let Ok(ret) = serde_ipld_dagcbor::from_slice::<Record>(block) {
+            Some(Ok(ret))
+        } else if let Ok(v) = data::from_cbor(block) {
+            Some(Err(CommitBlockParseError::InvalidParseTargetType(v)))
+        } else {
+            Some(Err(CommitBlockParseError::UnknownError(block.to_vec())))
+        }
+    }
+
+    pub fn get_raw(&self, key: &Cid) -> Option<&'a [u8]> {
+        self.index.get(key).map(|range| &self.raw[range.clone()])
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = &'a Cid> {
+        self.index.keys()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_commit_blocks() {
+        let post = serde_ipld_dagcbor::to_vec(&serde_json::json!({
+            "$type": "app.bsky.feed.post",
+            "text": "으어어",
+            "createdAt": "2024-01-01T00:00:00.000Z",
+        }))
+        .unwrap();
+        let other = serde_ipld_dagcbor::to_vec(&serde_json::json!({ "$type": 1 })).unwrap();
+        let post_cid = crate::atproto_subscription::mock_relay::cid_for(&post);
+        let other_cid = crate::atproto_subscription::mock_relay::cid_for(&other);
+        let raw = CommitRawBlocks::from_blocks(
+            &[post_cid],
+            [(&post_cid, post.as_slice()), (&other_cid, other.as_slice())],
+        );
+
+        let blocks = raw.parse().unwrap();
+        assert_eq!(blocks.keys().count(), 2);
+        assert_eq!(blocks.get_raw(&other_cid), Some(other.as_slice()));
+        let Some(Ok(Record::Post(post))) = blocks.get(&post_cid) else {
+            panic!("expected post");
+        };
+        assert_eq!(post.text, "으어어");
+        assert!(matches!(
+            blocks.get(&other_cid),
+            Some(Err(CommitBlockParseError::InvalidParseTargetType(_)))
+        ));
+        assert!(blocks.get(&Cid::default()).is_none());
+
+        // Clones share the blocks.
+        let clone = raw.clone();
+        assert_eq!(clone.raw().as_ptr(), raw.raw().as_ptr());
+        assert_eq!(clone.parse().unwrap().keys().count(), 2);
+
+        let mut truncated = raw.raw().to_vec();
+        truncated.pop();
+        let truncated = CommitRawBlocks::from(serde_bytes::ByteBuf::from(truncated));
+        assert!(matches!(truncated.parse(), Err(CarIndexError::Truncated)));
+    }
+}
@@ -0,0 +1,324 @@
+//! Values of the atproto data model held as `serde_json::Value`, in [the JSON form](https://atproto.com/specs/data-model).
+//!
+//! DAG-CBOR marks CIDs with a tag, which serde can't keep while it buffers a value to find `$type` in it.
+//! Reading values through here turns CIDs into `{"$link": ...}` and bytes into `{"$bytes": ...}` instead,
+//! and writing them to a binary format turns them back.
+
+use std::{fmt, str::FromStr};
+
+use rs_car::Cid;
+use serde::{
+    de::{self, DeserializeOwned},
+    ser::{SerializeMap, SerializeSeq},
+    Deserialize, Serialize,
+};
+use serde_json::Value;
+
+/// Read any value of the data model, from DAG-CBOR or JSON alike.
+pub fn deserialize<'de, D: de::Deserializer<'de>>(deserializer: D) -> Result<Value, D::Error> {
+    Data::deserialize(deserializer).map(|data| data.0)
+}
+
+/// Write a value read by [`deserialize`], putting CIDs and bytes back for binary formats.
+pub fn serialize<S: serde::Serializer>(value: &Value, serializer: S) -> Result<S::Ok, S::Error> {
+    if serializer.is_human_readable() {
+        value.serialize(serializer)
+    } else {
+        DataRef(value).serialize(serializer)
+    }
+}
+
+/// Read a DAG-CBOR block.
+pub fn from_cbor(
+    bytes: &[u8],
+) -> Result<Value, serde_ipld_dagcbor::DecodeError<std::convert::Infallible>> {
+    serde_ipld_dagcbor::from_slice::<Data>(bytes).map(|data| data.0)
+}
+
+/// Write a DAG-CBOR block, which hashes to the CID the value was read with.
+pub fn to_cbor(
+    value: &Value,
+) -> Result<Vec<u8>, serde_ipld_dagcbor::EncodeError<std::collections::TryReserveError>> {
+    serde_ipld_dagcbor::to_vec(&DataRef(value))
+}
+
+/// `$type` of an object, which tells what the object is.
+pub fn type_of(value: &Value) -> Option<&str> {
+    value.get("$type")?.as_str()
+}
+
+pub fn from_value<T: DeserializeOwned, E: de::Error>(value: Value) -> Result<T, E> {
+    serde_json::from_value(value).map_err(E::custom)
+}
+
+/// Object standing for a CID, e.g. `{"$link": "bafy..."}`.
+fn link(value: &Value) -> Option<&str> {
+    match value.as_object()?.get("$link")? {
+        Value::String(link) if value.as_object()?.len() == 1 => Some(link),
+        _ => None,
+    }
+}
+
+/// Object standing for bytes, e.g. `{"$bytes": "base64"}`.
+fn bytes(value: &Value) -> Option<&str> {
+    match value.as_object()?.get("$bytes")? {
+        Value::String(bytes) if value.as_object()?.len() == 1 => Some(bytes),
+        _ => None,
+    }
+}
+
+struct Data(Value);
+
+impl<'de> Deserialize<'de> for Data {
+    fn deserialize<D: de::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_any(DataVisitor)
+    }
+}
+
+struct DataVisitor;
+
+impl<'de> de::Visitor<'de> for DataVisitor {
+    type Value = Data;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a value of the atproto data model")
+    }
+
+    fn visit_bool<E>(self, v: bool) -> Result<Data, E> {
+        Ok(Data(v.into()))
+    }
+
+    fn visit_i64<E>(self, v: i64) -> Result<Data, E> {
+        Ok(Data(v.into()))
+    }
+
+    fn visit_u64<E>(self, v: u64) -> Result<Data, E> {
+        Ok(Data(v.into()))
+    }
+
+    fn visit_f64<E>(self, v: f64) -> Result<Data, E> {
+        Ok(Data(v.into()))
+    }
+
+    fn visit_str<E>(self, v: &str) -> Result<Data, E> {
+        Ok(Data(v.into()))
+    }
+
+    fn visit_string<E>(self, v: String) -> Result<Data, E> {
+        Ok(Data(v.into()))
+    }
+
+    fn visit_bytes<E>(self, v: &[u8]) -> Result<Data, E> {
+        Ok(Data(
+            serde_json::json!({ "$bytes": multibase::Base::Base64.encode(v) }),
+        ))
+    }
+
+    fn visit_none<E>(self) -> Result<Data, E> {
+        Ok(Data(Value::Null))
+    }
+
+    fn visit_unit<E>(self) -> Result<Data, E> {
+        Ok(Data(Value::Null))
+    }
+
+    fn visit_some<D: de::Deserializer<'de>>(self, deserializer: D) -> Result<Data, D::Error> {
+        Data::deserialize(deserializer)
+    }
+
+    /// DAG-CBOR hands CIDs as a newtype struct of their bytes.
+    fn visit_newtype_struct<D: de::Deserializer<'de>>(
+        self,
+        deserializer: D,
+    ) -> Result<Data, D::Error> {
+        let cid = deserializer.deserialize_bytes(CidVisitor)?;
+        Ok(Data(serde_json::json!({ "$link": cid.to_string() })))
+    }
+
+    fn visit_seq<A: de::SeqAccess<'de>>(self, mut seq: A) -> Result<Data, A::Error> {
+        let mut ret = Vec::with_capacity(seq.size_hint().unwrap_or_default());
+        while let Some(Data(value)) = seq.next_element()? {
+            ret.push(value);
+        }
+        Ok(Data(Value::Array(ret)))
+    }
+
+    fn visit_map<A: de::MapAccess<'de>>(self, mut map: A) -> Result<Data, A::Error> {
+        let mut ret = serde_json::Map::new();
+        while let Some((key, Data(value))) = map.next_entry::<String, Data>()? {
+            ret.insert(key, value);
+        }
+        Ok(Data(Value::Object(ret)))
+    }
+}
+
+struct CidVisitor;
+
+impl<'de> de::Visitor<'de> for CidVisitor {
+    type Value = Cid;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("bytes of a CID")
+    }
+
+    fn visit_bytes<E: de::Error>(self, v: &[u8]) -> Result<Cid, E> {
+        Cid::try_from(v).map_err(E::custom)
+    }
+}
+
+struct DataRef<'a>(&'a Value);
+
+impl Serialize for DataRef<'_> {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        use serde::ser::Error;
+
+        if let Some(link) = link(self.0) {
+            return Cid::from_str(link)
+                .map_err(S::Error::custom)?
+                .serialize(serializer);
+        }
+        if let Some(bytes) = bytes(self.0) {
+            let bytes = multibase::Base::Base64
+                .decode(bytes)
+                .map_err(S::Error::custom)?;
+            return serializer.serialize_bytes(&bytes);
+        }
+
+        match self.0 {
+            Value::Array(values) => {
+                let mut seq = serializer.serialize_seq(Some(values.len()))?;
+                for value in values {
+                    seq.serialize_element(&DataRef(value))?;
+                }
+                seq.end()
+            }
+            Value::Object(values) => {
+                // Keys of DAG-CBOR go shorter first.
+                let mut entries = values.iter().collect::<Vec<_>>();
+                entries.sort_by(|(a, _), (b, _)| a.len().cmp(&b.len()).then_with(|| a.cmp(b)));
+
+                let mut map = serializer.serialize_map(Some(entries.len()))?;
+                for (key, value) in entries {
+                    map.serialize_entry(key, &DataRef(value))?;
+                }
+                map.end()
+            }
+            value => value.serialize(serializer),
+        }
+    }
+}
+
+/// `cid-link` fields as [`Cid`], written as `{"$link": ...}` to JSON.
+pub mod cid_link {
+    use super::*;
+
+    pub fn serialize<S: serde::Serializer>(cid: &Cid, serializer: S) -> Result<S::Ok, S::Error> {
+        if serializer.is_human_readable() {
+            serde_json::json!({ "$link": cid.to_string() }).serialize(serializer)
+        } else {
+            cid.serialize(serializer)
+        }
+    }
+
+    pub fn deserialize<'de, D: de::Deserializer<'de>>(deserializer: D) -> Result<Cid, D::Error> {
+        let value = super::deserialize(deserializer)?;
+        let link = link(&value)
+            .ok_or_else(|| de::Error::custom(format!("Expected CID link, found {value}")))?;
+        Cid::from_str(link).map_err(de::Error::custom)
+    }
+
+    /// Optional `cid-link` fields.
+    pub mod option {
+        use super::*;
+
+        pub fn serialize<S: serde::Serializer>(
+            cid: &Option<Cid>,
+            serializer: S,
+        ) -> Result<S::Ok, S::Error> {
+            match cid {
+                Some(cid) => serializer.serialize_some(&LinkRef(cid)),
+                None => serializer.serialize_none(),
+            }
+        }
+
+        pub fn deserialize<'de, D: de::Deserializer<'de>>(
+            deserializer: D,
+        ) -> Result<Option<Cid>, D::Error> {
+            Ok(Option::<Link>::deserialize(deserializer)?.map(|link| link.0))
+        }
+    }
+
+    /// Arrays of `cid-link`.
+    pub mod vec {
+        use super::*;
+
+        pub fn serialize<S: serde::Serializer>(
+            cids: &[Cid],
+            serializer: S,
+        ) -> Result<S::Ok, S::Error> {
+            serializer.collect_seq(cids.iter().map(LinkRef))
+        }
+
+        pub fn deserialize<'de, D: de::Deserializer<'de>>(
+            deserializer: D,
+        ) -> Result<Vec<Cid>, D::Error> {
+            Ok(Vec::<Link>::deserialize(deserializer)?
+                .into_iter()
+                .map(|link| link.0)
+                .collect())
+        }
+    }
+
+    struct Link(Cid);
+
+    impl<'de> Deserialize<'de> for Link {
+        fn deserialize<D: de::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+            deserialize(deserializer).map(Link)
+        }
+    }
+
+    struct LinkRef<'a>(&'a Cid);
+
+    impl Serialize for LinkRef<'_> {
+        fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            serialize(self.0, serializer)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cid_and_bytes() {
+        let cid =
+            Cid::from_str("bafkreibme22gw2h7y2h7tg2fhqotaqjucnbc24deqo72b6mkl2egezxhvy").unwrap();
+        #[derive(serde::Serialize)]
+        struct Raw {
+            cid: Cid,
+            #[serde(with = "serde_bytes")]
+            bytes: Vec<u8>,
+        }
+        let cbor = serde_ipld_dagcbor::to_vec(&Raw {
+            cid,
+            bytes: vec![0, 1, 2],
+        })
+        .unwrap();
+
+        let value = from_cbor(&cbor).unwrap();
+        assert_eq!(
+            value,
+            serde_json::json!({
+                "cid": { "$link": cid.to_string() },
+                "bytes": { "$bytes": "AAEC" },
+            })
+        );
+
+        #[derive(serde::Serialize)]
+        struct Wrapped(#[serde(serialize_with = "serialize")] Value);
+        let encoded = serde_ipld_dagcbor::to_vec(&Wrapped(value.clone())).unwrap();
+        assert_eq!(encoded, cbor);
+        assert_eq!(serde_json::to_value(Wrapped(value.clone())).unwrap(), value);
+    }
+}
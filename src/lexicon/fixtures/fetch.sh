@@ -0,0 +1,35 @@
+#!/bin/sh
+# Save a post as `com.atproto.sync.getRecord` returns it, a CAR file of the post and the proof of it,
+# as <name>.car here. The tests of `src/lexicon.rs` check each of them.
+#
+#     src/lexicon/fixtures/fetch.sh at://<did:plc>/app.bsky.feed.post/<rkey> <name>
+set -eu
+
+uri=${1:-}
+name=${2:-}
+case "$uri" in
+at://did:plc:*/app.bsky.feed.post/*) ;;
+*)
+	echo "usage: $0 at://<did:plc>/app.bsky.feed.post/<rkey> <name>" >&2
+	exit 1
+	;;
+esac
+if [ -z "$name" ]; then
+	echo "usage: $0 at://<did:plc>/app.bsky.feed.post/<rkey> <name>" >&2
+	exit 1
+fi
+
+path=${uri#at://}
+did=${path%%/*}
+rkey=${path##*/}
+dir=$(cd "$(dirname "$0")" && pwd)
+
+pds=$(curl -fsSL "https://plc.directory/$did" |
+	sed -n 's/.*"type":"AtprotoPersonalDataServer","serviceEndpoint":"\([^"]*\)".*/\1/p')
+if [ -z "$pds" ]; then
+	echo "No PDS of $did" >&2
+	exit 1
+fi
+
+curl -fsSL -o "$dir/$name.car" \
+	"$pds/xrpc/com.atproto.sync.getRecord?did=$did&collection=app.bsky.feed.post&rkey=$rkey"
@@ -0,0 +1,456 @@
+//! Identifiers of atproto, validated by the syntax of [the specs](https://atproto.com/specs/overview).
+
+use std::{fmt::Display, str::FromStr};
+
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum IdentifierError {
+    #[error("Invalid DID - {0}")]
+    Did(String),
+    #[error("Invalid handle - {0}")]
+    Handle(String),
+    #[error("Invalid NSID - {0}")]
+    Nsid(String),
+    #[error("Invalid TID - {0}")]
+    Tid(String),
+    #[error("Invalid record key - {0}")]
+    RecordKey(String),
+}
+
+macro_rules! identifier {
+    ($(#[$meta:meta])* $name:ident, $error:ident) => {
+        $(#[$meta])*
+        #[derive(
+            Debug,
+            Clone,
+            PartialEq,
+            Eq,
+            Hash,
+            PartialOrd,
+            Ord,
+            serde_with::DeserializeFromStr,
+            serde_with::SerializeDisplay,
+        )]
+        pub struct $name(String);
+
+        impl $name {
+            pub fn as_str(&self) -> &str {
+                &self.0
+            }
+        }
+
+        impl AsRef<str> for $name {
+            fn as_ref(&self) -> &str {
+                &self.0
+            }
+        }
+
+        impl Display for $name {
+            fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+                f.write_str(&self.0)
+            }
+        }
+
+        impl FromStr for $name {
+            type Err = IdentifierError;
+
+            fn from_str(s: &str) -> Result<Self, Self::Err> {
+                Self::validate(s)
+                    .map(Self)
+                    .ok_or_else(|| IdentifierError::$error(s.to_string()))
+            }
+        }
+
+        impl PartialEq<str> for $name {
+            fn eq(&self, other: &str) -> bool {
+                self.0 == other
+            }
+        }
+
+        impl PartialEq<&str> for $name {
+            fn eq(&self, other: &&str) -> bool {
+                self.0 == *other
+            }
+        }
+
+        impl From<$name> for String {
+            fn from(value: $name) -> Self {
+                value.0
+            }
+        }
+    };
+}
+
+identifier!(
+    /// `did:{method}:{identifier}`. Only the syntax is checked, not whether the method is supported.
+    Did,
+    Did
+);
+
+identifier!(
+    /// Domain name of an account, kept in lowercase as handles are case-insensitive.
+    Handle,
+    Handle
+);
+
+identifier!(
+    /// Name of a lexicon, a reversed domain name followed by a name segment such as `app.bsky.feed.post`.
+    Nsid,
+    Nsid
+);
+
+identifier!(
+    /// Timestamp identifier, the usual record key of records made by clients.
+    Tid,
+    Tid
+);
+
+identifier!(
+    /// Key of a record in a collection.
+    RecordKey,
+    RecordKey
+);
+
+/// Label of a domain name, which starts and ends with an alphanumeric character.
+fn is_domain_label(label: &str) -> bool {
+    (1..=63).contains(&label.len())
+        && label
+            .bytes()
+            .all(|c| c.is_ascii_alphanumeric() || c == b'-')
+        && !label.starts_with('-')
+        && !label.ends_with('-')
+}
+
+impl Did {
+    const MAX_LEN: usize = 2048;
+
+    fn validate(s: &str) -> Option<String> {
+        let (method, id) = s.strip_prefix("did:")?.split_once(':')?;
+        let mut id_bytes = id.bytes();
+        while let Some(c) = id_bytes.next() {
+            match c {
+                b'%' => {
+                    let hex = [id_bytes.next()?, id_bytes.next()?];
+                    if !hex.iter().all(u8::is_ascii_hexdigit) {
+                        return None;
+                    }
+                }
+                c if c.is_ascii_alphanumeric() || b"._:-".contains(&c) => {}
+                _ => return None,
+            }
+        }
+
+        (s.len() <= Self::MAX_LEN
+            && !method.is_empty()
+            && method.bytes().all(|c| c.is_ascii_lowercase())
+            && !id.is_empty()
+            && !id.ends_with(':'))
+        .then(|| s.to_string())
+    }
+
+    pub fn method(&self) -> &str {
+        self.0[4..].split_once(':').map_or("", |(method, _)| method)
+    }
+}
+
+impl Handle {
+    const MAX_LEN: usize = 253;
+
+    fn validate(s: &str) -> Option<String> {
+        let labels = s.split('.').collect::<Vec<_>>();
+        let tld = labels.last()?;
+
+        (s.len() <= Self::MAX_LEN
+            && labels.len() >= 2
+            && labels.iter().all(|label| is_domain_label(label))
+            && tld.starts_with(|c: char| c.is_ascii_alphabetic()))
+        .then(|| s.to_ascii_lowercase())
+    }
+}
+
+impl Nsid {
+    const MAX_LEN: usize = 317;
+    const MAX_AUTHORITY_LEN: usize = 253;
+
+    fn validate(s: &str) -> Option<String> {
+        let (authority, name) = s.rsplit_once('.')?;
+        let segments = authority.split('.').collect::<Vec<_>>();
+
+        (s.len() <= Self::MAX_LEN
+            && authority.len() <= Self::MAX_AUTHORITY_LEN
+            && segments.len() >= 2
+            && segments.iter().all(|segment| is_domain_label(segment))
+            && !segments[0].starts_with(|c: char| c.is_ascii_digit())
+            && (1..=63).contains(&name.len())
+            && name.bytes().all(|c| c.is_ascii_alphanumeric())
+            && name.starts_with(|c: char| c.is_ascii_alphabetic()))
+        .then(|| s.to_string())
+    }
+
+    /// Domain part, in the reversed order as written in the NSID.
+    pub fn authority(&self) -> &str {
+        self.0
+            .rsplit_once('.')
+            .map_or("", |(authority, _)| authority)
+    }
+
+    pub fn name(&self) -> &str {
+        self.0.rsplit_once('.').map_or("", |(_, name)| name)
+    }
+}
+
+impl Tid {
+    const LEN: usize = 13;
+    /// Base32 in the order of ASCII, so TIDs sort as their timestamps do.
+    const ALPHABET: &'static [u8; 32] = b"234567abcdefghijklmnopqrstuvwxyz";
+    const CLOCK_ID_BITS: u32 = 10;
+
+    fn validate(s: &str) -> Option<String> {
+        // The top bit of the 64-bit value is always 0.
+        (s.len() == Self::LEN
+            && s.bytes().all(|c| Self::ALPHABET.contains(&c))
+            && b"234567abcdefghij".contains(&s.as_bytes()[0]))
+        .then(|| s.to_string())
+    }
+
+    /// Build from microseconds since the UNIX epoch and a clock identifier, of which only the low 10 bits are taken.
+    pub fn from_parts(timestamp_micros: u64, clock_id: u16) -> Self {
+        let value = ((timestamp_micros & ((1 << 53) - 1)) << Self::CLOCK_ID_BITS)
+            | (clock_id as u64 & ((1 << Self::CLOCK_ID_BITS) - 1));
+        let ret = (0..Self::LEN)
+            .rev()
+            .map(|i| Self::ALPHABET[((value >> (i * 5)) & 0x1f) as usize] as char)
+            .collect();
+
+        Self(ret)
+    }
+
+    fn value(&self) -> u64 {
+        self.0.bytes().fold(0, |acc, c| {
+            let digit = Self::ALPHABET
+                .iter()
+                .position(|a| *a == c)
+                .unwrap_or_default();
+            (acc << 5) | digit as u64
+        })
+    }
+
+    pub fn timestamp_micros(&self) -> u64 {
+        self.value() >> Self::CLOCK_ID_BITS
+    }
+
+    pub fn clock_id(&self) -> u16 {
+        (self.value() & ((1 << Self::CLOCK_ID_BITS) - 1)) as u16
+    }
+}
+
+impl RecordKey {
+    const MAX_LEN: usize = 512;
+
+    fn validate(s: &str) -> Option<String> {
+        ((1..=Self::MAX_LEN).contains(&s.len())
+            && s.bytes()
+                .all(|c| c.is_ascii_alphanumeric() || b"._:~-".contains(&c))
+            && s != "."
+            && s != "..")
+            .then(|| s.to_string())
+    }
+}
+
+impl From<Tid> for RecordKey {
+    fn from(value: Tid) -> Self {
+        Self(value.0)
+    }
+}
+
+/// Account in the authority of an [`super::AtUri`], either by DID or by handle.
+#[derive(
+    Debug, Clone, PartialEq, Eq, Hash, serde_with::DeserializeFromStr, serde_with::SerializeDisplay,
+)]
+pub enum AtIdentifier {
+    Did(Did),
+    Handle(Handle),
+}
+
+impl Display for AtIdentifier {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AtIdentifier::Did(did) => did.fmt(f),
+            AtIdentifier::Handle(handle) => handle.fmt(f),
+        }
+    }
+}
+
+impl FromStr for AtIdentifier {
+    type Err = IdentifierError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.starts_with("did:") {
+            s.parse().map(AtIdentifier::Did)
+        } else {
+            s.parse().map(AtIdentifier::Handle)
+        }
+    }
+}
+
+impl From<Did> for AtIdentifier {
+    fn from(value: Did) -> Self {
+        AtIdentifier::Did(value)
+    }
+}
+
+impl From<Handle> for AtIdentifier {
+    fn from(value: Handle) -> Self {
+        AtIdentifier::Handle(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+
+    use super::*;
+
+    #[test]
+    fn test_did() {
+        for valid in [
+            "did:plc:eygmaihciaxprqvxpfvl6flk",
+            "did:web:example.com",
+            "did:web:localhost%3A3000",
+            "did:method:a:b:c",
+        ] {
+            assert_eq!(valid.parse::<Did>().unwrap(), valid);
+        }
+        for invalid in [
+            "did:plc:",
+            "did::abc",
+            "did:PLC:abc",
+            "did:web:example.com:",
+            "did:web:bad%3",
+            "did:web:bad%zz",
+            "did:web:white space",
+            "DID:plc:abc",
+            "plc:abc",
+        ] {
+            assert!(invalid.parse::<Did>().is_err(), "{invalid}");
+        }
+        assert_eq!(
+            "did:web:example.com".parse::<Did>().unwrap().method(),
+            "web"
+        );
+    }
+
+    #[test]
+    fn test_handle() {
+        assert_eq!(
+            "Alice.BSKY.social".parse::<Handle>().unwrap(),
+            "alice.bsky.social"
+        );
+        assert!("xn--ls8h.test".parse::<Handle>().is_ok());
+        assert!("a.b-c.io".parse::<Handle>().is_ok());
+        for invalid in [
+            "alice",
+            "alice.",
+            ".alice.test",
+            "-alice.test",
+            "alice-.test",
+            "alice.123",
+            "al_ice.test",
+            "a..test",
+        ] {
+            assert!(invalid.parse::<Handle>().is_err(), "{invalid}");
+        }
+        assert!(format!("{}.test", "a".repeat(64))
+            .parse::<Handle>()
+            .is_err());
+    }
+
+    #[test]
+    fn test_nsid() {
+        let nsid = "app.bsky.feed.post".parse::<Nsid>().unwrap();
+        assert_eq!(nsid.authority(), "app.bsky.feed");
+        assert_eq!(nsid.name(), "post");
+        assert!("com.example.fooBar2".parse::<Nsid>().is_ok());
+        for invalid in [
+            "app.bsky",
+            "app.bsky.feed.",
+            "1app.bsky.post",
+            "app.bsky.feed.2post",
+            "app.bsky.feed.po-st",
+            "app.-bsky.post",
+            "app..post",
+        ] {
+            assert!(invalid.parse::<Nsid>().is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn test_tid() {
+        let tid = "3l3qo2vuowo2b".parse::<Tid>().unwrap();
+        assert_eq!(Tid::from_parts(tid.timestamp_micros(), tid.clock_id()), tid);
+        assert_eq!(Tid::from_parts(0, 0), "2222222222222");
+        for invalid in [
+            "3l3qo2vuowo2",
+            "3l3qo2vuowo2b2",
+            "kl3qo2vuowo2b",
+            "3l3qo2vuowo21",
+        ] {
+            assert!(invalid.parse::<Tid>().is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn test_record_key() {
+        for valid in ["self", "3l3qo2vuowo2b", "a:b~c_d.e-f", "..."] {
+            assert!(valid.parse::<RecordKey>().is_ok(), "{valid}");
+        }
+        for invalid in ["", ".", "..", "a/b", "a b", "a#b"] {
+            assert!(invalid.parse::<RecordKey>().is_err(), "{invalid}");
+        }
+        assert!("a".repeat(513).parse::<RecordKey>().is_err());
+    }
+
+    #[test]
+    fn test_at_identifier() {
+        assert!(matches!(
+            "did:plc:abc".parse::<AtIdentifier>(),
+            Ok(AtIdentifier::Did(_))
+        ));
+        assert!(matches!(
+            "alice.test".parse::<AtIdentifier>(),
+            Ok(AtIdentifier::Handle(_))
+        ));
+        assert!("did:plc".parse::<AtIdentifier>().is_err());
+    }
+
+    proptest! {
+        #[test]
+        fn test_tid_parts(timestamp in 0u64..(1 << 53), clock_id in 0u16..1024) {
+            let tid = Tid::from_parts(timestamp, clock_id);
+            prop_assert_eq!(tid.to_string().parse::<Tid>().unwrap(), tid.clone());
+            prop_assert_eq!(tid.timestamp_micros(), timestamp);
+            prop_assert_eq!(tid.clock_id(), clock_id);
+        }
+
+        #[test]
+        fn test_tid_order(a in 0u64..(1 << 53), b in 0u64..(1 << 53)) {
+            prop_assert_eq!(Tid::from_parts(a, 0).cmp(&Tid::from_parts(b, 0)), a.cmp(&b));
+        }
+
+        /// Whatever is accepted is shown as it is taken in, except for the case of handles.
+        #[test]
+        fn test_parse_display(s in "[a-zA-Z0-9.:%_~-]{0,40}") {
+            if let Ok(v) = s.parse::<Did>() {
+                prop_assert_eq!(v.to_string(), s.clone());
+            }
+            if let Ok(v) = s.parse::<Handle>() {
+                prop_assert_eq!(v.to_string(), s.to_ascii_lowercase());
+            }
+            if let Ok(v) = s.parse::<Nsid>() {
+                prop_assert_eq!(v.to_string(), s.clone());
+            }
+            if let Ok(v) = s.parse::<RecordKey>() {
+                prop_assert_eq!(v.to_string(), s.clone());
+            }
+        }
+    }
+}
@@ -1,2 +1,4 @@
 pub mod atproto_subscription;
+pub mod identity;
 pub mod lexicon;
+pub mod xrpc;
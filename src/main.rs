@@ -1,32 +1,82 @@
 use std::{
     io::{Cursor, Read},
     net::ToSocketAddrs,
+    path::PathBuf,
     sync::Arc,
+    time::Duration,
 };
 
 use anyhow::{anyhow, Context};
 use axum::Extension;
-use clap::Parser;
-use config::Config;
+use clap::{Parser, Subcommand};
+use config::{Config, LEGACY_FEED};
 use log::{error, info};
 
 mod algos;
 mod config;
 mod data;
+mod matcher;
 mod routes;
 mod subscription;
 
 use eueoeo_feed::*;
 
-use atproto_subscription::FirehoseSubscription;
-use subscription::{ServiceSubscriptionHandler, UserProfile};
+use atproto_subscription::{
+    capture::{self, CaptureReader, CaptureWriter},
+    continuity, cursor,
+    dead_letter::{self, DeadLetterKind},
+    hosts, jetstream,
+    pipeline::PipelinePolicy,
+    too_big, verify, FailoverPolicy, FirehoseSubscription, FlushPolicy, KeepalivePolicy,
+    ReconnectPolicy, Source,
+};
+use config::SubscriptionSource;
+use subscription::ServiceSubscriptionHandler;
+
+/// How often posts past the retention of their feeds, or of feeds no longer declared, are pruned.
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60 * 60);
 
 #[derive(Parser, Debug)]
 enum Args {
     Run,
+    /// Run while appending every frame received from the service to a capture file
+    Record {
+        output: PathBuf,
+    },
+    /// Feed frames of a capture file to the subscription handler and exit
+    Replay {
+        input: PathBuf,
+        /// Keep the original gaps between frames instead of replaying at full speed
+        #[arg(long)]
+        realtime: bool,
+    },
+    /// Inspect and reprocess events which failed to be processed
+    Deadletter {
+        #[command(subcommand)]
+        command: DeadLetterCommand,
+    },
     Login,
 }
 
+#[derive(Subcommand, Debug)]
+enum DeadLetterCommand {
+    /// Print stored dead letters
+    List,
+    /// Run dead letters through the handler again and remove the handled ones
+    Retry {
+        /// Dead letters to retry. Every one of them when omitted
+        ids: Vec<i64>,
+    },
+    /// Remove dead letters
+    Purge {
+        /// Dead letters to remove. Every failed one when omitted
+        ids: Vec<i64>,
+        /// Remove commits too big waiting for their records to be fetched as well, when ids are omitted
+        #[arg(long)]
+        too_big: bool,
+    },
+}
+
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     env_logger::init();
@@ -47,24 +97,192 @@ async fn main() -> anyhow::Result<()> {
     let db_pool = sqlx::SqlitePool::connect(&config.sqlite_db).await?;
     sqlx::migrate!().run(&db_pool).await?;
     info!("DB migration completed");
+    let adopted = algos::adopt(&db_pool).await?;
+    if adopted > 0 {
+        info!("{adopted} posts indexed before feeds are put in {LEGACY_FEED}");
+    }
 
     if let Args::Login = args {
         // TODO: login and save key
         return Ok(());
     }
 
-    let (sender, receiver) = crossbeam::channel::bounded::<UserProfile>(30);
     let (stop_sender, mut stop_receiver) = tokio::sync::watch::channel(false);
     let stop_sender = Arc::new(stop_sender);
 
-    let subscription = FirehoseSubscription::new(
-        db_pool.clone(),
-        config.subscription_endpoint.clone(),
-        ServiceSubscriptionHandler::new(db_pool.clone(), sender),
-        stop_sender.clone(),
-    )
-    .await?;
-    let subscription_join = subscription.run()?;
+    let source = match config.subscription_source {
+        SubscriptionSource::Firehose => Source::Firehose,
+        SubscriptionSource::Jetstream => {
+            let zstd_dictionary = config
+                .jetstream_zstd_dictionary
+                .as_ref()
+                .map(|path| {
+                    std::fs::read(path)
+                        .with_context(|| format!("Failed to read zstd dictionary - {path}"))
+                })
+                .transpose()?;
+            Source::Jetstream(jetstream::Options::new(
+                config.jetstream_wanted_collections.clone(),
+                zstd_dictionary.as_deref(),
+            ))
+        }
+    };
+    let builder =
+        FirehoseSubscription::builder(ServiceSubscriptionHandler::new(config.feeds.clone()))
+            .database(db_pool.clone())
+            .source(source)
+            .reconnect_policy(ReconnectPolicy {
+                initial_delay: config.subscription_reconnect_delay.to_std()?,
+                max_delay: config.subscription_reconnect_max_delay.to_std()?,
+                reset_after: config.subscription_reconnect_reset_after.to_std()?,
+            })
+            .flush_policy(FlushPolicy {
+                max_events: config.subscription_flush_events,
+                max_interval: config.subscription_flush_interval.to_std()?,
+            })
+            .stop_signal(stop_sender.clone());
+    let builder = if config.subscription_endpoints.is_empty() {
+        builder.endpoint(config.subscription_endpoint.clone())
+    } else {
+        builder.endpoints(
+            config.subscription_endpoints.clone(),
+            FailoverPolicy {
+                failover_after: config.subscription_failover_after,
+                return_interval: config.subscription_return_interval.to_std()?,
+            },
+        )
+    };
+    let builder = match &args {
+        // Frames of a capture are not where the service is at, so nothing the live subscription
+        // keeps is touched by them.
+        Args::Replay { .. } => builder
+            .cursor_store(cursor::MemoryCursorStore::default())
+            .continuity_store(continuity::MemoryContinuityStore::default())
+            .dead_letter_store(dead_letter::MemoryDeadLetterStore::default()),
+        _ => builder,
+    };
+    let subscription = builder
+        .build()?
+        .with_keepalive(KeepalivePolicy {
+            ping_interval: config.subscription_ping_interval.to_std()?,
+            idle_timeout: config.subscription_idle_timeout.to_std()?,
+        })
+        .with_pipeline(PipelinePolicy {
+            decode_workers: config.subscription_decode_workers,
+            queue_depth: config.subscription_queue_depth,
+        })
+        .with_error_policy(config.subscription_error_policy);
+    let resolver: Arc<dyn identity::DidResolver> = Arc::new(
+        identity::CachedDidResolver::new(
+            identity::HttpDidResolver::new(&config.plc_directory)?,
+            config.did_cache_ttl.to_std()?,
+        )
+        .with_capacity(config.did_cache_capacity),
+    );
+    let subscription = if config.subscription_verify {
+        if config.subscription_source != SubscriptionSource::Firehose {
+            return Err(anyhow!(
+                "subscription_verify is supported only with the firehose source"
+            ));
+        }
+        subscription.with_verifier(verify::Verifier::new(resolver.clone()))
+    } else {
+        subscription
+    };
+    // Replay sends no requests to the PDSes of commits too big in the capture.
+    let replay = matches!(args, Args::Replay { .. });
+    if config.subscription_fetch_too_big
+        && config.subscription_source != SubscriptionSource::Firehose
+    {
+        return Err(anyhow!(
+            "subscription_fetch_too_big is supported only with the firehose source"
+        ));
+    }
+    let subscription = if config.subscription_fetch_too_big && !replay {
+        subscription.with_record_fetcher(too_big::RecordFetcher::new(
+            resolver.clone(),
+            too_big::FetchPolicy {
+                interval: config.too_big_fetch_interval.to_std()?,
+                max_retries: config.too_big_fetch_max_retries,
+                retry_delay: config.too_big_fetch_retry_delay.to_std()?,
+                ..Default::default()
+            },
+        )?)
+    } else {
+        subscription
+    };
+
+    let subscription = match &args {
+        Args::Record { output } => subscription.with_capture(CaptureWriter::open(output)?),
+        Args::Replay { input, realtime } => {
+            let mut subscription = subscription;
+            subscription
+                .replay(capture::frames(CaptureReader::open(input)?, *realtime))
+                .await?;
+            info!("Replay completed");
+            return Ok(());
+        }
+        Args::Deadletter { command } => {
+            let store = subscription.dead_letter_store();
+            match command {
+                DeadLetterCommand::List => {
+                    for letter in store.list().await? {
+                        println!(
+                            "#{} ({}) seq: {}, repo: {}, at: {}\n  {}",
+                            letter.id,
+                            letter.kind,
+                            letter
+                                .seq
+                                .map_or_else(|| "-".to_string(), |v| v.to_string()),
+                            letter.repo.as_deref().unwrap_or("-"),
+                            letter.created_at,
+                            letter.error
+                        );
+                    }
+                }
+                DeadLetterCommand::Retry { ids } => {
+                    let (handled, failed) = subscription
+                        .retry_dead_letters((!ids.is_empty()).then_some(ids.as_slice()))
+                        .await?;
+                    println!("{handled} handled, {failed} failed again");
+                }
+                DeadLetterCommand::Purge { ids, too_big } if ids.is_empty() => {
+                    let mut purged = store.purge(DeadLetterKind::Failed).await?;
+                    if *too_big {
+                        purged += store.purge(DeadLetterKind::TooBig).await?;
+                    }
+                    println!("{purged} purged");
+                }
+                DeadLetterCommand::Purge { ids, .. } => {
+                    let mut conn = db_pool.acquire().await?;
+                    for id in ids {
+                        if !store.delete(&mut conn, *id).await? {
+                            error!("Dead letter #{id} does not exist");
+                        }
+                    }
+                }
+            }
+            return Ok(());
+        }
+        _ => subscription,
+    };
+    let subscription_metrics = subscription.metrics();
+    let mut subscription_hosts = config.subscription_hosts.clone();
+    if let Some(seed) = &config.subscription_host_seed {
+        let discovered = hosts::list_hosts(seed).await?;
+        info!("{} hosts are discovered from {seed}", discovered.len());
+        subscription_hosts.extend(discovered);
+    }
+    let subscription_join = if subscription_hosts.is_empty() {
+        subscription.run()?
+    } else {
+        if config.subscription_source != SubscriptionSource::Firehose {
+            return Err(anyhow!(
+                "subscription_hosts is supported only with the firehose source"
+            ));
+        }
+        subscription.run_hosts(subscription_hosts, resolver)?
+    };
 
     let listener = tokio::net::TcpListener::bind(
         &((config.listen_host.as_str(), config.port)
@@ -75,12 +293,29 @@ async fn main() -> anyhow::Result<()> {
     )
     .await?;
 
-    let algos = algos::create();
+    let algos = algos::create(&config.feeds);
+    let prune_db = db_pool.clone();
+    let prune_feeds = config.feeds.clone();
+    let mut prune_stop_receiver = stop_sender.subscribe();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(PRUNE_INTERVAL);
+        loop {
+            tokio::select! {
+                _ = interval.tick() => (),
+                _ = prune_stop_receiver.wait_for(|v| *v) => break,
+            }
+            match algos::prune(&prune_db, &prune_feeds).await {
+                Ok(0) => (),
+                Ok(pruned) => info!("{pruned} posts are pruned from feeds"),
+                Err(e) => error!("Failed to prune feeds - {e:?}"),
+            }
+        }
+    });
 
     let router = routes::create_router(&config, algos);
     let app = router
         .layer(Extension(db_pool))
-        .layer(Extension(receiver))
+        .layer(Extension(subscription_metrics))
         .layer(Extension(Arc::new(config)));
     let server = axum::serve(listener, app.into_make_service());
 
@@ -0,0 +1,388 @@
+//! Rules telling which posts go to a feed by their text, configured as `matchers` of each of `feeds`
+//! in `config.json`.
+//!
+//! ```json
+//! "matchers": [
+//!     { "type": "exact", "text": "으어어" },
+//!     { "type": "normalized", "text": "으어어", "form": "nfkc" },
+//!     { "type": "regex", "pattern": "^으어어+\\s*[ㅠㅜ]*$" },
+//!     { "type": "hangul", "pattern": "으+어{2,}" }
+//! ]
+//! ```
+//!
+//! A post goes to a feed when any of the rules of the feed matches its text.
+
+use std::sync::LazyLock;
+
+use regex::Regex;
+use unicode_normalization::UnicodeNormalization;
+
+const HANGUL_SYLLABLES: std::ops::RangeInclusive<char> = '\u{AC00}'..='\u{D7A3}';
+/// Vowels of syllables come in this order both as compatibility jamo and as conjoining jamo.
+const COMPAT_VOWEL_BASE: u32 = 0x314F;
+const CONJOINING_VOWEL_BASE: u32 = 0x1161;
+
+static PUNCTUATION: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"[\p{P}\p{S}]").expect("Punctuation pattern should be valid"));
+
+#[derive(Debug, thiserror::Error)]
+pub enum MatcherError {
+    #[error(transparent)]
+    InvalidRegex(#[from] regex::Error),
+    #[error("Invalid Hangul pattern {pattern:?} at {position} - {reason}")]
+    InvalidHangulPattern {
+        pattern: String,
+        position: usize,
+        reason: &'static str,
+    },
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum NormalizationForm {
+    Nfc,
+    #[default]
+    Nfkc,
+}
+
+/// How text is normalized before it is compared.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Deserialize)]
+pub struct Normalization {
+    #[serde(default)]
+    pub form: NormalizationForm,
+    #[serde(default = "default_true")]
+    pub strip_whitespace: bool,
+    /// Strip punctuation and symbols, e.g. `…`, `~` and emoji.
+    #[serde(default = "default_true")]
+    pub strip_punctuation: bool,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+impl Normalization {
+    pub fn apply(&self, text: &str) -> String {
+        let text: String = match self.form {
+            NormalizationForm::Nfc => text.nfc().collect(),
+            NormalizationForm::Nfkc => text.nfkc().collect(),
+        };
+        let text = if self.strip_punctuation {
+            PUNCTUATION.replace_all(&text, "").into_owned()
+        } else {
+            text
+        };
+        if self.strip_whitespace {
+            text.chars().filter(|c| !c.is_whitespace()).collect()
+        } else {
+            text
+        }
+    }
+}
+
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+enum MatcherConfig {
+    Exact {
+        text: String,
+    },
+    Normalized {
+        text: String,
+        #[serde(flatten)]
+        normalization: Normalization,
+    },
+    Regex {
+        pattern: String,
+    },
+    Hangul {
+        pattern: String,
+    },
+}
+
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(try_from = "MatcherConfig")]
+pub enum Matcher {
+    /// Text as it is.
+    Exact(String),
+    /// Text normalized the same way as `text`, which is kept normalized.
+    Normalized {
+        text: String,
+        normalization: Normalization,
+    },
+    /// Text in NFC matched anywhere by the pattern, unless the pattern is anchored.
+    Regex(Regex),
+    /// Text in NFC without whitespace and punctuation, matched as a whole by a pattern of
+    /// syllables compiled by [`Matcher::hangul`].
+    Hangul(Regex),
+}
+
+impl TryFrom<MatcherConfig> for Matcher {
+    type Error = MatcherError;
+
+    fn try_from(config: MatcherConfig) -> Result<Self, Self::Error> {
+        Ok(match config {
+            MatcherConfig::Exact { text } => Matcher::Exact(text),
+            MatcherConfig::Normalized {
+                text,
+                normalization,
+            } => Matcher::Normalized {
+                text: normalization.apply(&text),
+                normalization,
+            },
+            MatcherConfig::Regex { pattern } => Matcher::Regex(Regex::new(&pattern)?),
+            MatcherConfig::Hangul { pattern } => Matcher::hangul(&pattern)?,
+        })
+    }
+}
+
+impl Matcher {
+    /// Rules of the feed unless configured otherwise.
+    pub fn defaults() -> Vec<Matcher> {
+        vec![Matcher::Exact("으어어".to_string())]
+    }
+
+    /// Pattern of characters each followed by an optional quantifier, `*`, `+`, `?`, `{n}`, `{n,}` or `{n,m}`.
+    ///
+    /// A syllable without a final consonant may also be drawn out by its vowel,
+    /// e.g. `어{2,}` takes `어어` and `어ㅓㅓ` alike, though not `ㅓㅓ`.
+    pub fn hangul(pattern: &str) -> Result<Self, MatcherError> {
+        let error = |position, reason| MatcherError::InvalidHangulPattern {
+            pattern: pattern.to_string(),
+            position,
+            reason,
+        };
+
+        let mut regex = "^".to_string();
+        let mut chars = pattern.char_indices().peekable();
+        while let Some((position, c)) = chars.next() {
+            if c.is_whitespace() || "*+?{},".contains(c) {
+                return Err(error(position, "expected a character"));
+            }
+            let (min, max) = match chars.peek().copied() {
+                Some((start, '{')) => {
+                    let end = start
+                        + pattern[start..]
+                            .find('}')
+                            .ok_or_else(|| error(start, "unclosed quantifier"))?;
+                    let quantifier = &pattern[start + 1..end];
+                    let parse = |n: &str| {
+                        n.parse::<u32>()
+                            .map_err(|_| error(start, "invalid count of quantifier"))
+                    };
+                    let (min, max) = match quantifier.split_once(',') {
+                        None => (parse(quantifier)?, Some(parse(quantifier)?)),
+                        Some((min, "")) => (parse(min)?, None),
+                        Some((min, max)) => (parse(min)?, Some(parse(max)?)),
+                    };
+                    if max.is_some_and(|max| max < min || max == 0) {
+                        return Err(error(start, "invalid range of quantifier"));
+                    }
+                    while chars.next_if(|(i, _)| *i <= end).is_some() {}
+                    (min, max)
+                }
+                Some((_, quantifier @ ('*' | '+' | '?'))) => {
+                    chars.next();
+                    match quantifier {
+                        '*' => (0, None),
+                        '+' => (1, None),
+                        _ => (0, Some(1)),
+                    }
+                }
+                _ => (1, Some(1)),
+            };
+
+            regex.push_str(&Self::repeat(c, min, max));
+        }
+        regex.push('$');
+
+        Ok(Matcher::Hangul(Regex::new(&regex)?))
+    }
+
+    /// Regex of `c` repeated `min` to `max` times, drawn out by its vowel if it is a syllable without a final consonant.
+    fn repeat(c: char, min: u32, max: Option<u32>) -> String {
+        let quantifier = |min: u32, max: Option<u32>| match max {
+            Some(max) => format!("{{{min},{max}}}"),
+            None => format!("{{{min},}}"),
+        };
+        let literal = regex::escape(&c.to_string());
+
+        let Some(vowel) = Self::vowel(c) else {
+            return format!("(?:{literal}){}", quantifier(min, max));
+        };
+        let vowels = format!(
+            "[{}{}]",
+            char::from_u32(COMPAT_VOWEL_BASE + vowel).unwrap(),
+            char::from_u32(CONJOINING_VOWEL_BASE + vowel).unwrap()
+        );
+        let drawn_out = format!(
+            "{literal}(?:{literal}|{vowels}){}",
+            quantifier(min.saturating_sub(1), max.map(|max| max - 1))
+        );
+        if min == 0 {
+            format!("(?:{drawn_out})?")
+        } else {
+            drawn_out
+        }
+    }
+
+    /// Index of the vowel of a syllable without a final consonant.
+    fn vowel(c: char) -> Option<u32> {
+        if !HANGUL_SYLLABLES.contains(&c) {
+            return None;
+        }
+        let index = c as u32 - *HANGUL_SYLLABLES.start() as u32;
+        index.is_multiple_of(28).then_some(index / 28 % 21)
+    }
+
+    pub fn is_match(&self, text: &str) -> bool {
+        match self {
+            Matcher::Exact(expected) => text == expected,
+            Matcher::Normalized {
+                text: expected,
+                normalization,
+            } => normalization.apply(text) == *expected,
+            Matcher::Regex(regex) => regex.is_match(&text.nfc().collect::<String>()),
+            Matcher::Hangul(regex) => regex.is_match(
+                &Normalization {
+                    form: NormalizationForm::Nfc,
+                    strip_whitespace: true,
+                    strip_punctuation: true,
+                }
+                .apply(text),
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matcher(config: serde_json::Value) -> Matcher {
+        serde_json::from_value(config).unwrap()
+    }
+
+    fn check(matcher: &Matcher, accepted: &[&str], rejected: &[&str]) {
+        for text in accepted {
+            assert!(matcher.is_match(text), "{matcher:?} should accept {text:?}");
+        }
+        for text in rejected {
+            assert!(
+                !matcher.is_match(text),
+                "{matcher:?} should reject {text:?}"
+            );
+        }
+    }
+
+    /// `으어어` with its syllables decomposed into jamo.
+    const NFD: &str = "\u{110B}\u{1173}\u{110B}\u{1165}\u{110B}\u{1165}";
+
+    #[test]
+    fn test_exact() {
+        check(
+            &matcher(serde_json::json!({ "type": "exact", "text": "으어어" })),
+            &["으어어"],
+            &["으어어어", "으어어…", " 으어어", NFD, "으어"],
+        );
+    }
+
+    #[test]
+    fn test_normalized() {
+        check(
+            &matcher(serde_json::json!({ "type": "normalized", "text": "으어어" })),
+            &[
+                "으어어",
+                "으어어…",
+                "으어어!!",
+                "으 어 어",
+                "으어어~",
+                "으어어\u{3000}",
+                "으어어！",
+                "「으어어」",
+                "으어어🥲",
+                NFD,
+            ],
+            &["으어어어", "으어어 ㅠㅠ", "으어", "으어어 좋아", ""],
+        );
+        check(
+            &matcher(serde_json::json!({
+                "type": "normalized",
+                "text": "으어어",
+                "form": "nfc",
+                "strip_whitespace": false,
+                "strip_punctuation": false,
+            })),
+            &["으어어", NFD],
+            &["으어어…", "으 어 어", "으어어！"],
+        );
+        // Full-width letters are the same as others only in NFKC.
+        let nfkc = matcher(serde_json::json!({ "type": "normalized", "text": "eueoeo" }));
+        let nfc =
+            matcher(serde_json::json!({ "type": "normalized", "text": "eueoeo", "form": "nfc" }));
+        check(&nfkc, &["ｅｕｅｏｅｏ", "eueoeo."], &["EUEOEO"]);
+        check(&nfc, &["eueoeo."], &["ｅｕｅｏｅｏ"]);
+    }
+
+    #[test]
+    fn test_regex() {
+        check(
+            &matcher(serde_json::json!({ "type": "regex", "pattern": "^으어어+\\s*[ㅠㅜ]*$" })),
+            &["으어어", "으어어어", "으어어 ㅠㅠ", "으어어ㅜ", NFD],
+            &["으어", "으어어…", "아 으어어", "으어어 ㅋㅋ"],
+        );
+        let e = serde_json::from_value::<Matcher>(
+            serde_json::json!({ "type": "regex", "pattern": "으(어" }),
+        )
+        .unwrap_err();
+        assert!(e.to_string().contains("regex parse error"), "{e}");
+    }
+
+    #[test]
+    fn test_hangul() {
+        check(
+            &matcher(serde_json::json!({ "type": "hangul", "pattern": "으+어{2,}" })),
+            &[
+                "으어어",
+                "으어어어어",
+                "으으어어",
+                "으어ㅓㅓ",
+                "으어어ㅓ",
+                "으어어…",
+                "으 어 어!",
+                "으어어\u{1165}",
+                NFD,
+            ],
+            &[
+                "으어",
+                "어어",
+                "으ㅓㅓ",
+                "으어어 ㅠㅠ",
+                "으엉어",
+                "으어어요",
+                "아으어어",
+                "",
+            ],
+        );
+        check(
+            &matcher(serde_json::json!({ "type": "hangul", "pattern": "아?으어{1,2}ㅠ*" })),
+            &["으어", "아으어어", "으어ㅠㅠ", "으어ㅓ"],
+            &["으어어어", "아아으어", "으어ㅠㅋ"],
+        );
+        // Syllables with a final consonant are not drawn out by their vowel.
+        check(
+            &matcher(serde_json::json!({ "type": "hangul", "pattern": "엉+" })),
+            &["엉", "엉엉엉"],
+            &["엉ㅓ", "어"],
+        );
+
+        for pattern in ["+어", "으{2", "으{a}", "으{3,1}", "으{0}", "으 어", ""] {
+            let ret = Matcher::hangul(pattern);
+            if pattern.is_empty() {
+                // An empty pattern takes only posts without any letter.
+                assert!(ret.unwrap().is_match("…"));
+            } else {
+                assert!(ret.is_err(), "{pattern:?} should be invalid");
+            }
+        }
+    }
+}
@@ -2,6 +2,7 @@ use axum::Router;
 
 use crate::{algos::AlgoHandlers, config::Config};
 
+mod health;
 mod stream;
 mod well_known;
 mod xrpc;
@@ -14,4 +15,5 @@ pub fn create_router<S: Clone + Send + Sync + 'static>(
         .nest("/.well-known", well_known::create_router(config))
         .nest("/xrpc", xrpc::create_router(config, algos))
         .nest("/stream", stream::create_router())
+        .nest("/health", health::create_router())
 }
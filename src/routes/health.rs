@@ -0,0 +1,42 @@
+use std::sync::Arc;
+
+use axum::{
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    routing::get,
+    Extension, Json, Router,
+};
+use eueoeo_feed::atproto_subscription::{continuity, pipeline::PipelineMetrics};
+use log::error;
+use sqlx::SqlitePool;
+
+async fn health(
+    Extension(db): Extension<SqlitePool>,
+    Extension(metrics): Extension<Arc<PipelineMetrics>>,
+) -> Response {
+    match continuity::desynced_repos(&db).await {
+        Ok(desynced_repos) => (
+            StatusCode::OK,
+            Json(serde_json::json!({
+                "subscription": metrics.snapshot(),
+                "desyncedRepos": desynced_repos.len(),
+            })),
+        ),
+        Err(e) => {
+            error!("Failed to get desynced repos - {e:?}");
+
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(serde_json::json!({
+                    "error": "InternalServerError",
+                    "message": "Error: Internal server error",
+                })),
+            )
+        }
+    }
+    .into_response()
+}
+
+pub fn create_router<S: Clone + Send + Sync + 'static>() -> Router<S> {
+    Router::new().route("/", get(health))
+}
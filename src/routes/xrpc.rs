@@ -13,9 +13,12 @@ use sqlx::SqlitePool;
 use crate::{
     algos::{AlgoHandlers, Context},
     config::Config,
-    lexicon::{app::bsky::feed::get_feed_skeleton, AtUri},
+    lexicon::{app::bsky::feed::get_feed_skeleton, identifier::AtIdentifier, AtUri},
 };
 
+/// Collection of feed generator records, which `feed` of a request points to.
+const FEED_GENERATOR: &str = "app.bsky.feed.generator";
+
 async fn feed_generation(
     Extension(db): Extension<SqlitePool>,
     Extension(config): Extension<Arc<Config>>,
@@ -34,14 +37,11 @@ async fn feed_generation(
     };
 
     if let (true, true, Some(algo)) = (
-        feed_uri.authority == config.publisher_did,
-        feed_uri
-            .collection
-            .map(|c| c == "app.bsky.feed.generator")
-            .unwrap_or_default(),
+        matches!(&feed_uri.authority, AtIdentifier::Did(did) if *did == config.publisher_did),
+        feed_uri.collection.is_some_and(|c| c == FEED_GENERATOR),
         feed_uri.rkey.and_then(|name| algos.get(&name)),
     ) {
-        match algo.handle(Context { db, config }, params).await {
+        match algo.handle(Context { db }, params).await {
             Ok(body) => (StatusCode::OK, Json(serde_json::json!(body))),
             Err(e) => {
                 error!("Failed to generate feed - {e:?}");
@@ -75,8 +75,12 @@ pub fn create_router<S: Clone + Send + Sync + 'static>(
     let feeds = algos
         .keys()
         .map(|shortname| {
-            let uri =
-                AtUri::new(config.publisher_did.clone(), Some(shortname.clone()), None).to_string();
+            let uri = AtUri::new(
+                config.publisher_did.clone(),
+                Some(FEED_GENERATOR.parse().unwrap()),
+                Some(shortname.clone()),
+            )
+            .to_string();
             serde_json::json!({ "uri": uri })
         })
         .collect::<Vec<_>>();
@@ -1,57 +1,50 @@
+use std::sync::Arc;
+
 use async_trait::async_trait;
-use crossbeam::channel::Sender;
 use log::{debug, warn};
-use sqlx::SqlitePool;
+use sqlx::SqliteConnection;
 
 use crate::{
     atproto_subscription::FirehoseSubscriptionHandler,
+    config::FeedConfig,
     lexicon::{
-        com::atproto::sync::subscribe_repos::{OutputSchema as RepoEvent, Record, RepoOpAction},
-        AtUri,
+        app::bsky,
+        com::atproto::sync::subscribe_repos::{OutputSchema as RepoEvent, RepoOpAction},
+        identifier::Did,
+        AtUri, Record,
     },
 };
 
-#[serde_with::serde_as]
-#[derive(Clone, serde::Deserialize, serde::Serialize)]
-pub struct UserProfile {
-    pub name: String,
-    pub avatar: String,
-    #[serde_as(as = "serde_with::TimestampSeconds<i64>")]
-    pub last_cached: chrono::DateTime<chrono::Utc>,
-}
-
 #[derive(Clone)]
 pub struct ServiceSubscriptionHandler {
-    db: SqlitePool,
-    sse_sender: Sender<UserProfile>,
-    user_profiles: scc::hash_map::HashMap<String, UserProfile>,
+    feeds: Arc<[FeedConfig]>,
 }
 
 impl ServiceSubscriptionHandler {
-    pub fn new(db: SqlitePool, sse_sender: Sender<UserProfile>) -> Self {
+    pub fn new(feeds: Vec<FeedConfig>) -> Self {
         Self {
-            db,
-            sse_sender,
-            user_profiles: scc::hash_map::HashMap::new(),
+            feeds: feeds.into(),
         }
     }
 }
 
 #[async_trait]
 impl FirehoseSubscriptionHandler for ServiceSubscriptionHandler {
-    async fn handle_event(&self, event: RepoEvent) -> anyhow::Result<()> {
-        let RepoEvent::Commit(event) = event else {
-            return Ok(());
-        };
+    fn watched_collections(&self) -> Option<Vec<String>> {
+        Some(vec![bsky::feed::post::ID.to_string()])
+    }
 
-        let Some(blocks) = event.blocks else {
-            debug!("drop no-blocks commit event");
+    async fn handle_event(
+        &self,
+        conn: &mut SqliteConnection,
+        event: RepoEvent,
+    ) -> anyhow::Result<()> {
+        let RepoEvent::Commit(event) = event else {
             return Ok(());
         };
 
-        let blocks = blocks.parse().await?;
-
         let author = event.repo;
+        let did = author.parse::<Did>()?;
 
         for op in event.ops {
             match op.action {
@@ -59,18 +52,38 @@ impl FirehoseSubscriptionHandler for ServiceSubscriptionHandler {
                     let Some(cid) = &op.cid else {
                         continue;
                     };
+                    // Commits too big carry no block, unless their records are fetched since.
+                    if event.blocks.raw().is_empty() {
+                        continue;
+                    }
+                    let blocks = event.blocks.parse()?;
                     let Some(block) = blocks.get(cid) else {
-                        warn!(
-                            "Could not find block of cid({cid}) on op. block_keys: {}",
-                            itertools::join(blocks.keys(), ", ")
-                        );
+                        // Records gone before they are fetched are left out.
+                        if !event.too_big {
+                            warn!(
+                                "Could not find block of cid({cid}) on op. block_keys: {}",
+                                itertools::join(blocks.keys(), ", ")
+                            );
+                        }
                         continue;
                     };
-                    let item = block?;
+                    // A record which can't be read leaves out the post alone, not the whole commit.
+                    let item = match block {
+                        Ok(item) => item,
+                        Err(e) => {
+                            warn!("Failed to read record of {author}/{} - {e}", op.path);
+                            continue;
+                        }
+                    };
                     if let Record::Post(post) = item {
                         debug!(r#"new post [{}] - """{}""""#, author, post.text);
-                        if post.text == "으어어" {
-                            let uri = AtUri::with_auth_path(author.clone(), op.path).to_string();
+                        let feeds = self
+                            .feeds
+                            .iter()
+                            .filter(|feed| feed.matchers.iter().any(|m| m.is_match(&post.text)))
+                            .collect::<Vec<_>>();
+                        if !feeds.is_empty() {
+                            let uri = AtUri::with_auth_path(did.clone(), &op.path)?.to_string();
                             let cid = cid.to_string();
                             let now = chrono::Utc::now().to_rfc3339();
                             sqlx::query!(
@@ -86,18 +99,32 @@ impl FirehoseSubscriptionHandler for ServiceSubscriptionHandler {
                                 author,
                                 now
                             )
-                            .execute(&self.db)
+                            .execute(&mut *conn)
                             .await?;
-
-                            // self.sse_sender.send(event.repo.clone())?;
+                            for feed in feeds {
+                                let shortname = feed.shortname.as_str();
+                                sqlx::query!(
+                                    r#"
+                                    INSERT INTO `feed_post` (`feed`, `uri`) VALUES (?, ?)
+                                        ON CONFLICT DO NOTHING
+                                "#,
+                                    shortname,
+                                    uri
+                                )
+                                .execute(&mut *conn)
+                                .await?;
+                            }
                         }
                     }
                 }
-                RepoOpAction::Update => { /* Not supported yet */ }
+                RepoOpAction::Update | RepoOpAction::Other(_) => { /* Not supported yet */ }
                 RepoOpAction::Delete => {
-                    let uri = AtUri::with_auth_path(author.clone(), op.path).to_string();
+                    let uri = AtUri::with_auth_path(did.clone(), &op.path)?.to_string();
                     sqlx::query!("DELETE FROM `post` where uri = ?", uri)
-                        .execute(&self.db)
+                        .execute(&mut *conn)
+                        .await?;
+                    sqlx::query!("DELETE FROM `feed_post` where uri = ?", uri)
+                        .execute(&mut *conn)
                         .await?;
                 }
             }
@@ -106,3 +133,279 @@ impl FirehoseSubscriptionHandler for ServiceSubscriptionHandler {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, time::Duration};
+
+    use eueoeo_feed::{
+        atproto_subscription::{
+            dead_letter,
+            mock_relay::{self, memory_db, records, MockPds, MockRelay, Op},
+            too_big::{FetchPolicy, RecordFetcher},
+            FirehoseSubscription, FlushPolicy, ReconnectPolicy, Source, SubscriptionMessage,
+        },
+        identity::{DidDocument, PublicKey, StubDidResolver},
+    };
+    use sqlx::SqlitePool;
+    use tokio::sync::watch;
+
+    use super::*;
+    use crate::config::Config;
+
+    const AUTHOR: &str = "did:plc:author";
+
+    async fn wait_for_posts(db: &SqlitePool, expected: &[&str]) {
+        let ret = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                let posts: Vec<String> =
+                    sqlx::query_scalar("SELECT `uri` FROM `post` ORDER BY `uri`")
+                        .fetch_all(db)
+                        .await
+                        .unwrap();
+                if posts == expected {
+                    break;
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await;
+        assert!(ret.is_ok(), "posts did not become {expected:?}");
+    }
+
+    #[tokio::test]
+    async fn test_index_from_relay() {
+        let feeds = serde_json::from_value(serde_json::json!([
+            { "shortname": "eueoeo", "matchers": [{ "type": "hangul", "pattern": "으+어{2,}" }] },
+            { "shortname": "kkk", "matchers": [{ "type": "hangul", "pattern": "ㅋ{3,}" }] },
+        ]))
+        .unwrap();
+        let relay = MockRelay::start().await.unwrap();
+        let db = memory_db().await;
+        let (stop_tx, _) = watch::channel(false);
+        let stop_tx = Arc::new(stop_tx);
+        let subscription = FirehoseSubscription::new(
+            db.clone(),
+            relay.endpoint(),
+            Source::Firehose,
+            ServiceSubscriptionHandler::new(feeds),
+            ReconnectPolicy::default(),
+            FlushPolicy {
+                max_events: 100,
+                max_interval: Duration::from_millis(10),
+            },
+            stop_tx.clone(),
+        )
+        .await
+        .unwrap();
+        let join = subscription.run().unwrap();
+        relay.wait_for_connections(1).await;
+
+        let subject = format!("at://{AUTHOR}/app.bsky.feed.post/1");
+        relay.push(
+            1,
+            mock_relay::commit(
+                1,
+                AUTHOR,
+                "3kaaaaaaaaa22",
+                vec![
+                    Op::Create {
+                        path: "app.bsky.feed.post/1".to_string(),
+                        record: records::post("으어어"),
+                    },
+                    Op::Create {
+                        path: "app.bsky.feed.post/2".to_string(),
+                        record: records::post("hello"),
+                    },
+                    Op::Create {
+                        path: "app.bsky.feed.post/3".to_string(),
+                        record: records::post("으어ㅓㅓ…"),
+                    },
+                    Op::Create {
+                        path: "app.bsky.feed.post/4".to_string(),
+                        record: records::post("ㅋㅋㅋㅋ!"),
+                    },
+                    Op::Create {
+                        path: "app.bsky.feed.like/1".to_string(),
+                        record: records::like(&subject, "bafyreia"),
+                    },
+                    Op::Create {
+                        path: "app.bsky.feed.repost/1".to_string(),
+                        record: records::repost(&subject, "bafyreia"),
+                    },
+                    Op::Create {
+                        path: "app.bsky.graph.follow/1".to_string(),
+                        record: records::follow("did:plc:other"),
+                    },
+                ],
+            ),
+        );
+        relay.push(2, mock_relay::identity(2, AUTHOR, Some("author.test")));
+        relay.push(3, mock_relay::account(3, AUTHOR, true, None));
+        relay.push(4, mock_relay::tombstone(4, "did:plc:gone"));
+        relay.send(mock_relay::info("OutdatedCursor", None));
+        let drawn_out = format!("at://{AUTHOR}/app.bsky.feed.post/3");
+        let laughing = format!("at://{AUTHOR}/app.bsky.feed.post/4");
+        wait_for_posts(&db, &[&subject, &drawn_out, &laughing]).await;
+
+        relay.push(
+            5,
+            mock_relay::commit(
+                5,
+                AUTHOR,
+                "3kaaaaaaaaa23",
+                vec![Op::Delete {
+                    path: "app.bsky.feed.post/1".to_string(),
+                }],
+            ),
+        );
+        wait_for_posts(&db, &[&drawn_out, &laughing]).await;
+        let feed_posts: Vec<(String, String)> =
+            sqlx::query_as("SELECT `feed`, `uri` FROM `feed_post` ORDER BY `uri`")
+                .fetch_all(&db)
+                .await
+                .unwrap();
+        assert_eq!(
+            feed_posts,
+            [
+                ("eueoeo".to_string(), drawn_out),
+                ("kkk".to_string(), laughing)
+            ]
+        );
+
+        stop_tx.send(true).unwrap();
+        join.await.unwrap().unwrap();
+        let cursor: String = sqlx::query_scalar("SELECT `value` FROM `app_state` WHERE `key` = ?")
+            .bind(format!("bsky_cursor:{}", relay.endpoint()))
+            .fetch_one(&db)
+            .await
+            .unwrap();
+        assert_eq!(cursor, "5");
+    }
+
+    #[tokio::test]
+    async fn test_index_too_big_commit() {
+        let pds = MockPds::start().await.unwrap();
+        pds.insert(AUTHOR, "app.bsky.feed.post/1", records::post("으어어"));
+        let key = k256::ecdsa::SigningKey::random(&mut rand::thread_rng());
+        let resolver = StubDidResolver::default();
+        resolver.insert(DidDocument::new(
+            AUTHOR,
+            &PublicKey::K256(*key.verifying_key()),
+            Some(&pds.endpoint()),
+        ));
+
+        let db = memory_db().await;
+        let mut subscription = FirehoseSubscription::new(
+            db.clone(),
+            String::new(),
+            Source::Firehose,
+            ServiceSubscriptionHandler::new(serde_json::from_str::<Config>("{}").unwrap().feeds),
+            ReconnectPolicy::default(),
+            FlushPolicy::default(),
+            Arc::new(watch::channel(false).0),
+        )
+        .await
+        .unwrap()
+        .with_record_fetcher(
+            RecordFetcher::new(Arc::new(resolver), FetchPolicy::default())
+                .unwrap()
+                .allow_private_hosts()
+                .unwrap(),
+        );
+        let frame = mock_relay::too_big_commit(
+            1,
+            AUTHOR,
+            "3kaaaaaaaaa22",
+            vec![Op::Create {
+                path: "app.bsky.feed.post/1".to_string(),
+                record: records::post("으어어"),
+            }],
+        );
+        subscription
+            .replay(futures_util::stream::iter([Ok(
+                tokio_tungstenite::tungstenite::Message::Binary(frame),
+            )]))
+            .await
+            .unwrap();
+
+        wait_for_posts(&db, &[&format!("at://{AUTHOR}/app.bsky.feed.post/1")]).await;
+        // Kept as a dead letter only until the records are fetched.
+        assert!(dead_letter::list(&db).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_skip_commits_without_blocks() {
+        let db = memory_db().await;
+        let handler =
+            ServiceSubscriptionHandler::new(serde_json::from_str::<Config>("{}").unwrap().feeds);
+        let ops = || {
+            vec![Op::Create {
+                path: "app.bsky.feed.post/1".to_string(),
+                record: records::post("으어어"),
+            }]
+        };
+        let event = |frame: Vec<u8>| {
+            let SubscriptionMessage::Message(event) =
+                FirehoseSubscription::<ServiceSubscriptionHandler>::parse_message(&frame).unwrap()
+            else {
+                panic!("expected event");
+            };
+            event
+        };
+
+        // Records of a commit too big are not fetched.
+        let too_big = event(mock_relay::too_big_commit(
+            1,
+            AUTHOR,
+            "3kaaaaaaaaa22",
+            ops(),
+        ));
+        let mut empty = event(mock_relay::commit(2, AUTHOR, "3kaaaaaaaaa23", ops()));
+        let RepoEvent::Commit(commit) = &mut empty else {
+            panic!("expected commit");
+        };
+        commit.blocks = serde_bytes::ByteBuf::new().into();
+
+        let mut conn = db.acquire().await.unwrap();
+        handler.handle_event(&mut conn, too_big).await.unwrap();
+        handler.handle_event(&mut conn, empty).await.unwrap();
+        drop(conn);
+        wait_for_posts(&db, &[]).await;
+    }
+
+    #[tokio::test]
+    async fn test_skip_unreadable_records() {
+        let feeds = serde_json::from_value(serde_json::json!([
+            { "shortname": "eueoeo", "matchers": [{ "type": "hangul", "pattern": "으+어{2,}" }] },
+        ]))
+        .unwrap();
+        let db = memory_db().await;
+        let handler = ServiceSubscriptionHandler::new(feeds);
+        let frame = mock_relay::commit(
+            1,
+            AUTHOR,
+            "3kaaaaaaaaa22",
+            vec![
+                Op::Create {
+                    path: "app.bsky.feed.post/1".to_string(),
+                    record: serde_json::json!({ "$type": "app.bsky.feed.post", "text": 1 }),
+                },
+                Op::Create {
+                    path: "app.bsky.feed.post/2".to_string(),
+                    record: records::post("으어어"),
+                },
+            ],
+        );
+        let SubscriptionMessage::Message(event) =
+            FirehoseSubscription::<ServiceSubscriptionHandler>::parse_message(&frame).unwrap()
+        else {
+            panic!("expected event");
+        };
+
+        let mut conn = db.acquire().await.unwrap();
+        handler.handle_event(&mut conn, event).await.unwrap();
+        drop(conn);
+        wait_for_posts(&db, &[&format!("at://{AUTHOR}/app.bsky.feed.post/2")]).await;
+    }
+}
@@ -0,0 +1,739 @@
+//! Client of XRPC, the HTTP API of atproto services, logged in to an account of a PDS if needed.
+//!
+//! Requests are sent with the access token of the session. When the PDS tells it has expired,
+//! the session is refreshed once with the refresh token and the request is sent again.
+
+use std::{sync::RwLock, time::Duration};
+
+use anyhow::{anyhow, Context};
+use reqwest::{Method, RequestBuilder, StatusCode};
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::lexicon::{
+    car::CommitRawBlocks,
+    com::atproto::{
+        repo::{delete_record, get_record, put_record},
+        server::{create_session, refresh_session},
+        sync::get_repo,
+    },
+    identifier::{AtIdentifier, Did, Handle, IdentifierError, Nsid, RecordKey, Tid},
+    Record,
+};
+
+/// Tokens of an account logged in to its PDS.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Session {
+    pub did: Did,
+    pub handle: Handle,
+    pub access_jwt: String,
+    pub refresh_jwt: String,
+}
+
+impl Session {
+    fn new(
+        did: &str,
+        handle: &str,
+        access_jwt: String,
+        refresh_jwt: String,
+    ) -> Result<Self, XrpcError> {
+        Ok(Self {
+            did: did.parse()?,
+            handle: handle.parse()?,
+            access_jwt,
+            refresh_jwt,
+        })
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum XrpcError {
+    /// Error response, with the error name and message of its body if any.
+    #[error("XRPC responded {status} - {}: {}", .error.as_deref().unwrap_or("Unknown"), .message.as_deref().unwrap_or_default())]
+    Response {
+        status: StatusCode,
+        error: Option<String>,
+        message: Option<String>,
+    },
+    #[error("Not logged in")]
+    NoSession,
+    #[error("Record has no $type to tell its collection")]
+    UntypedRecord,
+    #[error("XRPC response stalled for {0:?}")]
+    Stalled(Duration),
+    #[error("Repository is larger than {0} bytes")]
+    RepoTooLarge(usize),
+    #[error(transparent)]
+    Http(#[from] reqwest::Error),
+    #[error("Invalid JSON of XRPC - {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("Invalid identifier in XRPC response - {0}")]
+    Identifier(#[from] IdentifierError),
+}
+
+impl XrpcError {
+    /// Name of the error told by the service, e.g. `RecordNotFound`.
+    pub fn error(&self) -> Option<&str> {
+        match self {
+            XrpcError::Response { error, .. } => error.as_deref(),
+            _ => None,
+        }
+    }
+}
+
+#[derive(serde::Deserialize)]
+struct ErrorBody {
+    error: Option<String>,
+    message: Option<String>,
+}
+
+/// Record read by [`XrpcClient::get_record`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct StoredRecord {
+    pub uri: String,
+    pub cid: Option<String>,
+    pub record: Record,
+}
+
+pub struct XrpcClient {
+    client: reqwest::Client,
+    host: url::Url,
+    session: RwLock<Option<Session>>,
+    /// Held while the session is refreshed, so concurrent requests refresh it only once.
+    refreshing: tokio::sync::Mutex<()>,
+    /// Bytes of a CAR file `getRepo` reads at most.
+    max_repo_len: usize,
+}
+
+/// Time given to the whole of a request whose response is small, i.e. all but `getRepo`.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+/// Time `getRepo` may go without receiving any of the CAR file, which has no total timeout
+/// as a large repository takes long to download.
+const READ_TIMEOUT: Duration = Duration::from_secs(30);
+/// Bytes of a CAR file `getRepo` reads at most by default, as the whole of it is kept in memory.
+const MAX_REPO_LEN: usize = 1 << 30;
+
+impl XrpcClient {
+    pub fn new(host: &str) -> anyhow::Result<Self> {
+        let host = url::Url::parse(host).with_context(|| format!("Invalid XRPC host - {host}"))?;
+        if host.cannot_be_a_base() {
+            return Err(anyhow!("Not a valid XRPC host - {host}"));
+        }
+
+        Ok(Self {
+            client: reqwest::Client::builder()
+                .connect_timeout(REQUEST_TIMEOUT)
+                .build()?,
+            host,
+            session: RwLock::new(None),
+            refreshing: Default::default(),
+            max_repo_len: MAX_REPO_LEN,
+        })
+    }
+
+    /// Read CAR files of `getRepo` up to `len` bytes, failing with [`XrpcError::RepoTooLarge`] past it.
+    pub fn with_max_repo_len(mut self, len: usize) -> Self {
+        self.max_repo_len = len;
+        self
+    }
+
+    /// Resume a session kept from before.
+    pub fn with_session(self, session: Session) -> Self {
+        *self.session.write().unwrap() = Some(session);
+        self
+    }
+
+    /// Current session, which changes as it is refreshed.
+    pub fn session(&self) -> Option<Session> {
+        self.session.read().unwrap().clone()
+    }
+
+    /// Send a query, with `params` as the query string.
+    pub async fn query<P: Serialize + ?Sized, O: DeserializeOwned>(
+        &self,
+        nsid: &str,
+        params: &P,
+    ) -> Result<O, XrpcError> {
+        let response = self
+            .call(Method::GET, nsid, Some(REQUEST_TIMEOUT), |request| {
+                request.query(params)
+            })
+            .await?;
+        decode(response).await
+    }
+
+    /// Send a procedure, with `input` as the JSON body.
+    pub async fn procedure<I: Serialize + ?Sized, O: DeserializeOwned>(
+        &self,
+        nsid: &str,
+        input: &I,
+    ) -> Result<O, XrpcError> {
+        let response = self
+            .call(Method::POST, nsid, Some(REQUEST_TIMEOUT), |request| {
+                request.json(input)
+            })
+            .await?;
+        decode(response).await
+    }
+
+    /// Log in with a handle or another identifier, and keep the session for later requests.
+    pub async fn create_session(
+        &self,
+        identifier: &str,
+        password: &str,
+    ) -> Result<Session, XrpcError> {
+        let input = create_session::InputSchema {
+            identifier: identifier.to_string(),
+            password: password.to_string(),
+            auth_factor_token: None,
+            allow_takendown: None,
+        };
+        let response = self
+            .send(
+                Method::POST,
+                create_session::ID,
+                None,
+                Some(REQUEST_TIMEOUT),
+                &|request| request.json(&input),
+            )
+            .await?;
+        let output: create_session::OutputSchema = decode(response).await?;
+        let session = Session::new(
+            &output.did,
+            &output.handle,
+            output.access_jwt,
+            output.refresh_jwt,
+        )?;
+        *self.session.write().unwrap() = Some(session.clone());
+
+        Ok(session)
+    }
+
+    /// Replace the tokens of the session with new ones.
+    pub async fn refresh_session(&self) -> Result<Session, XrpcError> {
+        let _refreshing = self.refreshing.lock().await;
+        let session = self.session().ok_or(XrpcError::NoSession)?;
+        self.refresh_with(&session.refresh_jwt).await
+    }
+
+    /// Create or update a record of the logged in account.
+    pub async fn put_record(
+        &self,
+        rkey: &RecordKey,
+        record: &Record,
+    ) -> Result<put_record::OutputSchema, XrpcError> {
+        let session = self.session().ok_or(XrpcError::NoSession)?;
+        let input = put_record::InputSchema {
+            repo: session.did.into(),
+            collection: record.nsid().ok_or(XrpcError::UntypedRecord)?.to_string(),
+            rkey: rkey.to_string(),
+            validate: None,
+            record: serde_json::to_value(record)?,
+            swap_record: None,
+            swap_commit: None,
+        };
+        self.procedure(put_record::ID, &input).await
+    }
+
+    /// Record of any repository, or `None` when there is no such record.
+    pub async fn get_record(
+        &self,
+        repo: &AtIdentifier,
+        collection: &Nsid,
+        rkey: &RecordKey,
+    ) -> Result<Option<StoredRecord>, XrpcError> {
+        let params = get_record::QueryParams {
+            repo: repo.to_string(),
+            collection: collection.to_string(),
+            rkey: rkey.to_string(),
+            cid: None,
+        };
+        let output: get_record::OutputSchema = match self.query(get_record::ID, &params).await {
+            Ok(output) => output,
+            Err(e) if e.error() == Some("RecordNotFound") => return Ok(None),
+            Err(e) => return Err(e),
+        };
+
+        Ok(Some(StoredRecord {
+            uri: output.uri,
+            cid: output.cid,
+            record: serde_json::from_value(output.value)?,
+        }))
+    }
+
+    /// Delete a record of the logged in account. Deleting a record that doesn't exist succeeds.
+    pub async fn delete_record(
+        &self,
+        collection: &Nsid,
+        rkey: &RecordKey,
+    ) -> Result<delete_record::OutputSchema, XrpcError> {
+        let session = self.session().ok_or(XrpcError::NoSession)?;
+        let input = delete_record::InputSchema {
+            repo: session.did.into(),
+            collection: collection.to_string(),
+            rkey: rkey.to_string(),
+            swap_record: None,
+            swap_commit: None,
+        };
+        self.procedure(delete_record::ID, &input).await
+    }
+
+    /// CAR file of the whole repository, or of what changed after revision `since`.
+    /// It is read chunk by chunk with no total timeout, failing only if it stalls for [`READ_TIMEOUT`].
+    pub async fn get_repo(
+        &self,
+        did: &Did,
+        since: Option<&Tid>,
+    ) -> Result<CommitRawBlocks, XrpcError> {
+        let params = get_repo::QueryParams {
+            did: did.to_string(),
+            since: since.map(ToString::to_string),
+        };
+        let mut response = self
+            .call(Method::GET, get_repo::ID, None, |request| {
+                request.query(&params)
+            })
+            .await?;
+        let too_large = XrpcError::RepoTooLarge(self.max_repo_len);
+        if response
+            .content_length()
+            .is_some_and(|len| len > self.max_repo_len as u64)
+        {
+            return Err(too_large);
+        }
+        let mut car = Vec::new();
+        while let Some(chunk) = tokio::time::timeout(READ_TIMEOUT, response.chunk())
+            .await
+            .map_err(|_| XrpcError::Stalled(READ_TIMEOUT))??
+        {
+            if car.len() + chunk.len() > self.max_repo_len {
+                return Err(too_large);
+            }
+            car.extend_from_slice(&chunk);
+        }
+
+        Ok(serde_bytes::ByteBuf::from(car).into())
+    }
+
+    /// Send a request with the access token, refreshing the session once if the token has expired.
+    /// `timeout` bounds the whole request, including reading the response.
+    async fn call(
+        &self,
+        method: Method,
+        nsid: &str,
+        timeout: Option<Duration>,
+        build: impl Fn(RequestBuilder) -> RequestBuilder,
+    ) -> Result<reqwest::Response, XrpcError> {
+        let token = self.session().map(|session| session.access_jwt);
+        let ret = self
+            .send(method.clone(), nsid, token.as_deref(), timeout, &build)
+            .await;
+        match (ret, token) {
+            (Err(e), Some(stale)) if e.error() == Some("ExpiredToken") => {
+                self.refresh(&stale).await?;
+                let token = self.session().map(|session| session.access_jwt);
+                self.send(method, nsid, token.as_deref(), timeout, &build)
+                    .await
+            }
+            (ret, _) => ret,
+        }
+    }
+
+    /// Refresh the session unless it has been refreshed since `stale` was taken.
+    async fn refresh(&self, stale: &str) -> Result<(), XrpcError> {
+        let _refreshing = self.refreshing.lock().await;
+        let session = self.session().ok_or(XrpcError::NoSession)?;
+        if session.access_jwt != stale {
+            return Ok(());
+        }
+        self.refresh_with(&session.refresh_jwt).await.map(|_| ())
+    }
+
+    async fn refresh_with(&self, refresh_jwt: &str) -> Result<Session, XrpcError> {
+        let response = self
+            .send(
+                Method::POST,
+                refresh_session::ID,
+                Some(refresh_jwt),
+                Some(REQUEST_TIMEOUT),
+                &|request| request,
+            )
+            .await?;
+        let output: refresh_session::OutputSchema = decode(response).await?;
+        let session = Session::new(
+            &output.did,
+            &output.handle,
+            output.access_jwt,
+            output.refresh_jwt,
+        )?;
+        *self.session.write().unwrap() = Some(session.clone());
+
+        Ok(session)
+    }
+
+    async fn send(
+        &self,
+        method: Method,
+        nsid: &str,
+        token: Option<&str>,
+        timeout: Option<Duration>,
+        build: &impl Fn(RequestBuilder) -> RequestBuilder,
+    ) -> Result<reqwest::Response, XrpcError> {
+        let mut url = self.host.clone();
+        url.path_segments_mut()
+            .expect("XRPC host is checked to be a base")
+            .pop_if_empty()
+            .push("xrpc")
+            .push(nsid);
+        let mut request = build(self.client.request(method, url));
+        if let Some(token) = token {
+            request = request.bearer_auth(token);
+        }
+        if let Some(timeout) = timeout {
+            request = request.timeout(timeout);
+        }
+
+        let response = request.send().await?;
+        let status = response.status();
+        if status.is_success() {
+            return Ok(response);
+        }
+        let body = response.json::<ErrorBody>().await.ok();
+        let (error, message) = body.map(|b| (b.error, b.message)).unwrap_or_default();
+        Err(XrpcError::Response {
+            status,
+            error,
+            message,
+        })
+    }
+}
+
+/// Output of a query or procedure. An empty body is taken as an empty object,
+/// as some services answer so to procedures of which all output is optional.
+async fn decode<O: DeserializeOwned>(response: reqwest::Response) -> Result<O, XrpcError> {
+    let body = response.bytes().await?;
+    let body = if body.is_empty() { &b"{}"[..] } else { &body };
+    Ok(serde_json::from_slice(body)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::BTreeMap,
+        sync::{Arc, Mutex},
+    };
+
+    use axum::{
+        extract::{Query, State},
+        http::HeaderMap,
+        response::{IntoResponse, Response},
+        routing::{get, post},
+        Json,
+    };
+    use serde_json::json;
+
+    use super::*;
+    use crate::{
+        atproto_subscription::mock_relay::{cid_for, records},
+        lexicon::{app::bsky::feed::post, data},
+    };
+
+    const DID: &str = "did:plc:alice";
+
+    /// PDS of a single account, which rotates its numbered tokens on each refresh.
+    #[derive(Default)]
+    struct Pds {
+        access: u32,
+        refresh: u32,
+        refreshes: u32,
+        records: BTreeMap<(String, String), serde_json::Value>,
+    }
+
+    impl Pds {
+        fn tokens(&self) -> (String, String) {
+            (
+                format!("access-{}", self.access),
+                format!("refresh-{}", self.refresh),
+            )
+        }
+
+        fn session(&self) -> serde_json::Value {
+            let (access, refresh) = self.tokens();
+            json!({ "did": DID, "handle": "alice.test", "accessJwt": access, "refreshJwt": refresh })
+        }
+    }
+
+    type Shared = Arc<Mutex<Pds>>;
+
+    fn error(status: StatusCode, error: &str) -> Response {
+        (
+            status,
+            Json(json!({ "error": error, "message": format!("{error}!") })),
+        )
+            .into_response()
+    }
+
+    fn bearer(headers: &HeaderMap) -> Option<&str> {
+        headers
+            .get("authorization")?
+            .to_str()
+            .ok()?
+            .strip_prefix("Bearer ")
+    }
+
+    /// Error response unless the access token is the current one.
+    /// Tokens of earlier numbers are expired.
+    fn unauthorized(pds: &Pds, headers: &HeaderMap) -> Option<Response> {
+        match bearer(headers) {
+            Some(token) if token == pds.tokens().0 => None,
+            Some(token) if token.starts_with("access-") => {
+                Some(error(StatusCode::BAD_REQUEST, "ExpiredToken"))
+            }
+            _ => Some(error(StatusCode::UNAUTHORIZED, "AuthenticationRequired")),
+        }
+    }
+
+    async fn serve(pds: Shared) -> String {
+        let app = axum::Router::new()
+            .route(
+                "/xrpc/com.atproto.server.createSession",
+                post(
+                    |State(pds): State<Shared>, Json(input): Json<serde_json::Value>| async move {
+                        if input["identifier"] != "alice.test" || input["password"] != "hunter2" {
+                            return error(StatusCode::UNAUTHORIZED, "AuthenticationRequired");
+                        }
+                        Json(pds.lock().unwrap().session()).into_response()
+                    },
+                ),
+            )
+            .route(
+                "/xrpc/com.atproto.server.refreshSession",
+                post(|State(pds): State<Shared>, headers: HeaderMap| async move {
+                    let mut pds = pds.lock().unwrap();
+                    if bearer(&headers) != Some(&pds.tokens().1) {
+                        return error(StatusCode::BAD_REQUEST, "ExpiredToken");
+                    }
+                    pds.access += 1;
+                    pds.refresh += 1;
+                    pds.refreshes += 1;
+                    Json(pds.session()).into_response()
+                }),
+            )
+            .route(
+                "/xrpc/com.atproto.repo.putRecord",
+                post(
+                    |State(pds): State<Shared>,
+                     headers: HeaderMap,
+                     Json(input): Json<serde_json::Value>| async move {
+                        let mut pds = pds.lock().unwrap();
+                        if let Some(response) = unauthorized(&pds, &headers) {
+                            return response;
+                        }
+                        let collection = input["collection"].as_str().unwrap().to_string();
+                        let rkey = input["rkey"].as_str().unwrap().to_string();
+                        assert_eq!(input["repo"], DID);
+                        assert_eq!(input["record"]["$type"], collection);
+                        let uri = format!("at://{DID}/{collection}/{rkey}");
+                        pds.records.insert((collection, rkey), input["record"].clone());
+                        Json(json!({ "uri": uri, "cid": "bafy" })).into_response()
+                    },
+                ),
+            )
+            .route(
+                "/xrpc/com.atproto.repo.getRecord",
+                get(
+                    |State(pds): State<Shared>,
+                     Query(params): Query<get_record::QueryParams>| async move {
+                        let pds = pds.lock().unwrap();
+                        match pds.records.get(&(params.collection, params.rkey)) {
+                            Some(value) => Json(json!({
+                                "uri": "at://did:plc:alice/app.bsky.feed.post/3l3qo2vuowo2b",
+                                "value": value,
+                            }))
+                            .into_response(),
+                            None => error(StatusCode::BAD_REQUEST, "RecordNotFound"),
+                        }
+                    },
+                ),
+            )
+            .route(
+                "/xrpc/com.atproto.repo.deleteRecord",
+                post(
+                    |State(pds): State<Shared>,
+                     headers: HeaderMap,
+                     Json(input): Json<delete_record::InputSchema>| async move {
+                        let mut pds = pds.lock().unwrap();
+                        if let Some(response) = unauthorized(&pds, &headers) {
+                            return response;
+                        }
+                        pds.records.remove(&(input.collection, input.rkey));
+                        // Older PDSes answer with nothing.
+                        StatusCode::OK.into_response()
+                    },
+                ),
+            )
+            .route(
+                "/xrpc/com.atproto.sync.getRepo",
+                get(
+                    |State(pds): State<Shared>,
+                     Query(params): Query<get_repo::QueryParams>| async move {
+                        if params.did != DID {
+                            return error(StatusCode::BAD_REQUEST, "RepoNotFound");
+                        }
+                        let pds = pds.lock().unwrap();
+                        let blocks = pds
+                            .records
+                            .values()
+                            .map(|value| {
+                                #[derive(serde::Serialize)]
+                                struct Data(
+                                    #[serde(serialize_with = "data::serialize")] serde_json::Value,
+                                );
+                                let block = serde_ipld_dagcbor::to_vec(&Data(value.clone())).unwrap();
+                                (cid_for(&block), block)
+                            })
+                            .collect::<Vec<_>>();
+                        let car = CommitRawBlocks::from_blocks(
+                            &[],
+                            blocks.iter().map(|(cid, block)| (cid, block.as_slice())),
+                        );
+                        car.raw().to_vec().into_response()
+                    },
+                ),
+            )
+            .with_state(pds);
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        format!("http://{addr}")
+    }
+
+    fn post_record(text: &str) -> Record {
+        serde_json::from_value(records::post(text)).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_records() {
+        let pds = Shared::default();
+        let client = XrpcClient::new(&serve(pds.clone()).await).unwrap();
+        let rkey = "3l3qo2vuowo2b".parse::<RecordKey>().unwrap();
+        let collection = post::ID.parse::<Nsid>().unwrap();
+        let repo = AtIdentifier::from(DID.parse::<Did>().unwrap());
+
+        assert!(matches!(
+            client.put_record(&rkey, &post_record("으어어")).await,
+            Err(XrpcError::NoSession)
+        ));
+        let e = client
+            .create_session("alice.test", "wrong")
+            .await
+            .unwrap_err();
+        assert_eq!(e.error(), Some("AuthenticationRequired"));
+        assert!(matches!(
+            e,
+            XrpcError::Response { status: StatusCode::UNAUTHORIZED, message: Some(ref message), .. }
+                if message == "AuthenticationRequired!"
+        ));
+
+        let session = client
+            .create_session("alice.test", "hunter2")
+            .await
+            .unwrap();
+        assert_eq!(session.did, DID);
+        assert_eq!(session.handle, "alice.test");
+        assert_eq!(client.session(), Some(session));
+
+        let output = client
+            .put_record(&rkey, &post_record("으어어"))
+            .await
+            .unwrap();
+        assert_eq!(output.uri, format!("at://{DID}/{}/{rkey}", post::ID));
+        let stored = client
+            .get_record(&repo, &collection, &rkey)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(stored.record, post_record("으어어"));
+        let untyped = Record::Unknown(json!({ "text": "으어어" }));
+        assert!(matches!(
+            client.put_record(&rkey, &untyped).await,
+            Err(XrpcError::UntypedRecord)
+        ));
+
+        let car = client.get_repo(&DID.parse().unwrap(), None).await.unwrap();
+        let blocks = car.parse().unwrap();
+        let [cid] = blocks.keys().collect::<Vec<_>>()[..] else {
+            panic!("expected a single block");
+        };
+        let Record::Post(record) = blocks.get(cid).unwrap().unwrap() else {
+            panic!("expected post");
+        };
+        assert_eq!(record.text, "으어어");
+        let small = XrpcClient::new(client.host.as_str())
+            .unwrap()
+            .with_max_repo_len(16);
+        assert!(matches!(
+            small.get_repo(&DID.parse().unwrap(), None).await,
+            Err(XrpcError::RepoTooLarge(16))
+        ));
+        let e = client
+            .get_repo(&"did:plc:bob".parse().unwrap(), None)
+            .await
+            .unwrap_err();
+        assert_eq!(e.error(), Some("RepoNotFound"));
+
+        client.delete_record(&collection, &rkey).await.unwrap();
+        assert!(client
+            .get_record(&repo, &collection, &rkey)
+            .await
+            .unwrap()
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn test_refresh_expired_token() {
+        let pds = Shared::default();
+        let client = Arc::new(XrpcClient::new(&serve(pds.clone()).await).unwrap());
+        client
+            .create_session("alice.test", "hunter2")
+            .await
+            .unwrap();
+        assert_eq!(
+            client.refresh_session().await.unwrap().access_jwt,
+            "access-1"
+        );
+
+        // The access token expires while the refresh token is still valid.
+        pds.lock().unwrap().access += 1;
+        let puts = ["3l3qo2vuowo2b", "3l3qo2vuowo2c", "3l3qo2vuowo2d"].map(|rkey| {
+            let client = client.clone();
+            tokio::spawn(async move {
+                client
+                    .put_record(&rkey.parse().unwrap(), &post_record(rkey))
+                    .await
+                    .map(|_| ())
+            })
+        });
+        for put in puts {
+            put.await.unwrap().unwrap();
+        }
+        let refreshes = pds.lock().unwrap().refreshes;
+        assert_eq!(refreshes, 2, "expired token should be refreshed once");
+        assert_eq!(client.session().unwrap().access_jwt, "access-3");
+        assert_eq!(client.session().unwrap().refresh_jwt, "refresh-2");
+        assert_eq!(pds.lock().unwrap().records.len(), 3);
+
+        // Without a valid refresh token, the expired token is the error.
+        {
+            let mut pds = pds.lock().unwrap();
+            pds.access += 1;
+            pds.refresh += 1;
+        }
+        let e = client
+            .put_record(&"3l3qo2vuowo2e".parse().unwrap(), &post_record("으어어"))
+            .await
+            .unwrap_err();
+        assert_eq!(e.error(), Some("ExpiredToken"));
+    }
+}